@@ -0,0 +1,15 @@
+-- Roll history
+CREATE TABLE rolls (
+    id TEXT PRIMARY KEY,
+    campaign_id TEXT NOT NULL REFERENCES campaigns(id) ON DELETE CASCADE,
+    character_id TEXT REFERENCES characters(id) ON DELETE SET NULL,
+    roll_kind TEXT NOT NULL, -- enum: dice, skill_check, saving_throw, attack, damage, initiative
+    roll_type TEXT NOT NULL, -- enum: normal, advantage, disadvantage, critical
+    dice_notation TEXT NOT NULL,
+    total INTEGER NOT NULL,
+    roll JSON NOT NULL,
+    created_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX idx_rolls_campaign_created ON rolls (campaign_id, created_at);
+CREATE INDEX idx_rolls_character_created ON rolls (character_id, created_at);
@@ -0,0 +1,16 @@
+-- Combat tracker
+CREATE TABLE combats (
+    id TEXT PRIMARY KEY,
+    campaign_id TEXT NOT NULL REFERENCES campaigns(id) ON DELETE CASCADE,
+    map_id TEXT REFERENCES maps(id) ON DELETE SET NULL,
+    name TEXT NOT NULL,
+    initiative_type TEXT NOT NULL, -- enum: individual, group, side
+    round INTEGER NOT NULL DEFAULT 0,
+    turn_index INTEGER NOT NULL DEFAULT 0,
+    participants JSON NOT NULL,
+    is_active BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMP NOT NULL,
+    updated_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX idx_combats_campaign ON combats (campaign_id);
@@ -0,0 +1,11 @@
+-- Rest history
+CREATE TABLE rests (
+    id TEXT PRIMARY KEY,
+    campaign_id TEXT NOT NULL REFERENCES campaigns(id) ON DELETE CASCADE,
+    character_id TEXT REFERENCES characters(id) ON DELETE SET NULL,
+    rest_type TEXT NOT NULL, -- enum: short, long
+    details JSON NOT NULL,
+    created_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX idx_rests_campaign_created ON rests (campaign_id, created_at);
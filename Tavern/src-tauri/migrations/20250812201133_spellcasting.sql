@@ -0,0 +1,2 @@
+-- Spell slots, spellcasting ability and concentration
+ALTER TABLE characters ADD COLUMN spellcasting JSON;
@@ -0,0 +1,13 @@
+-- Planned encounters
+CREATE TABLE encounters (
+    id TEXT PRIMARY KEY,
+    campaign_id TEXT NOT NULL REFERENCES campaigns(id) ON DELETE CASCADE,
+    map_id TEXT REFERENCES maps(id) ON DELETE SET NULL,
+    name TEXT NOT NULL,
+    creatures JSON NOT NULL,
+    party_character_ids JSON NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    updated_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX idx_encounters_campaign ON encounters (campaign_id);
@@ -0,0 +1,13 @@
+-- Bestiary stat blocks; a NULL campaign_id makes the stat block global
+CREATE TABLE stat_blocks (
+    id TEXT PRIMARY KEY,
+    campaign_id TEXT REFERENCES campaigns(id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    challenge_rating REAL NOT NULL,
+    data JSON NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    updated_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX idx_stat_blocks_campaign ON stat_blocks (campaign_id);
+CREATE INDEX idx_stat_blocks_name ON stat_blocks (name);
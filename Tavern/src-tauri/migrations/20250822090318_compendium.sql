@@ -0,0 +1,13 @@
+-- Imported compendium spells, weapons, armor and items; monsters go to stat_blocks
+CREATE TABLE compendium_entries (
+    id TEXT PRIMARY KEY,
+    source TEXT NOT NULL,
+    kind TEXT NOT NULL, -- enum: spell, weapon, armor, item
+    name TEXT NOT NULL,
+    data JSON NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    updated_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX idx_compendium_entries_kind_name ON compendium_entries (kind, name);
+CREATE INDEX idx_compendium_entries_source ON compendium_entries (source);
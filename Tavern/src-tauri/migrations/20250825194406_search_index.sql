@@ -0,0 +1,45 @@
+-- Full-text search over campaign content. owner_id is the row's own id, or
+-- the character's for spells, features and items, so a character's rows can
+-- be rebuilt together.
+CREATE VIRTUAL TABLE search_index USING fts5(
+    campaign_id UNINDEXED,
+    kind UNINDEXED, -- enum: campaign, character, spell, feature, item, map, asset
+    entity_id UNINDEXED,
+    owner_id UNINDEXED,
+    title,
+    body,
+    tokenize = 'porter unicode61'
+);
+
+INSERT INTO search_index (campaign_id, kind, entity_id, owner_id, title, body)
+SELECT id, 'campaign', id, id, name, COALESCE(description, '') FROM campaigns;
+
+INSERT INTO search_index (campaign_id, kind, entity_id, owner_id, title, body)
+SELECT campaign_id, 'character', id, id, name, notes FROM characters;
+
+INSERT INTO search_index (campaign_id, kind, entity_id, owner_id, title, body)
+SELECT c.campaign_id, 'spell', json_extract(s.value, '$.id'), c.id, json_extract(s.value, '$.name'), json_extract(s.value, '$.description')
+FROM characters c, json_each(c.spells) s;
+
+INSERT INTO search_index (campaign_id, kind, entity_id, owner_id, title, body)
+SELECT c.campaign_id, 'feature', json_extract(f.value, '$.id'), c.id, json_extract(f.value, '$.name'), json_extract(f.value, '$.description')
+FROM characters c, json_each(c.features) f;
+
+INSERT INTO search_index (campaign_id, kind, entity_id, owner_id, title, body)
+SELECT c.campaign_id, 'item', json_extract(i.value, '$.id'), c.id, json_extract(i.value, '$.name'), COALESCE(json_extract(i.value, '$.description'), '')
+FROM characters c, json_each(c.equipment, '$.items') i;
+
+INSERT INTO search_index (campaign_id, kind, entity_id, owner_id, title, body)
+SELECT c.campaign_id, 'item', json_extract(i.value, '$.id'), c.id, json_extract(i.value, '$.name'), ''
+FROM characters c, json_each(c.equipment, '$.weapons') i;
+
+INSERT INTO search_index (campaign_id, kind, entity_id, owner_id, title, body)
+SELECT c.campaign_id, 'item', json_extract(i.value, '$.id'), c.id, json_extract(i.value, '$.name'), ''
+FROM characters c, json_each(c.equipment, '$.armor') i;
+
+INSERT INTO search_index (campaign_id, kind, entity_id, owner_id, title, body)
+SELECT campaign_id, 'map', id, id, name, COALESCE(description, '') FROM maps;
+
+INSERT INTO search_index (campaign_id, kind, entity_id, owner_id, title, body)
+SELECT campaign_id, 'asset', id, id, name, (SELECT COALESCE(group_concat(t.value, ' '), '') FROM json_each(assets.tags) t)
+FROM assets;
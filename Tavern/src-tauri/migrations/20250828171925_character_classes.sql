@@ -0,0 +1,18 @@
+-- Class levels for multiclass characters; existing characters get a single
+-- entry for their class
+ALTER TABLE characters ADD COLUMN classes JSON;
+
+UPDATE characters SET classes = json_array(json_object(
+    'class', character_class,
+    'subclass', NULL,
+    'level', level,
+    'hit_die', CASE lower(trim(character_class))
+        WHEN 'barbarian' THEN 12
+        WHEN 'fighter' THEN 10
+        WHEN 'paladin' THEN 10
+        WHEN 'ranger' THEN 10
+        WHEN 'sorcerer' THEN 6
+        WHEN 'wizard' THEN 6
+        ELSE 8
+    END
+));
@@ -0,0 +1,7 @@
+-- Coins and items a party has found but not yet shared out, one pool per campaign
+CREATE TABLE treasure_pools (
+    campaign_id TEXT PRIMARY KEY REFERENCES campaigns(id) ON DELETE CASCADE,
+    currency JSON NOT NULL,
+    items JSON NOT NULL,
+    updated_at TIMESTAMP NOT NULL
+);
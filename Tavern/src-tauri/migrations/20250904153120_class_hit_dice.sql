@@ -0,0 +1,18 @@
+-- Spent hit dice are tracked per class; the spent pool a character had is
+-- shared out over their classes in the order they were taken
+UPDATE characters SET classes = (
+    SELECT json_group_array(json(json_set(value, '$.hit_dice_used', max(0, min(
+        json_extract(value, '$.level'),
+        json_extract(characters.combat_stats, '$.hit_dice_used') - levels_before
+    )))))
+    FROM (
+        SELECT key, value, coalesce(sum(json_extract(value, '$.level')) OVER (
+            ORDER BY key ROWS BETWEEN UNBOUNDED PRECEDING AND 1 PRECEDING
+        ), 0) AS levels_before
+        FROM json_each(characters.classes)
+        ORDER BY key
+    )
+)
+WHERE json_extract(combat_stats, '$.hit_dice_used') > 0;
+
+UPDATE characters SET combat_stats = json_remove(combat_stats, '$.hit_dice_used');
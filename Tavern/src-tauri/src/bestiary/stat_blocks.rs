@@ -0,0 +1,156 @@
+use chrono::Utc;
+use rand::Rng;
+use uuid::Uuid;
+
+use crate::combat::damage;
+use crate::database::models::{DamageType, Position, StatBlock, StatBlockData, Token, TokenInstance};
+use crate::dice::parser;
+use crate::dice::roller::DiceRoller;
+use crate::errors::{AppError, AppResult};
+
+/// XP for each challenge rating from 0 to 30, below 1 as eighths
+const XP_BY_CHALLENGE_RATING: [(f64, i64); 34] = [
+    (0.0, 10),
+    (0.125, 25),
+    (0.25, 50),
+    (0.5, 100),
+    (1.0, 200),
+    (2.0, 450),
+    (3.0, 700),
+    (4.0, 1100),
+    (5.0, 1800),
+    (6.0, 2300),
+    (7.0, 2900),
+    (8.0, 3900),
+    (9.0, 5000),
+    (10.0, 5900),
+    (11.0, 7200),
+    (12.0, 8400),
+    (13.0, 10000),
+    (14.0, 11500),
+    (15.0, 13000),
+    (16.0, 15000),
+    (17.0, 18000),
+    (18.0, 20000),
+    (19.0, 22000),
+    (20.0, 25000),
+    (21.0, 33000),
+    (22.0, 41000),
+    (23.0, 50000),
+    (24.0, 62000),
+    (25.0, 75000),
+    (26.0, 90000),
+    (27.0, 105000),
+    (28.0, 120000),
+    (29.0, 135000),
+    (30.0, 155000),
+];
+
+pub fn new_stat_block(campaign_id: Option<String>, data: StatBlockData) -> AppResult<StatBlock> {
+    validate(&data)?;
+    let now = Utc::now();
+    Ok(StatBlock {
+        id: Uuid::new_v4().to_string(),
+        campaign_id,
+        data,
+        created_at: now,
+        updated_at: now,
+    })
+}
+
+pub fn validate(data: &StatBlockData) -> AppResult<()> {
+    if data.name.trim().is_empty() {
+        return Err(AppError::InvalidInput("A stat block needs a name".to_string()));
+    }
+    if data.hit_points < 1 {
+        return Err(AppError::InvalidInput(format!("{} needs at least 1 hit point", data.name)));
+    }
+    if xp_for_challenge_rating(data.challenge_rating).is_none() {
+        return Err(AppError::InvalidInput(format!(
+            "{} has an invalid challenge rating: {}",
+            data.name, data.challenge_rating
+        )));
+    }
+    if let Some(hit_dice) = &data.hit_dice {
+        parser::parse(hit_dice)?;
+    }
+    Ok(())
+}
+
+/// The XP a creature of this challenge rating is worth, if it is one
+pub fn xp_for_challenge_rating(challenge_rating: f64) -> Option<i64> {
+    XP_BY_CHALLENGE_RATING
+        .iter()
+        .find(|(rating, _)| (rating - challenge_rating).abs() < f64::EPSILON)
+        .map(|&(_, xp)| xp)
+}
+
+/// A token for one creature spawned from a stat block, with hit points of its
+/// own: rolled from the hit dice when asked, otherwise the average
+pub fn spawn_token<R: Rng>(
+    roller: &mut DiceRoller<R>,
+    stat_block: &StatBlock,
+    name: String,
+    position: Position,
+    roll_hit_points: bool,
+) -> AppResult<Token> {
+    let data = &stat_block.data;
+    let hit_points = match (&data.hit_dice, roll_hit_points) {
+        (Some(hit_dice), true) => roller.roll_expr(&parser::parse(hit_dice)?)?.total.max(1),
+        _ => data.hit_points,
+    };
+
+    Ok(Token {
+        id: Uuid::new_v4().to_string(),
+        character_id: None,
+        name,
+        image_url: None,
+        position,
+        size: data.size.clone(),
+        notes: String::new(),
+        is_hidden: false,
+        initiative: None,
+        stat_block_id: Some(stat_block.id.clone()),
+        instance: Some(TokenInstance {
+            hit_points,
+            max_hit_points: hit_points,
+            temporary_hit_points: 0,
+            conditions: Vec::new(),
+        }),
+    })
+}
+
+/// Deal damage to a spawned token, after the stat block's resistances;
+/// temporary hit points soak it first
+pub fn damage_token(token: &mut Token, stat_block: &StatBlock, amount: i64, damage_type: DamageType) -> AppResult<i64> {
+    let data = &stat_block.data;
+    let applied = damage::resisted_damage(
+        amount,
+        damage_type,
+        &data.damage_resistances,
+        &data.damage_immunities,
+        &data.damage_vulnerabilities,
+    );
+
+    let instance = instance_mut(token)?;
+    let absorbed = applied.min(instance.temporary_hit_points);
+    instance.temporary_hit_points -= absorbed;
+    instance.hit_points = (instance.hit_points - (applied - absorbed)).max(0);
+    Ok(applied)
+}
+
+/// Restore a spawned token's hit points, up to its maximum
+pub fn heal_token(token: &mut Token, amount: i64) -> AppResult<i64> {
+    let instance = instance_mut(token)?;
+    let before = instance.hit_points;
+    instance.hit_points = (instance.hit_points + amount.max(0)).min(instance.max_hit_points);
+    Ok(instance.hit_points - before)
+}
+
+fn instance_mut(token: &mut Token) -> AppResult<&mut TokenInstance> {
+    let name = token.name.clone();
+    token
+        .instance
+        .as_mut()
+        .ok_or_else(|| AppError::InvalidInput(format!("{} has no hit points of its own", name)))
+}
@@ -0,0 +1,43 @@
+//! Standalone signaling server, for running on a machine players can reach
+//! from anywhere so they can join sessions by invite code
+//!
+//! ```text
+//! tavern-signaling [address]
+//! ```
+//!
+//! Listens on `0.0.0.0:7351` unless given an address. It speaks plain
+//! WebSocket; put it behind a reverse proxy for TLS, and players can use
+//! a `wss://` URL.
+
+use std::net::SocketAddr;
+
+use tokio::net::TcpListener;
+use tracing::info;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+
+// Shared with the app, which also runs it in the background
+#[allow(dead_code)]
+#[path = "../networking/signaling/server.rs"]
+mod server;
+
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| "tavern_signaling=info".into()),
+        )
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+
+    let address = match std::env::args().nth(1) {
+        Some(address) => address
+            .parse()
+            .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{}: {}", address, error)))?,
+        None => SocketAddr::from(([0, 0, 0, 0], server::DEFAULT_PORT)),
+    };
+    let listener = TcpListener::bind(address).await?;
+    info!("Signaling server listening on {}", listener.local_addr()?);
+    server::serve(listener).await;
+    Ok(())
+}
@@ -0,0 +1,633 @@
+use crate::database::models::{CharacterStats, ClassLevel, RechargeType};
+use crate::errors::{AppError, AppResult};
+
+/// How a class casts spells, which decides the slots it gets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Spellcaster {
+    None,
+    /// Bards, clerics, druids, sorcerers and wizards
+    Full,
+    /// Paladins and rangers, who start casting at 2nd level
+    Half,
+    /// Eldritch knights and arcane tricksters, who start casting at 3rd level
+    Third,
+    /// Warlock pact magic
+    Pact,
+}
+
+/// Uses of a feature at a class level: how many, and when they come back
+pub type UsesRule = fn(i64, &CharacterStats) -> (i64, RechargeType);
+
+pub struct ClassFeature {
+    pub level: i64,
+    pub name: &'static str,
+    pub description: &'static str,
+    pub uses: Option<UsesRule>,
+}
+
+pub struct ClassProgression {
+    pub name: &'static str,
+    pub hit_die: u32,
+    pub spellcaster: Spellcaster,
+    pub spellcasting_ability: Option<&'static str>,
+    /// Ability scores of 13 needed to multiclass into or out of the class
+    pub prerequisites: &'static [&'static str],
+    /// Whether any one of the prerequisites is enough rather than all of them
+    pub any_prerequisite: bool,
+    /// Class levels that give an ability score improvement or a feat
+    pub improvement_levels: &'static [i64],
+    pub features: &'static [ClassFeature],
+}
+
+/// Subclasses of otherwise non-casting classes that learn a third caster's
+/// spells, with the ability they cast with
+const THIRD_CASTERS: [(&str, &str); 2] = [("Eldritch Knight", "intelligence"), ("Arcane Trickster", "intelligence")];
+
+/// Hit die sizes a class can have
+const HIT_DICE: [u32; 4] = [6, 8, 10, 12];
+
+/// Highest level a character can reach, across all their classes
+pub const MAX_LEVEL: i64 = 20;
+
+/// Ability score a multiclassing prerequisite needs
+pub const PREREQUISITE_SCORE: i64 = 13;
+
+const STANDARD_IMPROVEMENTS: &[i64] = &[4, 8, 12, 16, 19];
+
+/// Slots per spell level for a full caster of each level, 1st to 9th
+const FULL_CASTER_SLOTS: [[i64; 9]; 20] = [
+    [2, 0, 0, 0, 0, 0, 0, 0, 0],
+    [3, 0, 0, 0, 0, 0, 0, 0, 0],
+    [4, 2, 0, 0, 0, 0, 0, 0, 0],
+    [4, 3, 0, 0, 0, 0, 0, 0, 0],
+    [4, 3, 2, 0, 0, 0, 0, 0, 0],
+    [4, 3, 3, 0, 0, 0, 0, 0, 0],
+    [4, 3, 3, 1, 0, 0, 0, 0, 0],
+    [4, 3, 3, 2, 0, 0, 0, 0, 0],
+    [4, 3, 3, 3, 1, 0, 0, 0, 0],
+    [4, 3, 3, 3, 2, 0, 0, 0, 0],
+    [4, 3, 3, 3, 2, 1, 0, 0, 0],
+    [4, 3, 3, 3, 2, 1, 0, 0, 0],
+    [4, 3, 3, 3, 2, 1, 1, 0, 0],
+    [4, 3, 3, 3, 2, 1, 1, 0, 0],
+    [4, 3, 3, 3, 2, 1, 1, 1, 0],
+    [4, 3, 3, 3, 2, 1, 1, 1, 0],
+    [4, 3, 3, 3, 2, 1, 1, 1, 1],
+    [4, 3, 3, 3, 3, 1, 1, 1, 1],
+    [4, 3, 3, 3, 3, 2, 1, 1, 1],
+    [4, 3, 3, 3, 3, 2, 2, 1, 1],
+];
+
+/// Progression for a class by name, for the classes in the SRD
+pub fn progression(class: &str) -> Option<&'static ClassProgression> {
+    let class = class.trim();
+    CLASSES.iter().find(|progression| progression.name.eq_ignore_ascii_case(class))
+}
+
+/// Hit die size for a class, d8 for classes this table doesn't know
+pub fn hit_die(class: &str) -> u32 {
+    progression(class).map_or(8, |progression| progression.hit_die)
+}
+
+/// Class levels that grant an ability score improvement, the usual ones for
+/// classes this table doesn't know
+pub fn improvement_levels(class: &str) -> &'static [i64] {
+    progression(class).map_or(STANDARD_IMPROVEMENTS, |progression| progression.improvement_levels)
+}
+
+/// How levels in a class count towards spellcasting, taking its subclass
+/// into account
+pub fn spellcaster(class: &ClassLevel) -> Spellcaster {
+    let is_third_caster = class
+        .subclass
+        .as_deref()
+        .is_some_and(|subclass| THIRD_CASTERS.iter().any(|(name, _)| name.eq_ignore_ascii_case(subclass.trim())));
+    if is_third_caster {
+        return Spellcaster::Third;
+    }
+    progression(&class.class).map_or(Spellcaster::None, |progression| progression.spellcaster)
+}
+
+/// Ability a class casts with, if it casts at all
+pub fn spellcasting_ability(class: &ClassLevel) -> Option<&'static str> {
+    if spellcaster(class) == Spellcaster::Third {
+        let subclass = class.subclass.as_deref().unwrap_or_default().trim();
+        return THIRD_CASTERS
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(subclass))
+            .map(|(_, ability)| *ability);
+    }
+    progression(&class.class).and_then(|progression| progression.spellcasting_ability)
+}
+
+/// Caster level for spell slots
+///
+/// A character with one spellcasting class uses that class's own table, so
+/// half casters round up and start at 2nd level. With more than one, levels
+/// are added up: all of them for full casters, half (rounded down) for
+/// paladins and rangers and a third for eldritch knights and arcane
+/// tricksters. Warlock levels only count towards pact magic.
+pub fn caster_level(classes: &[ClassLevel]) -> i64 {
+    let casters: Vec<(Spellcaster, i64)> = classes
+        .iter()
+        .map(|class| (spellcaster(class), class.level))
+        .filter(|(kind, _)| matches!(kind, Spellcaster::Full | Spellcaster::Half | Spellcaster::Third))
+        .collect();
+
+    match casters.as_slice() {
+        [(Spellcaster::Half, level)] if *level >= 2 => (level + 1) / 2,
+        [(Spellcaster::Third, level)] if *level >= 3 => (level + 2) / 3,
+        [(Spellcaster::Full, level)] => *level,
+        [_] => 0,
+        _ => casters
+            .iter()
+            .map(|(kind, level)| match kind {
+                Spellcaster::Full => *level,
+                Spellcaster::Half => level / 2,
+                _ => level / 3,
+            })
+            .sum(),
+    }
+}
+
+/// Levels in warlock, which decide pact magic slots
+pub fn warlock_level(classes: &[ClassLevel]) -> i64 {
+    classes
+        .iter()
+        .filter(|class| spellcaster(class) == Spellcaster::Pact)
+        .map(|class| class.level)
+        .sum()
+}
+
+/// Check a character's class list: at least one class, each named once with
+/// a level of at least 1, a real hit die and no more of them spent than it
+/// has, and no more than 20 levels
+pub fn validate_classes(classes: &[ClassLevel]) -> AppResult<()> {
+    if classes.is_empty() {
+        return Err(AppError::InvalidInput("A character needs at least one class".to_string()));
+    }
+    for (index, class) in classes.iter().enumerate() {
+        let name = class.class.trim();
+        if name.is_empty() {
+            return Err(AppError::InvalidInput("Every class needs a name".to_string()));
+        }
+        if class.level < 1 {
+            return Err(AppError::InvalidInput(format!("{} needs a level of at least 1", name)));
+        }
+        if !HIT_DICE.contains(&class.hit_die) {
+            return Err(AppError::InvalidInput(format!("{} can't have a d{} hit die", name, class.hit_die)));
+        }
+        if class.hit_dice_used < 0 || class.hit_dice_used > class.level {
+            return Err(AppError::InvalidInput(format!(
+                "{} can't have {} hit dice spent at level {}",
+                name, class.hit_dice_used, class.level
+            )));
+        }
+        if classes[..index].iter().any(|earlier| earlier.class.trim().eq_ignore_ascii_case(name)) {
+            return Err(AppError::InvalidInput(format!("{} is listed more than once", name)));
+        }
+    }
+    let total: i64 = classes.iter().map(|class| class.level).sum();
+    if total > MAX_LEVEL {
+        return Err(AppError::InvalidInput(format!(
+            "Class levels add up to {}, more than {}",
+            total, MAX_LEVEL
+        )));
+    }
+    Ok(())
+}
+
+/// Proficiency bonus at a character level
+pub fn proficiency_bonus(level: i64) -> i64 {
+    2 + (level.clamp(1, MAX_LEVEL) - 1) / 4
+}
+
+/// Spell slots per spell level for a full caster of the given level, skipping
+/// levels with none
+pub fn full_caster_slots(caster_level: i64) -> Vec<(i64, i64)> {
+    if caster_level < 1 {
+        return Vec::new();
+    }
+    FULL_CASTER_SLOTS[(caster_level.min(20) - 1) as usize]
+        .iter()
+        .enumerate()
+        .filter(|(_, &count)| count > 0)
+        .map(|(index, &count)| (index as i64 + 1, count))
+        .collect()
+}
+
+/// Pact magic slots at a warlock level: how many, and their level
+pub fn pact_slots(warlock_level: i64) -> Option<(i64, i64)> {
+    let count = match warlock_level {
+        i64::MIN..=0 => return None,
+        1 => 1,
+        2..=10 => 2,
+        11..=16 => 3,
+        _ => 4,
+    };
+    Some((count, ((warlock_level + 1) / 2).min(5)))
+}
+
+/// Whether a character's scores meet a class's multiclassing prerequisites
+pub fn meets_prerequisites(progression: &ClassProgression, stats: &CharacterStats) -> bool {
+    let meets = |ability: &&str| stats.score(ability).unwrap_or(0) >= PREREQUISITE_SCORE;
+    if progression.any_prerequisite {
+        progression.prerequisites.iter().any(meets)
+    } else {
+        progression.prerequisites.iter().all(meets)
+    }
+}
+
+/// The prerequisites in words, e.g. `Strength 13 or Dexterity 13`
+pub fn prerequisite_text(progression: &ClassProgression) -> String {
+    let joiner = if progression.any_prerequisite { " or " } else { " and " };
+    progression
+        .prerequisites
+        .iter()
+        .map(|ability| {
+            let mut chars = ability.chars();
+            let first = chars.next().map(|c| c.to_ascii_uppercase()).unwrap_or_default();
+            format!("{}{} {}", first, chars.as_str(), PREREQUISITE_SCORE)
+        })
+        .collect::<Vec<_>>()
+        .join(joiner)
+}
+
+fn at_least_one(modifier: i64) -> i64 {
+    modifier.max(1)
+}
+
+const fn feature(level: i64, name: &'static str, description: &'static str) -> ClassFeature {
+    ClassFeature { level, name, description, uses: None }
+}
+
+const fn limited(level: i64, name: &'static str, description: &'static str, uses: UsesRule) -> ClassFeature {
+    ClassFeature { level, name, description, uses: Some(uses) }
+}
+
+static CLASSES: [ClassProgression; 12] = [
+    ClassProgression {
+        name: "Barbarian",
+        hit_die: 12,
+        spellcaster: Spellcaster::None,
+        spellcasting_ability: None,
+        prerequisites: &["strength"],
+        any_prerequisite: false,
+        improvement_levels: STANDARD_IMPROVEMENTS,
+        features: &[
+            limited(1, "Rage", "Bonus action: advantage on Strength checks and saves, bonus melee damage and resistance to bludgeoning, piercing and slashing damage.", |level, _| {
+                let uses = match level {
+                    1..=2 => 2,
+                    3..=5 => 3,
+                    6..=11 => 4,
+                    12..=16 => 5,
+                    _ => 6,
+                };
+                (uses, RechargeType::LongRest)
+            }),
+            feature(1, "Unarmored Defense", "Without armor, AC is 10 + Dexterity modifier + Constitution modifier."),
+            feature(2, "Reckless Attack", "Attack with advantage on Strength melee attacks, but attacks against you have advantage until your next turn."),
+            feature(2, "Danger Sense", "Advantage on Dexterity saves against effects you can see."),
+            feature(3, "Primal Path", "Choose a path that shapes your rage."),
+            feature(5, "Extra Attack", "Attack twice when you take the Attack action."),
+            feature(5, "Fast Movement", "Speed increases by 10 feet while not wearing heavy armor."),
+            feature(7, "Feral Instinct", "Advantage on initiative rolls."),
+            feature(9, "Brutal Critical", "Roll extra weapon damage dice on a critical hit with a melee attack."),
+            feature(11, "Relentless Rage", "Drop to 1 hit point instead of 0 while raging on a successful Constitution save."),
+            feature(15, "Persistent Rage", "Rage only ends early if you fall unconscious or choose to end it."),
+            feature(18, "Indomitable Might", "Use your Strength score in place of a lower Strength check total."),
+            feature(20, "Primal Champion", "Strength and Constitution increase by 4, to a maximum of 24."),
+        ],
+    },
+    ClassProgression {
+        name: "Bard",
+        hit_die: 8,
+        spellcaster: Spellcaster::Full,
+        spellcasting_ability: Some("charisma"),
+        prerequisites: &["charisma"],
+        any_prerequisite: false,
+        improvement_levels: STANDARD_IMPROVEMENTS,
+        features: &[
+            limited(1, "Bardic Inspiration", "Bonus action: give a creature an inspiration die to add to one check, attack or save.", |level, stats| {
+                let recharge = if level >= 5 { RechargeType::ShortRest } else { RechargeType::LongRest };
+                (at_least_one(stats.get_modifier("charisma")), recharge)
+            }),
+            feature(2, "Jack of All Trades", "Add half your proficiency bonus to ability checks you aren't proficient in."),
+            feature(2, "Song of Rest", "Allies who spend hit dice during your short rest regain extra hit points."),
+            feature(3, "Bard College", "Choose a college that shapes your performance."),
+            feature(3, "Expertise", "Double your proficiency bonus for two skills."),
+            feature(5, "Font of Inspiration", "Bardic Inspiration comes back on a short rest."),
+            feature(6, "Countercharm", "Allies within 30 feet have advantage on saves against being frightened or charmed."),
+            feature(10, "Magical Secrets", "Learn two spells from any class."),
+            feature(20, "Superior Inspiration", "Regain a use of Bardic Inspiration when you roll initiative with none left."),
+        ],
+    },
+    ClassProgression {
+        name: "Cleric",
+        hit_die: 8,
+        spellcaster: Spellcaster::Full,
+        spellcasting_ability: Some("wisdom"),
+        prerequisites: &["wisdom"],
+        any_prerequisite: false,
+        improvement_levels: STANDARD_IMPROVEMENTS,
+        features: &[
+            feature(1, "Divine Domain", "Choose a domain tied to your deity."),
+            limited(2, "Channel Divinity", "Channel divine energy to turn undead or fuel a domain effect.", |level, _| {
+                let uses = match level {
+                    i64::MIN..=5 => 1,
+                    6..=17 => 2,
+                    _ => 3,
+                };
+                (uses, RechargeType::ShortRest)
+            }),
+            feature(5, "Destroy Undead", "Undead that fail the save against Turn Undead are destroyed."),
+            limited(10, "Divine Intervention", "Call on your deity to intervene on your behalf.", |_, _| (1, RechargeType::LongRest)),
+        ],
+    },
+    ClassProgression {
+        name: "Druid",
+        hit_die: 8,
+        spellcaster: Spellcaster::Full,
+        spellcasting_ability: Some("wisdom"),
+        prerequisites: &["wisdom"],
+        any_prerequisite: false,
+        improvement_levels: STANDARD_IMPROVEMENTS,
+        features: &[
+            feature(1, "Druidic", "You know the secret language of druids."),
+            limited(2, "Wild Shape", "Magically assume the shape of a beast you have seen.", |_, _| (2, RechargeType::ShortRest)),
+            feature(2, "Druid Circle", "Choose a circle of druids to belong to."),
+            feature(18, "Timeless Body", "You age one year for every ten that pass."),
+            feature(18, "Beast Spells", "Cast spells in beast shape."),
+            feature(20, "Archdruid", "Use Wild Shape without limit."),
+        ],
+    },
+    ClassProgression {
+        name: "Fighter",
+        hit_die: 10,
+        spellcaster: Spellcaster::None,
+        spellcasting_ability: None,
+        prerequisites: &["strength", "dexterity"],
+        any_prerequisite: true,
+        improvement_levels: &[4, 6, 8, 12, 14, 16, 19],
+        features: &[
+            feature(1, "Fighting Style", "Adopt a particular style of fighting as your specialty."),
+            limited(1, "Second Wind", "Bonus action: regain 1d10 + fighter level hit points.", |_, _| (1, RechargeType::ShortRest)),
+            limited(2, "Action Surge", "Take one additional action on your turn.", |level, _| {
+                (if level >= 17 { 2 } else { 1 }, RechargeType::ShortRest)
+            }),
+            feature(3, "Martial Archetype", "Choose an archetype that shapes your fighting."),
+            feature(5, "Extra Attack", "Attack twice when you take the Attack action; three times at 11th level and four at 20th."),
+            limited(9, "Indomitable", "Reroll a failed saving throw.", |level, _| {
+                let uses = match level {
+                    i64::MIN..=12 => 1,
+                    13..=16 => 2,
+                    _ => 3,
+                };
+                (uses, RechargeType::LongRest)
+            }),
+        ],
+    },
+    ClassProgression {
+        name: "Monk",
+        hit_die: 8,
+        spellcaster: Spellcaster::None,
+        spellcasting_ability: None,
+        prerequisites: &["dexterity", "wisdom"],
+        any_prerequisite: false,
+        improvement_levels: STANDARD_IMPROVEMENTS,
+        features: &[
+            feature(1, "Unarmored Defense", "Without armor or a shield, AC is 10 + Dexterity modifier + Wisdom modifier."),
+            feature(1, "Martial Arts", "Use Dexterity for unarmed strikes and monk weapons, and make an unarmed strike as a bonus action."),
+            limited(2, "Ki", "Spend ki points on Flurry of Blows, Patient Defense and Step of the Wind.", |level, _| (level, RechargeType::ShortRest)),
+            feature(2, "Unarmored Movement", "Speed increases while not wearing armor or wielding a shield."),
+            feature(3, "Monastic Tradition", "Choose a monastic tradition."),
+            feature(3, "Deflect Missiles", "Reaction: reduce damage from a ranged weapon attack, and catch the missile."),
+            feature(4, "Slow Fall", "Reaction: reduce falling damage by five times your monk level."),
+            feature(5, "Extra Attack", "Attack twice when you take the Attack action."),
+            feature(5, "Stunning Strike", "Spend 1 ki point to try to stun a creature you hit."),
+            feature(6, "Ki-Empowered Strikes", "Unarmed strikes count as magical."),
+            feature(7, "Evasion", "Take no damage on a successful Dexterity save for half, and half on a failure."),
+            feature(7, "Stillness of Mind", "Action: end one effect charming or frightening you."),
+            feature(10, "Purity of Body", "Immune to disease and poison."),
+            feature(13, "Tongue of the Sun and Moon", "Understand all spoken languages, and be understood by any creature that speaks one."),
+            feature(14, "Diamond Soul", "Proficiency in all saving throws; spend 1 ki point to reroll a failed one."),
+            feature(15, "Timeless Body", "You no longer age or need food and water."),
+            feature(18, "Empty Body", "Spend ki points to become invisible or cast astral projection."),
+            feature(20, "Perfect Self", "Regain 4 ki points when you roll initiative with none left."),
+        ],
+    },
+    ClassProgression {
+        name: "Paladin",
+        hit_die: 10,
+        spellcaster: Spellcaster::Half,
+        spellcasting_ability: Some("charisma"),
+        prerequisites: &["strength", "charisma"],
+        any_prerequisite: false,
+        improvement_levels: STANDARD_IMPROVEMENTS,
+        features: &[
+            limited(1, "Divine Sense", "Sense celestials, fiends and undead within 60 feet.", |_, stats| {
+                (1 + stats.get_modifier("charisma"), RechargeType::LongRest)
+            }),
+            limited(1, "Lay on Hands", "A pool of healing equal to five times your paladin level.", |level, _| {
+                (5 * level, RechargeType::LongRest)
+            }),
+            feature(2, "Fighting Style", "Adopt a particular style of fighting as your specialty."),
+            feature(2, "Divine Smite", "Expend a spell slot on a melee hit to deal extra radiant damage."),
+            feature(3, "Divine Health", "Immune to disease."),
+            feature(3, "Sacred Oath", "Swear the oath that binds you as a paladin."),
+            limited(3, "Channel Divinity", "Channel divine energy to fuel an oath effect.", |_, _| (1, RechargeType::ShortRest)),
+            feature(5, "Extra Attack", "Attack twice when you take the Attack action."),
+            feature(6, "Aura of Protection", "You and allies within 10 feet add your Charisma modifier to saving throws."),
+            feature(10, "Aura of Courage", "You and allies within 10 feet can't be frightened."),
+            feature(11, "Improved Divine Smite", "Melee weapon hits deal an extra 1d8 radiant damage."),
+            limited(14, "Cleansing Touch", "Action: end one spell on yourself or a willing creature.", |_, stats| {
+                (at_least_one(stats.get_modifier("charisma")), RechargeType::LongRest)
+            }),
+        ],
+    },
+    ClassProgression {
+        name: "Ranger",
+        hit_die: 10,
+        spellcaster: Spellcaster::Half,
+        spellcasting_ability: Some("wisdom"),
+        prerequisites: &["dexterity", "wisdom"],
+        any_prerequisite: false,
+        improvement_levels: STANDARD_IMPROVEMENTS,
+        features: &[
+            feature(1, "Favored Enemy", "Advantage on tracking and recalling lore about a chosen type of creature."),
+            feature(1, "Natural Explorer", "Expertise at travel and survival in a chosen terrain."),
+            feature(2, "Fighting Style", "Adopt a particular style of fighting as your specialty."),
+            feature(3, "Ranger Archetype", "Choose an archetype to emulate."),
+            feature(3, "Primeval Awareness", "Expend a spell slot to sense nearby creatures of certain types."),
+            feature(5, "Extra Attack", "Attack twice when you take the Attack action."),
+            feature(8, "Land's Stride", "Nonmagical difficult terrain costs no extra movement."),
+            feature(10, "Hide in Plain Sight", "Camouflage yourself for +10 to Stealth while you stay still."),
+            feature(14, "Vanish", "Hide as a bonus action, and you can't be tracked by nonmagical means."),
+            feature(18, "Feral Senses", "Attacks against creatures you can't see don't have disadvantage."),
+            feature(20, "Foe Slayer", "Once per turn, add your Wisdom modifier to an attack or damage roll against a favored enemy."),
+        ],
+    },
+    ClassProgression {
+        name: "Rogue",
+        hit_die: 8,
+        spellcaster: Spellcaster::None,
+        spellcasting_ability: None,
+        prerequisites: &["dexterity"],
+        any_prerequisite: false,
+        improvement_levels: &[4, 8, 10, 12, 16, 19],
+        features: &[
+            feature(1, "Expertise", "Double your proficiency bonus for two skills."),
+            feature(1, "Sneak Attack", "Once per turn, deal extra damage to a creature you hit with advantage or beside an ally."),
+            feature(1, "Thieves' Cant", "You know the secret mix of jargon and code of thieves."),
+            feature(2, "Cunning Action", "Dash, Disengage or Hide as a bonus action."),
+            feature(3, "Roguish Archetype", "Choose an archetype to emulate."),
+            feature(5, "Uncanny Dodge", "Reaction: halve the damage of an attack from an attacker you can see."),
+            feature(7, "Evasion", "Take no damage on a successful Dexterity save for half, and half on a failure."),
+            feature(11, "Reliable Talent", "Treat a d20 roll of 9 or lower as a 10 on checks you're proficient in."),
+            feature(14, "Blindsense", "Know where hidden or invisible creatures within 10 feet are."),
+            feature(15, "Slippery Mind", "Proficiency in Wisdom saving throws."),
+            feature(18, "Elusive", "No attack roll has advantage against you while you aren't incapacitated."),
+            limited(20, "Stroke of Luck", "Turn a miss into a hit, or a failed check into a 20.", |_, _| (1, RechargeType::ShortRest)),
+        ],
+    },
+    ClassProgression {
+        name: "Sorcerer",
+        hit_die: 6,
+        spellcaster: Spellcaster::Full,
+        spellcasting_ability: Some("charisma"),
+        prerequisites: &["charisma"],
+        any_prerequisite: false,
+        improvement_levels: STANDARD_IMPROVEMENTS,
+        features: &[
+            feature(1, "Sorcerous Origin", "Choose the source of your innate magic."),
+            limited(2, "Font of Magic", "Sorcery points to spend on metamagic or trade for spell slots.", |level, _| {
+                (level, RechargeType::LongRest)
+            }),
+            feature(3, "Metamagic", "Twist your spells with sorcery points."),
+            feature(20, "Sorcerous Restoration", "Regain 4 sorcery points on a short rest."),
+        ],
+    },
+    ClassProgression {
+        name: "Warlock",
+        hit_die: 8,
+        spellcaster: Spellcaster::Pact,
+        spellcasting_ability: Some("charisma"),
+        prerequisites: &["charisma"],
+        any_prerequisite: false,
+        improvement_levels: STANDARD_IMPROVEMENTS,
+        features: &[
+            feature(1, "Otherworldly Patron", "Strike a bargain with an otherworldly being."),
+            feature(2, "Eldritch Invocations", "Learn fragments of forbidden knowledge."),
+            feature(3, "Pact Boon", "Your patron grants you a pact of the blade, chain or tome."),
+            limited(11, "Mystic Arcanum (6th level)", "Cast a chosen 6th-level spell without a slot.", |_, _| (1, RechargeType::LongRest)),
+            limited(13, "Mystic Arcanum (7th level)", "Cast a chosen 7th-level spell without a slot.", |_, _| (1, RechargeType::LongRest)),
+            limited(15, "Mystic Arcanum (8th level)", "Cast a chosen 8th-level spell without a slot.", |_, _| (1, RechargeType::LongRest)),
+            limited(17, "Mystic Arcanum (9th level)", "Cast a chosen 9th-level spell without a slot.", |_, _| (1, RechargeType::LongRest)),
+            limited(20, "Eldritch Master", "Spend a minute entreating your patron to regain all pact magic slots.", |_, _| (1, RechargeType::LongRest)),
+        ],
+    },
+    ClassProgression {
+        name: "Wizard",
+        hit_die: 6,
+        spellcaster: Spellcaster::Full,
+        spellcasting_ability: Some("intelligence"),
+        prerequisites: &["intelligence"],
+        any_prerequisite: false,
+        improvement_levels: STANDARD_IMPROVEMENTS,
+        features: &[
+            limited(1, "Arcane Recovery", "After a short rest, recover spell slots totalling up to half your wizard level.", |_, _| {
+                (1, RechargeType::Daily)
+            }),
+            feature(2, "Arcane Tradition", "Choose a school of magic to specialize in."),
+            feature(18, "Spell Mastery", "Cast a chosen 1st- and 2nd-level spell at their lowest level without a slot."),
+            feature(20, "Signature Spells", "Two 3rd-level spells are always prepared and can each be cast once without a slot."),
+        ],
+    },
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn class(name: &str, level: i64) -> ClassLevel {
+        ClassLevel { class: name.to_string(), subclass: None, level, hit_die: hit_die(name), hit_dice_used: 0 }
+    }
+
+    fn subclassed(name: &str, subclass: &str, level: i64) -> ClassLevel {
+        ClassLevel { subclass: Some(subclass.to_string()), ..class(name, level) }
+    }
+
+    #[test]
+    fn multiclass_caster_levels_add_up_by_kind_of_caster() {
+        assert_eq!(caster_level(&[class("Wizard", 5), class("Cleric", 3)]), 8);
+        assert_eq!(caster_level(&[class("Paladin", 3), class("Sorcerer", 2)]), 3);
+        assert_eq!(caster_level(&[class("Ranger", 5), subclassed("Fighter", "Eldritch Knight", 7)]), 4);
+        // Too few half and third caster levels to count for anything together
+        let dabbler = [class("Paladin", 1), subclassed("Rogue", "Arcane Trickster", 2), class("Wizard", 1)];
+        assert_eq!(caster_level(&dabbler), 1);
+        // Warlock and non-casting levels don't count
+        assert_eq!(caster_level(&[class("Warlock", 3), class("Wizard", 2), class("Fighter", 5)]), 2);
+
+        let slots = full_caster_slots(caster_level(&[class("Wizard", 5), class("Cleric", 3)]));
+        assert_eq!(slots, [(1, 4), (2, 3), (3, 3), (4, 2)]);
+    }
+
+    #[test]
+    fn a_single_half_or_third_caster_uses_their_own_table() {
+        assert_eq!(caster_level(&[class("Paladin", 1)]), 0);
+        assert_eq!(caster_level(&[class("Paladin", 2)]), 1);
+        assert_eq!(caster_level(&[class("Ranger", 5)]), 3);
+        assert_eq!(caster_level(&[subclassed("Fighter", "Eldritch Knight", 2)]), 0);
+        assert_eq!(caster_level(&[subclassed("Fighter", " eldritch knight ", 3)]), 1);
+        assert_eq!(caster_level(&[subclassed("Rogue", "Arcane Trickster", 7)]), 3);
+        assert_eq!(caster_level(&[class("Fighter", 7)]), 0);
+        assert_eq!(caster_level(&[class("Warlock", 5)]), 0);
+    }
+
+    #[test]
+    fn slot_tables_cover_every_level() {
+        assert!(full_caster_slots(0).is_empty());
+        assert_eq!(full_caster_slots(1), [(1, 2)]);
+        assert_eq!(full_caster_slots(25), full_caster_slots(20));
+        assert_eq!(full_caster_slots(20).len(), 9);
+
+        assert_eq!(pact_slots(0), None);
+        assert_eq!(pact_slots(1), Some((1, 1)));
+        assert_eq!(pact_slots(2), Some((2, 1)));
+        assert_eq!(pact_slots(5), Some((2, 3)));
+        assert_eq!(pact_slots(11), Some((3, 5)));
+        assert_eq!(pact_slots(17), Some((4, 5)));
+        assert_eq!(warlock_level(&[class("Warlock", 3), class("Wizard", 2)]), 3);
+    }
+
+    #[test]
+    fn proficiency_bonus_rises_every_four_levels() {
+        let bonuses: Vec<_> = [0, 1, 4, 5, 9, 13, 17, 20, 30].into_iter().map(proficiency_bonus).collect();
+        assert_eq!(bonuses, [2, 2, 2, 3, 4, 5, 6, 6, 6]);
+    }
+
+    #[test]
+    fn class_lists_are_validated() {
+        assert!(validate_classes(&[class("Fighter", 10), class("Wizard", 10)]).is_ok());
+        assert!(validate_classes(&[]).is_err());
+        assert!(validate_classes(&[class(" ", 1)]).is_err());
+        assert!(validate_classes(&[class("Fighter", 0)]).is_err());
+        assert!(validate_classes(&[ClassLevel { hit_die: 4, ..class("Fighter", 1) }]).is_err());
+        assert!(validate_classes(&[ClassLevel { hit_dice_used: 2, ..class("Fighter", 1) }]).is_err());
+        assert!(validate_classes(&[class("Fighter", 1), class("fighter ", 1)]).is_err());
+        assert!(validate_classes(&[class("Fighter", 11), class("Wizard", 10)]).is_err());
+    }
+
+    #[test]
+    fn prerequisites_need_any_or_all_scores() {
+        let fighter = progression("Fighter").unwrap();
+        let paladin = progression("Paladin").unwrap();
+        let mut stats = CharacterStats { strength: 13, ..Default::default() };
+        assert!(meets_prerequisites(fighter, &stats));
+        assert!(!meets_prerequisites(paladin, &stats));
+        stats.charisma = 13;
+        assert!(meets_prerequisites(paladin, &stats));
+        assert_eq!(prerequisite_text(fighter), "Strength 13 or Dexterity 13");
+        assert_eq!(prerequisite_text(paladin), "Strength 13 and Charisma 13");
+    }
+}
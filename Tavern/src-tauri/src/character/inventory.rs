@@ -0,0 +1,351 @@
+use uuid::Uuid;
+
+use crate::database::models::{ArmorType, Currency, Equipment, InventoryEntry, Item};
+use crate::errors::{AppError, AppResult};
+
+/// Put something in a character's equipment and return the id it ends up
+/// under
+///
+/// Items join a stack of the same item if there is one: the same compendium
+/// entry, or for homebrew items the same name, value and weight. Anything
+/// without an id, or with one already in use, is given a new one.
+pub fn add(equipment: &mut Equipment, entry: InventoryEntry) -> AppResult<String> {
+    match entry {
+        InventoryEntry::Item(mut item) => {
+            if item.quantity < 1 {
+                return Err(AppError::InvalidInput(format!("Can't add {} of {}", item.quantity, item.name)));
+            }
+            if let Some(stack) = equipment.items.iter_mut().find(|existing| same_item(existing, &item)) {
+                stack.quantity += item.quantity;
+                return Ok(stack.id.clone());
+            }
+            item.id = fresh_id(equipment, item.id);
+            let id = item.id.clone();
+            equipment.items.push(item);
+            Ok(id)
+        }
+        InventoryEntry::Weapon(mut weapon) => {
+            weapon.id = fresh_id(equipment, weapon.id);
+            let id = weapon.id.clone();
+            equipment.weapons.push(weapon);
+            Ok(id)
+        }
+        InventoryEntry::Armor(mut armor) => {
+            armor.id = fresh_id(equipment, armor.id);
+            let id = armor.id.clone();
+            equipment.armor.push(armor);
+            Ok(id)
+        }
+    }
+}
+
+/// Take something out of a character's equipment: all of it, or `quantity`
+/// from a stack of items, which comes away under a new id
+///
+/// Weapons and armor are taken whole and unequipped on the way out.
+pub fn take(equipment: &mut Equipment, id: &str, quantity: Option<i64>) -> AppResult<InventoryEntry> {
+    if let Some(index) = equipment.items.iter().position(|item| item.id == id) {
+        let stack = &mut equipment.items[index];
+        let quantity = quantity.unwrap_or(stack.quantity);
+        if quantity < 1 || quantity > stack.quantity {
+            return Err(AppError::InvalidInput(format!(
+                "Can't take {} of {}; there are {}",
+                quantity, stack.name, stack.quantity
+            )));
+        }
+        if quantity == stack.quantity {
+            return Ok(InventoryEntry::Item(equipment.items.remove(index)));
+        }
+        stack.quantity -= quantity;
+        return Ok(InventoryEntry::Item(Item {
+            id: Uuid::new_v4().to_string(),
+            quantity,
+            ..stack.clone()
+        }));
+    }
+
+    if let Some(quantity) = quantity.filter(|&quantity| quantity != 1) {
+        return Err(AppError::InvalidInput(format!("Can't take {} of a weapon or armor", quantity)));
+    }
+    if let Some(index) = equipment.weapons.iter().position(|weapon| weapon.id == id) {
+        let mut weapon = equipment.weapons.remove(index);
+        weapon.is_equipped = false;
+        return Ok(InventoryEntry::Weapon(weapon));
+    }
+    if let Some(index) = equipment.armor.iter().position(|armor| armor.id == id) {
+        let mut armor = equipment.armor.remove(index);
+        armor.is_equipped = false;
+        return Ok(InventoryEntry::Armor(armor));
+    }
+    Err(AppError::NotFound(format!("Item {}", id)))
+}
+
+/// Move `quantity` of a stack of items into a stack of its own, returning
+/// the new stack's id
+pub fn split(equipment: &mut Equipment, id: &str, quantity: i64) -> AppResult<String> {
+    let stack = equipment
+        .items
+        .iter()
+        .find(|item| item.id == id)
+        .ok_or_else(|| AppError::NotFound(format!("Item {}", id)))?;
+    if quantity >= stack.quantity {
+        return Err(AppError::InvalidInput(format!(
+            "Can't split {} from a stack of {}",
+            quantity, stack.quantity
+        )));
+    }
+    // take() only keeps the id when the whole stack goes, which was ruled out above
+    let InventoryEntry::Item(item) = take(equipment, id, Some(quantity))? else {
+        unreachable!("items are taken as items");
+    };
+    let new_id = item.id.clone();
+    equipment.items.push(item);
+    Ok(new_id)
+}
+
+/// Merge one stack of items into another of the same item
+pub fn stack(equipment: &mut Equipment, id: &str, into_id: &str) -> AppResult<()> {
+    if id == into_id {
+        return Err(AppError::InvalidInput("Can't stack an item onto itself".to_string()));
+    }
+    let find = |id: &str| {
+        equipment
+            .items
+            .iter()
+            .position(|item| item.id == id)
+            .ok_or_else(|| AppError::NotFound(format!("Item {}", id)))
+    };
+    let (from, into) = (find(id)?, find(into_id)?);
+    if !same_item(&equipment.items[from], &equipment.items[into]) {
+        return Err(AppError::InvalidInput(format!(
+            "{} and {} aren't the same item",
+            equipment.items[from].name, equipment.items[into].name
+        )));
+    }
+    let item = equipment.items.remove(from);
+    let into = if from < into { into - 1 } else { into };
+    equipment.items[into].quantity += item.quantity;
+    Ok(())
+}
+
+/// Equip or unequip a weapon or piece of armor
+///
+/// Only one suit of armor and one shield can be worn, so equipping either
+/// takes off whatever was worn in its place.
+pub fn set_equipped(equipment: &mut Equipment, id: &str, equipped: bool) -> AppResult<()> {
+    if let Some(weapon) = equipment.weapons.iter_mut().find(|weapon| weapon.id == id) {
+        weapon.is_equipped = equipped;
+        return Ok(());
+    }
+    let Some(index) = equipment.armor.iter().position(|armor| armor.id == id) else {
+        return match equipment.items.iter().find(|item| item.id == id) {
+            Some(item) => Err(AppError::InvalidInput(format!("{} can't be equipped", item.name))),
+            None => Err(AppError::NotFound(format!("Item {}", id))),
+        };
+    };
+    if equipped {
+        let is_shield = matches!(equipment.armor[index].armor_type, ArmorType::Shield);
+        for armor in equipment.armor.iter_mut() {
+            if matches!(armor.armor_type, ArmorType::Shield) == is_shield {
+                armor.is_equipped = false;
+            }
+        }
+    }
+    equipment.armor[index].is_equipped = equipped;
+    Ok(())
+}
+
+/// Split coins evenly between `shares` characters, making change so each
+/// share is the fewest coins; copper that won't divide evenly is returned
+/// as the remainder
+pub fn split_currency(total: &Currency, shares: usize) -> (Vec<Currency>, Currency) {
+    let copper = total.total_in_copper();
+    if shares == 0 {
+        return (Vec::new(), Currency::from_copper(copper));
+    }
+    let shares = shares as i64;
+    let share = Currency::from_copper(copper / shares);
+    (
+        (0..shares).map(|_| share.clone()).collect(),
+        Currency::from_copper(copper % shares),
+    )
+}
+
+fn same_item(a: &Item, b: &Item) -> bool {
+    match (&a.compendium_id, &b.compendium_id) {
+        (Some(a), Some(b)) => a == b,
+        (None, None) => a.name.trim().eq_ignore_ascii_case(b.name.trim()) && a.value == b.value && a.weight == b.weight,
+        _ => false,
+    }
+}
+
+fn fresh_id(equipment: &Equipment, id: String) -> String {
+    let in_use = equipment.items.iter().any(|item| item.id == id)
+        || equipment.weapons.iter().any(|weapon| weapon.id == id)
+        || equipment.armor.iter().any(|armor| armor.id == id);
+    if id.trim().is_empty() || in_use {
+        Uuid::new_v4().to_string()
+    } else {
+        id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::models::{Armor, DamageType, ItemRarity, ItemType, Weapon};
+
+    fn item(id: &str, name: &str, quantity: i64) -> Item {
+        Item {
+            id: id.to_string(),
+            name: name.to_string(),
+            description: String::new(),
+            quantity,
+            weight: 1.0,
+            value: 5,
+            rarity: ItemRarity::Common,
+            item_type: ItemType::AdventuringGear,
+            compendium_id: None,
+        }
+    }
+
+    fn armor(id: &str, armor_type: ArmorType, is_equipped: bool) -> Armor {
+        Armor {
+            id: id.to_string(),
+            name: id.to_string(),
+            armor_class: 14,
+            armor_type,
+            stealth_disadvantage: false,
+            is_equipped,
+            compendium_id: None,
+        }
+    }
+
+    fn sword() -> Weapon {
+        Weapon {
+            id: "sword".to_string(),
+            name: "Longsword".to_string(),
+            damage_dice: "1d8".to_string(),
+            damage_type: DamageType::Slashing,
+            properties: Vec::new(),
+            range: None,
+            is_equipped: true,
+            is_proficient: true,
+            compendium_id: None,
+        }
+    }
+
+    fn quantities(equipment: &Equipment) -> Vec<(&str, i64)> {
+        equipment.items.iter().map(|item| (item.name.as_str(), item.quantity)).collect()
+    }
+
+    fn coins(currency: &Currency) -> [i64; 5] {
+        [currency.platinum, currency.gold, currency.electrum, currency.silver, currency.copper]
+    }
+
+    #[test]
+    fn adding_joins_a_stack_of_the_same_item() {
+        let mut equipment = Equipment::default();
+        let id = add(&mut equipment, InventoryEntry::Item(item("", "Torch", 2))).unwrap();
+        assert!(!id.is_empty());
+        assert_eq!(add(&mut equipment, InventoryEntry::Item(item("other", " torch ", 3))).unwrap(), id);
+        // A different value makes a different item
+        let mut dearer = item(&id, "Torch", 1);
+        dearer.value = 50;
+        assert_ne!(add(&mut equipment, InventoryEntry::Item(dearer)).unwrap(), id);
+        assert_eq!(quantities(&equipment), [("Torch", 5), ("Torch", 1)]);
+        assert!(add(&mut equipment, InventoryEntry::Item(item("", "Rope", 0))).is_err());
+    }
+
+    #[test]
+    fn taking_part_of_a_stack_leaves_the_rest() {
+        let mut equipment = Equipment::default();
+        equipment.items.push(item("torches", "Torch", 5));
+        equipment.weapons.push(sword());
+
+        let InventoryEntry::Item(taken) = take(&mut equipment, "torches", Some(2)).unwrap() else {
+            panic!("expected an item");
+        };
+        assert_ne!(taken.id, "torches");
+        assert_eq!((taken.quantity, equipment.items[0].quantity), (2, 3));
+        assert!(take(&mut equipment, "torches", Some(4)).is_err());
+        assert!(take(&mut equipment, "torches", Some(0)).is_err());
+        let InventoryEntry::Item(rest) = take(&mut equipment, "torches", None).unwrap() else {
+            panic!("expected an item");
+        };
+        assert_eq!((rest.id.as_str(), rest.quantity), ("torches", 3));
+        assert!(equipment.items.is_empty());
+
+        assert!(take(&mut equipment, "sword", Some(2)).is_err());
+        let InventoryEntry::Weapon(weapon) = take(&mut equipment, "sword", None).unwrap() else {
+            panic!("expected a weapon");
+        };
+        assert!(!weapon.is_equipped);
+        assert!(matches!(take(&mut equipment, "sword", None), Err(AppError::NotFound(_))));
+    }
+
+    #[test]
+    fn split_stacks_can_be_stacked_back_in_either_order() {
+        let mut equipment = Equipment::default();
+        equipment.items.push(item("rope", "Rope", 1));
+        equipment.items.push(item("arrows", "Arrow", 20));
+        let split_id = split(&mut equipment, "arrows", 5).unwrap();
+        assert_eq!(quantities(&equipment), [("Rope", 1), ("Arrow", 15), ("Arrow", 5)]);
+        assert!(split(&mut equipment, "arrows", 15).is_err());
+
+        // The stack removed comes before the one it goes into
+        stack(&mut equipment, "arrows", &split_id).unwrap();
+        assert_eq!(quantities(&equipment), [("Rope", 1), ("Arrow", 20)]);
+        assert_eq!(equipment.items[1].id, split_id);
+
+        // And after it
+        let second = split(&mut equipment, &split_id, 8).unwrap();
+        equipment.items.swap(0, 2);
+        stack(&mut equipment, &split_id, &second).unwrap();
+        assert_eq!(quantities(&equipment), [("Arrow", 20), ("Rope", 1)]);
+
+        assert!(stack(&mut equipment, &second, &second).is_err());
+        assert!(stack(&mut equipment, &second, "rope").is_err());
+    }
+
+    #[test]
+    fn one_suit_of_armor_and_one_shield_at_a_time() {
+        let mut equipment = Equipment {
+            items: vec![item("rope", "Rope", 1)],
+            armor: vec![
+                armor("leather", ArmorType::Light, true),
+                armor("shield", ArmorType::Shield, true),
+                armor("plate", ArmorType::Heavy, false),
+                armor("buckler", ArmorType::Shield, false),
+            ],
+            ..Default::default()
+        };
+        let worn = |equipment: &Equipment| -> Vec<String> {
+            equipment.armor.iter().filter(|armor| armor.is_equipped).map(|armor| armor.id.clone()).collect()
+        };
+
+        set_equipped(&mut equipment, "plate", true).unwrap();
+        assert_eq!(worn(&equipment), ["shield", "plate"]);
+        set_equipped(&mut equipment, "buckler", true).unwrap();
+        assert_eq!(worn(&equipment), ["plate", "buckler"]);
+        set_equipped(&mut equipment, "plate", false).unwrap();
+        assert_eq!(worn(&equipment), ["buckler"]);
+
+        assert!(matches!(set_equipped(&mut equipment, "rope", true), Err(AppError::InvalidInput(_))));
+        assert!(matches!(set_equipped(&mut equipment, "missing", true), Err(AppError::NotFound(_))));
+    }
+
+    #[test]
+    fn currency_splits_into_even_shares_and_a_remainder() {
+        let purse = Currency { copper: 3, silver: 2, electrum: 1, gold: 10, platinum: 1 };
+        // 2073 copper in all
+        let (shares, remainder) = split_currency(&purse, 4);
+        assert_eq!(shares.len(), 4);
+        assert!(shares.iter().all(|share| coins(share) == [0, 5, 0, 1, 8]));
+        assert_eq!(coins(&remainder), [0, 0, 0, 0, 1]);
+
+        let (shares, remainder) = split_currency(&purse, 0);
+        assert!(shares.is_empty());
+        assert_eq!(coins(&remainder), [2, 0, 0, 7, 3]);
+    }
+}
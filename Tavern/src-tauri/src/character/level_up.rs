@@ -0,0 +1,466 @@
+use rand::Rng;
+use uuid::Uuid;
+
+use crate::character::classes::{self, ClassProgression};
+use crate::database::models::{
+    AbilityImprovement, Character, CharacterStats, ClassLevel, Feature, FeatureSource, FeatureUses, HitPointMethod,
+    LevelUpChoices, LevelUpResult, SpellSlots, VariantRules,
+};
+use crate::dice::parser::{DiceExpr, DiceTerm};
+use crate::dice::roller::DiceRoller;
+use crate::errors::{AppError, AppResult};
+
+/// Highest an ability score can be raised by an improvement or feat
+pub const MAX_IMPROVED_SCORE: i64 = 20;
+
+/// Points an ability score improvement spreads over the character's abilities
+const IMPROVEMENT_POINTS: i64 = 2;
+
+/// Raise a character by one level in one of their classes, or take a first
+/// level in a new one
+///
+/// Hit points go up by the class's hit die, rolled or averaged, plus the
+/// Constitution modifier (at least 1 in all). The class's features for its
+/// new level are added and the uses of features the character already has
+/// grow with it; spell slots follow the caster level of all their classes.
+///
+/// At class levels with an ability score improvement, the campaign's variant
+/// rules decide what may be chosen: raised scores when
+/// `customizing_ability_scores` is on, a feat when `feats` is on, and nothing
+/// at all when neither is. Taking up a new class needs `multiclassing` and
+/// the prerequisite scores for it and every class the character already has.
+pub fn level_up<R: Rng>(
+    roller: &mut DiceRoller<R>,
+    character: &mut Character,
+    choices: &LevelUpChoices,
+    rules: &VariantRules,
+) -> AppResult<LevelUpResult> {
+    // Work on a copy so choices that turn out to be invalid change nothing
+    let mut leveled = character.clone();
+    let result = gain_level(roller, &mut leveled, choices, rules)?;
+    *character = leveled;
+    Ok(result)
+}
+
+fn gain_level<R: Rng>(
+    roller: &mut DiceRoller<R>,
+    character: &mut Character,
+    choices: &LevelUpChoices,
+    rules: &VariantRules,
+) -> AppResult<LevelUpResult> {
+    if character.total_level() >= classes::MAX_LEVEL {
+        return Err(AppError::InvalidInput(format!(
+            "{} is already level {}",
+            character.name,
+            classes::MAX_LEVEL
+        )));
+    }
+    let class = match choices.class.as_deref().map(str::trim) {
+        Some(class) => class.to_string(),
+        None => character.character_class.clone(),
+    };
+    let index = match character.classes.iter().position(|entry| entry.class.trim().eq_ignore_ascii_case(&class)) {
+        Some(index) => {
+            character.classes[index].level += 1;
+            index
+        }
+        None => {
+            let progression = check_multiclass(character, &class, rules)?;
+            character.classes.push(ClassLevel {
+                class: progression.name.to_string(),
+                subclass: None,
+                level: 1,
+                hit_die: progression.hit_die,
+                hit_dice_used: 0,
+            });
+            character.classes.len() - 1
+        }
+    };
+    let entry = character.classes[index].clone();
+    let progression = classes::progression(&entry.class);
+    let previous_level = character.level;
+
+    let constitution_before = character.stats.get_modifier("constitution");
+    let feat = apply_improvement(character, &entry, choices.improvement.as_ref(), rules)?;
+    let constitution = character.stats.get_modifier("constitution");
+
+    let (hit_point_roll, hit_die) = match choices.hit_points {
+        HitPointMethod::Average => (None, entry.hit_die as i64 / 2 + 1),
+        HitPointMethod::Roll => {
+            let roll = roller.roll_expr(&DiceExpr::Dice(DiceTerm::new(1, entry.hit_die)))?;
+            let total = roll.total;
+            (Some(roll), total)
+        }
+    };
+    // A higher Constitution modifier counts for every earlier level too
+    let hit_points_gained = (hit_die + constitution).max(1) + (constitution - constitution_before) * previous_level;
+
+    let stats = &mut character.combat_stats;
+    stats.max_hit_points += hit_points_gained;
+    // A dying character stays at 0 until healed
+    if stats.hit_points > 0 {
+        stats.hit_points = (stats.hit_points + hit_points_gained).clamp(1, stats.max_hit_points);
+    }
+    character.sync_classes();
+    character.stats.proficiency_bonus = character.proficiency_bonus();
+
+    let (mut features_gained, features_improved) = match progression {
+        Some(progression) => grant_features(character, progression, entry.level),
+        None => (Vec::new(), Vec::new()),
+    };
+    if let Some(feat) = feat {
+        features_gained.push(feat.name.clone());
+        character.features.push(feat);
+    }
+    update_spell_slots(character, &entry);
+
+    Ok(LevelUpResult {
+        character_id: character.id.clone(),
+        class: entry.class,
+        class_level: entry.level,
+        level: character.level,
+        hit_point_roll,
+        hit_points_gained,
+        proficiency_bonus: character.stats.proficiency_bonus,
+        features_gained,
+        features_improved,
+        spell_slots: character.spellcasting.slots.clone(),
+        pact_slots: character.spellcasting.pact_slots.clone(),
+    })
+}
+
+/// Make sure the campaign allows multiclassing and the character has the
+/// scores to take up a new class alongside the ones they have
+fn check_multiclass(
+    character: &Character,
+    class: &str,
+    rules: &VariantRules,
+) -> AppResult<&'static ClassProgression> {
+    if !rules.multiclassing {
+        return Err(AppError::InvalidInput("Multiclassing is turned off for this campaign".to_string()));
+    }
+    let target = classes::progression(class)
+        .ok_or_else(|| AppError::InvalidInput(format!("Unknown class: {}", class)))?;
+
+    for current in character.classes.iter().filter_map(|entry| classes::progression(&entry.class)) {
+        if !classes::meets_prerequisites(current, &character.stats) {
+            return Err(AppError::InvalidInput(format!(
+                "{} needs {} to multiclass out of {}",
+                character.name,
+                classes::prerequisite_text(current),
+                current.name
+            )));
+        }
+    }
+    if !classes::meets_prerequisites(target, &character.stats) {
+        return Err(AppError::InvalidInput(format!(
+            "{} needs {} to multiclass into {}",
+            character.name,
+            classes::prerequisite_text(target),
+            target.name
+        )));
+    }
+    Ok(target)
+}
+
+/// Check and apply the ability score improvement for a class level; a feat
+/// is returned for the caller to add once the class's own features are in
+fn apply_improvement(
+    character: &mut Character,
+    class: &ClassLevel,
+    improvement: Option<&AbilityImprovement>,
+    rules: &VariantRules,
+) -> AppResult<Option<Feature>> {
+    let (class_level, class) = (class.level, class.class.as_str());
+    if !classes::improvement_levels(class).contains(&class_level) {
+        if improvement.is_some() {
+            return Err(AppError::InvalidInput(format!(
+                "{} level {} doesn't grant an ability score improvement",
+                class, class_level
+            )));
+        }
+        return Ok(None);
+    }
+
+    match improvement {
+        None if rules.customizing_ability_scores || rules.feats => Err(AppError::InvalidInput(format!(
+            "{} level {} grants an ability score improvement; choose one",
+            class, class_level
+        ))),
+        None => Ok(None),
+        Some(AbilityImprovement::AbilityScores { increases }) => {
+            if !rules.customizing_ability_scores {
+                return Err(AppError::InvalidInput(
+                    "Ability score improvements are turned off for this campaign".to_string(),
+                ));
+            }
+            let total: i64 = increases.values().sum();
+            if total != IMPROVEMENT_POINTS || increases.values().any(|&points| points < 1) {
+                return Err(AppError::InvalidInput(format!(
+                    "An ability score improvement adds {} points over one or two abilities",
+                    IMPROVEMENT_POINTS
+                )));
+            }
+            let mut stats = character.stats.clone();
+            for (ability, points) in increases {
+                raise_score(&mut stats, ability, *points)?;
+            }
+            character.stats = stats;
+            Ok(None)
+        }
+        Some(AbilityImprovement::Feat { name, description, ability }) => {
+            if !rules.feats {
+                return Err(AppError::InvalidInput("Feats are turned off for this campaign".to_string()));
+            }
+            let name = name.trim();
+            if name.is_empty() {
+                return Err(AppError::InvalidInput("A feat needs a name".to_string()));
+            }
+            let taken = character.features.iter().any(|feature| {
+                matches!(feature.source, FeatureSource::Feat) && feature.name.eq_ignore_ascii_case(name)
+            });
+            if taken {
+                return Err(AppError::InvalidInput(format!("{} already has the {} feat", character.name, name)));
+            }
+            if let Some(ability) = ability {
+                raise_score(&mut character.stats, ability, 1)?;
+            }
+            Ok(Some(Feature {
+                id: Uuid::new_v4().to_string(),
+                name: name.to_string(),
+                description: description.clone(),
+                source: FeatureSource::Feat,
+                uses: None,
+            }))
+        }
+    }
+}
+
+fn raise_score(stats: &mut CharacterStats, ability: &str, points: i64) -> AppResult<()> {
+    let score = stats
+        .score_mut(ability)
+        .ok_or_else(|| AppError::InvalidInput(format!("Unknown ability: {}", ability)))?;
+    if *score + points > MAX_IMPROVED_SCORE {
+        return Err(AppError::InvalidInput(format!(
+            "{} can't be raised above {}",
+            ability, MAX_IMPROVED_SCORE
+        )));
+    }
+    *score += points;
+    Ok(())
+}
+
+/// Add the class's features for `class_level` and bring the uses of features
+/// the character already has up to date; returns the names of both
+fn grant_features(
+    character: &mut Character,
+    progression: &ClassProgression,
+    class_level: i64,
+) -> (Vec<String>, Vec<String>) {
+    let mut gained = Vec::new();
+    let mut improved = Vec::new();
+
+    for class_feature in progression.features.iter().filter(|feature| feature.level <= class_level) {
+        let uses = class_feature.uses.map(|rule| rule(class_level, &character.stats));
+        let existing = character.features.iter_mut().find(|feature| {
+            matches!(feature.source, FeatureSource::Class) && feature.name.eq_ignore_ascii_case(class_feature.name)
+        });
+
+        match (existing, uses) {
+            (Some(feature), Some((max_uses, recharge))) => {
+                let changed = feature
+                    .uses
+                    .as_ref()
+                    .is_none_or(|uses| uses.max_uses != max_uses || uses.recharge != recharge);
+                if changed {
+                    // Uses gained are available straight away; ones lost go from what's left
+                    let current = feature.uses.as_ref().map_or(max_uses, |uses| {
+                        (uses.current_uses + max_uses - uses.max_uses).clamp(0, max_uses)
+                    });
+                    feature.uses = Some(FeatureUses { max_uses, current_uses: current, recharge });
+                    improved.push(feature.name.clone());
+                }
+            }
+            (Some(_), None) => {}
+            (None, uses) if class_feature.level == class_level => {
+                character.features.push(Feature {
+                    id: Uuid::new_v4().to_string(),
+                    name: class_feature.name.to_string(),
+                    description: class_feature.description.to_string(),
+                    source: FeatureSource::Class,
+                    uses: uses.map(|(max_uses, recharge)| FeatureUses {
+                        max_uses,
+                        current_uses: max_uses,
+                        recharge,
+                    }),
+                });
+                gained.push(class_feature.name.to_string());
+            }
+            // Earlier features the character doesn't have are left to the player
+            (None, _) => {}
+        }
+    }
+    (gained, improved)
+}
+
+/// Raise slot maximums to those for the character's caster level and
+/// warlock level, keeping how many of each are used
+fn update_spell_slots(character: &mut Character, leveled: &ClassLevel) {
+    let caster_level = character.caster_level();
+    let warlock_level = classes::warlock_level(&character.classes);
+    let spellcasting = &mut character.spellcasting;
+
+    for (level, max) in classes::full_caster_slots(caster_level) {
+        match spellcasting.slot_mut(level) {
+            Some(slot) => slot.max = slot.max.max(max),
+            None => spellcasting.slots.push(SpellSlots { level, max, used: 0 }),
+        }
+    }
+    spellcasting.slots.sort_by_key(|slot| slot.level);
+    if let Some((max, level)) = classes::pact_slots(warlock_level) {
+        let used = spellcasting.pact_slots.as_ref().map_or(0, |pact| pact.used.min(max));
+        spellcasting.pact_slots = Some(SpellSlots { level, max, used });
+    }
+    if spellcasting.ability.is_none() {
+        spellcasting.ability = classes::spellcasting_ability(leveled).map(str::to_string);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fighter(level: i64) -> Character {
+        let mut character = Character::sample("Ann");
+        character.level = level;
+        character.classes[0].level = level;
+        character
+    }
+
+    fn take_level(
+        character: &mut Character,
+        choices: &LevelUpChoices,
+        rules: &VariantRules,
+    ) -> AppResult<LevelUpResult> {
+        level_up(&mut DiceRoller::from_seed(7), character, choices, rules)
+    }
+
+    fn scores(increases: &[(&str, i64)]) -> Option<AbilityImprovement> {
+        let increases = increases.iter().map(|(ability, points)| (ability.to_string(), *points)).collect();
+        Some(AbilityImprovement::AbilityScores { increases })
+    }
+
+    fn feat(name: &str, ability: Option<&str>) -> Option<AbilityImprovement> {
+        Some(AbilityImprovement::Feat {
+            name: name.to_string(),
+            description: String::new(),
+            ability: ability.map(str::to_string),
+        })
+    }
+
+    fn improvements() -> VariantRules {
+        VariantRules { customizing_ability_scores: true, feats: true, ..Default::default() }
+    }
+
+    #[test]
+    fn gains_average_hit_points_and_new_class_features() {
+        let mut character = fighter(1);
+        character.stats.constitution = 14;
+        let result = take_level(&mut character, &LevelUpChoices::default(), &VariantRules::default()).unwrap();
+
+        assert_eq!((result.level, result.class_level, result.hit_points_gained), (2, 2, 8));
+        assert!(result.hit_point_roll.is_none());
+        assert_eq!(result.features_gained, ["Action Surge"]);
+        assert_eq!((character.combat_stats.hit_points, character.combat_stats.max_hit_points), (18, 18));
+    }
+
+    #[test]
+    fn rolled_hit_points_stay_within_the_hit_die_and_dying_characters_stay_at_zero() {
+        let mut character = fighter(1);
+        character.combat_stats.hit_points = 0;
+        let choices = LevelUpChoices { hit_points: HitPointMethod::Roll, ..Default::default() };
+        let result = take_level(&mut character, &choices, &VariantRules::default()).unwrap();
+
+        let roll = result.hit_point_roll.unwrap().total;
+        assert!((1..=10).contains(&roll));
+        assert_eq!(result.hit_points_gained, roll);
+        assert_eq!(character.combat_stats.hit_points, 0);
+        assert_eq!(character.combat_stats.max_hit_points, 10 + roll);
+    }
+
+    #[test]
+    fn improvements_follow_the_campaign_rules() {
+        let choose = |improvement| LevelUpChoices { improvement, ..Default::default() };
+
+        // Nothing to choose when the campaign allows neither
+        let mut character = fighter(3);
+        assert!(take_level(&mut character, &choose(None), &VariantRules::default()).is_ok());
+
+        let mut character = fighter(3);
+        assert!(take_level(&mut character, &choose(None), &improvements()).is_err());
+        assert!(take_level(&mut character, &choose(scores(&[("strength", 3)])), &improvements()).is_err());
+        let feats_only = VariantRules { feats: true, ..Default::default() };
+        assert!(take_level(&mut character, &choose(scores(&[("strength", 2)])), &feats_only).is_err());
+        assert_eq!((character.level, character.stats.strength), (3, 10));
+
+        take_level(&mut character, &choose(scores(&[("strength", 1), ("dexterity", 1)])), &improvements()).unwrap();
+        assert_eq!((character.stats.strength, character.stats.dexterity), (11, 11));
+        // Level 5 has no improvement to take
+        assert!(take_level(&mut character, &choose(scores(&[("strength", 2)])), &improvements()).is_err());
+    }
+
+    #[test]
+    fn a_higher_constitution_counts_for_earlier_levels() {
+        let mut character = fighter(3);
+        let choices = LevelUpChoices { improvement: scores(&[("constitution", 2)]), ..Default::default() };
+        let result = take_level(&mut character, &choices, &improvements()).unwrap();
+        // 6 for the level and 1 more for each of the three before it
+        assert_eq!(result.hit_points_gained, 10);
+        assert_eq!(character.combat_stats.max_hit_points, 20);
+    }
+
+    #[test]
+    fn feats_raise_one_score_and_cannot_be_taken_twice() {
+        let mut character = fighter(3);
+        character.stats.strength = 19;
+        let choices = LevelUpChoices { improvement: feat("Tough", Some("strength")), ..Default::default() };
+        let result = take_level(&mut character, &choices, &improvements()).unwrap();
+        assert!(result.features_gained.contains(&"Tough".to_string()));
+        assert_eq!(character.stats.strength, MAX_IMPROVED_SCORE);
+
+        take_level(&mut character, &LevelUpChoices::default(), &improvements()).unwrap();
+        let again = LevelUpChoices { improvement: feat("tough", None), ..Default::default() };
+        assert!(take_level(&mut character, &again, &improvements()).is_err());
+        let over = LevelUpChoices { improvement: feat("Athlete", Some("strength")), ..Default::default() };
+        assert!(take_level(&mut character, &over, &improvements()).is_err());
+        assert_eq!(character.level, 5);
+    }
+
+    #[test]
+    fn multiclassing_needs_the_rule_and_prerequisites() {
+        let rules = VariantRules { multiclassing: true, ..Default::default() };
+        let wizard = LevelUpChoices { class: Some("wizard".to_string()), ..Default::default() };
+        let mut character = fighter(1);
+
+        assert!(take_level(&mut character, &wizard, &VariantRules::default()).is_err());
+        character.stats.intelligence = 13;
+        // Leaving Fighter needs Strength or Dexterity 13 as well
+        assert!(take_level(&mut character, &wizard, &rules).is_err());
+        character.stats.dexterity = 13;
+        let result = take_level(&mut character, &wizard, &rules).unwrap();
+
+        assert_eq!((result.class.as_str(), result.class_level, result.level), ("Wizard", 1, 2));
+        assert_eq!(result.features_gained, ["Arcane Recovery"]);
+        assert_eq!(character.classes.len(), 2);
+        let slots: Vec<_> = character.spellcasting.slots.iter().map(|slot| (slot.level, slot.max)).collect();
+        assert_eq!(slots, [(1, 2)]);
+        assert_eq!(character.spellcasting.ability.as_deref(), Some("intelligence"));
+    }
+
+    #[test]
+    fn no_level_past_twenty() {
+        let mut character = fighter(classes::MAX_LEVEL);
+        assert!(take_level(&mut character, &LevelUpChoices::default(), &VariantRules::default()).is_err());
+        assert_eq!(character.level, classes::MAX_LEVEL);
+    }
+}
@@ -0,0 +1,6 @@
+pub mod classes;
+pub mod inventory;
+pub mod level_up;
+pub mod rest;
+pub mod sheet;
+pub mod spellcasting;
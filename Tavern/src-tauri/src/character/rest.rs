@@ -0,0 +1,304 @@
+use chrono::Utc;
+use rand::Rng;
+use uuid::Uuid;
+
+use crate::combat::damage;
+use crate::database::models::{Character, HitDiceSpend, RechargeType, RestRecord, RestType};
+use crate::dice::parser::{BinaryOp, DiceExpr, DiceTerm};
+use crate::dice::roller::DiceRoller;
+use crate::errors::{AppError, AppResult};
+
+/// Take a short rest, spending some of the character's remaining hit dice
+///
+/// Each hit die is its class's own and heals its roll plus the Constitution
+/// modifier. Features that recharge on a short rest and pact magic slots come
+/// back.
+pub fn short_rest<R: Rng>(
+    roller: &mut DiceRoller<R>,
+    character: &mut Character,
+    hit_dice: &[HitDiceSpend],
+) -> AppResult<RestRecord> {
+    ensure_alive(character)?;
+    let mut spent = vec![0; character.classes.len()];
+    for spend in hit_dice {
+        let class = spend.class.trim();
+        let index = character
+            .classes
+            .iter()
+            .position(|entry| entry.class.trim().eq_ignore_ascii_case(class))
+            .ok_or_else(|| AppError::InvalidInput(format!("{} has no levels in {}", character.name, class)))?;
+        if spend.count < 0 {
+            return Err(AppError::InvalidInput(format!("Can't spend {} hit dice", spend.count)));
+        }
+        spent[index] += spend.count;
+    }
+    for (class, &count) in character.classes.iter().zip(&spent) {
+        let available = class.level - class.hit_dice_used;
+        if count > available {
+            return Err(AppError::InvalidInput(format!(
+                "{} has {} {} hit dice to spend, not {}",
+                character.name, available.max(0), class.class, count
+            )));
+        }
+    }
+
+    let mut record = new_record(character, RestType::Short);
+    let total: i64 = spent.iter().sum();
+    let dice = character
+        .classes
+        .iter()
+        .zip(&spent)
+        .filter(|(_, &count)| count > 0)
+        .map(|(class, &count)| DiceExpr::Labeled {
+            label: class.class.clone(),
+            expr: Box::new(DiceExpr::Dice(DiceTerm::new(count as u32, class.hit_die))),
+        })
+        .reduce(|lhs, rhs| DiceExpr::Binary { op: BinaryOp::Add, lhs: Box::new(lhs), rhs: Box::new(rhs) });
+    if let Some(dice) = dice {
+        let constitution = character.stats.get_modifier("constitution") * total;
+        let expr = DiceExpr::Binary {
+            op: BinaryOp::Add,
+            lhs: Box::new(dice),
+            rhs: Box::new(DiceExpr::Labeled {
+                label: "Constitution".to_string(),
+                expr: Box::new(DiceExpr::Number(constitution)),
+            }),
+        };
+        let roll = roller.roll_expr(&expr)?;
+
+        for (class, count) in character.classes.iter_mut().zip(spent) {
+            class.hit_dice_used += count;
+        }
+        let stats = &mut character.combat_stats;
+        let before = stats.hit_points;
+        stats.hit_points = (stats.hit_points + roll.total.max(0)).min(stats.max_hit_points);
+        if before == 0 && stats.hit_points > 0 {
+            damage::revive(stats);
+        }
+
+        record.hit_dice_spent = total;
+        record.hit_points_restored = stats.hit_points - before;
+        record.hit_dice_roll = Some(roll);
+    }
+
+    record.features_recharged = recharge_features(character, &[RechargeType::ShortRest]);
+    if let Some(pact) = character.spellcasting.pact_slots.as_mut() {
+        record.spell_slots_restored = pact.used;
+        pact.used = 0;
+    }
+    reset_death_saves(character);
+    Ok(record)
+}
+
+/// Take a long rest: back to full hit points, half of all hit dice regained
+/// (at least one), every spell slot back, and every feature that recharges on
+/// a short or long rest or daily gets its uses back
+///
+/// Hit dice come back largest first. A character needs at least 1 hit point
+/// to benefit.
+pub fn long_rest(character: &mut Character) -> AppResult<RestRecord> {
+    ensure_alive(character)?;
+    if character.combat_stats.hit_points <= 0 {
+        return Err(AppError::InvalidInput(format!(
+            "{} needs at least 1 hit point to benefit from a long rest",
+            character.name
+        )));
+    }
+
+    let mut record = new_record(character, RestType::Long);
+    let stats = &mut character.combat_stats;
+    record.hit_points_restored = stats.max_hit_points - stats.hit_points;
+    stats.hit_points = stats.max_hit_points;
+
+    let mut to_regain = (character.total_level() / 2).max(1);
+    let mut by_size: Vec<_> = character.classes.iter_mut().collect();
+    by_size.sort_by_key(|class| std::cmp::Reverse(class.hit_die));
+    for class in by_size {
+        let regained = to_regain.min(class.hit_dice_used);
+        class.hit_dice_used -= regained;
+        to_regain -= regained;
+        record.hit_dice_restored += regained;
+    }
+
+    record.features_recharged = recharge_features(
+        character,
+        &[RechargeType::ShortRest, RechargeType::LongRest, RechargeType::Daily],
+    );
+    let spellcasting = &mut character.spellcasting;
+    for slots in spellcasting.slots.iter_mut().chain(spellcasting.pact_slots.as_mut()) {
+        record.spell_slots_restored += slots.used;
+        slots.used = 0;
+    }
+    reset_death_saves(character);
+    Ok(record)
+}
+
+fn ensure_alive(character: &Character) -> AppResult<()> {
+    if character.combat_stats.is_dead {
+        return Err(AppError::InvalidInput(format!("{} is dead", character.name)));
+    }
+    Ok(())
+}
+
+fn new_record(character: &Character, rest_type: RestType) -> RestRecord {
+    RestRecord {
+        id: Uuid::new_v4().to_string(),
+        campaign_id: character.campaign_id.clone(),
+        character_id: character.id.clone(),
+        character_name: character.name.clone(),
+        rest_type,
+        hit_dice_spent: 0,
+        hit_dice_roll: None,
+        hit_points_restored: 0,
+        hit_dice_restored: 0,
+        features_recharged: Vec::new(),
+        spell_slots_restored: 0,
+        created_at: Utc::now(),
+    }
+}
+
+/// Refill the uses of features with one of the given recharge types, returning
+/// the names of those that had any to get back
+fn recharge_features(character: &mut Character, recharges: &[RechargeType]) -> Vec<String> {
+    let mut recharged = Vec::new();
+    for feature in &mut character.features {
+        if let Some(uses) = feature.uses.as_mut() {
+            if recharges.contains(&uses.recharge) && uses.current_uses < uses.max_uses {
+                uses.current_uses = uses.max_uses;
+                recharged.push(feature.name.clone());
+            }
+        }
+    }
+    recharged
+}
+
+fn reset_death_saves(character: &mut Character) {
+    character.combat_stats.death_saves_success = 0;
+    character.combat_stats.death_saves_failure = 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::models::{ClassLevel, Feature, FeatureSource, FeatureUses, SpellSlots};
+
+    /// A fighter 2 / wizard 3 with Constitution 14, down to 1 hit point
+    fn multiclassed() -> Character {
+        let mut character = Character::sample("Ann");
+        character.stats.constitution = 14;
+        character.classes = vec![
+            ClassLevel { class: "Fighter".to_string(), subclass: None, level: 2, hit_die: 10, hit_dice_used: 0 },
+            ClassLevel { class: "Wizard".to_string(), subclass: None, level: 3, hit_die: 6, hit_dice_used: 0 },
+        ];
+        character.sync_classes();
+        character.combat_stats.hit_points = 1;
+        character.combat_stats.max_hit_points = 100;
+        character
+    }
+
+    fn feature(name: &str, recharge: RechargeType) -> Feature {
+        Feature {
+            id: name.to_lowercase(),
+            name: name.to_string(),
+            description: String::new(),
+            source: FeatureSource::Class,
+            uses: Some(FeatureUses { max_uses: 2, current_uses: 0, recharge }),
+        }
+    }
+
+    fn spend(class: &str, count: i64) -> HitDiceSpend {
+        HitDiceSpend { class: class.to_string(), count }
+    }
+
+    fn used(character: &Character) -> Vec<i64> {
+        character.classes.iter().map(|class| class.hit_dice_used).collect()
+    }
+
+    #[test]
+    fn short_rests_roll_each_class_its_own_hit_dice() {
+        let mut roller = DiceRoller::from_seed(3);
+        let mut character = multiclassed();
+
+        let record = short_rest(&mut roller, &mut character, &[spend("fighter", 1), spend(" Wizard ", 2)]).unwrap();
+        let roll = record.hit_dice_roll.unwrap();
+        assert_eq!(roll.dice_notation, "1d10[Fighter] + 2d6[Wizard] + 6[Constitution]");
+        assert_eq!(record.hit_dice_spent, 3);
+        assert_eq!(record.hit_points_restored, roll.total);
+        assert_eq!(character.combat_stats.hit_points, 1 + roll.total);
+        assert_eq!(used(&character), [1, 2]);
+    }
+
+    #[test]
+    fn short_rests_refuse_hit_dice_the_character_lacks() {
+        let mut roller = DiceRoller::from_seed(3);
+        let mut character = multiclassed();
+        character.classes[1].hit_dice_used = 2;
+
+        assert!(short_rest(&mut roller, &mut character, &[spend("wizard", 2)]).is_err());
+        assert!(short_rest(&mut roller, &mut character, &[spend("fighter", 1), spend("fighter", 2)]).is_err());
+        assert!(short_rest(&mut roller, &mut character, &[spend("rogue", 1)]).is_err());
+        assert!(short_rest(&mut roller, &mut character, &[spend("fighter", -1)]).is_err());
+        assert_eq!(used(&character), [0, 2]);
+
+        let record = short_rest(&mut roller, &mut character, &[]).unwrap();
+        assert!(record.hit_dice_roll.is_none());
+        assert_eq!(character.combat_stats.hit_points, 1);
+    }
+
+    #[test]
+    fn short_rests_recharge_short_rest_features_and_pact_slots() {
+        let mut roller = DiceRoller::from_seed(3);
+        let mut character = multiclassed();
+        character.features = vec![
+            feature("Second Wind", RechargeType::ShortRest),
+            feature("Arcane Recovery", RechargeType::LongRest),
+        ];
+        character.spellcasting.slots = vec![SpellSlots { level: 1, max: 4, used: 3 }];
+        character.spellcasting.pact_slots = Some(SpellSlots { level: 2, max: 2, used: 2 });
+
+        let record = short_rest(&mut roller, &mut character, &[]).unwrap();
+        assert_eq!(record.features_recharged, ["Second Wind"]);
+        assert_eq!(record.spell_slots_restored, 2);
+        assert_eq!(character.spellcasting.slots[0].used, 3);
+    }
+
+    #[test]
+    fn long_rests_regain_half_the_hit_dice_largest_first() {
+        let mut character = multiclassed();
+        character.classes[0].hit_dice_used = 1;
+        character.classes[1].hit_dice_used = 3;
+
+        let record = long_rest(&mut character).unwrap();
+        assert_eq!(record.hit_dice_restored, 2);
+        assert_eq!(used(&character), [0, 2]);
+        assert_eq!(character.combat_stats.hit_points, 100);
+        assert_eq!(record.hit_points_restored, 99);
+    }
+
+    #[test]
+    fn long_rests_regain_at_least_one_hit_die() {
+        let mut character = Character::sample("Ann");
+        character.classes[0].hit_dice_used = 1;
+        assert_eq!(long_rest(&mut character).unwrap().hit_dice_restored, 1);
+        assert_eq!(used(&character), [0]);
+    }
+
+    #[test]
+    fn long_rests_restore_every_slot_and_feature_but_need_a_hit_point() {
+        let mut character = multiclassed();
+        character.features = vec![
+            feature("Second Wind", RechargeType::ShortRest),
+            feature("Arcane Recovery", RechargeType::LongRest),
+            feature("Boon", RechargeType::Weekly),
+        ];
+        character.spellcasting.slots = vec![SpellSlots { level: 1, max: 4, used: 3 }];
+        character.spellcasting.pact_slots = Some(SpellSlots { level: 2, max: 2, used: 1 });
+
+        let record = long_rest(&mut character).unwrap();
+        assert_eq!(record.features_recharged, ["Second Wind", "Arcane Recovery"]);
+        assert_eq!(record.spell_slots_restored, 4);
+
+        character.combat_stats.hit_points = 0;
+        assert!(long_rest(&mut character).is_err());
+    }
+}
@@ -0,0 +1,314 @@
+use crate::database::models::{
+    Armor, ArmorType, Character, CharacterSheet, DerivedStats, DiceModifier, Encumbrance, Skills,
+    WeaponAttackBonus,
+};
+use crate::dice::checks;
+
+/// Armor class without armor
+const BASE_ARMOR_CLASS: i64 = 10;
+
+/// Most Dexterity medium armor adds to armor class
+const MEDIUM_ARMOR_DEX_CAP: i64 = 2;
+
+/// What a shield adds when its own armor class isn't filled in
+const SHIELD_BONUS: i64 = 2;
+
+/// Carrying capacity, and the variant encumbrance thresholds, in pounds per
+/// point of Strength
+const CARRYING_CAPACITY: f32 = 15.0;
+const ENCUMBERED: f32 = 5.0;
+const HEAVILY_ENCUMBERED: f32 = 10.0;
+
+/// A character with the values derived from them
+pub fn character_sheet(character: Character) -> CharacterSheet {
+    let derived = derive_stats(&character);
+    CharacterSheet { character, derived }
+}
+
+/// Work out a character's armor class, initiative, passive scores,
+/// encumbrance and attack bonuses from their stats, skills and equipment,
+/// rather than the numbers stored in their combat stats
+pub fn derive_stats(character: &Character) -> DerivedStats {
+    let stats = &character.stats;
+    let proficiency_bonus = character.proficiency_bonus();
+    let passive = |skill: &str| {
+        let ability = Skills::ability_for(skill).unwrap_or_default();
+        let bonus = character.skills.get(skill).map_or(0, |proficiency| proficiency.bonus(proficiency_bonus));
+        10 + stats.get_modifier(ability) + bonus
+    };
+
+    let armor_class_modifiers = armor_class(character);
+    let strength = stats.strength.max(0) as f32;
+    let carried_weight: f32 = character
+        .equipment
+        .items
+        .iter()
+        .map(|item| item.weight * item.quantity.max(0) as f32)
+        .sum();
+    let encumbrance = if carried_weight > strength * CARRYING_CAPACITY {
+        Encumbrance::OverCapacity
+    } else if carried_weight > strength * HEAVILY_ENCUMBERED {
+        Encumbrance::HeavilyEncumbered
+    } else if carried_weight > strength * ENCUMBERED {
+        Encumbrance::Encumbered
+    } else {
+        Encumbrance::Unencumbered
+    };
+
+    let weapon_attacks = character
+        .equipment
+        .weapons
+        .iter()
+        .map(|weapon| {
+            let ability = checks::weapon_ability(stats, weapon);
+            let modifier = stats.get_modifier(ability);
+            let proficiency = if weapon.is_proficient { proficiency_bonus } else { 0 };
+            let damage = match modifier {
+                0 => weapon.damage_dice.clone(),
+                m if m < 0 => format!("{} - {}", weapon.damage_dice, -m),
+                m => format!("{} + {}", weapon.damage_dice, m),
+            };
+            WeaponAttackBonus {
+                weapon_id: weapon.id.clone(),
+                weapon_name: weapon.name.clone(),
+                is_equipped: weapon.is_equipped,
+                ability: ability.to_string(),
+                attack_bonus: modifier + proficiency,
+                damage,
+                damage_type: weapon.damage_type,
+            }
+        })
+        .collect();
+
+    DerivedStats {
+        level: character.total_level(),
+        proficiency_bonus,
+        caster_level: character.caster_level(),
+        armor_class: armor_class_modifiers.iter().map(|modifier| modifier.value).sum(),
+        armor_class_modifiers,
+        initiative_bonus: stats.get_modifier("dexterity"),
+        passive_perception: passive("perception"),
+        passive_investigation: passive("investigation"),
+        passive_insight: passive("insight"),
+        carried_weight,
+        carrying_capacity: strength * CARRYING_CAPACITY,
+        encumbrance,
+        stealth_disadvantage: equipped_armor(character).any(|armor| armor.stealth_disadvantage),
+        weapon_attacks,
+    }
+}
+
+fn equipped_armor(character: &Character) -> impl Iterator<Item = &Armor> {
+    character.equipment.armor.iter().filter(|armor| armor.is_equipped)
+}
+
+/// The parts of the best armor class the character has: the body armor worn
+/// (the best of it, if more than one piece is marked equipped) or unarmored
+/// defense, and a shield
+///
+/// Light armor adds all of the Dexterity modifier, medium armor up to +2 and
+/// heavy armor none. Barbarians add Constitution when unarmored, and monks
+/// add Wisdom when they have neither armor nor a shield.
+fn armor_class(character: &Character) -> Vec<DiceModifier> {
+    let stats = &character.stats;
+    let dexterity = stats.get_modifier("dexterity");
+    let shield = equipped_armor(character)
+        .filter(|armor| matches!(armor.armor_type, ArmorType::Shield))
+        .max_by_key(|shield| shield_bonus(shield));
+
+    let mut options: Vec<Vec<DiceModifier>> = equipped_armor(character)
+        .filter_map(|armor| {
+            let dexterity = match armor.armor_type {
+                ArmorType::Light => dexterity,
+                ArmorType::Medium => dexterity.min(MEDIUM_ARMOR_DEX_CAP),
+                ArmorType::Heavy => 0,
+                ArmorType::Shield => return None,
+            };
+            Some(vec![modifier(&armor.name, armor.armor_class, "armor"), modifier("Dexterity", dexterity, "ability")])
+        })
+        .collect();
+
+    if options.is_empty() {
+        options.push(vec![
+            modifier("Unarmored", BASE_ARMOR_CLASS, "armor"),
+            modifier("Dexterity", dexterity, "ability"),
+        ]);
+        let has_class = |class: &str| character.class_level(class).is_some();
+        if has_class("Barbarian") {
+            options.push(vec![
+                modifier("Unarmored Defense", BASE_ARMOR_CLASS, "class"),
+                modifier("Dexterity", dexterity, "ability"),
+                modifier("Constitution", stats.get_modifier("constitution"), "ability"),
+            ]);
+        }
+        if has_class("Monk") && shield.is_none() {
+            options.push(vec![
+                modifier("Unarmored Defense", BASE_ARMOR_CLASS, "class"),
+                modifier("Dexterity", dexterity, "ability"),
+                modifier("Wisdom", stats.get_modifier("wisdom"), "ability"),
+            ]);
+        }
+    }
+
+    let mut best = options
+        .into_iter()
+        .max_by_key(|parts| parts.iter().map(|part| part.value).sum::<i64>())
+        .unwrap_or_default();
+    best.retain(|part| part.value != 0 || part.source != "ability");
+    if let Some(shield) = shield {
+        best.push(modifier(&shield.name, shield_bonus(shield), "shield"));
+    }
+    best
+}
+
+fn shield_bonus(shield: &Armor) -> i64 {
+    if shield.armor_class > 0 {
+        shield.armor_class
+    } else {
+        SHIELD_BONUS
+    }
+}
+
+fn modifier(name: &str, value: i64, source: &str) -> DiceModifier {
+    DiceModifier {
+        name: name.to_string(),
+        value,
+        source: source.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::models::{Item, ItemRarity, ItemType, SkillProficiency};
+
+    fn armor(name: &str, armor_class: i64, armor_type: ArmorType) -> Armor {
+        Armor {
+            id: name.to_lowercase(),
+            name: name.to_string(),
+            armor_class,
+            stealth_disadvantage: matches!(armor_type, ArmorType::Heavy),
+            armor_type,
+            is_equipped: true,
+            compendium_id: None,
+        }
+    }
+
+    fn wearing(dexterity: i64, armor: Vec<Armor>) -> Character {
+        let mut character = Character::sample("Ann");
+        character.stats.dexterity = dexterity;
+        character.equipment.armor = armor;
+        character
+    }
+
+    fn of_class(class: &str, character: Character) -> Character {
+        let mut character = character;
+        character.character_class = class.to_string();
+        character.classes[0].class = class.to_string();
+        character
+    }
+
+    fn armor_class_parts(character: &Character) -> (i64, Vec<(String, i64)>) {
+        let derived = derive_stats(character);
+        let parts = derived.armor_class_modifiers.into_iter().map(|part| (part.name, part.value)).collect();
+        (derived.armor_class, parts)
+    }
+
+    fn carrying(weight: f32, quantity: i64) -> Encumbrance {
+        let mut character = Character::sample("Ann");
+        character.equipment.items = vec![Item {
+            id: "sack".to_string(),
+            name: "Sack".to_string(),
+            description: String::new(),
+            quantity,
+            weight,
+            value: 0,
+            rarity: ItemRarity::Common,
+            item_type: ItemType::AdventuringGear,
+            compendium_id: None,
+        }];
+        derive_stats(&character).encumbrance
+    }
+
+    #[test]
+    fn unarmored_characters_add_all_their_dexterity() {
+        let (total, parts) = armor_class_parts(&wearing(14, Vec::new()));
+        assert_eq!(total, 12);
+        assert_eq!(parts, [("Unarmored".to_string(), 10), ("Dexterity".to_string(), 2)]);
+        // A modifier of 0 isn't listed
+        assert_eq!(armor_class_parts(&wearing(10, Vec::new())).1.len(), 1);
+    }
+
+    #[test]
+    fn armor_caps_dexterity_by_its_type_and_shields_add_on_top() {
+        assert_eq!(armor_class_parts(&wearing(18, vec![armor("Leather", 11, ArmorType::Light)])).0, 15);
+        assert_eq!(armor_class_parts(&wearing(18, vec![armor("Scale Mail", 14, ArmorType::Medium)])).0, 16);
+        assert_eq!(armor_class_parts(&wearing(8, vec![armor("Scale Mail", 14, ArmorType::Medium)])).0, 13);
+        assert_eq!(armor_class_parts(&wearing(18, vec![armor("Plate", 18, ArmorType::Heavy)])).0, 18);
+
+        let shielded = wearing(18, vec![armor("Shield", 0, ArmorType::Shield), armor("Scale", 14, ArmorType::Medium)]);
+        let (total, parts) = armor_class_parts(&shielded);
+        assert_eq!(total, 18);
+        let names: Vec<_> = parts.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["Scale", "Dexterity", "Shield"]);
+    }
+
+    #[test]
+    fn only_the_best_equipped_armor_and_shield_count() {
+        let mut pieces = vec![
+            armor("Leather", 11, ArmorType::Light),
+            armor("Plate", 18, ArmorType::Heavy),
+            armor("Shield", 0, ArmorType::Shield),
+            armor("Shield +1", 3, ArmorType::Shield),
+        ];
+        pieces[1].is_equipped = false;
+        let character = wearing(14, pieces);
+        let (total, parts) = armor_class_parts(&character);
+        assert_eq!(total, 11 + 2 + 3);
+        assert_eq!(parts.last().unwrap(), &("Shield +1".to_string(), 3));
+        // Unequipped plate doesn't impose disadvantage either
+        assert!(!derive_stats(&character).stealth_disadvantage);
+        assert!(derive_stats(&wearing(10, vec![armor("Plate", 18, ArmorType::Heavy)])).stealth_disadvantage);
+    }
+
+    #[test]
+    fn unarmored_defense_for_barbarians_and_monks() {
+        let mut barbarian = of_class("Barbarian", wearing(14, Vec::new()));
+        barbarian.stats.constitution = 16;
+        assert_eq!(armor_class_parts(&barbarian).0, 15);
+        barbarian.equipment.armor.push(armor("Shield", 0, ArmorType::Shield));
+        assert_eq!(armor_class_parts(&barbarian).0, 17);
+        barbarian.equipment.armor.push(armor("Breastplate", 14, ArmorType::Medium));
+        assert_eq!(armor_class_parts(&barbarian).0, 18);
+
+        let mut monk = of_class("Monk", wearing(14, Vec::new()));
+        monk.stats.wisdom = 16;
+        assert_eq!(armor_class_parts(&monk).0, 15);
+        // A monk with a shield falls back to 10 + Dexterity
+        monk.equipment.armor.push(armor("Shield", 0, ArmorType::Shield));
+        assert_eq!(armor_class_parts(&monk).0, 14);
+    }
+
+    #[test]
+    fn passive_scores_use_skill_proficiency() {
+        let mut character = Character::sample("Ann");
+        character.stats.wisdom = 14;
+        character.stats.intelligence = 12;
+        character.skills.perception = SkillProficiency::Proficient;
+        character.skills.investigation = SkillProficiency::Expertise;
+        let derived = derive_stats(&character);
+        assert_eq!(derived.passive_perception, 14);
+        assert_eq!(derived.passive_investigation, 15);
+        assert_eq!(derived.passive_insight, 12);
+    }
+
+    #[test]
+    fn encumbrance_follows_strength() {
+        // Strength 10 carries up to 150 pounds
+        assert_eq!(carrying(50.0, 1), Encumbrance::Unencumbered);
+        assert_eq!(carrying(17.0, 3), Encumbrance::Encumbered);
+        assert_eq!(carrying(101.0, 1), Encumbrance::HeavilyEncumbered);
+        assert_eq!(carrying(151.0, 1), Encumbrance::OverCapacity);
+        assert_eq!(carrying(500.0, -1), Encumbrance::Unencumbered);
+    }
+}
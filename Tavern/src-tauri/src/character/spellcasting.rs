@@ -0,0 +1,280 @@
+use chrono::Utc;
+use rand::Rng;
+
+use crate::database::models::{Character, Concentration, DiceSettings, RollType, Spell, SpellCast};
+use crate::dice::parser::{self, BinaryOp, DiceExpr};
+use crate::dice::roller::DiceRoller;
+use crate::errors::{AppError, AppResult};
+
+/// Character levels at which cantrips gain another step of damage
+const CANTRIP_TIERS: [i64; 3] = [5, 11, 17];
+
+/// Cast one of the character's spells
+///
+/// Leveled spells use a slot of `slot_level` (the spell's own level if not
+/// given), falling back to a pact slot of that level; with no level asked for,
+/// any pact slot high enough will do. Rituals take no slot but must be ritual
+/// spells, and other leveled spells must be prepared. Damage is rolled with
+/// the spell's scaling for the slot used, or the caster's level for cantrips.
+pub fn cast_spell<R: Rng>(
+    roller: &mut DiceRoller<R>,
+    character: &mut Character,
+    spell_id: &str,
+    slot_level: Option<i64>,
+    as_ritual: bool,
+    settings: &DiceSettings,
+) -> AppResult<SpellCast> {
+    let spell = character
+        .spells
+        .iter()
+        .find(|spell| spell.id == spell_id)
+        .cloned()
+        .ok_or_else(|| AppError::NotFound(format!("Spell {}", spell_id)))?;
+
+    if as_ritual && !spell.is_ritual {
+        return Err(AppError::InvalidInput(format!("{} can't be cast as a ritual", spell.name)));
+    }
+    if spell.level > 0 && !as_ritual && !spell.is_prepared {
+        return Err(AppError::InvalidInput(format!("{} is not prepared", spell.name)));
+    }
+
+    let (cast_level, used_pact_slot) = if spell.level == 0 || as_ritual {
+        (spell.level, false)
+    } else {
+        spend_slot(character, &spell, slot_level)?
+    };
+
+    let damage = if spell.damage.is_some() {
+        let expr = damage_expr(&spell, cast_level, character.level)?;
+        Some(roller.roll_with_settings(&expr, RollType::Normal, settings)?)
+    } else {
+        None
+    };
+
+    // A new concentration spell ends the old one
+    let mut ended_concentration = None;
+    if spell.requires_concentration() {
+        ended_concentration = character.spellcasting.concentration.take().map(|c| c.spell_name);
+        character.spellcasting.concentration = Some(Concentration {
+            spell_id: spell.id.clone(),
+            spell_name: spell.name.clone(),
+            started_at: Utc::now(),
+        });
+    }
+
+    Ok(SpellCast {
+        character_id: character.id.clone(),
+        spell_id: spell.id.clone(),
+        spell_name: spell.name.clone(),
+        spell_level: spell.level,
+        slot_level: (spell.level > 0 && !as_ritual).then_some(cast_level),
+        used_pact_slot,
+        as_ritual,
+        save_dc: character.spellcasting.spell_save_dc(&character.stats),
+        attack_bonus: character.spellcasting.spell_attack_bonus(&character.stats),
+        damage,
+        damage_type: spell.damage.as_ref().map(|damage| damage.damage_type),
+        concentration: character.spellcasting.concentration.as_ref().map(|c| c.spell_name.clone()),
+        ended_concentration,
+    })
+}
+
+/// Use up a slot for a leveled spell; returns the level it was cast at and
+/// whether that was a pact slot
+fn spend_slot(character: &mut Character, spell: &Spell, slot_level: Option<i64>) -> AppResult<(i64, bool)> {
+    let requested = slot_level.unwrap_or(spell.level);
+    if requested < spell.level || requested > 9 {
+        return Err(AppError::InvalidInput(format!(
+            "{} can't be cast with a level {} slot",
+            spell.name, requested
+        )));
+    }
+
+    let spellcasting = &mut character.spellcasting;
+    if let Some(slot) = spellcasting.slot_mut(requested).filter(|slot| slot.remaining() > 0) {
+        slot.used += 1;
+        return Ok((requested, false));
+    }
+    if let Some(pact) = spellcasting.pact_slots.as_mut().filter(|pact| pact.remaining() > 0) {
+        let fits = pact.level == requested || (slot_level.is_none() && pact.level >= spell.level);
+        if fits {
+            pact.used += 1;
+            return Ok((pact.level, true));
+        }
+    }
+    Err(AppError::InvalidInput(format!(
+        "{} has no level {} spell slots left",
+        character.name, requested
+    )))
+}
+
+/// The spell's damage dice with scaling added: once per slot level above the
+/// spell's own (up to the scaling's maximum level), or once per cantrip tier
+/// the caster has reached
+pub fn damage_expr(spell: &Spell, cast_level: i64, character_level: i64) -> AppResult<DiceExpr> {
+    let damage = spell
+        .damage
+        .as_ref()
+        .ok_or_else(|| AppError::InvalidInput(format!("{} doesn't deal damage", spell.name)))?;
+    let base = parser::parse(&damage.damage_dice)?;
+
+    let Some(scaling) = &damage.scaling else {
+        return Ok(base);
+    };
+    let steps = if spell.level == 0 {
+        CANTRIP_TIERS.iter().filter(|&&tier| character_level >= tier).count() as i64
+    } else {
+        (cast_level.min(scaling.max_level) - spell.level).max(0)
+    };
+    if steps == 0 {
+        return Ok(base);
+    }
+
+    let per_level = parser::parse(&scaling.per_level)?;
+    // Matching plain dice merge into one term, e.g. 8d6 cast at 5th becomes 10d6
+    if let (DiceExpr::Dice(base_term), DiceExpr::Dice(step_term)) = (&base, &per_level) {
+        if base_term.sides == step_term.sides && base_term.keep.is_none() && step_term.keep.is_none()
+            && base_term.explode == step_term.explode
+        {
+            let mut term = base_term.clone();
+            term.count += step_term.count * steps as u32;
+            return Ok(DiceExpr::Dice(term));
+        }
+    }
+
+    let mut expr = base;
+    for _ in 0..steps {
+        expr = DiceExpr::Binary {
+            op: BinaryOp::Add,
+            lhs: Box::new(expr),
+            rhs: Box::new(DiceExpr::Group(Box::new(per_level.clone()))),
+        };
+    }
+    Ok(expr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::models::{DamageType, SpellComponents, SpellDamage, SpellScaling, SpellSchool, SpellSlots};
+
+    fn spell(id: &str, level: i64) -> Spell {
+        Spell {
+            id: id.to_string(),
+            name: id.to_string(),
+            level,
+            school: SpellSchool::Evocation,
+            casting_time: "1 action".to_string(),
+            range: "60 feet".to_string(),
+            components: SpellComponents { verbal: true, somatic: true, material: false, material_description: None },
+            duration: "Instantaneous".to_string(),
+            description: String::new(),
+            is_prepared: true,
+            is_ritual: false,
+            damage: None,
+            compendium_id: None,
+        }
+    }
+
+    fn damaging(id: &str, level: i64, dice: &str, per_level: &str, max_level: i64) -> Spell {
+        Spell {
+            damage: Some(SpellDamage {
+                damage_dice: dice.to_string(),
+                damage_type: DamageType::Fire,
+                scaling: Some(SpellScaling { per_level: per_level.to_string(), max_level }),
+            }),
+            ..spell(id, level)
+        }
+    }
+
+    /// A 5th level wizard with Intelligence 16, two 1st and two 3rd level
+    /// slots, and one 2nd level pact slot
+    fn caster(spells: Vec<Spell>) -> Character {
+        let mut character = Character::sample("Mage");
+        character.level = 5;
+        character.stats.intelligence = 16;
+        character.stats.proficiency_bonus = 3;
+        character.spells = spells;
+        character.spellcasting.ability = Some("intelligence".to_string());
+        character.spellcasting.slots =
+            vec![SpellSlots { level: 1, max: 2, used: 0 }, SpellSlots { level: 3, max: 2, used: 0 }];
+        character.spellcasting.pact_slots = Some(SpellSlots { level: 2, max: 1, used: 0 });
+        character
+    }
+
+    fn cast(character: &mut Character, spell_id: &str, slot: Option<i64>, as_ritual: bool) -> AppResult<SpellCast> {
+        let mut roller = DiceRoller::from_seed(1);
+        cast_spell(&mut roller, character, spell_id, slot, as_ritual, &DiceSettings::default())
+    }
+
+    #[test]
+    fn upcasting_adds_dice_up_to_the_scaling_maximum() {
+        let fireball = damaging("fireball", 3, "8d6", "1d6", 9);
+        assert_eq!(damage_expr(&fireball, 3, 5).unwrap().to_string(), "8d6");
+        assert_eq!(damage_expr(&fireball, 5, 5).unwrap().to_string(), "10d6");
+        let capped = damaging("capped", 1, "1d8", "1d8", 3);
+        assert_eq!(damage_expr(&capped, 6, 11).unwrap().to_string(), "3d8");
+        let mixed = damaging("mixed", 1, "1d8", "1d6", 9);
+        assert_eq!(damage_expr(&mixed, 3, 5).unwrap().to_string(), "1d8 + (1d6) + (1d6)");
+        assert!(damage_expr(&spell("shield", 1), 1, 5).is_err());
+    }
+
+    #[test]
+    fn cantrips_scale_with_character_level() {
+        let fire_bolt = damaging("fire bolt", 0, "1d10", "1d10", 0);
+        assert_eq!(damage_expr(&fire_bolt, 0, 4).unwrap().to_string(), "1d10");
+        assert_eq!(damage_expr(&fire_bolt, 0, 5).unwrap().to_string(), "2d10");
+        assert_eq!(damage_expr(&fire_bolt, 0, 11).unwrap().to_string(), "3d10");
+        assert_eq!(damage_expr(&fire_bolt, 0, 20).unwrap().to_string(), "4d10");
+    }
+
+    #[test]
+    fn leveled_spells_spend_slots_then_pact_slots() {
+        let mut character = caster(vec![damaging("fireball", 3, "8d6", "1d6", 9), spell("shield", 1)]);
+
+        let first = cast(&mut character, "fireball", None, false).unwrap();
+        assert_eq!((first.slot_level, first.used_pact_slot), (Some(3), false));
+        assert_eq!(first.damage.unwrap().dice_notation, "8d6");
+        assert_eq!((first.save_dc, first.attack_bonus), (Some(14), Some(6)));
+        cast(&mut character, "fireball", Some(3), false).unwrap();
+        assert!(cast(&mut character, "fireball", None, false).is_err());
+        assert!(cast(&mut character, "fireball", Some(2), false).is_err());
+
+        // A 1st level spell can go in the 2nd level pact slot once 1st level
+        // slots run out, but only when no level was asked for
+        cast(&mut character, "shield", None, false).unwrap();
+        cast(&mut character, "shield", None, false).unwrap();
+        assert!(cast(&mut character, "shield", Some(1), false).is_err());
+        let pact = cast(&mut character, "shield", None, false).unwrap();
+        assert_eq!((pact.slot_level, pact.used_pact_slot), (Some(2), true));
+        assert!(cast(&mut character, "shield", None, false).is_err());
+    }
+
+    #[test]
+    fn rituals_and_cantrips_take_no_slot_but_other_spells_must_be_prepared() {
+        let detect_magic = Spell { is_ritual: true, is_prepared: false, ..spell("detect magic", 1) };
+        let unprepared = Spell { is_prepared: false, ..spell("sleep", 1) };
+        let mut character = caster(vec![detect_magic, unprepared, spell("light", 0)]);
+
+        assert!(cast(&mut character, "detect magic", None, false).is_err());
+        let ritual = cast(&mut character, "detect magic", None, true).unwrap();
+        assert_eq!(ritual.slot_level, None);
+        assert!(cast(&mut character, "sleep", None, false).is_err());
+        assert!(cast(&mut character, "sleep", None, true).is_err());
+        assert_eq!(cast(&mut character, "light", None, false).unwrap().slot_level, None);
+        assert!(character.spellcasting.slots.iter().all(|slot| slot.used == 0));
+        assert!(matches!(cast(&mut character, "wish", None, false), Err(AppError::NotFound(_))));
+    }
+
+    #[test]
+    fn a_new_concentration_spell_ends_the_last() {
+        let concentration = |id| Spell { duration: "Concentration, up to 1 minute".to_string(), ..spell(id, 1) };
+        let mut character = caster(vec![concentration("bless"), concentration("faerie fire")]);
+
+        let bless = cast(&mut character, "bless", None, false).unwrap();
+        assert_eq!((bless.concentration.as_deref(), bless.ended_concentration), (Some("bless"), None));
+        let faerie_fire = cast(&mut character, "faerie fire", None, false).unwrap();
+        assert_eq!(faerie_fire.concentration.as_deref(), Some("faerie fire"));
+        assert_eq!(faerie_fire.ended_concentration.as_deref(), Some("bless"));
+    }
+}
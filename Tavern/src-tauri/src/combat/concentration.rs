@@ -0,0 +1,56 @@
+use rand::Rng;
+
+use crate::database::models::{Character, Concentration, ConcentrationCheck, DiceSettings};
+use crate::dice::checks;
+use crate::dice::roller::DiceRoller;
+use crate::errors::AppResult;
+
+/// Save DC to keep concentrating after taking damage: half the damage, at least 10
+pub fn concentration_dc(damage: i64) -> i64 {
+    (damage / 2).max(10)
+}
+
+/// Check a character's concentration after they lose hit points
+///
+/// Dropping to 0 hit points ends it outright; otherwise they make a
+/// Constitution save and lose the spell on a failure. Returns `None` when
+/// there was nothing to check.
+pub fn check_concentration<R: Rng>(
+    roller: &mut DiceRoller<R>,
+    character: &mut Character,
+    damage: i64,
+    settings: &DiceSettings,
+) -> AppResult<Option<ConcentrationCheck>> {
+    if damage <= 0 {
+        return Ok(None);
+    }
+    let Some(concentration) = &character.spellcasting.concentration else {
+        return Ok(None);
+    };
+
+    let spell_name = concentration.spell_name.clone();
+    let dc = concentration_dc(damage);
+    let (roll, maintained) = if character.combat_stats.hit_points <= 0 || character.combat_stats.is_dead {
+        (None, false)
+    } else {
+        let roll = checks::roll_concentration_save(roller, character, settings)?;
+        let maintained = roll.total >= dc;
+        (Some(roll), maintained)
+    };
+
+    if !maintained {
+        end_concentration(character);
+    }
+    Ok(Some(ConcentrationCheck {
+        character_id: character.id.clone(),
+        spell_name,
+        dc,
+        roll,
+        maintained,
+    }))
+}
+
+/// Stop concentrating, returning the spell that ended
+pub fn end_concentration(character: &mut Character) -> Option<Concentration> {
+    character.spellcasting.concentration.take()
+}
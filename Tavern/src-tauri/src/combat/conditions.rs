@@ -0,0 +1,192 @@
+use crate::database::models::{Combat, CombatParticipant, Condition, ConditionExpiry};
+
+/// Count down the conditions that tick at `moment`, removing and returning
+/// those that run out
+pub fn tick_conditions(conditions: &mut Vec<Condition>, moment: ConditionExpiry) -> Vec<Condition> {
+    let mut expired = Vec::new();
+    conditions.retain_mut(|condition| {
+        if condition.expires != moment {
+            return true;
+        }
+        match condition.duration.as_mut() {
+            Some(duration) => {
+                *duration -= 1;
+                if *duration <= 0 {
+                    expired.push(condition.clone());
+                    return false;
+                }
+                true
+            }
+            None => true,
+        }
+    });
+    expired
+}
+
+/// Remove the conditions kept up by a caster's concentration, on one spell or any
+pub fn drop_concentration(conditions: &mut Vec<Condition>, caster_id: &str, spell: Option<&str>) -> Vec<Condition> {
+    let mut dropped = Vec::new();
+    conditions.retain(|condition| {
+        let linked = condition.concentration.as_ref().is_some_and(|link| {
+            link.caster_id == caster_id && spell.is_none_or(|spell| link.spell.eq_ignore_ascii_case(spell))
+        });
+        if linked {
+            dropped.push(condition.clone());
+        }
+        !linked
+    });
+    dropped
+}
+
+/// Whose conditions a tick counts down
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConditionHolder {
+    Character(String),
+    /// A token spawned from a stat block, which keeps its own conditions
+    Token(String),
+}
+
+impl ConditionHolder {
+    /// The character a participant is, or else their token
+    pub fn of(participant: &CombatParticipant) -> Option<Self> {
+        match (&participant.character_id, &participant.token_id) {
+            (Some(character_id), _) => Some(Self::Character(character_id.clone())),
+            (None, Some(token_id)) => Some(Self::Token(token_id.clone())),
+            (None, None) => None,
+        }
+    }
+}
+
+/// The condition ticks a turn change causes, in order
+///
+/// The participant whose turn ended ticks first, then everyone if a new round
+/// started, then the participant whose turn it is now.
+pub fn turn_change_ticks(
+    combat: &Combat,
+    ended: Option<&CombatParticipant>,
+    round_started: bool,
+) -> Vec<(ConditionHolder, ConditionExpiry)> {
+    let mut ticks = Vec::new();
+    if let Some(holder) = ended.and_then(ConditionHolder::of) {
+        ticks.push((holder, ConditionExpiry::EndOfTurn));
+    }
+    if round_started {
+        for holder in combat.participants.iter().filter_map(ConditionHolder::of) {
+            ticks.push((holder, ConditionExpiry::EndOfRound));
+        }
+    }
+    if let Some(holder) = combat.current_participant().and_then(ConditionHolder::of) {
+        ticks.push((holder, ConditionExpiry::StartOfTurn));
+    }
+    ticks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::combat::initiative::new_combat;
+    use crate::database::models::{CombatSide, ConcentrationLink, InitiativeType, TurnStatus};
+
+    fn condition(name: &str, duration: Option<i64>, expires: ConditionExpiry) -> Condition {
+        Condition {
+            name: name.to_string(),
+            description: String::new(),
+            duration,
+            source: "test".to_string(),
+            expires,
+            concentration: None,
+        }
+    }
+
+    fn concentrating(name: &str, caster_id: &str, spell: &str) -> Condition {
+        Condition {
+            concentration: Some(ConcentrationLink { caster_id: caster_id.to_string(), spell: spell.to_string() }),
+            ..condition(name, None, ConditionExpiry::EndOfRound)
+        }
+    }
+
+    fn names(conditions: &[Condition]) -> Vec<&str> {
+        conditions.iter().map(|condition| condition.name.as_str()).collect()
+    }
+
+    fn participant(id: &str, character_id: Option<&str>, token_id: Option<&str>) -> CombatParticipant {
+        CombatParticipant {
+            id: id.to_string(),
+            character_id: character_id.map(str::to_string),
+            token_id: token_id.map(str::to_string),
+            name: id.to_string(),
+            side: CombatSide::Enemies,
+            group: None,
+            initiative: Some(10),
+            initiative_bonus: 0,
+            dexterity: 10,
+            status: TurnStatus::Ready,
+        }
+    }
+
+    #[test]
+    fn only_conditions_for_the_moment_count_down() {
+        let mut conditions = vec![
+            condition("blinded", Some(1), ConditionExpiry::EndOfTurn),
+            condition("prone", None, ConditionExpiry::EndOfTurn),
+            condition("frightened", Some(2), ConditionExpiry::EndOfTurn),
+            condition("stunned", Some(1), ConditionExpiry::StartOfTurn),
+        ];
+
+        let expired = tick_conditions(&mut conditions, ConditionExpiry::EndOfTurn);
+        assert_eq!(names(&expired), ["blinded"]);
+        assert_eq!(names(&conditions), ["prone", "frightened", "stunned"]);
+        assert_eq!(conditions[1].duration, Some(1));
+        assert_eq!(conditions[2].duration, Some(1));
+
+        let expired = tick_conditions(&mut conditions, ConditionExpiry::EndOfTurn);
+        assert_eq!(names(&expired), ["frightened"]);
+        assert_eq!(names(&conditions), ["prone", "stunned"]);
+    }
+
+    #[test]
+    fn ending_concentration_drops_only_that_casters_conditions() {
+        let mut conditions = vec![
+            concentrating("restrained", "mage", "Web"),
+            concentrating("charmed", "mage", "Hypnotic Pattern"),
+            concentrating("blessed", "cleric", "Bless"),
+            condition("prone", None, ConditionExpiry::EndOfRound),
+        ];
+
+        let dropped = drop_concentration(&mut conditions, "mage", Some("web"));
+        assert_eq!(names(&dropped), ["restrained"]);
+        let dropped = drop_concentration(&mut conditions, "mage", None);
+        assert_eq!(names(&dropped), ["charmed"]);
+        assert_eq!(names(&conditions), ["blessed", "prone"]);
+    }
+
+    #[test]
+    fn turn_changes_tick_the_ended_turn_then_the_round_then_the_new_turn() {
+        let mut combat = new_combat("campaign", None, "Ambush", InitiativeType::Individual);
+        combat.participants = vec![
+            participant("hero", Some("hero"), Some("hero token")),
+            participant("orc", None, Some("orc token")),
+            participant("trap", None, None),
+        ];
+        combat.round = 2;
+        combat.turn_index = 0;
+        let ended = combat.participants[1].clone();
+
+        assert_eq!(
+            turn_change_ticks(&combat, Some(&ended), true),
+            [
+                (ConditionHolder::Token("orc token".to_string()), ConditionExpiry::EndOfTurn),
+                (ConditionHolder::Character("hero".to_string()), ConditionExpiry::EndOfRound),
+                (ConditionHolder::Token("orc token".to_string()), ConditionExpiry::EndOfRound),
+                (ConditionHolder::Character("hero".to_string()), ConditionExpiry::StartOfTurn),
+            ],
+        );
+
+        combat.turn_index = 1;
+        let ended = combat.participants[2].clone();
+        assert_eq!(
+            turn_change_ticks(&combat, Some(&ended), false),
+            [(ConditionHolder::Token("orc token".to_string()), ConditionExpiry::StartOfTurn)],
+        );
+    }
+}
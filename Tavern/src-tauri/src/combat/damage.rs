@@ -0,0 +1,332 @@
+use rand::Rng;
+
+use crate::database::models::{
+    Character, CombatSettings, CombatStats, Condition, ConditionExpiry, DamageType, DeathSaveResult, DiceSettings,
+    HitPointChange,
+};
+use crate::dice::checks;
+use crate::dice::roller::DiceRoller;
+use crate::errors::{AppError, AppResult};
+
+/// Source given to the unconscious condition added at 0 hit points, so
+/// healing removes that one and no other
+const DOWNED_SOURCE: &str = "0 hit points";
+
+/// Damage after immunity, resistance and vulnerability; resistance halves
+/// (rounding down) before vulnerability doubles
+pub fn adjusted_damage(character: &Character, amount: i64, damage_type: DamageType) -> i64 {
+    let stats = &character.combat_stats;
+    resisted_damage(amount, damage_type, &stats.resistances, &stats.immunities, &stats.vulnerabilities)
+}
+
+/// Damage after the given immunities, resistances and vulnerabilities
+pub fn resisted_damage(
+    amount: i64,
+    damage_type: DamageType,
+    resistances: &[DamageType],
+    immunities: &[DamageType],
+    vulnerabilities: &[DamageType],
+) -> i64 {
+    if immunities.contains(&damage_type) {
+        return 0;
+    }
+    let mut damage = amount.max(0);
+    if resistances.contains(&damage_type) {
+        damage /= 2;
+    }
+    if vulnerabilities.contains(&damage_type) {
+        damage *= 2;
+    }
+    damage
+}
+
+/// Deal damage to a character
+///
+/// Temporary hit points soak damage first. Dropping to 0 knocks a character
+/// out, unless the leftover damage reaches their hit point maximum, which
+/// kills them outright. NPCs, and player characters when the campaign has
+/// death saves turned off, don't make death saves: NPCs die at 0, and player
+/// characters fall unconscious but stable. Damage taken while already at 0
+/// counts as a failed death save, two on a critical hit.
+pub fn apply_damage(
+    character: &mut Character,
+    amount: i64,
+    damage_type: DamageType,
+    is_critical: bool,
+    settings: &CombatSettings,
+) -> AppResult<HitPointChange> {
+    if character.combat_stats.is_dead {
+        return Err(AppError::InvalidInput(format!("{} is dead", character.name)));
+    }
+
+    let applied = adjusted_damage(character, amount, damage_type);
+    let is_npc = character.is_npc;
+    let name = character.name.clone();
+    let stats = &mut character.combat_stats;
+
+    let absorbed_by_temporary = applied.min(stats.temporary_hit_points);
+    stats.temporary_hit_points -= absorbed_by_temporary;
+    let damage = applied - absorbed_by_temporary;
+
+    let mut instant_death = false;
+    if damage > 0 {
+        if stats.hit_points > 0 {
+            let overflow = damage - stats.hit_points;
+            stats.hit_points = (stats.hit_points - damage).max(0);
+            if stats.hit_points == 0 {
+                if overflow >= stats.max_hit_points {
+                    instant_death = true;
+                    stats.is_dead = true;
+                } else if is_npc {
+                    stats.is_dead = true;
+                } else {
+                    stats.is_stable = !settings.death_saves;
+                    stats.death_saves_success = 0;
+                    stats.death_saves_failure = 0;
+                    add_unconscious(&mut stats.conditions, &name);
+                }
+            }
+        } else if damage >= stats.max_hit_points {
+            instant_death = true;
+            stats.is_dead = true;
+        } else if settings.death_saves {
+            stats.is_stable = false;
+            stats.death_saves_failure += if is_critical { 2 } else { 1 };
+            if stats.death_saves_failure >= 3 {
+                stats.death_saves_failure = 3;
+                stats.is_dead = true;
+            }
+        }
+    }
+
+    Ok(hit_point_change(character, amount, Some(damage_type), applied, absorbed_by_temporary, instant_death))
+}
+
+/// Restore hit points up to the maximum; any healing brings a downed
+/// character back to consciousness
+pub fn apply_healing(character: &mut Character, amount: i64) -> AppResult<HitPointChange> {
+    if character.combat_stats.is_dead {
+        return Err(AppError::InvalidInput(format!("{} is dead and can't be healed", character.name)));
+    }
+
+    let stats = &mut character.combat_stats;
+    let before = stats.hit_points;
+    stats.hit_points = (stats.hit_points + amount.max(0)).min(stats.max_hit_points);
+    if before == 0 && stats.hit_points > 0 {
+        revive(&mut character.combat_stats);
+    }
+
+    let applied = character.combat_stats.hit_points - before;
+    Ok(hit_point_change(character, amount, None, applied, 0, false))
+}
+
+/// Temporary hit points don't stack: the character keeps whichever is higher
+pub fn grant_temporary_hit_points(character: &mut Character, amount: i64) -> HitPointChange {
+    let stats = &mut character.combat_stats;
+    let before = stats.temporary_hit_points;
+    stats.temporary_hit_points = stats.temporary_hit_points.max(amount);
+    let applied = stats.temporary_hit_points - before;
+    hit_point_change(character, amount, None, applied, 0, false)
+}
+
+/// Roll a death save for a character at 0 hit points
+///
+/// 10 or higher succeeds and lower fails; a natural 1 counts as two failures
+/// and a natural 20 brings the character back with 1 hit point. Three
+/// successes stabilize the character, three failures kill them.
+pub fn roll_death_save<R: Rng>(
+    roller: &mut DiceRoller<R>,
+    character: &mut Character,
+    settings: &DiceSettings,
+) -> AppResult<DeathSaveResult> {
+    let stats = &character.combat_stats;
+    if stats.is_dead {
+        return Err(AppError::InvalidInput(format!("{} is dead", character.name)));
+    }
+    if stats.hit_points > 0 || stats.is_stable {
+        return Err(AppError::InvalidInput(format!("{} isn't making death saves", character.name)));
+    }
+
+    let roll = checks::roll_death_save(roller, settings)?;
+    let stats = &mut character.combat_stats;
+    let mut regained_consciousness = false;
+    match roll.natural_roll.unwrap_or(roll.total) {
+        20 => {
+            stats.hit_points = 1;
+            revive(stats);
+            regained_consciousness = true;
+        }
+        1 => stats.death_saves_failure += 2,
+        natural if natural >= 10 => stats.death_saves_success += 1,
+        _ => stats.death_saves_failure += 1,
+    }
+
+    if stats.death_saves_failure >= 3 {
+        stats.death_saves_failure = 3;
+        stats.is_dead = true;
+    } else if stats.death_saves_success >= 3 {
+        stats.death_saves_success = 3;
+        stats.is_stable = true;
+    }
+
+    Ok(DeathSaveResult {
+        character_id: character.id.clone(),
+        character_name: character.name.clone(),
+        roll,
+        successes: stats.death_saves_success,
+        failures: stats.death_saves_failure,
+        is_stable: stats.is_stable,
+        is_dead: stats.is_dead,
+        regained_consciousness,
+    })
+}
+
+fn add_unconscious(conditions: &mut Vec<Condition>, name: &str) {
+    if conditions.iter().any(|c| c.source == DOWNED_SOURCE) {
+        return;
+    }
+    conditions.push(Condition {
+        name: "Unconscious".to_string(),
+        description: format!("{} is dying at 0 hit points", name),
+        duration: None,
+        source: DOWNED_SOURCE.to_string(),
+        expires: ConditionExpiry::default(),
+        concentration: None,
+    });
+}
+
+/// Bring a character back from 0 hit points: no longer unconscious or dying
+pub fn revive(stats: &mut CombatStats) {
+    stats.conditions.retain(|c| c.source != DOWNED_SOURCE);
+    stats.death_saves_success = 0;
+    stats.death_saves_failure = 0;
+    stats.is_stable = false;
+}
+
+fn hit_point_change(
+    character: &Character,
+    amount: i64,
+    damage_type: Option<DamageType>,
+    applied: i64,
+    absorbed_by_temporary: i64,
+    instant_death: bool,
+) -> HitPointChange {
+    let stats = &character.combat_stats;
+    HitPointChange {
+        character_id: character.id.clone(),
+        character_name: character.name.clone(),
+        amount,
+        damage_type,
+        applied,
+        absorbed_by_temporary,
+        hit_points: stats.hit_points,
+        temporary_hit_points: stats.temporary_hit_points,
+        is_unconscious: stats.hit_points == 0 && !stats.is_dead,
+        is_dead: stats.is_dead,
+        instant_death,
+        concentration_check: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn death_saves() -> CombatSettings {
+        CombatSettings { death_saves: true, ..Default::default() }
+    }
+
+    fn is_unconscious(character: &Character) -> bool {
+        character.combat_stats.conditions.iter().any(|c| c.source == DOWNED_SOURCE)
+    }
+
+    #[test]
+    fn resistance_halves_before_vulnerability_doubles_and_immunity_wins() {
+        let fire = [DamageType::Fire];
+        assert_eq!(resisted_damage(7, DamageType::Fire, &fire, &[], &[]), 3);
+        assert_eq!(resisted_damage(7, DamageType::Fire, &[], &[], &fire), 14);
+        assert_eq!(resisted_damage(7, DamageType::Fire, &fire, &[], &fire), 6);
+        assert_eq!(resisted_damage(7, DamageType::Fire, &fire, &fire, &fire), 0);
+        assert_eq!(resisted_damage(7, DamageType::Cold, &fire, &fire, &fire), 7);
+        assert_eq!(resisted_damage(-3, DamageType::Cold, &[], &[], &[]), 0);
+    }
+
+    #[test]
+    fn temporary_hit_points_soak_damage_first() {
+        let mut character = Character::sample("Ann");
+        character.combat_stats.temporary_hit_points = 5;
+        character.combat_stats.resistances = vec![DamageType::Slashing];
+
+        let change = apply_damage(&mut character, 14, DamageType::Slashing, false, &death_saves()).unwrap();
+        assert_eq!((change.applied, change.absorbed_by_temporary), (7, 5));
+        assert_eq!((character.combat_stats.temporary_hit_points, character.combat_stats.hit_points), (0, 8));
+
+        let change = apply_damage(&mut character, 3, DamageType::Fire, false, &death_saves()).unwrap();
+        assert_eq!((change.absorbed_by_temporary, change.hit_points), (0, 5));
+    }
+
+    #[test]
+    fn damage_past_zero_by_the_maximum_kills_outright() {
+        let mut character = Character::sample("Ann");
+        let change = apply_damage(&mut character, 19, DamageType::Fire, false, &death_saves()).unwrap();
+        assert!(change.is_unconscious && !change.is_dead);
+        assert!(is_unconscious(&character));
+
+        let mut character = Character::sample("Ann");
+        let change = apply_damage(&mut character, 20, DamageType::Fire, false, &death_saves()).unwrap();
+        assert!(change.instant_death && change.is_dead);
+        assert!(apply_damage(&mut character, 1, DamageType::Fire, false, &death_saves()).is_err());
+    }
+
+    #[test]
+    fn hits_at_zero_fail_death_saves_and_crits_fail_two() {
+        let mut character = Character::sample("Ann");
+        apply_damage(&mut character, 10, DamageType::Piercing, false, &death_saves()).unwrap();
+        assert_eq!(character.combat_stats.death_saves_failure, 0);
+
+        apply_damage(&mut character, 1, DamageType::Piercing, false, &death_saves()).unwrap();
+        assert_eq!(character.combat_stats.death_saves_failure, 1);
+        let change = apply_damage(&mut character, 1, DamageType::Piercing, true, &death_saves()).unwrap();
+        assert_eq!(character.combat_stats.death_saves_failure, 3);
+        assert!(change.is_dead && !change.instant_death);
+
+        // A hit at 0 as big as the maximum kills outright too
+        let mut character = Character::sample("Ann");
+        apply_damage(&mut character, 10, DamageType::Piercing, false, &death_saves()).unwrap();
+        assert!(apply_damage(&mut character, 10, DamageType::Piercing, false, &death_saves()).unwrap().instant_death);
+    }
+
+    #[test]
+    fn npcs_die_at_zero_and_without_death_saves_players_are_stable() {
+        let mut goblin = Character::sample("Goblin");
+        goblin.is_npc = true;
+        assert!(apply_damage(&mut goblin, 10, DamageType::Slashing, false, &death_saves()).unwrap().is_dead);
+
+        let mut character = Character::sample("Ann");
+        let settings = CombatSettings::default();
+        apply_damage(&mut character, 10, DamageType::Slashing, false, &settings).unwrap();
+        assert!(character.combat_stats.is_stable);
+        apply_damage(&mut character, 1, DamageType::Slashing, true, &settings).unwrap();
+        assert_eq!(character.combat_stats.death_saves_failure, 0);
+    }
+
+    #[test]
+    fn healing_a_downed_character_brings_them_round() {
+        let mut character = Character::sample("Ann");
+        apply_damage(&mut character, 10, DamageType::Fire, false, &death_saves()).unwrap();
+        apply_damage(&mut character, 1, DamageType::Fire, false, &death_saves()).unwrap();
+
+        let change = apply_healing(&mut character, 25).unwrap();
+        assert_eq!((change.applied, change.hit_points), (10, 10));
+        assert!(!is_unconscious(&character));
+        assert_eq!(character.combat_stats.death_saves_failure, 0);
+    }
+
+    #[test]
+    fn temporary_hit_points_keep_the_higher_amount() {
+        let mut character = Character::sample("Ann");
+        assert_eq!(grant_temporary_hit_points(&mut character, 8).applied, 8);
+        assert_eq!(grant_temporary_hit_points(&mut character, 5).applied, 0);
+        assert_eq!(character.combat_stats.temporary_hit_points, 8);
+    }
+}
@@ -0,0 +1,332 @@
+use chrono::Utc;
+use rand::Rng;
+use uuid::Uuid;
+
+use crate::bestiary::stat_blocks;
+use crate::database::models::{
+    Character, CreateEncounterRequest, Encounter, EncounterCreature, EncounterDifficulty, EncounterRating,
+    Position, StatBlock, Token, TokenSize, XpThresholds,
+};
+use crate::dice::roller::DiceRoller;
+use crate::errors::{AppError, AppResult};
+
+/// XP thresholds per character level 1-20: easy, medium, hard, deadly
+const XP_THRESHOLDS: [[i64; 4]; 20] = [
+    [25, 50, 75, 100],
+    [50, 100, 150, 200],
+    [75, 150, 225, 400],
+    [125, 250, 375, 500],
+    [250, 500, 750, 1100],
+    [300, 600, 900, 1400],
+    [350, 750, 1100, 1700],
+    [450, 900, 1400, 2100],
+    [550, 1100, 1600, 2400],
+    [600, 1200, 1900, 2800],
+    [800, 1600, 2400, 3600],
+    [1000, 2000, 3000, 4500],
+    [1100, 2200, 3400, 5100],
+    [1250, 2500, 3800, 5700],
+    [1400, 2800, 4300, 6400],
+    [1600, 3200, 4800, 7200],
+    [2000, 3900, 5900, 8800],
+    [2100, 4200, 6300, 9500],
+    [2400, 4900, 7300, 10900],
+    [2800, 5700, 8500, 12700],
+];
+
+/// Encounter multipliers, with an extra step at each end for small and large parties
+const MULTIPLIERS: [f64; 8] = [0.5, 1.0, 1.5, 2.0, 2.5, 3.0, 4.0, 5.0];
+
+/// Most tokens one deployment or spawn may put on a map
+pub const MAX_DEPLOYED_TOKENS: i64 = 100;
+
+pub fn new_encounter(request: CreateEncounterRequest) -> AppResult<Encounter> {
+    validate_creatures(&request.creatures)?;
+    let now = Utc::now();
+    Ok(Encounter {
+        id: Uuid::new_v4().to_string(),
+        campaign_id: request.campaign_id,
+        map_id: request.map_id,
+        name: request.name,
+        creatures: request.creatures,
+        party_character_ids: request.party_character_ids,
+        created_at: now,
+        updated_at: now,
+    })
+}
+
+pub fn validate_creatures(creatures: &[EncounterCreature]) -> AppResult<()> {
+    for creature in creatures {
+        if creature.character_id.is_some() == creature.stat_block_id.is_some() {
+            return Err(AppError::InvalidInput(
+                "Each creature needs either a character or a stat block".to_string(),
+            ));
+        }
+        if creature.count < 1 {
+            return Err(AppError::InvalidInput(format!(
+                "Creature {} needs a count of at least 1",
+                creature.source_id()
+            )));
+        }
+        if creature.xp < 0 {
+            return Err(AppError::InvalidInput(format!(
+                "Creature {} can't be worth negative XP",
+                creature.source_id()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// XP thresholds for one character of the given level
+pub fn thresholds_for_level(level: i64) -> XpThresholds {
+    let [easy, medium, hard, deadly] = XP_THRESHOLDS[(level.clamp(1, 20) - 1) as usize];
+    XpThresholds { easy, medium, hard, deadly }
+}
+
+/// The multiplier for a number of monsters, stepped up for parties of fewer
+/// than three and down for parties of six or more
+pub fn encounter_multiplier(monster_count: i64, party_size: i64) -> f64 {
+    let mut index: usize = match monster_count {
+        i64::MIN..=1 => 1,
+        2 => 2,
+        3..=6 => 3,
+        7..=10 => 4,
+        11..=14 => 5,
+        _ => 6,
+    };
+    if party_size < 3 {
+        index += 1;
+    } else if party_size >= 6 {
+        index -= 1;
+    }
+    MULTIPLIERS[index]
+}
+
+/// Rate an encounter against the party's levels
+pub fn rate_encounter(creatures: &[EncounterCreature], party: &[Character]) -> AppResult<EncounterRating> {
+    if party.is_empty() {
+        return Err(AppError::InvalidInput("An encounter needs a party to be rated against".to_string()));
+    }
+
+    let thresholds = party.iter().fold(XpThresholds::default(), |total, character| {
+        let level = thresholds_for_level(character.level);
+        XpThresholds {
+            easy: total.easy + level.easy,
+            medium: total.medium + level.medium,
+            hard: total.hard + level.hard,
+            deadly: total.deadly + level.deadly,
+        }
+    });
+
+    let monster_count: i64 = creatures.iter().map(|c| c.count).sum();
+    let total_xp: i64 = creatures.iter().map(|c| c.count * c.xp).sum();
+    let party_size = party.len() as i64;
+    let multiplier = encounter_multiplier(monster_count, party_size);
+    let adjusted_xp = (total_xp as f64 * multiplier).round() as i64;
+
+    let difficulty = if adjusted_xp >= thresholds.deadly {
+        EncounterDifficulty::Deadly
+    } else if adjusted_xp >= thresholds.hard {
+        EncounterDifficulty::Hard
+    } else if adjusted_xp >= thresholds.medium {
+        EncounterDifficulty::Medium
+    } else if adjusted_xp >= thresholds.easy {
+        EncounterDifficulty::Easy
+    } else {
+        EncounterDifficulty::Trivial
+    };
+
+    Ok(EncounterRating {
+        party_size,
+        thresholds,
+        monster_count,
+        total_xp,
+        multiplier,
+        adjusted_xp,
+        difficulty,
+    })
+}
+
+/// What an encounter creature is drawn from
+pub enum CreatureSource {
+    Character(Character),
+    StatBlock(StatBlock),
+}
+
+/// One token per creature, laid out in a square block from `origin`. Tokens
+/// for stat blocks each get their own hit points, rolled when asked.
+pub fn encounter_tokens<R: Rng>(
+    roller: &mut DiceRoller<R>,
+    creatures: &[(EncounterCreature, CreatureSource)],
+    origin: &Position,
+    grid_size: i64,
+    roll_hit_points: bool,
+) -> AppResult<Vec<Token>> {
+    let total = creatures.iter().fold(0i64, |total, (creature, _)| total.saturating_add(creature.count));
+    check_deployment_size(total)?;
+    let mut positions = formation(origin, total, grid_size).into_iter();
+
+    let mut tokens = Vec::new();
+    for (creature, source) in creatures {
+        for number in 1..=creature.count {
+            let position = positions.next().unwrap_or_else(|| origin.clone());
+            let token = match source {
+                CreatureSource::Character(character) => Token {
+                    id: Uuid::new_v4().to_string(),
+                    character_id: Some(character.id.clone()),
+                    name: numbered_name(&character.name, number, creature.count),
+                    image_url: character.avatar_url.clone(),
+                    position,
+                    size: TokenSize::Medium,
+                    notes: String::new(),
+                    is_hidden: false,
+                    initiative: None,
+                    stat_block_id: None,
+                    instance: None,
+                },
+                CreatureSource::StatBlock(stat_block) => {
+                    let name = numbered_name(&stat_block.data.name, number, creature.count);
+                    stat_blocks::spawn_token(roller, stat_block, name, position, roll_hit_points)?
+                }
+            };
+            tokens.push(token);
+        }
+    }
+    Ok(tokens)
+}
+
+/// Make sure `count` tokens are few enough to place at once
+pub fn check_deployment_size(count: i64) -> AppResult<()> {
+    if count > MAX_DEPLOYED_TOKENS {
+        return Err(AppError::InvalidInput(format!(
+            "Can't place {} tokens at once; the most is {}",
+            count, MAX_DEPLOYED_TOKENS
+        )));
+    }
+    Ok(())
+}
+
+/// Positions for `count` tokens in a square block from `origin`, one grid
+/// cell apart
+pub fn formation(origin: &Position, count: i64, grid_size: i64) -> Vec<Position> {
+    let per_row = (count as f64).sqrt().ceil().max(1.0) as i64;
+    let cell = grid_size.max(1) as f32;
+    (0..count)
+        .map(|index| Position {
+            x: origin.x + (index % per_row) as f32 * cell,
+            y: origin.y + (index / per_row) as f32 * cell,
+            z: origin.z,
+        })
+        .collect()
+}
+
+/// Number creatures when there's more than one of them, e.g. `Goblin 3`
+pub fn numbered_name(name: &str, number: i64, count: i64) -> String {
+    if count > 1 {
+        format!("{} {}", name, number)
+    } else {
+        name.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn monsters(count: i64, xp: i64) -> EncounterCreature {
+        EncounterCreature { character_id: None, stat_block_id: Some("goblin".to_string()), count, xp }
+    }
+
+    fn hero(count: i64) -> EncounterCreature {
+        EncounterCreature { character_id: Some("ann".to_string()), stat_block_id: None, count, xp: 0 }
+    }
+
+    fn party(size: usize, level: i64) -> Vec<Character> {
+        (0..size)
+            .map(|index| {
+                let mut character = Character::sample(&format!("Hero {}", index + 1));
+                character.level = level;
+                character
+            })
+            .collect()
+    }
+
+    fn difficulty(creatures: &[EncounterCreature], party: &[Character]) -> EncounterDifficulty {
+        rate_encounter(creatures, party).unwrap().difficulty
+    }
+
+    #[test]
+    fn multipliers_step_with_monster_count_and_party_size() {
+        let bands = [(1, 1.0), (2, 1.5), (3, 2.0), (6, 2.0), (7, 2.5), (10, 2.5), (11, 3.0), (14, 3.0), (15, 4.0)];
+        for (monster_count, multiplier) in bands {
+            assert_eq!(encounter_multiplier(monster_count, 4), multiplier, "{} monsters", monster_count);
+        }
+        assert_eq!(encounter_multiplier(1, 2), 1.5);
+        assert_eq!(encounter_multiplier(15, 1), 5.0);
+        assert_eq!(encounter_multiplier(1, 6), 0.5);
+        assert_eq!(encounter_multiplier(15, 6), 3.0);
+    }
+
+    #[test]
+    fn thresholds_are_clamped_to_levels_one_to_twenty() {
+        assert_eq!(thresholds_for_level(0).easy, 25);
+        assert_eq!(thresholds_for_level(3).deadly, 400);
+        assert_eq!(thresholds_for_level(25).deadly, 12700);
+    }
+
+    #[test]
+    fn encounters_are_rated_on_multiplied_xp() {
+        let four = party(4, 3);
+        let rating = rate_encounter(&[monsters(4, 150)], &four).unwrap();
+        assert_eq!((rating.thresholds.easy, rating.thresholds.deadly), (300, 1600));
+        assert_eq!((rating.monster_count, rating.total_xp, rating.adjusted_xp), (4, 600, 1200));
+        assert_eq!(rating.difficulty, EncounterDifficulty::Hard);
+
+        assert_eq!(difficulty(&[monsters(1, 25)], &four), EncounterDifficulty::Trivial);
+        assert_eq!(difficulty(&[monsters(1, 450)], &four), EncounterDifficulty::Easy);
+        assert_eq!(difficulty(&[monsters(2, 200)], &four), EncounterDifficulty::Medium);
+        assert_eq!(difficulty(&[monsters(4, 100), monsters(4, 150)], &four), EncounterDifficulty::Deadly);
+        // The same monster is harder for a party of two
+        assert_eq!(difficulty(&[monsters(1, 300)], &party(2, 3)), EncounterDifficulty::Hard);
+        assert!(rate_encounter(&[monsters(1, 300)], &[]).is_err());
+    }
+
+    #[test]
+    fn creatures_need_one_source_a_count_and_no_negative_xp() {
+        assert!(validate_creatures(&[monsters(2, 50)]).is_ok());
+        assert!(validate_creatures(&[monsters(0, 50)]).is_err());
+        assert!(validate_creatures(&[monsters(1, -5)]).is_err());
+        let both = EncounterCreature { character_id: Some("hero".to_string()), ..monsters(1, 50) };
+        assert!(validate_creatures(&[both]).is_err());
+        let neither = EncounterCreature { stat_block_id: None, ..monsters(1, 50) };
+        assert!(validate_creatures(&[neither]).is_err());
+    }
+
+    #[test]
+    fn tokens_are_numbered_and_laid_out_in_a_block() {
+        let mut roller = DiceRoller::from_seed(1);
+        let origin = Position { x: 100.0, y: 50.0, z: None };
+        let creatures = [(hero(3), CreatureSource::Character(Character::sample("Ann")))];
+
+        let tokens = encounter_tokens(&mut roller, &creatures, &origin, 50, false).unwrap();
+        let names: Vec<_> = tokens.iter().map(|token| token.name.as_str()).collect();
+        assert_eq!(names, ["Ann 1", "Ann 2", "Ann 3"]);
+        let positions: Vec<_> = tokens.iter().map(|token| (token.position.x, token.position.y)).collect();
+        assert_eq!(positions, [(100.0, 50.0), (150.0, 50.0), (100.0, 100.0)]);
+        assert!(tokens.iter().all(|token| token.character_id.as_deref() == Some("ann")));
+    }
+
+    #[test]
+    fn deployments_are_capped() {
+        let mut roller = DiceRoller::from_seed(1);
+        let origin = Position { x: 0.0, y: 0.0, z: None };
+        let too_many = [
+            (hero(MAX_DEPLOYED_TOKENS), CreatureSource::Character(Character::sample("Ann"))),
+            (hero(1), CreatureSource::Character(Character::sample("Bo"))),
+        ];
+        assert!(encounter_tokens(&mut roller, &too_many, &origin, 50, false).is_err());
+        assert!(check_deployment_size(MAX_DEPLOYED_TOKENS).is_ok());
+        assert!(check_deployment_size(i64::MAX).is_err());
+    }
+}
@@ -0,0 +1,493 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+use chrono::Utc;
+use rand::Rng;
+use uuid::Uuid;
+
+use crate::database::models::{
+    AddCombatantRequest, Character, Combat, CombatParticipant, CombatSide, DiceRoll, DiceSettings,
+    InitiativeType, RollType, TurnStatus,
+};
+use crate::dice::checks;
+use crate::dice::roller::DiceRoller;
+use crate::errors::{AppError, AppResult};
+
+/// One initiative roll, shared by every participant in its unit
+#[derive(Debug, Clone)]
+pub struct UnitRoll {
+    pub participant_ids: Vec<String>,
+    pub roll: DiceRoll,
+}
+
+pub fn new_combat(campaign_id: &str, map_id: Option<String>, name: &str, initiative_type: InitiativeType) -> Combat {
+    let now = Utc::now();
+    Combat {
+        id: Uuid::new_v4().to_string(),
+        campaign_id: campaign_id.to_string(),
+        map_id,
+        name: name.to_string(),
+        initiative_type,
+        round: 0,
+        turn_index: 0,
+        participants: Vec::new(),
+        is_active: false,
+        created_at: now,
+        updated_at: now,
+    }
+}
+
+/// Build a participant, filling in whatever the request leaves out from the character
+pub fn participant_from_request(request: AddCombatantRequest, character: Option<&Character>) -> AppResult<CombatParticipant> {
+    let name = match (request.name, character) {
+        (Some(name), _) => name,
+        (None, Some(character)) => character.name.clone(),
+        (None, None) => {
+            return Err(AppError::InvalidInput("A combatant needs a name or a character".to_string()));
+        }
+    };
+    let side = request.side.unwrap_or(match character {
+        Some(character) if !character.is_npc => CombatSide::Party,
+        _ => CombatSide::Enemies,
+    });
+
+    Ok(CombatParticipant {
+        id: Uuid::new_v4().to_string(),
+        character_id: request.character_id,
+        token_id: request.token_id,
+        name,
+        side,
+        group: request.group,
+        initiative: request.initiative,
+        initiative_bonus: request
+            .initiative_bonus
+            .or(character.map(|c| c.combat_stats.initiative_bonus))
+            .unwrap_or(0),
+        dexterity: request.dexterity.or(character.map(|c| c.stats.dexterity)).unwrap_or(10),
+        status: TurnStatus::Ready,
+    })
+}
+
+/// Add a participant; once combat has started they slot into turn order by
+/// initiative without changing whose turn it is
+pub fn add_participant(combat: &mut Combat, participant: CombatParticipant) {
+    if combat.round == 0 {
+        combat.participants.push(participant);
+        sort_turn_order(combat);
+        return;
+    }
+
+    let position = match participant.initiative {
+        Some(initiative) => combat
+            .participants
+            .iter()
+            .position(|p| match p.initiative {
+                Some(other) => (other, p.dexterity) < (initiative, participant.dexterity),
+                None => true,
+            })
+            .unwrap_or(combat.participants.len()),
+        None => combat.participants.len(),
+    };
+    if position <= combat.turn_index && !combat.participants.is_empty() {
+        combat.turn_index += 1;
+    }
+    combat.participants.insert(position, participant);
+}
+
+pub fn remove_participant(combat: &mut Combat, participant_id: &str) -> AppResult<CombatParticipant> {
+    let index = combat
+        .participant_index(participant_id)
+        .ok_or_else(|| AppError::NotFound(format!("Participant {}", participant_id)))?;
+    let removed = combat.participants.remove(index);
+
+    if index < combat.turn_index {
+        combat.turn_index -= 1;
+    }
+    if combat.turn_index >= combat.participants.len() {
+        combat.turn_index = 0;
+    }
+    Ok(removed)
+}
+
+/// Roll initiative for each unit that needs it, or every unit when `reroll` is set
+///
+/// Units follow the combat's initiative type: each participant alone, each
+/// group (ungrouped participants still roll alone), or each side. A unit rolls
+/// once with the best initiative bonus among its members, and everyone in it
+/// takes that result.
+pub fn roll_initiative<R: Rng>(
+    roller: &mut DiceRoller<R>,
+    combat: &mut Combat,
+    requested: RollType,
+    settings: &DiceSettings,
+    reroll: bool,
+) -> AppResult<Vec<UnitRoll>> {
+    let mut units: Vec<(String, Vec<usize>)> = Vec::new();
+    for (index, participant) in combat.participants.iter().enumerate() {
+        if participant.initiative.is_some() && !reroll {
+            continue;
+        }
+        let key = unit_key(participant, combat.initiative_type);
+        match units.iter_mut().find(|(unit, _)| *unit == key) {
+            Some((_, members)) => members.push(index),
+            None => units.push((key, vec![index])),
+        }
+    }
+
+    let mut rolls = Vec::new();
+    for (_, members) in units {
+        let bonus = members
+            .iter()
+            .map(|&index| combat.participants[index].initiative_bonus)
+            .max()
+            .unwrap_or(0);
+        let roll = checks::roll_initiative(roller, bonus, requested, settings)?;
+        for &index in &members {
+            combat.participants[index].initiative = Some(roll.total);
+        }
+        rolls.push(UnitRoll {
+            participant_ids: members.iter().map(|&index| combat.participants[index].id.clone()).collect(),
+            roll,
+        });
+    }
+
+    sort_turn_order(combat);
+    Ok(rolls)
+}
+
+/// Order participants by initiative, breaking ties by the higher dexterity
+///
+/// Members of a unit stay together. Whoever's turn it is keeps it.
+pub fn sort_turn_order(combat: &mut Combat) {
+    let current_id = combat.current_participant().map(|p| p.id.clone());
+    let mode = combat.initiative_type;
+
+    let mut unit_dexterity: HashMap<String, i64> = HashMap::new();
+    for participant in &combat.participants {
+        let dexterity = unit_dexterity.entry(unit_key(participant, mode)).or_insert(i64::MIN);
+        *dexterity = (*dexterity).max(participant.dexterity);
+    }
+
+    combat.participants.sort_by_cached_key(|p| {
+        let key = unit_key(p, mode);
+        (Reverse(p.initiative.unwrap_or(i64::MIN)), Reverse(unit_dexterity[&key]), key)
+    });
+
+    if let Some(index) = current_id.and_then(|id| combat.participant_index(&id)) {
+        combat.turn_index = index;
+    }
+}
+
+/// Start the first round; every participant needs an initiative by now
+pub fn start_combat(combat: &mut Combat) -> AppResult<()> {
+    if combat.participants.is_empty() {
+        return Err(AppError::InvalidInput("Combat has no participants".to_string()));
+    }
+    let missing: Vec<&str> = combat
+        .participants
+        .iter()
+        .filter(|p| p.initiative.is_none())
+        .map(|p| p.name.as_str())
+        .collect();
+    if !missing.is_empty() {
+        return Err(AppError::InvalidInput(format!("No initiative for {}", missing.join(", "))));
+    }
+
+    combat.round = 0;
+    sort_turn_order(combat);
+    for participant in &mut combat.participants {
+        participant.status = TurnStatus::Ready;
+    }
+    combat.round = 1;
+    combat.turn_index = 0;
+    combat.is_active = true;
+    Ok(())
+}
+
+pub fn end_combat(combat: &mut Combat) {
+    combat.is_active = false;
+}
+
+/// Move to the next participant who isn't delaying; returns whether a new round started
+///
+/// A readied action that was never triggered lapses when its holder's turn comes round.
+pub fn next_turn(combat: &mut Combat) -> AppResult<bool> {
+    ensure_active(combat)?;
+    let count = combat.participants.len();
+    let mut index = combat.turn_index;
+    let mut round_started = false;
+
+    for _ in 0..count {
+        index += 1;
+        if index >= count {
+            index = 0;
+            combat.round += 1;
+            round_started = true;
+        }
+        if combat.participants[index].status != TurnStatus::Delayed {
+            combat.turn_index = index;
+            combat.participants[index].status = TurnStatus::Ready;
+            return Ok(round_started);
+        }
+    }
+    Err(AppError::InvalidInput("Every participant is delaying their turn".to_string()))
+}
+
+/// Step back to the previous participant who isn't delaying
+pub fn previous_turn(combat: &mut Combat) -> AppResult<()> {
+    ensure_active(combat)?;
+    let count = combat.participants.len();
+    let mut index = combat.turn_index;
+    let mut round = combat.round;
+
+    for _ in 0..count {
+        if index == 0 {
+            if round <= 1 {
+                return Err(AppError::InvalidInput("Already at the first turn of combat".to_string()));
+            }
+            index = count;
+            round -= 1;
+        }
+        index -= 1;
+        if combat.participants[index].status != TurnStatus::Delayed {
+            combat.turn_index = index;
+            combat.round = round;
+            return Ok(());
+        }
+    }
+    Err(AppError::InvalidInput("Every participant is delaying their turn".to_string()))
+}
+
+/// The current participant delays, and the turn passes on
+pub fn delay_turn(combat: &mut Combat) -> AppResult<bool> {
+    ensure_active(combat)?;
+    let index = combat.turn_index;
+    combat.participants[index].status = TurnStatus::Delayed;
+    next_turn(combat).inspect_err(|_| {
+        combat.participants[index].status = TurnStatus::Ready;
+    })
+}
+
+/// A delaying participant acts now, ahead of whoever's turn it was, and keeps
+/// that place in the order from here on
+pub fn resume_turn(combat: &mut Combat, participant_id: &str) -> AppResult<()> {
+    ensure_active(combat)?;
+    let index = combat
+        .participant_index(participant_id)
+        .ok_or_else(|| AppError::NotFound(format!("Participant {}", participant_id)))?;
+    if combat.participants[index].status != TurnStatus::Delayed {
+        return Err(AppError::InvalidInput(format!("{} is not delaying", combat.participants[index].name)));
+    }
+
+    let mut participant = combat.participants.remove(index);
+    if index < combat.turn_index {
+        combat.turn_index -= 1;
+    }
+    participant.initiative = combat.participants.get(combat.turn_index).and_then(|p| p.initiative);
+    participant.status = TurnStatus::Ready;
+    combat.participants.insert(combat.turn_index, participant);
+    Ok(())
+}
+
+/// The current participant readies an action for a trigger, and the turn passes on
+pub fn ready_action(combat: &mut Combat, trigger: &str) -> AppResult<bool> {
+    ensure_active(combat)?;
+    let index = combat.turn_index;
+    let round_started = next_turn(combat)?;
+    // Set after moving on, so a lone participant's own turn doesn't clear it
+    if index != combat.turn_index {
+        combat.participants[index].status = TurnStatus::Readied { trigger: trigger.to_string() };
+    }
+    Ok(round_started)
+}
+
+/// Use a readied action now that its trigger has happened; returns the trigger
+pub fn trigger_readied_action(combat: &mut Combat, participant_id: &str) -> AppResult<String> {
+    ensure_active(combat)?;
+    let participant = combat
+        .participants
+        .iter_mut()
+        .find(|p| p.id == participant_id)
+        .ok_or_else(|| AppError::NotFound(format!("Participant {}", participant_id)))?;
+    match std::mem::replace(&mut participant.status, TurnStatus::Ready) {
+        TurnStatus::Readied { trigger } => Ok(trigger),
+        status => {
+            participant.status = status;
+            Err(AppError::InvalidInput(format!("{} has no readied action", participant.name)))
+        }
+    }
+}
+
+fn ensure_active(combat: &Combat) -> AppResult<()> {
+    if !combat.is_active || combat.participants.is_empty() {
+        return Err(AppError::InvalidInput(format!("Combat {} is not running", combat.name)));
+    }
+    Ok(())
+}
+
+/// Participants with the same key roll initiative together
+fn unit_key(participant: &CombatParticipant, mode: InitiativeType) -> String {
+    match (mode, &participant.group) {
+        (InitiativeType::Side, _) => format!("side:{}", serde_json::to_string(&participant.side).unwrap_or_default()),
+        (InitiativeType::Group, Some(group)) => format!("group:{}", group),
+        _ => format!("participant:{}", participant.id),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn participant(id: &str, initiative: Option<i64>, dexterity: i64) -> CombatParticipant {
+        CombatParticipant {
+            id: id.to_string(),
+            character_id: None,
+            token_id: None,
+            name: id.to_string(),
+            side: CombatSide::Enemies,
+            group: None,
+            initiative,
+            initiative_bonus: 0,
+            dexterity,
+            status: TurnStatus::Ready,
+        }
+    }
+
+    /// A running combat with everyone at dexterity 10, in the order given
+    fn running(initiatives: &[(&str, i64)]) -> Combat {
+        let mut combat = new_combat("campaign", None, "Ambush", InitiativeType::Individual);
+        for &(id, initiative) in initiatives {
+            combat.participants.push(participant(id, Some(initiative), 10));
+        }
+        start_combat(&mut combat).unwrap();
+        combat
+    }
+
+    fn order(combat: &Combat) -> Vec<&str> {
+        combat.participants.iter().map(|p| p.id.as_str()).collect()
+    }
+
+    fn current(combat: &Combat) -> &str {
+        &combat.participants[combat.turn_index].id
+    }
+
+    #[test]
+    fn turn_order_breaks_ties_by_dexterity_and_keeps_the_current_turn() {
+        let mut combat = running(&[("a", 10)]);
+        combat.participants.push(participant("b", Some(15), 10));
+        combat.participants.push(participant("c", Some(10), 14));
+        sort_turn_order(&mut combat);
+        assert_eq!(order(&combat), ["b", "c", "a"]);
+        assert_eq!(current(&combat), "a");
+    }
+
+    #[test]
+    fn participants_added_mid_combat_slot_in_without_taking_the_turn() {
+        let mut combat = running(&[("b", 15), ("c", 12), ("a", 8)]);
+        next_turn(&mut combat).unwrap();
+        assert_eq!(current(&combat), "c");
+
+        add_participant(&mut combat, participant("d", Some(20), 10));
+        add_participant(&mut combat, participant("e", Some(13), 10));
+        // Ties go after those already there, so this one waits for next round
+        add_participant(&mut combat, participant("f", Some(12), 10));
+        add_participant(&mut combat, participant("g", None, 10));
+        assert_eq!(order(&combat), ["d", "b", "e", "c", "f", "a", "g"]);
+        assert_eq!(current(&combat), "c");
+    }
+
+    #[test]
+    fn removing_participants_keeps_the_turn_where_it_was() {
+        let mut combat = running(&[("a", 20), ("b", 15), ("c", 10), ("d", 5)]);
+        next_turn(&mut combat).unwrap();
+        next_turn(&mut combat).unwrap();
+        assert_eq!(current(&combat), "c");
+
+        remove_participant(&mut combat, "a").unwrap();
+        assert_eq!(current(&combat), "c");
+        remove_participant(&mut combat, "d").unwrap();
+        assert_eq!(current(&combat), "c");
+        // Removing whoever's turn it is at the end of the order wraps round
+        remove_participant(&mut combat, "c").unwrap();
+        assert_eq!(current(&combat), "b");
+        assert!(matches!(remove_participant(&mut combat, "c"), Err(AppError::NotFound(_))));
+    }
+
+    #[test]
+    fn turns_skip_delayed_participants_both_ways() {
+        let mut combat = running(&[("a", 20), ("b", 15), ("c", 10)]);
+        combat.participants[1].status = TurnStatus::Delayed;
+
+        assert!(!next_turn(&mut combat).unwrap());
+        assert_eq!(current(&combat), "c");
+        assert!(next_turn(&mut combat).unwrap());
+        assert_eq!((current(&combat), combat.round), ("a", 2));
+
+        previous_turn(&mut combat).unwrap();
+        assert_eq!((current(&combat), combat.round), ("c", 1));
+        previous_turn(&mut combat).unwrap();
+        assert_eq!((current(&combat), combat.round), ("a", 1));
+        assert!(previous_turn(&mut combat).is_err());
+    }
+
+    #[test]
+    fn a_delayed_participant_resumes_ahead_of_the_current_turn() {
+        let mut combat = running(&[("a", 20), ("b", 15), ("c", 10)]);
+        assert!(!delay_turn(&mut combat).unwrap());
+        assert_eq!(current(&combat), "b");
+        next_turn(&mut combat).unwrap();
+        assert_eq!(current(&combat), "c");
+
+        resume_turn(&mut combat, "a").unwrap();
+        assert_eq!(order(&combat), ["b", "a", "c"]);
+        assert_eq!(current(&combat), "a");
+        assert_eq!(combat.participants[1].initiative, Some(10));
+        assert_eq!(combat.participants[1].status, TurnStatus::Ready);
+
+        next_turn(&mut combat).unwrap();
+        assert_eq!(current(&combat), "c");
+        assert!(next_turn(&mut combat).unwrap());
+        assert_eq!(current(&combat), "b");
+        assert!(resume_turn(&mut combat, "c").is_err());
+    }
+
+    #[test]
+    fn the_last_one_standing_cannot_delay() {
+        let mut combat = running(&[("a", 20)]);
+        assert!(delay_turn(&mut combat).is_err());
+        assert_eq!(combat.participants[0].status, TurnStatus::Ready);
+    }
+
+    #[test]
+    fn groups_and_sides_share_one_roll() {
+        let mut roller = DiceRoller::from_seed(7);
+        let settings = DiceSettings::default();
+        let mut combat = new_combat("campaign", None, "Ambush", InitiativeType::Group);
+        for (id, group) in [("wolf 1", Some("wolves")), ("wolf 2", Some("wolves")), ("bandit", None)] {
+            let mut wolf = participant(id, None, 10);
+            wolf.group = group.map(str::to_string);
+            combat.participants.push(wolf);
+        }
+        combat.participants[1].initiative_bonus = 3;
+
+        let rolls = roll_initiative(&mut roller, &mut combat, RollType::Normal, &settings, false).unwrap();
+        assert_eq!(rolls.len(), 2);
+        let wolves = rolls.iter().find(|unit| unit.participant_ids.len() == 2).unwrap();
+        // The pack rolls with its best bonus
+        assert_eq!(wolves.roll.modifiers.iter().map(|m| m.value).sum::<i64>(), 3);
+        let initiative = |id: &str| combat.participants[combat.participant_index(id).unwrap()].initiative;
+        assert_eq!(initiative("wolf 1"), Some(wolves.roll.total));
+        assert_eq!(initiative("wolf 2"), Some(wolves.roll.total));
+        let wolves_at = combat.participant_index("wolf 1").unwrap();
+        assert_eq!(combat.participant_index("wolf 2").unwrap().abs_diff(wolves_at), 1);
+
+        // Only those without an initiative roll again unless asked
+        assert!(roll_initiative(&mut roller, &mut combat, RollType::Normal, &settings, false).unwrap().is_empty());
+
+        combat.initiative_type = InitiativeType::Side;
+        combat.participants[2].side = CombatSide::Party;
+        let rolls = roll_initiative(&mut roller, &mut combat, RollType::Normal, &settings, true).unwrap();
+        assert_eq!(rolls.len(), 2);
+        assert!(rolls.iter().any(|unit| unit.participant_ids.len() == 2));
+    }
+}
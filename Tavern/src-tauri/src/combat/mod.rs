@@ -0,0 +1,5 @@
+pub mod concentration;
+pub mod conditions;
+pub mod damage;
+pub mod encounter;
+pub mod initiative;
@@ -4,11 +4,21 @@ use crate::errors::{AppError, AppResult};
 //use crate::config::AppConfig;
 use crate::database::{models, DatabaseManager};
 use crate::database::models::{
-    Campaign, CampaignSettings, Character, CharacterStats, CreateCampaignData, CreateCharacterRequest, CreateMapRequest, CreateTokenRequest, Map, Token, UpdateCharacterRequest
+    AddCombatantRequest, Campaign, CampaignSettings, Character, CharacterSheet, CharacterStats, Combat, CombatParticipant, CombatSettings, CompendiumEntry, CompendiumImport, CompendiumKind, ConcentrationCheck, ConcentrationEnded, Condition, CreateCampaignData, CreateCharacterRequest, CreateCombatRequest, CreateEncounterRequest, CreateMapRequest, CreateTokenRequest, Currency, DamageType, DeathSaveResult, DiceRoll, DiceSettings, DiceStatistics, Encounter, EncounterCreature, EncounterRating, Equipment, ExpiredCondition, HitPointChange, HostSessionRequest, InitiativeRoll, InventoryEntry, Item, JoinInviteRequest, JoinSessionRequest, LevelUpChoices, LevelUpResult, Map, NetworkMessage, Payload, Position, RestRecord, RollHistoryPage, RollHistoryQuery, RollKind, RollType, SearchHit, SearchKind, SessionInfo, ShortRestRequest, SpawnStatBlockRequest, SpellCast, Spellcasting, StatBlock, StatBlockData, Token, TreasureDistribution, TreasurePool, TreasureShare, UpdateCharacterRequest, UpdateEncounterRequest, WeaponAttackRoll
 };
+use crate::character::{inventory, level_up, rest, sheet, spellcasting};
+use crate::dice::{self, checks, roller, stats, DiceRoller};
+use crate::combat::{concentration, conditions, damage, encounter, initiative};
+use crate::combat::conditions::ConditionHolder;
+use crate::combat::encounter::CreatureSource;
+use crate::bestiary::stat_blocks;
+use crate::compendium::entries;
+use crate::compendium::import::Compendium;
 
 use crate::state::AppState;      
 use crate::networking::NetworkManager;         
+use crate::networking::permissions::CampaignTokens;
+use crate::networking::session::{SessionEvent, SessionEvents};
 
 use tauri::{State, AppHandle, WebviewWindow, Manager, Emitter};
 use std::sync::Arc;
@@ -16,6 +26,7 @@ use tokio::sync::Mutex;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use std::collections::HashMap;
+use std::path::Path;
 
 // Type aliases for cleaner code
 type AppStateType = Arc<Mutex<AppState>>;
@@ -157,6 +168,7 @@ pub async fn create_character(
         player_name: request.player_name,
         character_class: request.character_class,
         level: request.level,
+        classes: request.classes,
         race: request.race,
         background: request.background,
         stats: request.stats,
@@ -191,6 +203,19 @@ pub async fn get_character(
     Ok(character)
 }
 
+/// A character with their armor class, initiative, passive scores,
+/// encumbrance and attack bonuses worked out from their sheet
+#[tauri::command]
+pub async fn get_character_sheet(
+    character_id: String,
+    database: State<'_, DatabaseType>,
+) -> AppResult<CharacterSheet> {
+    let db = database.lock().await;
+    let character = db.get_character(&character_id).await?
+        .ok_or_else(|| AppError::NotFound(format!("Character {}", character_id)))?;
+    Ok(sheet::character_sheet(character))
+}
+
 #[tauri::command]
 pub async fn update_character(
     character_id: String,
@@ -199,7 +224,19 @@ pub async fn update_character(
     app_handle: AppHandle,
 ) -> AppResult<()> {
     let db = database.lock().await;
+    let hit_points_before = match &request.combat_stats {
+        Some(_) => db.get_character(&character_id).await?.map(|c| c.combat_stats.hit_points),
+        None => None,
+    };
     db.update_character(&character_id.clone(), request).await?;
+
+    // Losing hit points this way still tests a caster's concentration
+    if let Some(before) = hit_points_before {
+        if let Some(mut character) = db.get_character(&character_id).await? {
+            let lost = before - character.combat_stats.hit_points;
+            check_concentration(&db, &app_handle, &mut character, lost).await?;
+        }
+    }
     
     // Emit event to frontend
     if let Some(window) = app_handle.get_webview_window("main") {
@@ -226,6 +263,41 @@ pub async fn delete_character(
     Ok(())
 }
 
+/// Raise a character one level, checking the choices against the campaign's
+/// variant rules
+#[tauri::command]
+pub async fn level_up(
+    character_id: String,
+    choices: LevelUpChoices,
+    database: State<'_, DatabaseType>,
+    app_handle: AppHandle,
+) -> AppResult<LevelUpResult> {
+    let db = database.lock().await;
+    let mut character = db.get_character(&character_id).await?
+        .ok_or_else(|| AppError::NotFound(format!("Character {}", character_id)))?;
+    let campaign = db.get_campaign(&character.campaign_id).await?
+        .ok_or_else(|| AppError::NotFound(format!("Campaign {}", character.campaign_id)))?;
+
+    let mut roller = DiceRoller::new();
+    let result = level_up::level_up(&mut roller, &mut character, &choices, &campaign.settings.variant_rules)?;
+    db.update_character(&character_id, UpdateCharacterRequest {
+        name: None,
+        level: None,
+        classes: Some(character.classes.clone()),
+        stats: Some(character.stats.clone()),
+        combat_stats: Some(character.combat_stats.clone()),
+        equipment: None,
+        notes: None,
+    }).await?;
+    db.update_features(&character_id, &character.features).await?;
+    db.update_spellcasting(&character_id, &character.spellcasting).await?;
+
+    if let Some(window) = app_handle.get_webview_window("main") {
+        let _ = window.emit("character-leveled-up", &result);
+    }
+    Ok(result)
+}
+
 // =============================================================================
 // Map Commands
 // =============================================================================
@@ -380,17 +452,43 @@ pub async fn delete_token(
     Ok(())
 }
 
+*/
 // =============================================================================
 // Dice Commands
 // =============================================================================
-/*
+
+/// Load the dice settings of a campaign, or the defaults when rolling outside one
+async fn dice_settings_for(db: &DatabaseManager, campaign_id: Option<&str>) -> AppResult<DiceSettings> {
+    match campaign_id {
+        Some(campaign_id) => {
+            let campaign = db.get_campaign(campaign_id).await?
+                .ok_or_else(|| AppError::NotFound(format!("Campaign {}", campaign_id)))?;
+            Ok(campaign.settings.dice_rolling)
+        }
+        None => Ok(DiceSettings::default()),
+    }
+}
+
 #[tauri::command]
 pub async fn roll_dice(
     dice_expression: String,
+    roll_type: Option<RollType>,
+    campaign_id: Option<String>,
+    character_id: Option<String>,
+    database: State<'_, DatabaseType>,
     app_handle: AppHandle,
-) -> AppResult<DiceResult> {
-    let roller = DiceRoller::new();
-    let result = roller.roll(&dice_expression)?;
+) -> AppResult<DiceRoll> {
+    let db = database.lock().await;
+    let settings = dice_settings_for(&db, campaign_id.as_deref()).await?;
+
+    let expr = dice::parse(&dice_expression)?;
+    let mut roller = DiceRoller::new();
+    let result = roller.roll_with_settings(&expr, roll_type.unwrap_or(RollType::Normal), &settings)?;
+
+    // Rolls made in a campaign are kept in its history
+    if let Some(campaign_id) = &campaign_id {
+        db.record_roll(campaign_id, character_id.as_deref(), RollKind::Dice, &result).await?;
+    }
     
     // Emit event to frontend for dice animation/effects
     if let Some(window) = app_handle.get_webview_window("main") {
@@ -400,43 +498,1700 @@ pub async fn roll_dice(
     Ok(result)
 }
 
+#[tauri::command]
+pub async fn roll_skill_check(
+    character_id: String,
+    skill: String,
+    roll_type: Option<RollType>,
+    database: State<'_, DatabaseType>,
+    app_handle: AppHandle,
+) -> AppResult<DiceRoll> {
+    let db = database.lock().await;
+    let character = db.get_character(&character_id).await?
+        .ok_or_else(|| AppError::NotFound(format!("Character {}", character_id)))?;
+    let settings = dice_settings_for(&db, Some(&character.campaign_id)).await?;
+
+    let mut roller = DiceRoller::new();
+    let result = checks::roll_skill_check(&mut roller, &character, &skill, roll_type.unwrap_or(RollType::Normal), &settings)?;
+    db.record_roll(&character.campaign_id, Some(&character.id), RollKind::SkillCheck, &result).await?;
+
+    if let Some(window) = app_handle.get_webview_window("main") {
+        let _ = window.emit("dice-rolled", &result);
+    }
+
+    Ok(result)
+}
+
+#[tauri::command]
+pub async fn roll_saving_throw(
+    character_id: String,
+    ability: String,
+    roll_type: Option<RollType>,
+    database: State<'_, DatabaseType>,
+    app_handle: AppHandle,
+) -> AppResult<DiceRoll> {
+    let db = database.lock().await;
+    let character = db.get_character(&character_id).await?
+        .ok_or_else(|| AppError::NotFound(format!("Character {}", character_id)))?;
+    let settings = dice_settings_for(&db, Some(&character.campaign_id)).await?;
+
+    let mut roller = DiceRoller::new();
+    let result = checks::roll_saving_throw(&mut roller, &character, &ability, roll_type.unwrap_or(RollType::Normal), &settings)?;
+    db.record_roll(&character.campaign_id, Some(&character.id), RollKind::SavingThrow, &result).await?;
+
+    if let Some(window) = app_handle.get_webview_window("main") {
+        let _ = window.emit("dice-rolled", &result);
+    }
+
+    Ok(result)
+}
+
+#[tauri::command]
+pub async fn roll_weapon_attack(
+    character_id: String,
+    weapon_id: String,
+    roll_type: Option<RollType>,
+    database: State<'_, DatabaseType>,
+    app_handle: AppHandle,
+) -> AppResult<WeaponAttackRoll> {
+    let db = database.lock().await;
+    let character = db.get_character(&character_id).await?
+        .ok_or_else(|| AppError::NotFound(format!("Character {}", character_id)))?;
+    let settings = dice_settings_for(&db, Some(&character.campaign_id)).await?;
+
+    let mut roller = DiceRoller::new();
+    let result = checks::roll_weapon_attack(&mut roller, &character, &weapon_id, roll_type.unwrap_or(RollType::Normal), &settings)?;
+    db.record_roll(&character.campaign_id, Some(&character.id), RollKind::Attack, &result.attack).await?;
+    if let Some(damage) = &result.damage {
+        db.record_roll(&character.campaign_id, Some(&character.id), RollKind::Damage, damage).await?;
+    }
+
+    if let Some(window) = app_handle.get_webview_window("main") {
+        let _ = window.emit("attack-rolled", &result);
+    }
+
+    Ok(result)
+}
+
+#[tauri::command]
+pub async fn get_roll_history(
+    campaign_id: String,
+    query: Option<RollHistoryQuery>,
+    database: State<'_, DatabaseType>,
+) -> AppResult<RollHistoryPage> {
+    let db = database.lock().await;
+    let page = db.get_roll_history(&campaign_id, &query.unwrap_or_default()).await?;
+    Ok(page)
+}
+
+/// Re-roll an expression from a recorded seed and the variables it used,
+/// reproducing the original dice
+#[tauri::command]
+pub async fn replay_dice_roll(
+    dice_expression: String,
+    seed: String,
+    variables: Option<HashMap<String, i64>>,
+) -> AppResult<DiceRoll> {
+    let seed = seed
+        .trim()
+        .parse()
+        .map_err(|_| AppError::InvalidInput(format!("Not a dice seed: {}", seed)))?;
+    DiceRoller::replay(&dice_expression, seed, &variables.unwrap_or_default())
+}
+
+/// Exact odds for an expression under a campaign's dice settings, e.g. the
+/// chance a spell's damage drops a creature with `target` hit points
+#[tauri::command]
+pub async fn get_dice_statistics(
+    dice_expression: String,
+    target: Option<i64>,
+    roll_type: Option<RollType>,
+    campaign_id: Option<String>,
+    database: State<'_, DatabaseType>,
+) -> AppResult<DiceStatistics> {
+    let settings = {
+        let db = database.lock().await;
+        dice_settings_for(&db, campaign_id.as_deref()).await?
+    };
+
+    let expr = dice::parse(&dice_expression)?;
+    let expr = roller::apply_settings(expr, roll_type.unwrap_or(RollType::Normal), &settings)?;
+    // Big expressions take a while; keep them off the async workers
+    tokio::task::spawn_blocking(move || {
+        let distribution = stats::distribution(&expr, &HashMap::new())?;
+        Ok(distribution.statistics(&dice_expression, target))
+    })
+    .await
+    .map_err(|error| AppError::Other(format!("Dice statistics failed: {}", error)))?
+}
+
+// =============================================================================
+// Combat Tracker Commands
+// =============================================================================
+
+async fn load_combat(db: &DatabaseManager, combat_id: &str) -> AppResult<Combat> {
+    db.get_combat(combat_id).await?
+        .ok_or_else(|| AppError::NotFound(format!("Combat {}", combat_id)))
+}
+
+/// Tell the frontend whose turn it is, and when a new round begins
+fn emit_turn_changed(app_handle: &AppHandle, combat: &Combat, round_started: bool) {
+    if let Some(window) = app_handle.get_webview_window("main") {
+        if round_started {
+            let _ = window.emit("combat-round-started", combat);
+        }
+        let _ = window.emit("combat-turn-changed", combat);
+    }
+}
+
+/// Count down conditions for a turn change and tell the frontend which ran out
+///
+/// Tokens spawned from a stat block tick their own conditions, found on the
+/// combat's map.
+async fn tick_conditions_for_turn(
+    db: &DatabaseManager,
+    app_handle: &AppHandle,
+    combat: &Combat,
+    ended: Option<&CombatParticipant>,
+    round_started: bool,
+) -> AppResult<Vec<ExpiredCondition>> {
+    let mut map = match &combat.map_id {
+        Some(map_id) => db.get_map(map_id).await?,
+        None => None,
+    };
+    let mut ticked_tokens = Vec::new();
+
+    let mut expired = Vec::new();
+    for (holder, moment) in conditions::turn_change_ticks(combat, ended, round_started) {
+        match holder {
+            ConditionHolder::Character(character_id) => {
+                let Some(mut character) = db.get_character(&character_id).await? else {
+                    continue;
+                };
+                let ran_out = conditions::tick_conditions(&mut character.combat_stats.conditions, moment);
+                // Durations changed even when nothing ran out
+                db.update_combat_stats(&character_id, &character.combat_stats).await?;
+                expired.extend(ran_out.into_iter().map(|condition| ExpiredCondition {
+                    character_id: Some(character.id.clone()),
+                    token_id: None,
+                    character_name: character.name.clone(),
+                    condition,
+                }));
+            }
+            ConditionHolder::Token(token_id) => {
+                let Some(token) = map.as_mut().and_then(|map| map.tokens.iter_mut().find(|token| token.id == token_id)) else {
+                    continue;
+                };
+                let Some(instance) = token.instance.as_mut() else {
+                    continue;
+                };
+                if instance.conditions.is_empty() {
+                    continue;
+                }
+                let ran_out = conditions::tick_conditions(&mut instance.conditions, moment);
+                expired.extend(ran_out.into_iter().map(|condition| ExpiredCondition {
+                    character_id: None,
+                    token_id: Some(token.id.clone()),
+                    character_name: token.name.clone(),
+                    condition,
+                }));
+                ticked_tokens.push(token.clone());
+            }
+        }
+    }
+
+    if let (Some(map), false) = (map, ticked_tokens.is_empty()) {
+        db.save_map_state(&map.id, map.tokens, map.fog_of_war).await?;
+        emit_tokens_updated(app_handle, &map.id, &ticked_tokens);
+    }
+    emit_expired_conditions(app_handle, &expired);
+    Ok(expired)
+}
+
+fn emit_expired_conditions(app_handle: &AppHandle, expired: &[ExpiredCondition]) {
+    if let Some(window) = app_handle.get_webview_window("main") {
+        for condition in expired {
+            let _ = window.emit("condition-expired", condition);
+        }
+    }
+}
+
+async fn build_participant(db: &DatabaseManager, request: AddCombatantRequest) -> AppResult<CombatParticipant> {
+    let character = match &request.character_id {
+        Some(character_id) => Some(
+            db.get_character(character_id).await?
+                .ok_or_else(|| AppError::NotFound(format!("Character {}", character_id)))?,
+        ),
+        None => None,
+    };
+    initiative::participant_from_request(request, character.as_ref())
+}
+
+#[tauri::command]
+pub async fn create_combat(
+    request: CreateCombatRequest,
+    database: State<'_, DatabaseType>,
+) -> AppResult<Combat> {
+    let db = database.lock().await;
+    let campaign = db.get_campaign(&request.campaign_id).await?
+        .ok_or_else(|| AppError::NotFound(format!("Campaign {}", request.campaign_id)))?;
+
+    let mut combat = initiative::new_combat(
+        &campaign.id,
+        request.map_id,
+        &request.name,
+        campaign.settings.combat_settings.initiative_type,
+    );
+    for participant in request.participants {
+        let participant = build_participant(&db, participant).await?;
+        initiative::add_participant(&mut combat, participant);
+    }
+
+    db.create_combat(&combat).await?;
+    Ok(combat)
+}
+
+#[tauri::command]
+pub async fn get_combats(
+    campaign_id: String,
+    database: State<'_, DatabaseType>,
+) -> AppResult<Vec<Combat>> {
+    let db = database.lock().await;
+    db.get_combats(&campaign_id).await
+}
+
+#[tauri::command]
+pub async fn get_combat(
+    combat_id: String,
+    database: State<'_, DatabaseType>,
+) -> AppResult<Combat> {
+    let db = database.lock().await;
+    load_combat(&db, &combat_id).await
+}
+
+#[tauri::command]
+pub async fn delete_combat(
+    combat_id: String,
+    database: State<'_, DatabaseType>,
+) -> AppResult<()> {
+    let db = database.lock().await;
+    db.delete_combat(&combat_id).await
+}
+
+#[tauri::command]
+pub async fn add_combat_participant(
+    combat_id: String,
+    participant: AddCombatantRequest,
+    database: State<'_, DatabaseType>,
+    app_handle: AppHandle,
+) -> AppResult<Combat> {
+    let db = database.lock().await;
+    let mut combat = load_combat(&db, &combat_id).await?;
+    let participant = build_participant(&db, participant).await?;
+    initiative::add_participant(&mut combat, participant);
+    db.save_combat_state(&combat).await?;
+
+    if let Some(window) = app_handle.get_webview_window("main") {
+        let _ = window.emit("combat-updated", &combat);
+    }
+    Ok(combat)
+}
+
+#[tauri::command]
+pub async fn remove_combat_participant(
+    combat_id: String,
+    participant_id: String,
+    database: State<'_, DatabaseType>,
+    app_handle: AppHandle,
+) -> AppResult<Combat> {
+    let db = database.lock().await;
+    let mut combat = load_combat(&db, &combat_id).await?;
+    initiative::remove_participant(&mut combat, &participant_id)?;
+    db.save_combat_state(&combat).await?;
+
+    if let Some(window) = app_handle.get_webview_window("main") {
+        let _ = window.emit("combat-updated", &combat);
+    }
+    Ok(combat)
+}
+
+/// Roll initiative for everyone without one (or everyone, when rerolling)
+/// following the campaign's initiative type, and sort the turn order
 #[tauri::command]
 pub async fn roll_initiative(
-    character_ids: Vec<String>,
+    combat_id: String,
+    roll_type: Option<RollType>,
+    reroll: Option<bool>,
     database: State<'_, DatabaseType>,
     app_handle: AppHandle,
-) -> AppResult<Vec<InitiativeResult>> {
+) -> AppResult<Combat> {
     let db = database.lock().await;
-    let roller = DiceRoller::new();
-    let mut results = Vec::new();
-    
-    for character_id in character_ids {
-        if let Some(character) = db.get_character(&character_id).await? {
-            let initiative_modifier = character.stats.dexterity_modifier();
-            let roll = roller.roll("1d20")?.total;
-            let total = roll + initiative_modifier;
-            
-            results.push(InitiativeResult {
-                character_id: character_id.clone(),
-                character_name: character.name,
-                roll,
-                modifier: initiative_modifier,
-                total,
-            });
+    let mut combat = load_combat(&db, &combat_id).await?;
+    let settings = dice_settings_for(&db, Some(&combat.campaign_id)).await?;
+
+    let mut roller = DiceRoller::new();
+    let rolls = initiative::roll_initiative(
+        &mut roller,
+        &mut combat,
+        roll_type.unwrap_or(RollType::Normal),
+        &settings,
+        reroll.unwrap_or(false),
+    )?;
+    db.save_combat_state(&combat).await?;
+
+    // Each character's roll goes into the history; a unit with none is recorded once
+    for unit in &rolls {
+        let mut recorded = false;
+        for participant in combat.participants.iter().filter(|p| unit.participant_ids.contains(&p.id)) {
+            if let Some(character_id) = &participant.character_id {
+                let roll = InitiativeRoll {
+                    character_id: character_id.clone(),
+                    character_name: participant.name.clone(),
+                    roll: unit.roll.clone(),
+                    initiative_bonus: participant.initiative_bonus,
+                    total: unit.roll.total,
+                };
+                db.record_initiative_roll(&combat.campaign_id, &roll).await?;
+                recorded = true;
+            }
+        }
+        if !recorded {
+            db.record_roll(&combat.campaign_id, None, RollKind::Initiative, &unit.roll).await?;
         }
     }
-    
-    // Sort by initiative (highest first)
-    results.sort_by(|a, b| b.total.cmp(&a.total));
-    
-    // Emit event to frontend
+
+    if let Some(map_id) = &combat.map_id {
+        let initiatives: HashMap<String, i64> = combat
+            .participants
+            .iter()
+            .filter_map(|p| Some((p.token_id.clone()?, p.initiative?)))
+            .collect();
+        db.set_token_initiatives(map_id, &initiatives).await?;
+    }
+
     if let Some(window) = app_handle.get_webview_window("main") {
-        let _ = window.emit("initiative-rolled", &results);
+        let _ = window.emit("initiative-rolled", &combat);
     }
-    
-    Ok(results)
+    Ok(combat)
+}
+
+#[tauri::command]
+pub async fn start_combat(
+    combat_id: String,
+    database: State<'_, DatabaseType>,
+    app_handle: AppHandle,
+) -> AppResult<Combat> {
+    let db = database.lock().await;
+    let mut combat = load_combat(&db, &combat_id).await?;
+    initiative::start_combat(&mut combat)?;
+    db.save_combat_state(&combat).await?;
+
+    emit_turn_changed(&app_handle, &combat, true);
+    tick_conditions_for_turn(&db, &app_handle, &combat, None, false).await?;
+    Ok(combat)
+}
+
+#[tauri::command]
+pub async fn end_combat(
+    combat_id: String,
+    database: State<'_, DatabaseType>,
+    app_handle: AppHandle,
+) -> AppResult<Combat> {
+    let db = database.lock().await;
+    let mut combat = load_combat(&db, &combat_id).await?;
+    initiative::end_combat(&mut combat);
+    db.save_combat_state(&combat).await?;
+
+    if let Some(window) = app_handle.get_webview_window("main") {
+        let _ = window.emit("combat-ended", &combat);
+    }
+    Ok(combat)
+}
+
+#[tauri::command]
+pub async fn next_turn(
+    combat_id: String,
+    database: State<'_, DatabaseType>,
+    app_handle: AppHandle,
+) -> AppResult<Combat> {
+    let db = database.lock().await;
+    let mut combat = load_combat(&db, &combat_id).await?;
+    let ended = combat.current_participant().cloned();
+    let round_started = initiative::next_turn(&mut combat)?;
+    db.save_combat_state(&combat).await?;
+
+    emit_turn_changed(&app_handle, &combat, round_started);
+    tick_conditions_for_turn(&db, &app_handle, &combat, ended.as_ref(), round_started).await?;
+    Ok(combat)
+}
+
+#[tauri::command]
+pub async fn previous_turn(
+    combat_id: String,
+    database: State<'_, DatabaseType>,
+    app_handle: AppHandle,
+) -> AppResult<Combat> {
+    let db = database.lock().await;
+    let mut combat = load_combat(&db, &combat_id).await?;
+    initiative::previous_turn(&mut combat)?;
+    db.save_combat_state(&combat).await?;
+
+    emit_turn_changed(&app_handle, &combat, false);
+    Ok(combat)
+}
+
+#[tauri::command]
+pub async fn delay_turn(
+    combat_id: String,
+    database: State<'_, DatabaseType>,
+    app_handle: AppHandle,
+) -> AppResult<Combat> {
+    let db = database.lock().await;
+    let mut combat = load_combat(&db, &combat_id).await?;
+    let round_started = initiative::delay_turn(&mut combat)?;
+    db.save_combat_state(&combat).await?;
+
+    // Delaying isn't taking a turn, so only the next participant's turn starts
+    emit_turn_changed(&app_handle, &combat, round_started);
+    tick_conditions_for_turn(&db, &app_handle, &combat, None, round_started).await?;
+    Ok(combat)
+}
+
+#[tauri::command]
+pub async fn resume_turn(
+    combat_id: String,
+    participant_id: String,
+    database: State<'_, DatabaseType>,
+    app_handle: AppHandle,
+) -> AppResult<Combat> {
+    let db = database.lock().await;
+    let mut combat = load_combat(&db, &combat_id).await?;
+    initiative::resume_turn(&mut combat, &participant_id)?;
+    db.save_combat_state(&combat).await?;
+
+    emit_turn_changed(&app_handle, &combat, false);
+    tick_conditions_for_turn(&db, &app_handle, &combat, None, false).await?;
+    Ok(combat)
+}
+
+#[tauri::command]
+pub async fn ready_action(
+    combat_id: String,
+    trigger: String,
+    database: State<'_, DatabaseType>,
+    app_handle: AppHandle,
+) -> AppResult<Combat> {
+    let db = database.lock().await;
+    let mut combat = load_combat(&db, &combat_id).await?;
+    let ended = combat.current_participant().cloned();
+    let round_started = initiative::ready_action(&mut combat, &trigger)?;
+    db.save_combat_state(&combat).await?;
+
+    emit_turn_changed(&app_handle, &combat, round_started);
+    tick_conditions_for_turn(&db, &app_handle, &combat, ended.as_ref(), round_started).await?;
+    Ok(combat)
+}
+
+#[tauri::command]
+pub async fn trigger_readied_action(
+    combat_id: String,
+    participant_id: String,
+    database: State<'_, DatabaseType>,
+    app_handle: AppHandle,
+) -> AppResult<Combat> {
+    let db = database.lock().await;
+    let mut combat = load_combat(&db, &combat_id).await?;
+    initiative::trigger_readied_action(&mut combat, &participant_id)?;
+    db.save_combat_state(&combat).await?;
+
+    if let Some(window) = app_handle.get_webview_window("main") {
+        let _ = window.emit("combat-updated", &combat);
+    }
+    Ok(combat)
+}
+
+/// Load the combat settings of a campaign
+async fn combat_settings_for(db: &DatabaseManager, campaign_id: &str) -> AppResult<CombatSettings> {
+    let campaign = db.get_campaign(campaign_id).await?
+        .ok_or_else(|| AppError::NotFound(format!("Campaign {}", campaign_id)))?;
+    Ok(campaign.settings.combat_settings)
+}
+
+fn emit_hit_points_changed(app_handle: &AppHandle, change: &HitPointChange) {
+    if let Some(window) = app_handle.get_webview_window("main") {
+        let _ = window.emit("hit-points-changed", change);
+    }
+}
+
+#[tauri::command]
+pub async fn apply_damage(
+    character_id: String,
+    amount: i64,
+    damage_type: DamageType,
+    is_critical: Option<bool>,
+    database: State<'_, DatabaseType>,
+    app_handle: AppHandle,
+) -> AppResult<HitPointChange> {
+    let db = database.lock().await;
+    let mut character = db.get_character(&character_id).await?
+        .ok_or_else(|| AppError::NotFound(format!("Character {}", character_id)))?;
+    let settings = combat_settings_for(&db, &character.campaign_id).await?;
+
+    let mut change = damage::apply_damage(&mut character, amount, damage_type, is_critical.unwrap_or(false), &settings)?;
+    db.update_combat_stats(&character_id, &character.combat_stats).await?;
+    change.concentration_check = check_concentration(&db, &app_handle, &mut character, change.applied).await?;
+
+    emit_hit_points_changed(&app_handle, &change);
+    Ok(change)
+}
+
+#[tauri::command]
+pub async fn apply_healing(
+    character_id: String,
+    amount: i64,
+    database: State<'_, DatabaseType>,
+    app_handle: AppHandle,
+) -> AppResult<HitPointChange> {
+    let db = database.lock().await;
+    let mut character = db.get_character(&character_id).await?
+        .ok_or_else(|| AppError::NotFound(format!("Character {}", character_id)))?;
+
+    let change = damage::apply_healing(&mut character, amount)?;
+    db.update_combat_stats(&character_id, &character.combat_stats).await?;
+
+    emit_hit_points_changed(&app_handle, &change);
+    Ok(change)
 }
-*/*/
+
+#[tauri::command]
+pub async fn grant_temporary_hit_points(
+    character_id: String,
+    amount: i64,
+    database: State<'_, DatabaseType>,
+    app_handle: AppHandle,
+) -> AppResult<HitPointChange> {
+    let db = database.lock().await;
+    let mut character = db.get_character(&character_id).await?
+        .ok_or_else(|| AppError::NotFound(format!("Character {}", character_id)))?;
+
+    let change = damage::grant_temporary_hit_points(&mut character, amount);
+    db.update_combat_stats(&character_id, &character.combat_stats).await?;
+
+    emit_hit_points_changed(&app_handle, &change);
+    Ok(change)
+}
+
+#[tauri::command]
+pub async fn roll_death_save(
+    character_id: String,
+    database: State<'_, DatabaseType>,
+    app_handle: AppHandle,
+) -> AppResult<DeathSaveResult> {
+    let db = database.lock().await;
+    let mut character = db.get_character(&character_id).await?
+        .ok_or_else(|| AppError::NotFound(format!("Character {}", character_id)))?;
+    if !combat_settings_for(&db, &character.campaign_id).await?.death_saves {
+        return Err(AppError::InvalidInput("Death saves are turned off for this campaign".to_string()));
+    }
+    let settings = dice_settings_for(&db, Some(&character.campaign_id)).await?;
+
+    let mut roller = DiceRoller::new();
+    let result = damage::roll_death_save(&mut roller, &mut character, &settings)?;
+    db.update_combat_stats(&character_id, &character.combat_stats).await?;
+    db.record_roll(&character.campaign_id, Some(&character_id), RollKind::DeathSave, &result.roll).await?;
+
+    if let Some(window) = app_handle.get_webview_window("main") {
+        let _ = window.emit("death-save-rolled", &result);
+    }
+    Ok(result)
+}
+
+/// Load every character taking part in a rest before changing any of them
+async fn load_characters(db: &DatabaseManager, character_ids: &[String]) -> AppResult<Vec<Character>> {
+    let mut characters = Vec::new();
+    for character_id in character_ids {
+        characters.push(
+            db.get_character(character_id).await?
+                .ok_or_else(|| AppError::NotFound(format!("Character {}", character_id)))?,
+        );
+    }
+    Ok(characters)
+}
+
+async fn save_rest(db: &DatabaseManager, character: &Character, record: &RestRecord) -> AppResult<()> {
+    db.update_combat_stats(&character.id, &character.combat_stats).await?;
+    db.update_classes(&character.id, &character.classes).await?;
+    db.update_features(&character.id, &character.features).await?;
+    db.update_spellcasting(&character.id, &character.spellcasting).await?;
+    db.record_rest(record).await
+}
+
+/// Short rest for a party, each character spending the hit dice the request
+/// lists for them
+#[tauri::command]
+pub async fn short_rest(
+    request: ShortRestRequest,
+    database: State<'_, DatabaseType>,
+    app_handle: AppHandle,
+) -> AppResult<Vec<RestRecord>> {
+    let db = database.lock().await;
+    let mut characters = load_characters(&db, &request.character_ids).await?;
+
+    let mut roller = DiceRoller::new();
+    let mut records = Vec::new();
+    for character in &mut characters {
+        let hit_dice = request.hit_dice.get(&character.id).map_or(&[][..], Vec::as_slice);
+        records.push(rest::short_rest(&mut roller, character, hit_dice)?);
+    }
+    for (character, record) in characters.iter().zip(&records) {
+        save_rest(&db, character, record).await?;
+    }
+
+    if let Some(window) = app_handle.get_webview_window("main") {
+        let _ = window.emit("rest-completed", &records);
+    }
+    Ok(records)
+}
+
+#[tauri::command]
+pub async fn long_rest(
+    character_ids: Vec<String>,
+    database: State<'_, DatabaseType>,
+    app_handle: AppHandle,
+) -> AppResult<Vec<RestRecord>> {
+    let db = database.lock().await;
+    let mut characters = load_characters(&db, &character_ids).await?;
+
+    let mut records = Vec::new();
+    for character in &mut characters {
+        records.push(rest::long_rest(character)?);
+    }
+    for (character, record) in characters.iter().zip(&records) {
+        save_rest(&db, character, record).await?;
+    }
+
+    if let Some(window) = app_handle.get_webview_window("main") {
+        let _ = window.emit("rest-completed", &records);
+    }
+    Ok(records)
+}
+
+#[tauri::command]
+pub async fn get_rest_history(
+    campaign_id: String,
+    character_id: Option<String>,
+    limit: Option<i64>,
+    database: State<'_, DatabaseType>,
+) -> AppResult<Vec<RestRecord>> {
+    let db = database.lock().await;
+    db.get_rest_history(&campaign_id, character_id.as_deref(), limit).await
+}
+
+/// Set a character's spellcasting ability and slot maximums
+#[tauri::command]
+pub async fn update_spellcasting(
+    character_id: String,
+    spellcasting: Spellcasting,
+    database: State<'_, DatabaseType>,
+) -> AppResult<()> {
+    let db = database.lock().await;
+    if let Some(ability) = &spellcasting.ability {
+        if CharacterStats::ability_name(ability).is_none() {
+            return Err(AppError::InvalidInput(format!("Unknown ability: {}", ability)));
+        }
+    }
+    db.update_spellcasting(&character_id, &spellcasting).await
+}
+
+#[tauri::command]
+pub async fn cast_spell(
+    character_id: String,
+    spell_id: String,
+    slot_level: Option<i64>,
+    as_ritual: Option<bool>,
+    database: State<'_, DatabaseType>,
+    app_handle: AppHandle,
+) -> AppResult<SpellCast> {
+    let db = database.lock().await;
+    let mut character = db.get_character(&character_id).await?
+        .ok_or_else(|| AppError::NotFound(format!("Character {}", character_id)))?;
+    let settings = dice_settings_for(&db, Some(&character.campaign_id)).await?;
+
+    let mut roller = DiceRoller::new();
+    let cast = spellcasting::cast_spell(
+        &mut roller,
+        &mut character,
+        &spell_id,
+        slot_level,
+        as_ritual.unwrap_or(false),
+        &settings,
+    )?;
+    db.update_spellcasting(&character_id, &character.spellcasting).await?;
+    if let Some(damage) = &cast.damage {
+        db.record_roll(&character.campaign_id, Some(&character_id), RollKind::Damage, damage).await?;
+    }
+    if let Some(spell_name) = &cast.ended_concentration {
+        concentration_ended(&db, &app_handle, &character, spell_name).await?;
+    }
+
+    if let Some(window) = app_handle.get_webview_window("main") {
+        let _ = window.emit("spell-cast", &cast);
+    }
+    Ok(cast)
+}
+
+/// Roll a Constitution save for a concentrating character who lost hit points,
+/// and clean up after the spell if it ends
+async fn check_concentration(
+    db: &DatabaseManager,
+    app_handle: &AppHandle,
+    character: &mut Character,
+    damage: i64,
+) -> AppResult<Option<ConcentrationCheck>> {
+    let settings = dice_settings_for(db, Some(&character.campaign_id)).await?;
+    let mut roller = DiceRoller::new();
+    let Some(check) = concentration::check_concentration(&mut roller, character, damage, &settings)? else {
+        return Ok(None);
+    };
+
+    if let Some(roll) = &check.roll {
+        db.record_roll(&character.campaign_id, Some(&character.id), RollKind::SavingThrow, roll).await?;
+    }
+    if let Some(window) = app_handle.get_webview_window("main") {
+        let _ = window.emit("concentration-checked", &check);
+    }
+    if !check.maintained {
+        db.update_spellcasting(&character.id, &character.spellcasting).await?;
+        concentration_ended(db, app_handle, character, &check.spell_name).await?;
+    }
+    Ok(Some(check))
+}
+
+/// Tell the frontend a caster stopped concentrating, and remove the
+/// conditions that depended on the spell
+async fn concentration_ended(
+    db: &DatabaseManager,
+    app_handle: &AppHandle,
+    caster: &Character,
+    spell_name: &str,
+) -> AppResult<Vec<ExpiredCondition>> {
+    if let Some(window) = app_handle.get_webview_window("main") {
+        let ended = ConcentrationEnded {
+            character_id: caster.id.clone(),
+            character_name: caster.name.clone(),
+            spell_name: spell_name.to_string(),
+        };
+        let _ = window.emit("concentration-ended", &ended);
+    }
+    drop_concentration_conditions(db, app_handle, &caster.campaign_id, &caster.id, Some(spell_name)).await
+}
+
+async fn drop_concentration_conditions(
+    db: &DatabaseManager,
+    app_handle: &AppHandle,
+    campaign_id: &str,
+    caster_id: &str,
+    spell: Option<&str>,
+) -> AppResult<Vec<ExpiredCondition>> {
+    let mut expired = Vec::new();
+    for mut character in db.get_characters(campaign_id).await? {
+        let dropped = conditions::drop_concentration(&mut character.combat_stats.conditions, caster_id, spell);
+        if dropped.is_empty() {
+            continue;
+        }
+        db.update_combat_stats(&character.id, &character.combat_stats).await?;
+        expired.extend(dropped.into_iter().map(|condition| ExpiredCondition {
+            character_id: Some(character.id.clone()),
+            token_id: None,
+            character_name: character.name.clone(),
+            condition,
+        }));
+    }
+
+    for mut map in db.get_maps(campaign_id).await? {
+        let mut changed_tokens = Vec::new();
+        for token in &mut map.tokens {
+            let Some(instance) = token.instance.as_mut() else {
+                continue;
+            };
+            let dropped = conditions::drop_concentration(&mut instance.conditions, caster_id, spell);
+            if dropped.is_empty() {
+                continue;
+            }
+            expired.extend(dropped.into_iter().map(|condition| ExpiredCondition {
+                character_id: None,
+                token_id: Some(token.id.clone()),
+                character_name: token.name.clone(),
+                condition,
+            }));
+            changed_tokens.push(token.clone());
+        }
+        if !changed_tokens.is_empty() {
+            db.save_map_state(&map.id, map.tokens, map.fog_of_war).await?;
+            emit_tokens_updated(app_handle, &map.id, &changed_tokens);
+        }
+    }
+
+    emit_expired_conditions(app_handle, &expired);
+    Ok(expired)
+}
+
+/// End a caster's concentration, on one spell or any, removing every
+/// condition in the campaign that depended on it
+#[tauri::command]
+pub async fn break_concentration(
+    campaign_id: String,
+    caster_id: String,
+    spell: Option<String>,
+    database: State<'_, DatabaseType>,
+    app_handle: AppHandle,
+) -> AppResult<Vec<ExpiredCondition>> {
+    let db = database.lock().await;
+
+    if let Some(mut caster) = db.get_character(&caster_id).await? {
+        let matches = caster.spellcasting.concentration.as_ref().is_some_and(|c| {
+            spell.as_deref().is_none_or(|spell| c.spell_name.eq_ignore_ascii_case(spell))
+        });
+        if matches {
+            if let Some(ended) = concentration::end_concentration(&mut caster) {
+                db.update_spellcasting(&caster.id, &caster.spellcasting).await?;
+                if let Some(window) = app_handle.get_webview_window("main") {
+                    let ended = ConcentrationEnded {
+                        character_id: caster.id.clone(),
+                        character_name: caster.name.clone(),
+                        spell_name: ended.spell_name,
+                    };
+                    let _ = window.emit("concentration-ended", &ended);
+                }
+            }
+        }
+    }
+
+    drop_concentration_conditions(&db, &app_handle, &campaign_id, &caster_id, spell.as_deref()).await
+}
+
+// =============================================================================
+// Encounter Commands
+// =============================================================================
+
+async fn load_encounter(db: &DatabaseManager, encounter_id: &str) -> AppResult<Encounter> {
+    db.get_encounter(encounter_id).await?
+        .ok_or_else(|| AppError::NotFound(format!("Encounter {}", encounter_id)))
+}
+
+/// Load the NPCs and stat blocks an encounter is made of, each paired with
+/// its creature entry
+async fn load_creatures(
+    db: &DatabaseManager,
+    campaign_id: &str,
+    creatures: &[EncounterCreature],
+) -> AppResult<Vec<(EncounterCreature, CreatureSource)>> {
+    encounter::validate_creatures(creatures)?;
+    let mut loaded = Vec::new();
+    for creature in creatures {
+        let source = if let Some(character_id) = &creature.character_id {
+            let character = db.get_character(character_id).await?
+                .ok_or_else(|| AppError::NotFound(format!("Character {}", character_id)))?;
+            if !character.is_npc || character.campaign_id != campaign_id {
+                return Err(AppError::InvalidInput(format!(
+                    "{} is not an NPC in this campaign",
+                    character.name
+                )));
+            }
+            CreatureSource::Character(character)
+        } else {
+            let stat_block_id = creature.source_id();
+            CreatureSource::StatBlock(load_stat_block(db, stat_block_id, Some(campaign_id)).await?)
+        };
+        loaded.push((creature.clone(), source));
+    }
+    Ok(loaded)
+}
+
+#[tauri::command]
+pub async fn create_encounter(
+    request: CreateEncounterRequest,
+    database: State<'_, DatabaseType>,
+) -> AppResult<Encounter> {
+    let db = database.lock().await;
+    load_creatures(&db, &request.campaign_id, &request.creatures).await?;
+    let encounter = encounter::new_encounter(request)?;
+    db.create_encounter(&encounter).await?;
+    Ok(encounter)
+}
+
+#[tauri::command]
+pub async fn get_encounters(
+    campaign_id: String,
+    database: State<'_, DatabaseType>,
+) -> AppResult<Vec<Encounter>> {
+    let db = database.lock().await;
+    db.get_encounters(&campaign_id).await
+}
+
+#[tauri::command]
+pub async fn get_encounter(
+    encounter_id: String,
+    database: State<'_, DatabaseType>,
+) -> AppResult<Encounter> {
+    let db = database.lock().await;
+    load_encounter(&db, &encounter_id).await
+}
+
+#[tauri::command]
+pub async fn update_encounter(
+    encounter_id: String,
+    request: UpdateEncounterRequest,
+    database: State<'_, DatabaseType>,
+) -> AppResult<Encounter> {
+    let db = database.lock().await;
+    let mut encounter = load_encounter(&db, &encounter_id).await?;
+
+    if let Some(name) = request.name {
+        encounter.name = name;
+    }
+    if let Some(map_id) = request.map_id {
+        encounter.map_id = Some(map_id);
+    }
+    if let Some(creatures) = request.creatures {
+        load_creatures(&db, &encounter.campaign_id, &creatures).await?;
+        encounter.creatures = creatures;
+    }
+    if let Some(party_character_ids) = request.party_character_ids {
+        encounter.party_character_ids = party_character_ids;
+    }
+
+    db.save_encounter(&encounter).await?;
+    Ok(encounter)
+}
+
+#[tauri::command]
+pub async fn delete_encounter(
+    encounter_id: String,
+    database: State<'_, DatabaseType>,
+) -> AppResult<()> {
+    let db = database.lock().await;
+    db.delete_encounter(&encounter_id).await
+}
+
+/// Rate an encounter against its party, or every player character in the
+/// campaign when it doesn't name one
+#[tauri::command]
+pub async fn rate_encounter(
+    encounter_id: String,
+    database: State<'_, DatabaseType>,
+) -> AppResult<EncounterRating> {
+    let db = database.lock().await;
+    let encounter = load_encounter(&db, &encounter_id).await?;
+
+    let party = if encounter.party_character_ids.is_empty() {
+        db.get_characters(&encounter.campaign_id).await?
+            .into_iter()
+            .filter(|character| !character.is_npc)
+            .collect()
+    } else {
+        load_characters(&db, &encounter.party_character_ids).await?
+    };
+    encounter::rate_encounter(&encounter.creatures, &party)
+}
+
+/// Place a token for every creature in the encounter on its map (or another
+/// of the campaign's maps), starting at `origin` or the map's top-left corner
+#[tauri::command]
+pub async fn deploy_encounter(
+    encounter_id: String,
+    map_id: Option<String>,
+    origin: Option<Position>,
+    roll_hit_points: Option<bool>,
+    database: State<'_, DatabaseType>,
+    app_handle: AppHandle,
+) -> AppResult<Vec<Token>> {
+    let db = database.lock().await;
+    let encounter = load_encounter(&db, &encounter_id).await?;
+    let map_id = map_id.or(encounter.map_id.clone())
+        .ok_or_else(|| AppError::InvalidInput(format!("{} has no map to deploy to", encounter.name)))?;
+    let map = db.get_map(&map_id).await?
+        .ok_or_else(|| AppError::NotFound(format!("Map {}", map_id)))?;
+    if map.campaign_id != encounter.campaign_id {
+        return Err(AppError::InvalidInput(format!("{} belongs to another campaign", map.name)));
+    }
+
+    let creatures = load_creatures(&db, &encounter.campaign_id, &encounter.creatures).await?;
+    let origin = origin.unwrap_or(Position { x: 0.0, y: 0.0, z: None });
+    let mut roller = DiceRoller::new();
+    let deployed = encounter::encounter_tokens(
+        &mut roller,
+        &creatures,
+        &origin,
+        map.grid_size,
+        roll_hit_points.unwrap_or(false),
+    )?;
+
+    let mut tokens = map.tokens;
+    tokens.extend(deployed.iter().cloned());
+    db.save_map_state(&map.id, tokens, map.fog_of_war).await?;
+
+    if let Some(window) = app_handle.get_webview_window("main") {
+        let _ = window.emit("encounter-deployed", serde_json::json!({
+            "encounter_id": encounter.id,
+            "map_id": map.id,
+            "tokens": deployed,
+        }));
+    }
+    Ok(deployed)
+}
+
+// =============================================================================
+// Bestiary Commands
+// =============================================================================
+
+/// Load a stat block, making sure a campaign may use it: global stat blocks
+/// are open to every campaign, the rest only to their own
+async fn load_stat_block(db: &DatabaseManager, stat_block_id: &str, campaign_id: Option<&str>) -> AppResult<StatBlock> {
+    let stat_block = db.get_stat_block(stat_block_id).await?
+        .ok_or_else(|| AppError::NotFound(format!("Stat block {}", stat_block_id)))?;
+    if let (Some(owner), Some(campaign_id)) = (stat_block.campaign_id.as_deref(), campaign_id) {
+        if owner != campaign_id {
+            return Err(AppError::InvalidInput(format!(
+                "{} belongs to another campaign",
+                stat_block.data.name
+            )));
+        }
+    }
+    Ok(stat_block)
+}
+
+/// Load a map and find one of its tokens
+async fn load_map_token(db: &DatabaseManager, map_id: &str, token_id: &str) -> AppResult<(Map, usize)> {
+    let map = db.get_map(map_id).await?
+        .ok_or_else(|| AppError::NotFound(format!("Map {}", map_id)))?;
+    let index = map.tokens.iter().position(|token| token.id == token_id)
+        .ok_or_else(|| AppError::NotFound(format!("Token {}", token_id)))?;
+    Ok((map, index))
+}
+
+/// Tell the frontend about tokens whose state changed
+fn emit_tokens_updated(app_handle: &AppHandle, map_id: &str, tokens: &[Token]) {
+    if let Some(window) = app_handle.get_webview_window("main") {
+        for token in tokens {
+            let _ = window.emit("token-updated", serde_json::json!({
+                "map_id": map_id,
+                "token": token,
+            }));
+        }
+    }
+}
+
+/// Save a map whose token changed and tell the frontend about it
+async fn save_map_token(db: &DatabaseManager, app_handle: &AppHandle, map: Map, index: usize) -> AppResult<Token> {
+    let token = map.tokens[index].clone();
+    db.save_map_state(&map.id, map.tokens, map.fog_of_war).await?;
+    emit_tokens_updated(app_handle, &map.id, std::slice::from_ref(&token));
+    Ok(token)
+}
+
+/// Add a stat block to a campaign's bestiary, or the global one without a campaign
+#[tauri::command]
+pub async fn create_stat_block(
+    campaign_id: Option<String>,
+    data: StatBlockData,
+    database: State<'_, DatabaseType>,
+) -> AppResult<StatBlock> {
+    let db = database.lock().await;
+    let stat_block = stat_blocks::new_stat_block(campaign_id, data)?;
+    db.create_stat_block(&stat_block).await?;
+    Ok(stat_block)
+}
+
+/// The global bestiary plus the campaign's own stat blocks
+#[tauri::command]
+pub async fn get_stat_blocks(
+    campaign_id: Option<String>,
+    database: State<'_, DatabaseType>,
+) -> AppResult<Vec<StatBlock>> {
+    let db = database.lock().await;
+    db.get_stat_blocks(campaign_id.as_deref()).await
+}
+
+#[tauri::command]
+pub async fn get_stat_block(
+    stat_block_id: String,
+    database: State<'_, DatabaseType>,
+) -> AppResult<StatBlock> {
+    let db = database.lock().await;
+    load_stat_block(&db, &stat_block_id, None).await
+}
+
+#[tauri::command]
+pub async fn update_stat_block(
+    stat_block_id: String,
+    data: StatBlockData,
+    database: State<'_, DatabaseType>,
+) -> AppResult<StatBlock> {
+    let db = database.lock().await;
+    let mut stat_block = load_stat_block(&db, &stat_block_id, None).await?;
+    stat_blocks::validate(&data)?;
+    stat_block.data = data;
+    db.save_stat_block(&stat_block).await?;
+    Ok(stat_block)
+}
+
+#[tauri::command]
+pub async fn delete_stat_block(
+    stat_block_id: String,
+    database: State<'_, DatabaseType>,
+) -> AppResult<()> {
+    let db = database.lock().await;
+    db.delete_stat_block(&stat_block_id).await
+}
+
+/// Put tokens for a stat block on a map, each with its own hit points
+#[tauri::command]
+pub async fn spawn_stat_block(
+    request: SpawnStatBlockRequest,
+    database: State<'_, DatabaseType>,
+    app_handle: AppHandle,
+) -> AppResult<Vec<Token>> {
+    let db = database.lock().await;
+    if request.count < 1 {
+        return Err(AppError::InvalidInput("Spawn at least one token".to_string()));
+    }
+    encounter::check_deployment_size(request.count)?;
+    let map = db.get_map(&request.map_id).await?
+        .ok_or_else(|| AppError::NotFound(format!("Map {}", request.map_id)))?;
+    let stat_block = load_stat_block(&db, &request.stat_block_id, Some(&map.campaign_id)).await?;
+
+    let origin = request.origin.unwrap_or(Position { x: 0.0, y: 0.0, z: None });
+    let mut roller = DiceRoller::new();
+    let mut spawned = Vec::new();
+    for (number, position) in (1..).zip(encounter::formation(&origin, request.count, map.grid_size)) {
+        let name = encounter::numbered_name(&stat_block.data.name, number, request.count);
+        spawned.push(stat_blocks::spawn_token(&mut roller, &stat_block, name, position, request.roll_hit_points)?);
+    }
+
+    let mut tokens = map.tokens;
+    tokens.extend(spawned.iter().cloned());
+    db.save_map_state(&map.id, tokens, map.fog_of_war).await?;
+
+    if let Some(window) = app_handle.get_webview_window("main") {
+        let _ = window.emit("stat-block-spawned", serde_json::json!({
+            "map_id": map.id,
+            "tokens": &spawned,
+        }));
+    }
+    Ok(spawned)
+}
+
+/// Damage one token spawned from a stat block, leaving the others alone
+#[tauri::command]
+pub async fn apply_token_damage(
+    map_id: String,
+    token_id: String,
+    amount: i64,
+    damage_type: DamageType,
+    database: State<'_, DatabaseType>,
+    app_handle: AppHandle,
+) -> AppResult<Token> {
+    let db = database.lock().await;
+    let (mut map, index) = load_map_token(&db, &map_id, &token_id).await?;
+    let stat_block_id = map.tokens[index].stat_block_id.clone()
+        .ok_or_else(|| AppError::InvalidInput(format!("{} wasn't spawned from a stat block", map.tokens[index].name)))?;
+    let stat_block = load_stat_block(&db, &stat_block_id, None).await?;
+
+    stat_blocks::damage_token(&mut map.tokens[index], &stat_block, amount, damage_type)?;
+    save_map_token(&db, &app_handle, map, index).await
+}
+
+#[tauri::command]
+pub async fn apply_token_healing(
+    map_id: String,
+    token_id: String,
+    amount: i64,
+    database: State<'_, DatabaseType>,
+    app_handle: AppHandle,
+) -> AppResult<Token> {
+    let db = database.lock().await;
+    let (mut map, index) = load_map_token(&db, &map_id, &token_id).await?;
+    stat_blocks::heal_token(&mut map.tokens[index], amount)?;
+    save_map_token(&db, &app_handle, map, index).await
+}
+
+/// Replace the conditions on one token spawned from a stat block
+#[tauri::command]
+pub async fn set_token_conditions(
+    map_id: String,
+    token_id: String,
+    conditions: Vec<Condition>,
+    database: State<'_, DatabaseType>,
+    app_handle: AppHandle,
+) -> AppResult<Token> {
+    let db = database.lock().await;
+    let (mut map, index) = load_map_token(&db, &map_id, &token_id).await?;
+    let token = &mut map.tokens[index];
+    let instance = token.instance.as_mut()
+        .ok_or_else(|| AppError::InvalidInput(format!("{} has no conditions of its own", token.name)))?;
+    instance.conditions = conditions;
+    save_map_token(&db, &app_handle, map, index).await
+}
+
+// =============================================================================
+// Compendium Commands
+// =============================================================================
+
+/// Import a compendium file, or every `.json` file in a directory
+///
+/// Every file is read and checked before anything is stored, so one bad
+/// entry leaves the compendium as it was.
+#[tauri::command]
+pub async fn import_compendium(
+    path: String,
+    database: State<'_, DatabaseType>,
+    app_handle: AppHandle,
+) -> AppResult<Vec<CompendiumImport>> {
+    let path = std::path::PathBuf::from(path);
+    let mut files = Vec::new();
+    if tokio::fs::metadata(&path).await?.is_dir() {
+        let mut dir = tokio::fs::read_dir(&path).await?;
+        while let Some(file) = dir.next_entry().await? {
+            let file = file.path();
+            if file.extension().is_some_and(|extension| extension == "json") {
+                files.push(file);
+            }
+        }
+        files.sort();
+    } else {
+        files.push(path);
+    }
+
+    let mut compendium = Compendium::default();
+    for file in &files {
+        let json = tokio::fs::read_to_string(file).await?;
+        compendium.add_file(&file.display().to_string(), &json)?;
+    }
+
+    let db = database.lock().await;
+    db.import_compendium(&compendium.entries, &compendium.stat_blocks).await?;
+
+    if let Some(window) = app_handle.get_webview_window("main") {
+        let _ = window.emit("compendium-imported", &compendium.imports);
+    }
+    Ok(compendium.imports)
+}
+
+#[tauri::command]
+pub async fn get_compendium_entries(
+    kind: Option<CompendiumKind>,
+    source: Option<String>,
+    database: State<'_, DatabaseType>,
+) -> AppResult<Vec<CompendiumEntry>> {
+    let db = database.lock().await;
+    db.get_compendium_entries(kind, source.as_deref()).await
+}
+
+#[tauri::command]
+pub async fn get_compendium_entry(
+    entry_id: String,
+    database: State<'_, DatabaseType>,
+) -> AppResult<CompendiumEntry> {
+    let db = database.lock().await;
+    db.get_compendium_entry(&entry_id).await?
+        .ok_or_else(|| AppError::NotFound(format!("Compendium entry {}", entry_id)))
+}
+
+/// Add a compendium spell, weapon, armor or item to a character's sheet
+#[tauri::command]
+pub async fn add_compendium_entry(
+    character_id: String,
+    entry_id: String,
+    quantity: Option<i64>,
+    database: State<'_, DatabaseType>,
+    app_handle: AppHandle,
+) -> AppResult<Character> {
+    let db = database.lock().await;
+    let entry = db.get_compendium_entry(&entry_id).await?
+        .ok_or_else(|| AppError::NotFound(format!("Compendium entry {}", entry_id)))?;
+    let mut character = db.get_character(&character_id).await?
+        .ok_or_else(|| AppError::NotFound(format!("Character {}", character_id)))?;
+
+    entries::add_to_character(&mut character, &entry, quantity.unwrap_or(1))?;
+    db.update_spells_and_equipment(&character.id, &character.spells, &character.equipment).await?;
+
+    if let Some(window) = app_handle.get_webview_window("main") {
+        let _ = window.emit("character-updated", &character.id);
+    }
+    Ok(character)
+}
+
+// =============================================================================
+// Search Commands
+// =============================================================================
+
+/// Full-text search over a campaign, optionally limited to some kinds of content
+#[tauri::command]
+pub async fn search(
+    campaign_id: String,
+    query: String,
+    kinds: Option<Vec<SearchKind>>,
+    database: State<'_, DatabaseType>,
+) -> AppResult<Vec<SearchHit>> {
+    let db = database.lock().await;
+    db.search(&campaign_id, &query, &kinds.unwrap_or_default()).await
+}
+
+// =============================================================================
+// Inventory Commands
+// =============================================================================
+
+/// Add an item, weapon or armor to a character's equipment, stacking items
+/// with ones they already have; returns the id it's kept under
+#[tauri::command]
+pub async fn add_item(
+    character_id: String,
+    entry: InventoryEntry,
+    database: State<'_, DatabaseType>,
+    app_handle: AppHandle,
+) -> AppResult<String> {
+    let db = database.lock().await;
+    let item_id = db.update_equipment(&[&character_id], |characters| {
+        inventory::add(&mut characters[0].equipment, entry)
+    }).await?;
+
+    emit_characters_updated(&app_handle, &[&character_id]);
+    Ok(item_id)
+}
+
+/// Remove something from a character's equipment, or some of a stack of
+/// items; returns what was removed
+#[tauri::command]
+pub async fn remove_item(
+    character_id: String,
+    item_id: String,
+    quantity: Option<i64>,
+    database: State<'_, DatabaseType>,
+    app_handle: AppHandle,
+) -> AppResult<InventoryEntry> {
+    let db = database.lock().await;
+    let removed = db.update_equipment(&[&character_id], |characters| {
+        inventory::take(&mut characters[0].equipment, &item_id, quantity)
+    }).await?;
+
+    emit_characters_updated(&app_handle, &[&character_id]);
+    Ok(removed)
+}
+
+/// Give something, or some of a stack of items, from one character to
+/// another in the same campaign; returns its id in the receiver's equipment
+#[tauri::command]
+pub async fn transfer_item(
+    from_character: String,
+    to_character: String,
+    item_id: String,
+    quantity: Option<i64>,
+    database: State<'_, DatabaseType>,
+    app_handle: AppHandle,
+) -> AppResult<String> {
+    if from_character == to_character {
+        return Err(AppError::InvalidInput("Can't transfer an item to the character who has it".to_string()));
+    }
+    let db = database.lock().await;
+    let new_id = db.update_equipment(&[&from_character, &to_character], |characters| {
+        let entry = inventory::take(&mut characters[0].equipment, &item_id, quantity)?;
+        inventory::add(&mut characters[1].equipment, entry)
+    }).await?;
+
+    emit_characters_updated(&app_handle, &[&from_character, &to_character]);
+    Ok(new_id)
+}
+
+/// Split part of a stack of items into a stack of its own; returns the new
+/// stack's id
+#[tauri::command]
+pub async fn split_item(
+    character_id: String,
+    item_id: String,
+    quantity: i64,
+    database: State<'_, DatabaseType>,
+    app_handle: AppHandle,
+) -> AppResult<String> {
+    let db = database.lock().await;
+    let new_id = db.update_equipment(&[&character_id], |characters| {
+        inventory::split(&mut characters[0].equipment, &item_id, quantity)
+    }).await?;
+
+    emit_characters_updated(&app_handle, &[&character_id]);
+    Ok(new_id)
+}
+
+/// Merge a stack of items into another stack of the same item
+#[tauri::command]
+pub async fn stack_items(
+    character_id: String,
+    item_id: String,
+    into_item_id: String,
+    database: State<'_, DatabaseType>,
+    app_handle: AppHandle,
+) -> AppResult<Equipment> {
+    let db = database.lock().await;
+    let equipment = db.update_equipment(&[&character_id], |characters| {
+        inventory::stack(&mut characters[0].equipment, &item_id, &into_item_id)?;
+        Ok(characters[0].equipment.clone())
+    }).await?;
+
+    emit_characters_updated(&app_handle, &[&character_id]);
+    Ok(equipment)
+}
+
+/// Equip a weapon or armor; putting on armor or a shield takes off the one
+/// worn before
+#[tauri::command]
+pub async fn equip_item(
+    character_id: String,
+    item_id: String,
+    database: State<'_, DatabaseType>,
+    app_handle: AppHandle,
+) -> AppResult<Equipment> {
+    set_item_equipped(&character_id, &item_id, true, &database, &app_handle).await
+}
+
+#[tauri::command]
+pub async fn unequip_item(
+    character_id: String,
+    item_id: String,
+    database: State<'_, DatabaseType>,
+    app_handle: AppHandle,
+) -> AppResult<Equipment> {
+    set_item_equipped(&character_id, &item_id, false, &database, &app_handle).await
+}
+
+async fn set_item_equipped(
+    character_id: &str,
+    item_id: &str,
+    equipped: bool,
+    database: &DatabaseType,
+    app_handle: &AppHandle,
+) -> AppResult<Equipment> {
+    let db = database.lock().await;
+    let equipment = db.update_equipment(&[character_id], |characters| {
+        inventory::set_equipped(&mut characters[0].equipment, item_id, equipped)?;
+        Ok(characters[0].equipment.clone())
+    }).await?;
+
+    emit_characters_updated(app_handle, &[character_id]);
+    Ok(equipment)
+}
+
+#[tauri::command]
+pub async fn get_treasure_pool(
+    campaign_id: String,
+    database: State<'_, DatabaseType>,
+) -> AppResult<TreasurePool> {
+    let db = database.lock().await;
+    db.get_treasure_pool(&campaign_id).await
+}
+
+/// Put coins and items the party has found into the campaign's treasure pool
+#[tauri::command]
+pub async fn add_treasure(
+    campaign_id: String,
+    currency: Option<Currency>,
+    items: Option<Vec<Item>>,
+    database: State<'_, DatabaseType>,
+    app_handle: AppHandle,
+) -> AppResult<TreasurePool> {
+    let currency = currency.unwrap_or_default();
+    if currency.is_negative() {
+        return Err(AppError::InvalidInput("Treasure can't have a negative number of coins".to_string()));
+    }
+    let db = database.lock().await;
+    let pool = db.update_treasure_pool(&campaign_id, &[], |pool, _| {
+        pool.currency.add(&currency);
+        // The pool is kept as an equipment list of items so they stack the same way
+        let mut hoard = Equipment { items: std::mem::take(&mut pool.items), ..Equipment::default() };
+        for item in items.unwrap_or_default() {
+            inventory::add(&mut hoard, InventoryEntry::Item(item))?;
+        }
+        pool.items = hoard.items;
+        Ok(pool.clone())
+    }).await?;
+
+    if let Some(window) = app_handle.get_webview_window("main") {
+        let _ = window.emit("treasure-pool-updated", &pool);
+    }
+    Ok(pool)
+}
+
+/// Share the coins in the treasure pool evenly between characters, making
+/// change as needed; copper that won't split evenly stays in the pool
+#[tauri::command]
+pub async fn distribute_treasure(
+    campaign_id: String,
+    character_ids: Vec<String>,
+    database: State<'_, DatabaseType>,
+    app_handle: AppHandle,
+) -> AppResult<TreasureDistribution> {
+    if character_ids.is_empty() {
+        return Err(AppError::InvalidInput("Choose who to share the treasure between".to_string()));
+    }
+    let ids: Vec<&str> = character_ids.iter().map(String::as_str).collect();
+    let db = database.lock().await;
+    let (distribution, pool) = db.update_treasure_pool(&campaign_id, &ids, |pool, characters| {
+        let (shares, remainder) = inventory::split_currency(&pool.currency, characters.len());
+        let shares = characters
+            .iter_mut()
+            .zip(shares)
+            .map(|(character, currency)| {
+                character.equipment.currency.add(&currency);
+                TreasureShare {
+                    character_id: character.character_id.clone(),
+                    character_name: character.name.clone(),
+                    currency,
+                }
+            })
+            .collect();
+        pool.currency = remainder.clone();
+        Ok((TreasureDistribution { campaign_id: campaign_id.clone(), shares, remainder }, pool.clone()))
+    }).await?;
+
+    emit_characters_updated(&app_handle, &ids);
+    if let Some(window) = app_handle.get_webview_window("main") {
+        let _ = window.emit("treasure-pool-updated", &pool);
+    }
+    Ok(distribution)
+}
+
+/// Give an item, or some of a stack, from the treasure pool to a character;
+/// returns its id in their equipment
+#[tauri::command]
+pub async fn claim_treasure_item(
+    campaign_id: String,
+    character_id: String,
+    item_id: String,
+    quantity: Option<i64>,
+    database: State<'_, DatabaseType>,
+    app_handle: AppHandle,
+) -> AppResult<String> {
+    let db = database.lock().await;
+    let (new_id, pool) = db.update_treasure_pool(&campaign_id, &[&character_id], |pool, characters| {
+        let mut hoard = Equipment { items: std::mem::take(&mut pool.items), ..Equipment::default() };
+        let claimed = inventory::take(&mut hoard, &item_id, quantity);
+        pool.items = hoard.items;
+        let new_id = inventory::add(&mut characters[0].equipment, claimed?)?;
+        Ok((new_id, pool.clone()))
+    }).await?;
+
+    emit_characters_updated(&app_handle, &[&character_id]);
+    if let Some(window) = app_handle.get_webview_window("main") {
+        let _ = window.emit("treasure-pool-updated", &pool);
+    }
+    Ok(new_id)
+}
+
+fn emit_characters_updated(app_handle: &AppHandle, character_ids: &[&str]) {
+    if let Some(window) = app_handle.get_webview_window("main") {
+        for character_id in character_ids {
+            let _ = window.emit("character-updated", character_id);
+        }
+    }
+}
+
+// =============================================================================
+// Network Commands
+// =============================================================================
+
+/// Host a session; players join with the returned address and fingerprint,
+/// or with the invite code when it's listed on a signaling server
+#[tauri::command]
+pub async fn host_session(
+    request: HostSessionRequest,
+    database: State<'_, DatabaseType>,
+    network: State<'_, NetworkType>,
+    app_handle: AppHandle,
+) -> AppResult<SessionInfo> {
+    let db = database.lock().await.clone();
+    db.get_campaign(&request.campaign_id).await?
+        .ok_or_else(|| AppError::NotFound(format!("Campaign {}", request.campaign_id)))?;
+    let tokens = Arc::new(CampaignTokens::new(db, &request.campaign_id));
+    let mut network_manager = network.lock().await;
+    network_manager
+        .host(request, Path::new(IDENTITY_DIRECTORY), tokens, forward_session_events(app_handle))
+        .await
+}
+
+#[tauri::command]
+pub async fn join_session(
+    request: JoinSessionRequest,
+    network: State<'_, NetworkType>,
+    app_handle: AppHandle,
+) -> AppResult<SessionInfo> {
+    let mut network_manager = network.lock().await;
+    network_manager.join(request, forward_session_events(app_handle)).await
+}
+
+/// Join a session listed on a signaling server with the DM's invite code
+#[tauri::command]
+pub async fn join_session_by_invite(
+    request: JoinInviteRequest,
+    network: State<'_, NetworkType>,
+    app_handle: AppHandle,
+) -> AppResult<SessionInfo> {
+    let mut network_manager = network.lock().await;
+    network_manager.join_by_invite(request, forward_session_events(app_handle)).await
+}
+
+/// Leave the session, or end it for everyone when hosting
+#[tauri::command]
+pub async fn leave_session(network: State<'_, NetworkType>) -> AppResult<()> {
+    let mut network_manager = network.lock().await;
+    network_manager.leave().await;
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn get_session(network: State<'_, NetworkType>) -> AppResult<Option<SessionInfo>> {
+    let network_manager = network.lock().await;
+    Ok(network_manager.session_info().await)
+}
+
+#[tauri::command]
+pub async fn send_network_message(
+    payload: Payload,
+    network: State<'_, NetworkType>,
+) -> AppResult<NetworkMessage> {
+    let network_manager = network.lock().await;
+    network_manager.send(payload).await
+}
+
+/// Issue a key for a player so they can join as themselves and move their
+/// characters' tokens; only while hosting
+#[tauri::command]
+pub async fn issue_player_key(player_name: String, network: State<'_, NetworkType>) -> AppResult<String> {
+    let network_manager = network.lock().await;
+    network_manager.issue_player_key(&player_name).await
+}
+
+/// Where the host keeps the certificate players pin, next to the database
+const IDENTITY_DIRECTORY: &str = "data";
+
+/// Pass session events on to the frontend for as long as the session lasts
+fn forward_session_events(app_handle: AppHandle) -> SessionEvents {
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+    tauri::async_runtime::spawn(async move {
+        while let Some(event) = receiver.recv().await {
+            let Some(window) = app_handle.get_webview_window("main") else {
+                continue;
+            };
+            let _ = match &event {
+                SessionEvent::Message(message) => window.emit("network-message", message),
+                SessionEvent::PeerJoined(peer) => window.emit("peer-joined", peer),
+                SessionEvent::PeerLeft(peer) => window.emit("peer-left", peer),
+                SessionEvent::Denied(denied) => window.emit("message-denied", denied),
+                SessionEvent::Ended { reason } => window.emit("session-ended", reason),
+            };
+        }
+    });
+    sender
+}
+
 // =============================================================================
 // Utility Structs
 // =============================================================================
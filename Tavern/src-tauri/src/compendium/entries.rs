@@ -0,0 +1,56 @@
+use uuid::Uuid;
+
+use crate::database::models::{Character, CompendiumContent, CompendiumEntry};
+use crate::errors::{AppError, AppResult};
+
+/// Copy a compendium entry onto a character's sheet, remembering which entry
+/// it came from
+///
+/// Spells can only be known once. Items the character already has from the
+/// same entry stack; weapons and armor are added unequipped, one per call.
+pub fn add_to_character(character: &mut Character, entry: &CompendiumEntry, quantity: i64) -> AppResult<()> {
+    if quantity < 1 {
+        return Err(AppError::InvalidInput("Add at least one".to_string()));
+    }
+    let compendium_id = Some(entry.id.clone());
+
+    match &entry.content {
+        CompendiumContent::Spell(spell) => {
+            if character.spells.iter().any(|known| known.compendium_id == compendium_id) {
+                return Err(AppError::InvalidInput(format!("{} already knows {}", character.name, spell.name)));
+            }
+            let mut spell = spell.clone();
+            spell.id = Uuid::new_v4().to_string();
+            spell.is_prepared = false;
+            spell.compendium_id = compendium_id;
+            character.spells.push(spell);
+        }
+        CompendiumContent::Weapon(weapon) => {
+            let mut weapon = weapon.clone();
+            weapon.id = Uuid::new_v4().to_string();
+            weapon.is_equipped = false;
+            weapon.compendium_id = compendium_id;
+            character.equipment.weapons.push(weapon);
+        }
+        CompendiumContent::Armor(armor) => {
+            let mut armor = armor.clone();
+            armor.id = Uuid::new_v4().to_string();
+            armor.is_equipped = false;
+            armor.compendium_id = compendium_id;
+            character.equipment.armor.push(armor);
+        }
+        CompendiumContent::Item(item) => {
+            let items = &mut character.equipment.items;
+            if let Some(existing) = items.iter_mut().find(|owned| owned.compendium_id == compendium_id) {
+                existing.quantity += quantity;
+            } else {
+                let mut item = item.clone();
+                item.id = Uuid::new_v4().to_string();
+                item.quantity = quantity;
+                item.compendium_id = compendium_id;
+                items.push(item);
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,275 @@
+use std::collections::HashMap;
+
+use chrono::Utc;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::bestiary::stat_blocks;
+use crate::database::models::{
+    Armor, CompendiumContent, CompendiumEntry, CompendiumImport, Item, Spell, StatBlock, StatBlockData, Weapon,
+};
+use crate::dice::parser;
+use crate::errors::{AppError, AppResult};
+
+/// A compendium file: a JSON object naming its source, with lists of entries
+///
+/// ```json
+/// {
+///   "source": "homebrew",
+///   "spells": [{ "name": "Fire Bolt", "level": 0, "school": "evocation", "casting_time": "1 action",
+///                "range": "120 feet", "components": { "verbal": true, "somatic": true, "material": false },
+///                "duration": "Instantaneous", "description": "...",
+///                "damage": { "damage_dice": "1d10", "damage_type": "fire",
+///                            "scaling": { "per_level": "1d10", "max_level": 0 } } }],
+///   "weapons": [{ "name": "Longsword", "damage_dice": "1d8", "damage_type": "slashing",
+///                 "properties": ["versatile"] }],
+///   "armor": [{ "name": "Chain Mail", "armor_class": 16, "armor_type": "heavy", "stealth_disadvantage": true }],
+///   "items": [{ "name": "Healer's Kit", "description": "...", "weight": 3, "value": 500,
+///               "rarity": "common", "item_type": "adventuring_gear" }],
+///   "monsters": [{ "name": "Goblin", "size": "small", "creature_type": "humanoid (goblinoid)", ... }]
+/// }
+/// ```
+///
+/// Spells, weapons, armor and items take the fields they have on a character
+/// sheet, and monsters the fields of a stat block, without ids or anything
+/// that belongs to one character: `is_prepared`, `is_equipped` and `quantity`
+/// are filled in when a character adds the entry. Descriptions, flags that
+/// are usually false, and optional fields like a spell's `damage` or a
+/// weapon's `range` may be left out, as may any of the lists.
+///
+/// Ids are made from the source and each entry's name, so importing a file
+/// again updates its entries rather than adding copies, and characters keep
+/// pointing at the same entries.
+#[derive(Debug, Deserialize)]
+pub struct CompendiumFile {
+    pub source: String,
+    #[serde(default)]
+    pub spells: Vec<Value>,
+    #[serde(default)]
+    pub weapons: Vec<Value>,
+    #[serde(default)]
+    pub armor: Vec<Value>,
+    #[serde(default)]
+    pub items: Vec<Value>,
+    #[serde(default)]
+    pub monsters: Vec<Value>,
+}
+
+/// Everything read from a batch of compendium files, ready to store
+#[derive(Debug, Default)]
+pub struct Compendium {
+    pub entries: Vec<CompendiumEntry>,
+    pub stat_blocks: Vec<StatBlock>,
+    pub imports: Vec<CompendiumImport>,
+    /// Where each id was read from, to catch the same entry in two places
+    origins: HashMap<String, String>,
+}
+
+impl Compendium {
+    /// Read one compendium file's JSON; `file` names it in errors
+    pub fn add_file(&mut self, file: &str, json: &str) -> AppResult<()> {
+        let compendium: CompendiumFile = serde_json::from_str(json)
+            .map_err(|e| AppError::InvalidInput(format!("{}: {}", file, e)))?;
+        let source = slug(&compendium.source);
+        if source.is_empty() {
+            return Err(AppError::InvalidInput(format!("{}: the compendium needs a source", file)));
+        }
+
+        let mut import = CompendiumImport {
+            file: file.to_string(),
+            source: compendium.source.clone(),
+            spells: 0,
+            weapons: 0,
+            armor: 0,
+            items: 0,
+            monsters: 0,
+        };
+
+        for (index, value) in compendium.spells.into_iter().enumerate() {
+            let entry = EntryRef { file, list: "spells", index, source: &source };
+            let spell: Spell = entry.parse(
+                value,
+                "spell",
+                &[
+                    ("is_prepared", Value::Bool(false)),
+                    ("is_ritual", Value::Bool(false)),
+                    ("description", Value::String(String::new())),
+                ],
+            )?;
+            if !(0..=9).contains(&spell.level) {
+                return Err(entry.error(&spell.name, format!("level {} isn't between 0 and 9", spell.level)));
+            }
+            if let Some(damage) = &spell.damage {
+                entry.check_dice(&spell.name, &damage.damage_dice)?;
+                if let Some(scaling) = &damage.scaling {
+                    entry.check_dice(&spell.name, &scaling.per_level)?;
+                }
+            }
+            self.add_entry(&entry, spell.id.clone(), &compendium.source, spell.name.clone(), CompendiumContent::Spell(spell))?;
+            import.spells += 1;
+        }
+
+        for (index, value) in compendium.weapons.into_iter().enumerate() {
+            let entry = EntryRef { file, list: "weapons", index, source: &source };
+            let weapon: Weapon = entry.parse(
+                value,
+                "weapon",
+                &[("is_equipped", Value::Bool(false)), ("properties", Value::Array(Vec::new())), ("range", Value::Null)],
+            )?;
+            entry.check_dice(&weapon.name, &weapon.damage_dice)?;
+            self.add_entry(&entry, weapon.id.clone(), &compendium.source, weapon.name.clone(), CompendiumContent::Weapon(weapon))?;
+            import.weapons += 1;
+        }
+
+        for (index, value) in compendium.armor.into_iter().enumerate() {
+            let entry = EntryRef { file, list: "armor", index, source: &source };
+            let armor: Armor = entry.parse(
+                value,
+                "armor",
+                &[("is_equipped", Value::Bool(false)), ("stealth_disadvantage", Value::Bool(false))],
+            )?;
+            self.add_entry(&entry, armor.id.clone(), &compendium.source, armor.name.clone(), CompendiumContent::Armor(armor))?;
+            import.armor += 1;
+        }
+
+        for (index, value) in compendium.items.into_iter().enumerate() {
+            let entry = EntryRef { file, list: "items", index, source: &source };
+            let item: Item = entry.parse(
+                value,
+                "item",
+                &[("quantity", Value::from(1)), ("description", Value::String(String::new()))],
+            )?;
+            if item.weight < 0.0 || item.value < 0 {
+                return Err(entry.error(&item.name, "weight and value can't be negative".to_string()));
+            }
+            self.add_entry(&entry, item.id.clone(), &compendium.source, item.name.clone(), CompendiumContent::Item(item))?;
+            import.items += 1;
+        }
+
+        for (index, value) in compendium.monsters.into_iter().enumerate() {
+            let entry = EntryRef { file, list: "monsters", index, source: &source };
+            let name = entry.name(&value)?;
+            let id = entry.id("monster", &name);
+            let data: StatBlockData = serde_json::from_value(value).map_err(|e| entry.error(&name, e.to_string()))?;
+            stat_blocks::validate(&data).map_err(|e| entry.error(&name, reason(e)))?;
+
+            self.claim(&entry, &id, &name)?;
+            let now = Utc::now();
+            self.stat_blocks.push(StatBlock {
+                id,
+                campaign_id: None,
+                data,
+                created_at: now,
+                updated_at: now,
+            });
+            import.monsters += 1;
+        }
+
+        self.imports.push(import);
+        Ok(())
+    }
+
+    fn add_entry(
+        &mut self,
+        entry: &EntryRef,
+        id: String,
+        source: &str,
+        name: String,
+        content: CompendiumContent,
+    ) -> AppResult<()> {
+        self.claim(entry, &id, &name)?;
+        let now = Utc::now();
+        self.entries.push(CompendiumEntry {
+            id,
+            source: source.to_string(),
+            name,
+            content,
+            created_at: now,
+            updated_at: now,
+        });
+        Ok(())
+    }
+
+    /// Make sure no other entry in the batch has this id
+    fn claim(&mut self, entry: &EntryRef, id: &str, name: &str) -> AppResult<()> {
+        let here = format!("{} {}[{}]", entry.file, entry.list, entry.index);
+        if let Some(first) = self.origins.insert(id.to_string(), here) {
+            return Err(entry.error(name, format!("already defined at {}", first)));
+        }
+        Ok(())
+    }
+}
+
+/// Where an entry sits, for ids and error messages
+struct EntryRef<'a> {
+    file: &'a str,
+    list: &'static str,
+    index: usize,
+    source: &'a str,
+}
+
+impl EntryRef<'_> {
+    fn error(&self, name: &str, message: String) -> AppError {
+        AppError::InvalidInput(format!("{}: {}[{}] \"{}\": {}", self.file, self.list, self.index, name, message))
+    }
+
+    fn name(&self, value: &Value) -> AppResult<String> {
+        value
+            .get("name")
+            .and_then(Value::as_str)
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::to_string)
+            .ok_or_else(|| {
+                AppError::InvalidInput(format!("{}: {}[{}] needs a name", self.file, self.list, self.index))
+            })
+    }
+
+    fn id(&self, kind: &str, name: &str) -> String {
+        format!("{}/{}/{}", self.source, kind, slug(name))
+    }
+
+    /// Deserialize an entry into its model, adding its id and filling in the
+    /// fields a compendium may leave out
+    fn parse<T: DeserializeOwned>(&self, mut value: Value, kind: &str, defaults: &[(&str, Value)]) -> AppResult<T> {
+        let name = self.name(&value)?;
+        let Some(fields) = value.as_object_mut() else {
+            return Err(self.error(&name, "expected an object".to_string()));
+        };
+        fields.insert("id".to_string(), Value::String(self.id(kind, &name)));
+        for (field, default) in defaults {
+            fields.entry(field.to_string()).or_insert_with(|| default.clone());
+        }
+        serde_json::from_value(value).map_err(|e| self.error(&name, e.to_string()))
+    }
+
+    fn check_dice(&self, name: &str, dice: &str) -> AppResult<()> {
+        parser::parse(dice).map_err(|e| self.error(name, reason(e)))?;
+        Ok(())
+    }
+}
+
+/// An error's message without the "Invalid input" prefix, to nest in another
+fn reason(error: AppError) -> String {
+    match error {
+        AppError::InvalidInput(message) => message,
+        other => other.to_string(),
+    }
+}
+
+/// Lowercase letters and digits, with anything else between words as one
+/// dash; apostrophes are dropped, so `Healer's Kit` becomes `healers-kit`
+fn slug(name: &str) -> String {
+    let mut slug = String::new();
+    for c in name.trim().chars() {
+        if c == '\'' || c == '\u{2019}' {
+            continue;
+        } else if c.is_alphanumeric() {
+            slug.extend(c.to_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_end_matches('-').to_string()
+}
@@ -1,15 +1,58 @@
-use sqlx::{SqlitePool, Row};
+use sqlx::{QueryBuilder, Sqlite, SqlitePool, Row};
+use std::collections::HashMap;
 use std::path::Path;
 use uuid::Uuid;
 use chrono::{DateTime, Utc};
 use serde_json;
 
+use crate::character::classes;
 use crate::errors::{AppError, AppResult};
 use crate::database::models::*;
 
 pub mod models;
 pub mod migrations;
 
+/// Search index rows for one campaign, character, map or asset, each
+/// statement taking its id; they match the backfill in the search_index migration
+const INDEX_CAMPAIGN: &[&str] = &[
+    "INSERT INTO search_index (campaign_id, kind, entity_id, owner_id, title, body)
+     SELECT id, 'campaign', id, id, name, COALESCE(description, '') FROM campaigns WHERE id = ?1",
+];
+
+const INDEX_CHARACTER: &[&str] = &[
+    "INSERT INTO search_index (campaign_id, kind, entity_id, owner_id, title, body)
+     SELECT campaign_id, 'character', id, id, name, notes FROM characters WHERE id = ?1",
+    "INSERT INTO search_index (campaign_id, kind, entity_id, owner_id, title, body)
+     SELECT c.campaign_id, 'spell', json_extract(s.value, '$.id'), c.id, json_extract(s.value, '$.name'), json_extract(s.value, '$.description')
+     FROM characters c, json_each(c.spells) s WHERE c.id = ?1",
+    "INSERT INTO search_index (campaign_id, kind, entity_id, owner_id, title, body)
+     SELECT c.campaign_id, 'feature', json_extract(f.value, '$.id'), c.id, json_extract(f.value, '$.name'), json_extract(f.value, '$.description')
+     FROM characters c, json_each(c.features) f WHERE c.id = ?1",
+    "INSERT INTO search_index (campaign_id, kind, entity_id, owner_id, title, body)
+     SELECT c.campaign_id, 'item', json_extract(i.value, '$.id'), c.id, json_extract(i.value, '$.name'), COALESCE(json_extract(i.value, '$.description'), '')
+     FROM characters c, json_each(c.equipment, '$.items') i WHERE c.id = ?1",
+    "INSERT INTO search_index (campaign_id, kind, entity_id, owner_id, title, body)
+     SELECT c.campaign_id, 'item', json_extract(i.value, '$.id'), c.id, json_extract(i.value, '$.name'), ''
+     FROM characters c, json_each(c.equipment, '$.weapons') i WHERE c.id = ?1",
+    "INSERT INTO search_index (campaign_id, kind, entity_id, owner_id, title, body)
+     SELECT c.campaign_id, 'item', json_extract(i.value, '$.id'), c.id, json_extract(i.value, '$.name'), ''
+     FROM characters c, json_each(c.equipment, '$.armor') i WHERE c.id = ?1",
+];
+
+const INDEX_MAP: &[&str] = &[
+    "INSERT INTO search_index (campaign_id, kind, entity_id, owner_id, title, body)
+     SELECT campaign_id, 'map', id, id, name, COALESCE(description, '') FROM maps WHERE id = ?1",
+];
+
+const INDEX_ASSET: &[&str] = &[
+    "INSERT INTO search_index (campaign_id, kind, entity_id, owner_id, title, body)
+     SELECT campaign_id, 'asset', id, id, name, (SELECT COALESCE(group_concat(t.value, ' '), '') FROM json_each(assets.tags) t)
+     FROM assets WHERE id = ?1",
+];
+
+/// Most hits a search returns
+const SEARCH_LIMIT: i64 = 50;
+
 #[derive(Debug, Clone)]
 pub struct DatabaseManager {
     pool: SqlitePool,
@@ -31,7 +74,7 @@ impl DatabaseManager {
 
     /// Run database migrations
     pub async fn run_migrations(&self) -> AppResult<()> {
-        sqlx::migrate!("./src/database").run(&self.pool).await?;
+        sqlx::migrate!("./migrations").run(&self.pool).await?;
         Ok(())
     }
 
@@ -68,6 +111,7 @@ impl DatabaseManager {
         .execute(&self.pool)
         .await?;
 
+        self.reindex(&id, INDEX_CAMPAIGN).await?;
         Ok(id)
     }
 
@@ -187,10 +231,10 @@ impl DatabaseManager {
             query_builder = query_builder.bind(settings_json);
         }
         query_builder = query_builder.bind(now);
-        query_builder = query_builder.bind(campaign_id);
+        query_builder = query_builder.bind(&campaign_id);
 
         query_builder.execute(&self.pool).await?;
-        Ok(())
+        self.reindex(&campaign_id, INDEX_CAMPAIGN).await
     }
 
     /// Delete a campaign
@@ -208,6 +252,41 @@ impl DatabaseManager {
             .execute(&self.pool)
             .await?;
 
+        sqlx::query("DELETE FROM rolls WHERE campaign_id = ?")
+            .bind(campaign_id)
+            .execute(&self.pool)
+            .await?;
+
+        sqlx::query("DELETE FROM combats WHERE campaign_id = ?")
+            .bind(campaign_id)
+            .execute(&self.pool)
+            .await?;
+
+        sqlx::query("DELETE FROM rests WHERE campaign_id = ?")
+            .bind(campaign_id)
+            .execute(&self.pool)
+            .await?;
+
+        sqlx::query("DELETE FROM encounters WHERE campaign_id = ?")
+            .bind(campaign_id)
+            .execute(&self.pool)
+            .await?;
+
+        sqlx::query("DELETE FROM stat_blocks WHERE campaign_id = ?")
+            .bind(campaign_id)
+            .execute(&self.pool)
+            .await?;
+
+        sqlx::query("DELETE FROM treasure_pools WHERE campaign_id = ?")
+            .bind(campaign_id)
+            .execute(&self.pool)
+            .await?;
+
+        sqlx::query("DELETE FROM search_index WHERE campaign_id = ?")
+            .bind(campaign_id)
+            .execute(&self.pool)
+            .await?;
+
         sqlx::query!("DELETE FROM campaigns WHERE id = ?", campaign_id)
             .execute(&self.pool)
             .await?;
@@ -232,22 +311,36 @@ impl DatabaseManager {
         let equipment_json = serde_json::to_string(&equipment)?;
         let spells_json = serde_json::to_string(&Vec::<Spell>::new())?;
         let features_json = serde_json::to_string(&Vec::<Feature>::new())?;
+        let class_levels = data.classes.clone().unwrap_or_else(|| {
+            vec![ClassLevel {
+                class: data.character_class.clone(),
+                subclass: None,
+                level: data.level,
+                hit_die: classes::hit_die(&data.character_class),
+                hit_dice_used: 0,
+            }]
+        });
+        classes::validate_classes(&class_levels)?;
+        let character_class = class_levels[0].class.clone();
+        let level: i64 = class_levels.iter().map(|class| class.level).sum();
+        let classes_json = serde_json::to_string(&class_levels)?;
 
         sqlx::query!(
             r#"
             INSERT INTO characters (
-                id, campaign_id, name, player_name, character_class, level, race, background,
+                id, campaign_id, name, player_name, character_class, level, classes, race, background,
                 stats, combat_stats, skills, equipment, spells, features, notes, is_npc,
                 created_at, updated_at
             )
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19)
             "#,
             id,
             data.campaign_id,
             data.name,
             data.player_name,
-            data.character_class,
-            data.level,
+            character_class,
+            level,
+            classes_json,
             data.race,
             data.background,
             stats_json,
@@ -264,6 +357,7 @@ impl DatabaseManager {
         .execute(&self.pool)
         .await?;
 
+        self.reindex(&id, INDEX_CHARACTER).await?;
         Ok(id)
     }
 
@@ -271,9 +365,9 @@ impl DatabaseManager {
     pub async fn get_characters(&self, campaign_id: &str) -> AppResult<Vec<Character>> {
         let rows = sqlx::query(
             r#"
-            SELECT id, campaign_id, name, player_name, character_class, level, race, background,
-                   stats, combat_stats, skills, equipment, spells, features, notes, avatar_url,
-                   is_npc, created_at, updated_at
+            SELECT id, campaign_id, name, player_name, character_class, level, classes, race, background,
+                   stats, combat_stats, skills, equipment, spells, spellcasting, features, notes,
+                   avatar_url, is_npc, created_at, updated_at
             FROM characters
             WHERE campaign_id = ?1
             ORDER BY name ASC
@@ -283,77 +377,16 @@ impl DatabaseManager {
         .fetch_all(&self.pool)
         .await?;
 
-        let mut characters = Vec::new();
-        for row in rows {
-            let id: String = row.try_get("id").unwrap_or_default();
-            let campaign_id: String = row.try_get("campaign_id").unwrap_or_default();
-            let name: String = row.try_get("name").unwrap_or_default();
-            let player_name: String = row.try_get("player_name").unwrap_or_default();
-            let character_class: String = row.try_get("character_class").unwrap_or_default();
-            let level = row.try_get("level").unwrap_or(1);
-            let race: String = row.try_get("race").unwrap_or_default();
-            let background: String = row.try_get("background").unwrap_or_default();
-            let stats: CharacterStats = match row.try_get::<Option<&str>, _>("stats")? {
-                Some(s) => serde_json::from_str(s)?,
-                None => CharacterStats::default(),
-            };
-            let combat_stats: CombatStats = match row.try_get::<Option<&str>, _>("combat_stats")? {
-                Some(s) => serde_json::from_str(s)?,
-                None => CombatStats::default(),
-            };
-            let skills: Skills = match row.try_get::<Option<&str>, _>("skills")? {
-                Some(s) => serde_json::from_str(s)?,
-                None => Skills::default(),
-            };
-            let equipment: Equipment = match row.try_get::<Option<&str>, _>("equipment")? {
-                Some(s) => serde_json::from_str(s)?,
-                None => Equipment::default(),
-            };
-            let spells: Vec<Spell> = match row.try_get::<Option<&str>, _>("spells")? {
-                Some(s) => serde_json::from_str(s)?,
-                None => Vec::new(),
-            };
-            let features: Vec<Feature> = match row.try_get::<Option<&str>, _>("features")? {
-                Some(s) => serde_json::from_str(s)?,
-                None => Vec::new(),
-            };
-            let notes: String = row.try_get("notes").unwrap_or_default();
-            let avatar_url: Option<String> = row.try_get("avatar_url").ok();
-            let is_npc: bool = row.try_get("is_npc").unwrap_or(false);
-            let created_at: DateTime<Utc> = row.try_get("created_at")?;
-            let updated_at: DateTime<Utc> = row.try_get("updated_at")?;
-            characters.push(Character {
-                id,
-                campaign_id,
-                name,
-                player_name: Some(player_name),
-                character_class,
-                level,
-                race,
-                background,
-                stats,
-                combat_stats,
-                skills,
-                equipment,
-                spells,
-                features,
-                notes,
-                avatar_url,
-                is_npc,
-                created_at: created_at,
-                updated_at: updated_at,
-            });
-        }
-        Ok(characters)
+        rows.iter().map(Self::character_from_row).collect()
     }
 
     /// Get a specific character
     pub async fn get_character(&self, character_id: &str) -> AppResult<Option<Character>> {
         let row = sqlx::query(
             r#"
-            SELECT id, campaign_id, name, player_name, character_class, level, race, background,
-                   stats, combat_stats, skills, equipment, spells, features, notes, avatar_url,
-                   is_npc, created_at, updated_at
+            SELECT id, campaign_id, name, player_name, character_class, level, classes, race, background,
+                   stats, combat_stats, skills, equipment, spells, spellcasting, features, notes,
+                   avatar_url, is_npc, created_at, updated_at
             FROM characters
             WHERE id = ?1
             "#
@@ -362,72 +395,88 @@ impl DatabaseManager {
         .fetch_optional(&self.pool)
         .await?;
 
-        if let Some(row) = row {
-            let id: String = row.try_get("id").unwrap_or_default();
-            let campaign_id: String = row.try_get("campaign_id").unwrap_or_default();
-            let name: String = row.try_get("name").unwrap_or_default();
-            let player_name: String = row.try_get("player_name").unwrap_or_default();
-            let character_class: String = row.try_get("character_class").unwrap_or_default();
-            let level = row.try_get("level").unwrap_or(1);
-            let race: String = row.try_get("race").unwrap_or_default();
-            let background: String = row.try_get("background").unwrap_or_default();
-            let stats: CharacterStats = match row.try_get::<Option<&str>, _>("stats")? {
-                Some(s) => serde_json::from_str(s)?,
-                None => CharacterStats::default(),
-            };
-            let combat_stats: CombatStats = match row.try_get::<Option<&str>, _>("combat_stats")? {
-                Some(s) => serde_json::from_str(s)?,
-                None => CombatStats::default(),
-            };
-            let skills: Skills = match row.try_get::<Option<&str>, _>("skills")? {
-                Some(s) => serde_json::from_str(s)?,
-                None => Skills::default(),
-            };
-            let equipment: Equipment = match row.try_get::<Option<&str>, _>("equipment")? {
-                Some(s) => serde_json::from_str(s)?,
-                None => Equipment::default(),
-            };
-            let spells: Vec<Spell> = match row.try_get::<Option<&str>, _>("spells")? {
-                Some(s) => serde_json::from_str(s)?,
-                None => Vec::new(),
-            };
-            let features: Vec<Feature> = match row.try_get::<Option<&str>, _>("features")? {
-                Some(s) => serde_json::from_str(s)?,
-                None => Vec::new(),
-            };
-            let notes: String = row.try_get("notes").unwrap_or_default();
-            let avatar_url: Option<String> = row.try_get("avatar_url").ok();
-            let is_npc: bool = row.try_get("is_npc").unwrap_or(false);
-            let created_at: DateTime<Utc> = row.try_get("created_at")?;
-            let updated_at: DateTime<Utc> = row.try_get("updated_at")?;
-            Ok(Some(Character {
-                id,
-                campaign_id,
-                name,
-                player_name: Some(player_name),
-                character_class,
-                level,
-                race,
-                background,
-                stats,
-                combat_stats,
-                skills,
-                equipment,
-                spells,
-                features,
-                notes,
-                avatar_url,
-                is_npc,
-                created_at: created_at,
-                updated_at: updated_at,
-            }))
-        } else {
-            Ok(None)
-        }
+        row.as_ref().map(Self::character_from_row).transpose()
+    }
+
+    fn character_from_row(row: &sqlx::sqlite::SqliteRow) -> AppResult<Character> {
+        let id: String = row.try_get("id").unwrap_or_default();
+        let campaign_id: String = row.try_get("campaign_id").unwrap_or_default();
+        let name: String = row.try_get("name").unwrap_or_default();
+        let player_name: String = row.try_get("player_name").unwrap_or_default();
+        let character_class: String = row.try_get("character_class").unwrap_or_default();
+        let level = row.try_get("level").unwrap_or(1);
+        let classes: Vec<ClassLevel> = match row.try_get::<Option<&str>, _>("classes")? {
+            Some(s) => serde_json::from_str(s)?,
+            None => Vec::new(),
+        };
+        let race: String = row.try_get("race").unwrap_or_default();
+        let background: String = row.try_get("background").unwrap_or_default();
+        let stats: CharacterStats = match row.try_get::<Option<&str>, _>("stats")? {
+            Some(s) => serde_json::from_str(s)?,
+            None => CharacterStats::default(),
+        };
+        let combat_stats: CombatStats = match row.try_get::<Option<&str>, _>("combat_stats")? {
+            Some(s) => serde_json::from_str(s)?,
+            None => CombatStats::default(),
+        };
+        let skills: Skills = match row.try_get::<Option<&str>, _>("skills")? {
+            Some(s) => serde_json::from_str(s)?,
+            None => Skills::default(),
+        };
+        let equipment: Equipment = match row.try_get::<Option<&str>, _>("equipment")? {
+            Some(s) => serde_json::from_str(s)?,
+            None => Equipment::default(),
+        };
+        let spells: Vec<Spell> = match row.try_get::<Option<&str>, _>("spells")? {
+            Some(s) => serde_json::from_str(s)?,
+            None => Vec::new(),
+        };
+        let spellcasting: Spellcasting = match row.try_get::<Option<&str>, _>("spellcasting")? {
+            Some(s) => serde_json::from_str(s)?,
+            None => Spellcasting::default(),
+        };
+        let features: Vec<Feature> = match row.try_get::<Option<&str>, _>("features")? {
+            Some(s) => serde_json::from_str(s)?,
+            None => Vec::new(),
+        };
+        let notes: String = row.try_get("notes").unwrap_or_default();
+        let avatar_url: Option<String> = row.try_get("avatar_url").ok();
+        let is_npc: bool = row.try_get("is_npc").unwrap_or(false);
+        let created_at: DateTime<Utc> = row.try_get("created_at")?;
+        let updated_at: DateTime<Utc> = row.try_get("updated_at")?;
+        let mut character = Character {
+            id,
+            campaign_id,
+            name,
+            player_name: Some(player_name),
+            character_class,
+            level,
+            classes,
+            race,
+            background,
+            stats,
+            combat_stats,
+            skills,
+            equipment,
+            spells,
+            spellcasting,
+            features,
+            notes,
+            avatar_url,
+            is_npc,
+            created_at: created_at,
+            updated_at: updated_at,
+        };
+        character.sync_classes();
+        Ok(character)
     }
 
     /// Update a character
-    pub async fn update_character(&self, character_id: &str, data: UpdateCharacterRequest) -> AppResult<()> {
+    ///
+    /// New class levels also set the character's class, total level and
+    /// proficiency bonus. A level on its own goes to a single-class
+    /// character's one class.
+    pub async fn update_character(&self, character_id: &str, mut data: UpdateCharacterRequest) -> AppResult<()> {
         let now = Utc::now();
 
         // Build dynamic update query
@@ -439,9 +488,41 @@ impl DatabaseManager {
             bind_values.push(name.clone());
         }
 
-        if let Some(level) = data.level {
+        let class_levels = match (data.classes.take(), data.level) {
+            (Some(class_levels), _) => Some(class_levels),
+            (None, Some(level)) => {
+                let character = self.get_character(character_id).await?
+                    .ok_or_else(|| AppError::NotFound(format!("Character {}", character_id)))?;
+                match character.classes.as_slice() {
+                    [class] => Some(vec![ClassLevel { level, ..class.clone() }]),
+                    _ => {
+                        return Err(AppError::InvalidInput(format!(
+                            "{} has more than one class; set their class levels instead",
+                            character.name
+                        )))
+                    }
+                }
+            }
+            (None, None) => None,
+        };
+        if let Some(class_levels) = &class_levels {
+            classes::validate_classes(class_levels)?;
+            let level: i64 = class_levels.iter().map(|class| class.level).sum();
+            query_parts.push("classes = ?".to_string());
+            bind_values.push(serde_json::to_string(class_levels)?);
+            query_parts.push("character_class = ?".to_string());
+            bind_values.push(class_levels[0].class.clone());
             query_parts.push("level = ?".to_string());
             bind_values.push(level.to_string());
+
+            let proficiency_bonus = classes::proficiency_bonus(level);
+            match &mut data.stats {
+                Some(stats) => stats.proficiency_bonus = proficiency_bonus,
+                None => {
+                    query_parts.push("stats = json_set(stats, '$.proficiency_bonus', CAST(? AS INTEGER))".to_string());
+                    bind_values.push(proficiency_bonus.to_string());
+                }
+            }
         }
 
         if let Some(stats) = &data.stats {
@@ -483,15 +564,92 @@ impl DatabaseManager {
         }
 
         query_builder.execute(&self.pool).await?;
+        self.reindex(character_id, INDEX_CHARACTER).await
+    }
+
+    /// Save a character's combat stats (hit points, conditions, death saves)
+    pub async fn update_combat_stats(&self, character_id: &str, combat_stats: &CombatStats) -> AppResult<()> {
+        let now = Utc::now();
+        let combat_stats_json = serde_json::to_string(combat_stats)?;
+
+        sqlx::query("UPDATE characters SET combat_stats = ?1, updated_at = ?2 WHERE id = ?3")
+            .bind(combat_stats_json)
+            .bind(now)
+            .bind(character_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Save a character's class levels as they are, with their spent hit dice
+    pub async fn update_classes(&self, character_id: &str, classes: &[ClassLevel]) -> AppResult<()> {
+        let now = Utc::now();
+        let classes_json = serde_json::to_string(classes)?;
+
+        sqlx::query("UPDATE characters SET classes = ?1, updated_at = ?2 WHERE id = ?3")
+            .bind(classes_json)
+            .bind(now)
+            .bind(character_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Save a character's spell slots and concentration
+    pub async fn update_spellcasting(&self, character_id: &str, spellcasting: &Spellcasting) -> AppResult<()> {
+        let now = Utc::now();
+        let spellcasting_json = serde_json::to_string(spellcasting)?;
+
+        sqlx::query("UPDATE characters SET spellcasting = ?1, updated_at = ?2 WHERE id = ?3")
+            .bind(spellcasting_json)
+            .bind(now)
+            .bind(character_id)
+            .execute(&self.pool)
+            .await?;
+
         Ok(())
     }
 
+    /// Save a character's spells and equipment
+    pub async fn update_spells_and_equipment(&self, character_id: &str, spells: &[Spell], equipment: &Equipment) -> AppResult<()> {
+        let now = Utc::now();
+        let spells_json = serde_json::to_string(spells)?;
+        let equipment_json = serde_json::to_string(equipment)?;
+
+        sqlx::query("UPDATE characters SET spells = ?1, equipment = ?2, updated_at = ?3 WHERE id = ?4")
+            .bind(spells_json)
+            .bind(equipment_json)
+            .bind(now)
+            .bind(character_id)
+            .execute(&self.pool)
+            .await?;
+
+        self.reindex(character_id, INDEX_CHARACTER).await
+    }
+
+    /// Save a character's features, with their remaining uses
+    pub async fn update_features(&self, character_id: &str, features: &[Feature]) -> AppResult<()> {
+        let now = Utc::now();
+        let features_json = serde_json::to_string(features)?;
+
+        sqlx::query("UPDATE characters SET features = ?1, updated_at = ?2 WHERE id = ?3")
+            .bind(features_json)
+            .bind(now)
+            .bind(character_id)
+            .execute(&self.pool)
+            .await?;
+
+        self.reindex(character_id, INDEX_CHARACTER).await
+    }
+
     /// Delete a character
     pub async fn delete_character(&self, character_id: &str) -> AppResult<()> {
         sqlx::query!("DELETE FROM characters WHERE id = ?", character_id)
             .execute(&self.pool)
             .await?;
-        Ok(())
+        self.unindex(character_id).await
     }
 
     // =============================================================================
@@ -524,6 +682,7 @@ impl DatabaseManager {
         .execute(&self.pool)
         .await?;
 
+        self.reindex(&id, INDEX_MAP).await?;
         Ok(id)
     }
 
@@ -678,6 +837,7 @@ impl DatabaseManager {
         .execute(&self.pool)
         .await?;
 
+        self.reindex(&id, INDEX_ASSET).await?;
         Ok(id)
     }
 
@@ -745,7 +905,7 @@ impl DatabaseManager {
         sqlx::query!("DELETE FROM assets WHERE id = ?", asset_id)
             .execute(&self.pool)
             .await?;
-        Ok(())
+        self.unindex(asset_id).await
     }
 
     // =============================================================================
@@ -781,4 +941,892 @@ impl DatabaseManager {
         }
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// Set the initiative shown on tokens, keyed by token id
+    pub async fn set_token_initiatives(&self, map_id: &str, initiatives: &HashMap<String, i64>) -> AppResult<()> {
+        if let Some(mut map) = self.get_map(map_id).await? {
+            for token in map.tokens.iter_mut() {
+                if let Some(&initiative) = initiatives.get(&token.id) {
+                    token.initiative = Some(initiative);
+                }
+            }
+            self.save_map_state(map_id, map.tokens, map.fog_of_war).await?;
+        }
+        Ok(())
+    }
+
+    // =============================================================================
+    // Roll History Operations
+    // =============================================================================
+
+    /// Record a roll in a campaign's history
+    pub async fn record_roll(&self, campaign_id: &str, character_id: Option<&str>, kind: RollKind, roll: &DiceRoll) -> AppResult<String> {
+        let id = Uuid::new_v4().to_string();
+        let now = Utc::now();
+        let kind_str = serde_json::to_string(&kind)?;
+        let roll_type_str = serde_json::to_string(&roll.roll_type)?;
+        let roll_json = serde_json::to_string(roll)?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO rolls (id, campaign_id, character_id, roll_kind, roll_type, dice_notation, total, roll, created_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+            "#
+        )
+        .bind(&id)
+        .bind(campaign_id)
+        .bind(character_id)
+        .bind(kind_str)
+        .bind(roll_type_str)
+        .bind(&roll.dice_notation)
+        .bind(roll.total)
+        .bind(roll_json)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(id)
+    }
+
+    /// Record an initiative roll in a campaign's history
+    pub async fn record_initiative_roll(&self, campaign_id: &str, initiative: &InitiativeRoll) -> AppResult<String> {
+        self.record_roll(campaign_id, Some(&initiative.character_id), RollKind::Initiative, &initiative.roll).await
+    }
+
+    /// Page through a campaign's rolls, newest first
+    pub async fn get_roll_history(&self, campaign_id: &str, query: &RollHistoryQuery) -> AppResult<RollHistoryPage> {
+        let limit = query.limit.unwrap_or(50).clamp(1, 500);
+        let offset = query.offset.unwrap_or(0).max(0);
+
+        let mut count_query = QueryBuilder::<Sqlite>::new("SELECT COUNT(*) AS count FROM rolls");
+        Self::push_roll_filters(&mut count_query, campaign_id, query)?;
+        let total_count: i64 = count_query.build().fetch_one(&self.pool).await?.try_get("count")?;
+
+        let mut select_query = QueryBuilder::<Sqlite>::new(
+            "SELECT id, campaign_id, character_id, roll_kind, roll, created_at FROM rolls"
+        );
+        Self::push_roll_filters(&mut select_query, campaign_id, query)?;
+        select_query.push(" ORDER BY created_at DESC LIMIT ");
+        select_query.push_bind(limit);
+        select_query.push(" OFFSET ");
+        select_query.push_bind(offset);
+        let rows = select_query.build().fetch_all(&self.pool).await?;
+
+        let mut rolls = Vec::new();
+        for row in rows {
+            let id: String = row.try_get("id").unwrap_or_default();
+            let campaign_id: String = row.try_get("campaign_id").unwrap_or_default();
+            let character_id: Option<String> = row.try_get("character_id").unwrap_or_default();
+            let kind: RollKind = serde_json::from_str(row.try_get::<&str, _>("roll_kind")?)?;
+            let roll: DiceRoll = serde_json::from_str(row.try_get::<&str, _>("roll")?)?;
+            let created_at: DateTime<Utc> = row.try_get("created_at")?;
+            rolls.push(RollRecord {
+                id,
+                campaign_id,
+                character_id,
+                kind,
+                roll,
+                created_at,
+            });
+        }
+
+        Ok(RollHistoryPage {
+            rolls,
+            total_count,
+            limit,
+            offset,
+        })
+    }
+
+    fn push_roll_filters(builder: &mut QueryBuilder<'_, Sqlite>, campaign_id: &str, query: &RollHistoryQuery) -> AppResult<()> {
+        builder.push(" WHERE campaign_id = ");
+        builder.push_bind(campaign_id.to_string());
+        if let Some(character_id) = &query.character_id {
+            builder.push(" AND character_id = ");
+            builder.push_bind(character_id.clone());
+        }
+        if let Some(kind) = &query.kind {
+            builder.push(" AND roll_kind = ");
+            builder.push_bind(serde_json::to_string(kind)?);
+        }
+        if let Some(roll_type) = &query.roll_type {
+            builder.push(" AND roll_type = ");
+            builder.push_bind(serde_json::to_string(roll_type)?);
+        }
+        if let Some(from) = query.from {
+            builder.push(" AND created_at >= ");
+            builder.push_bind(from);
+        }
+        if let Some(to) = query.to {
+            builder.push(" AND created_at <= ");
+            builder.push_bind(to);
+        }
+        Ok(())
+    }
+
+    // =============================================================================
+    // Rest Operations
+    // =============================================================================
+
+    /// Record a character's rest
+    pub async fn record_rest(&self, rest: &RestRecord) -> AppResult<()> {
+        let rest_type = serde_json::to_string(&rest.rest_type)?;
+        let details = serde_json::to_string(rest)?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO rests (id, campaign_id, character_id, rest_type, details, created_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+            "#
+        )
+        .bind(&rest.id)
+        .bind(&rest.campaign_id)
+        .bind(&rest.character_id)
+        .bind(rest_type)
+        .bind(details)
+        .bind(rest.created_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Get a campaign's rests, newest first, optionally for one character
+    pub async fn get_rest_history(&self, campaign_id: &str, character_id: Option<&str>, limit: Option<i64>) -> AppResult<Vec<RestRecord>> {
+        let limit = limit.unwrap_or(50).clamp(1, 500);
+        let rows = sqlx::query(
+            r#"
+            SELECT details FROM rests
+            WHERE campaign_id = ?1 AND (?2 IS NULL OR character_id = ?2)
+            ORDER BY created_at DESC
+            LIMIT ?3
+            "#
+        )
+        .bind(campaign_id)
+        .bind(character_id)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut rests = Vec::new();
+        for row in rows {
+            rests.push(serde_json::from_str(row.try_get::<&str, _>("details")?)?);
+        }
+        Ok(rests)
+    }
+
+    // =============================================================================
+    // Combat Operations
+    // =============================================================================
+
+    /// Store a new combat
+    pub async fn create_combat(&self, combat: &Combat) -> AppResult<()> {
+        let initiative_type = serde_json::to_string(&combat.initiative_type)?;
+        let participants_json = serde_json::to_string(&combat.participants)?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO combats (id, campaign_id, map_id, name, initiative_type, round, turn_index, participants, is_active, created_at, updated_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)
+            "#
+        )
+        .bind(&combat.id)
+        .bind(&combat.campaign_id)
+        .bind(&combat.map_id)
+        .bind(&combat.name)
+        .bind(initiative_type)
+        .bind(combat.round)
+        .bind(combat.turn_index as i64)
+        .bind(participants_json)
+        .bind(combat.is_active)
+        .bind(combat.created_at)
+        .bind(combat.updated_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Get all combats for a campaign, most recent first
+    pub async fn get_combats(&self, campaign_id: &str) -> AppResult<Vec<Combat>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT id, campaign_id, map_id, name, initiative_type, round, turn_index, participants, is_active, created_at, updated_at
+            FROM combats
+            WHERE campaign_id = ?1
+            ORDER BY created_at DESC
+            "#
+        )
+        .bind(campaign_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.iter().map(Self::combat_from_row).collect()
+    }
+
+    /// Get a specific combat
+    pub async fn get_combat(&self, combat_id: &str) -> AppResult<Option<Combat>> {
+        let row = sqlx::query(
+            r#"
+            SELECT id, campaign_id, map_id, name, initiative_type, round, turn_index, participants, is_active, created_at, updated_at
+            FROM combats
+            WHERE id = ?1
+            "#
+        )
+        .bind(combat_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        row.as_ref().map(Self::combat_from_row).transpose()
+    }
+
+    /// Save combat state (participants, round and turn)
+    pub async fn save_combat_state(&self, combat: &Combat) -> AppResult<()> {
+        let now = Utc::now();
+        let participants_json = serde_json::to_string(&combat.participants)?;
+
+        sqlx::query(
+            "UPDATE combats SET round = ?1, turn_index = ?2, participants = ?3, is_active = ?4, updated_at = ?5 WHERE id = ?6"
+        )
+        .bind(combat.round)
+        .bind(combat.turn_index as i64)
+        .bind(participants_json)
+        .bind(combat.is_active)
+        .bind(now)
+        .bind(&combat.id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Delete a combat
+    pub async fn delete_combat(&self, combat_id: &str) -> AppResult<()> {
+        sqlx::query("DELETE FROM combats WHERE id = ?")
+            .bind(combat_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    fn combat_from_row(row: &sqlx::sqlite::SqliteRow) -> AppResult<Combat> {
+        let id: String = row.try_get("id").unwrap_or_default();
+        let campaign_id: String = row.try_get("campaign_id").unwrap_or_default();
+        let map_id: Option<String> = row.try_get("map_id").unwrap_or_default();
+        let name: String = row.try_get("name").unwrap_or_default();
+        let initiative_type: InitiativeType = serde_json::from_str(row.try_get::<&str, _>("initiative_type")?)?;
+        let round: i64 = row.try_get("round").unwrap_or(0);
+        let turn_index: i64 = row.try_get("turn_index").unwrap_or(0);
+        let participants: Vec<CombatParticipant> = serde_json::from_str(row.try_get::<&str, _>("participants")?)?;
+        let is_active: bool = row.try_get("is_active").unwrap_or(false);
+        let created_at: DateTime<Utc> = row.try_get("created_at")?;
+        let updated_at: DateTime<Utc> = row.try_get("updated_at")?;
+        Ok(Combat {
+            id,
+            campaign_id,
+            map_id,
+            name,
+            initiative_type,
+            round,
+            turn_index: turn_index.max(0) as usize,
+            participants,
+            is_active,
+            created_at,
+            updated_at,
+        })
+    }
+
+    // =============================================================================
+    // Encounter Operations
+    // =============================================================================
+
+    /// Store a new encounter
+    pub async fn create_encounter(&self, encounter: &Encounter) -> AppResult<()> {
+        let creatures_json = serde_json::to_string(&encounter.creatures)?;
+        let party_json = serde_json::to_string(&encounter.party_character_ids)?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO encounters (id, campaign_id, map_id, name, creatures, party_character_ids, created_at, updated_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+            "#
+        )
+        .bind(&encounter.id)
+        .bind(&encounter.campaign_id)
+        .bind(&encounter.map_id)
+        .bind(&encounter.name)
+        .bind(creatures_json)
+        .bind(party_json)
+        .bind(encounter.created_at)
+        .bind(encounter.updated_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Get all encounters for a campaign
+    pub async fn get_encounters(&self, campaign_id: &str) -> AppResult<Vec<Encounter>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT id, campaign_id, map_id, name, creatures, party_character_ids, created_at, updated_at
+            FROM encounters
+            WHERE campaign_id = ?1
+            ORDER BY name
+            "#
+        )
+        .bind(campaign_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.iter().map(Self::encounter_from_row).collect()
+    }
+
+    /// Get a specific encounter
+    pub async fn get_encounter(&self, encounter_id: &str) -> AppResult<Option<Encounter>> {
+        let row = sqlx::query(
+            r#"
+            SELECT id, campaign_id, map_id, name, creatures, party_character_ids, created_at, updated_at
+            FROM encounters
+            WHERE id = ?1
+            "#
+        )
+        .bind(encounter_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        row.as_ref().map(Self::encounter_from_row).transpose()
+    }
+
+    /// Save an edited encounter
+    pub async fn save_encounter(&self, encounter: &Encounter) -> AppResult<()> {
+        let now = Utc::now();
+        let creatures_json = serde_json::to_string(&encounter.creatures)?;
+        let party_json = serde_json::to_string(&encounter.party_character_ids)?;
+
+        sqlx::query(
+            "UPDATE encounters SET name = ?1, map_id = ?2, creatures = ?3, party_character_ids = ?4, updated_at = ?5 WHERE id = ?6"
+        )
+        .bind(&encounter.name)
+        .bind(&encounter.map_id)
+        .bind(creatures_json)
+        .bind(party_json)
+        .bind(now)
+        .bind(&encounter.id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Delete an encounter
+    pub async fn delete_encounter(&self, encounter_id: &str) -> AppResult<()> {
+        sqlx::query("DELETE FROM encounters WHERE id = ?")
+            .bind(encounter_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    fn encounter_from_row(row: &sqlx::sqlite::SqliteRow) -> AppResult<Encounter> {
+        let id: String = row.try_get("id").unwrap_or_default();
+        let campaign_id: String = row.try_get("campaign_id").unwrap_or_default();
+        let map_id: Option<String> = row.try_get("map_id").unwrap_or_default();
+        let name: String = row.try_get("name").unwrap_or_default();
+        let creatures: Vec<EncounterCreature> = serde_json::from_str(row.try_get::<&str, _>("creatures")?)?;
+        let party_character_ids: Vec<String> = serde_json::from_str(row.try_get::<&str, _>("party_character_ids")?)?;
+        let created_at: DateTime<Utc> = row.try_get("created_at")?;
+        let updated_at: DateTime<Utc> = row.try_get("updated_at")?;
+        Ok(Encounter {
+            id,
+            campaign_id,
+            map_id,
+            name,
+            creatures,
+            party_character_ids,
+            created_at,
+            updated_at,
+        })
+    }
+
+    // =============================================================================
+    // Bestiary Operations
+    // =============================================================================
+
+    /// Store a new stat block
+    pub async fn create_stat_block(&self, stat_block: &StatBlock) -> AppResult<()> {
+        let data_json = serde_json::to_string(&stat_block.data)?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO stat_blocks (id, campaign_id, name, challenge_rating, data, created_at, updated_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+            "#
+        )
+        .bind(&stat_block.id)
+        .bind(&stat_block.campaign_id)
+        .bind(&stat_block.data.name)
+        .bind(stat_block.data.challenge_rating)
+        .bind(data_json)
+        .bind(stat_block.created_at)
+        .bind(stat_block.updated_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Get the global bestiary, plus a campaign's own stat blocks when one is given
+    pub async fn get_stat_blocks(&self, campaign_id: Option<&str>) -> AppResult<Vec<StatBlock>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT id, campaign_id, data, created_at, updated_at
+            FROM stat_blocks
+            WHERE campaign_id IS NULL OR campaign_id = ?1
+            ORDER BY challenge_rating, name
+            "#
+        )
+        .bind(campaign_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.iter().map(Self::stat_block_from_row).collect()
+    }
+
+    /// Get a specific stat block
+    pub async fn get_stat_block(&self, stat_block_id: &str) -> AppResult<Option<StatBlock>> {
+        let row = sqlx::query(
+            "SELECT id, campaign_id, data, created_at, updated_at FROM stat_blocks WHERE id = ?1"
+        )
+        .bind(stat_block_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        row.as_ref().map(Self::stat_block_from_row).transpose()
+    }
+
+    /// Save an edited stat block
+    pub async fn save_stat_block(&self, stat_block: &StatBlock) -> AppResult<()> {
+        let now = Utc::now();
+        let data_json = serde_json::to_string(&stat_block.data)?;
+
+        sqlx::query(
+            "UPDATE stat_blocks SET name = ?1, challenge_rating = ?2, data = ?3, updated_at = ?4 WHERE id = ?5"
+        )
+        .bind(&stat_block.data.name)
+        .bind(stat_block.data.challenge_rating)
+        .bind(data_json)
+        .bind(now)
+        .bind(&stat_block.id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Delete a stat block
+    pub async fn delete_stat_block(&self, stat_block_id: &str) -> AppResult<()> {
+        sqlx::query("DELETE FROM stat_blocks WHERE id = ?")
+            .bind(stat_block_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    fn stat_block_from_row(row: &sqlx::sqlite::SqliteRow) -> AppResult<StatBlock> {
+        let id: String = row.try_get("id").unwrap_or_default();
+        let campaign_id: Option<String> = row.try_get("campaign_id").unwrap_or_default();
+        let data: StatBlockData = serde_json::from_str(row.try_get::<&str, _>("data")?)?;
+        let created_at: DateTime<Utc> = row.try_get("created_at")?;
+        let updated_at: DateTime<Utc> = row.try_get("updated_at")?;
+        Ok(StatBlock {
+            id,
+            campaign_id,
+            data,
+            created_at,
+            updated_at,
+        })
+    }
+
+    // =============================================================================
+    // Compendium Operations
+    // =============================================================================
+
+    /// Store imported compendium entries and monsters in one transaction,
+    /// updating any already imported under the same ids
+    pub async fn import_compendium(&self, entries: &[CompendiumEntry], stat_blocks: &[StatBlock]) -> AppResult<()> {
+        let mut tx = self.pool.begin().await?;
+
+        for entry in entries {
+            let kind = serde_json::to_string(&entry.content.kind())?;
+            let data_json = match &entry.content {
+                CompendiumContent::Spell(spell) => serde_json::to_string(spell)?,
+                CompendiumContent::Weapon(weapon) => serde_json::to_string(weapon)?,
+                CompendiumContent::Armor(armor) => serde_json::to_string(armor)?,
+                CompendiumContent::Item(item) => serde_json::to_string(item)?,
+            };
+
+            sqlx::query(
+                r#"
+                INSERT INTO compendium_entries (id, source, kind, name, data, created_at, updated_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                ON CONFLICT (id) DO UPDATE SET
+                    source = excluded.source, kind = excluded.kind, name = excluded.name,
+                    data = excluded.data, updated_at = excluded.updated_at
+                "#
+            )
+            .bind(&entry.id)
+            .bind(&entry.source)
+            .bind(kind)
+            .bind(&entry.name)
+            .bind(data_json)
+            .bind(entry.created_at)
+            .bind(entry.updated_at)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        for stat_block in stat_blocks {
+            let data_json = serde_json::to_string(&stat_block.data)?;
+
+            sqlx::query(
+                r#"
+                INSERT INTO stat_blocks (id, campaign_id, name, challenge_rating, data, created_at, updated_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                ON CONFLICT (id) DO UPDATE SET
+                    name = excluded.name, challenge_rating = excluded.challenge_rating,
+                    data = excluded.data, updated_at = excluded.updated_at
+                "#
+            )
+            .bind(&stat_block.id)
+            .bind(&stat_block.campaign_id)
+            .bind(&stat_block.data.name)
+            .bind(stat_block.data.challenge_rating)
+            .bind(data_json)
+            .bind(stat_block.created_at)
+            .bind(stat_block.updated_at)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Get compendium entries, optionally of one kind or from one source
+    pub async fn get_compendium_entries(&self, kind: Option<CompendiumKind>, source: Option<&str>) -> AppResult<Vec<CompendiumEntry>> {
+        let mut query = QueryBuilder::<Sqlite>::new(
+            "SELECT id, source, kind, name, data, created_at, updated_at FROM compendium_entries WHERE 1 = 1"
+        );
+        if let Some(kind) = kind {
+            query.push(" AND kind = ");
+            query.push_bind(serde_json::to_string(&kind)?);
+        }
+        if let Some(source) = source {
+            query.push(" AND source = ");
+            query.push_bind(source.to_string());
+        }
+        query.push(" ORDER BY name");
+        let rows = query.build().fetch_all(&self.pool).await?;
+
+        rows.iter().map(Self::compendium_entry_from_row).collect()
+    }
+
+    /// Get a specific compendium entry
+    pub async fn get_compendium_entry(&self, entry_id: &str) -> AppResult<Option<CompendiumEntry>> {
+        let row = sqlx::query(
+            "SELECT id, source, kind, name, data, created_at, updated_at FROM compendium_entries WHERE id = ?1"
+        )
+        .bind(entry_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        row.as_ref().map(Self::compendium_entry_from_row).transpose()
+    }
+
+    fn compendium_entry_from_row(row: &sqlx::sqlite::SqliteRow) -> AppResult<CompendiumEntry> {
+        let id: String = row.try_get("id").unwrap_or_default();
+        let source: String = row.try_get("source").unwrap_or_default();
+        let name: String = row.try_get("name").unwrap_or_default();
+        let kind: CompendiumKind = serde_json::from_str(row.try_get::<&str, _>("kind")?)?;
+        let data: &str = row.try_get("data")?;
+        let content = match kind {
+            CompendiumKind::Spell => CompendiumContent::Spell(serde_json::from_str(data)?),
+            CompendiumKind::Weapon => CompendiumContent::Weapon(serde_json::from_str(data)?),
+            CompendiumKind::Armor => CompendiumContent::Armor(serde_json::from_str(data)?),
+            CompendiumKind::Item => CompendiumContent::Item(serde_json::from_str(data)?),
+        };
+        let created_at: DateTime<Utc> = row.try_get("created_at")?;
+        let updated_at: DateTime<Utc> = row.try_get("updated_at")?;
+        Ok(CompendiumEntry {
+            id,
+            source,
+            name,
+            content,
+            created_at,
+            updated_at,
+        })
+    }
+
+    // =============================================================================
+    // Inventory Operations
+    // =============================================================================
+
+    /// Change the equipment of one or more characters in the same campaign in
+    /// a single transaction, so an item moved between them can't be lost or
+    /// duplicated by a change made at the same time
+    ///
+    /// The characters are handed to `change` in the order given; nothing is
+    /// saved if it returns an error.
+    pub async fn update_equipment<T>(
+        &self,
+        character_ids: &[&str],
+        change: impl FnOnce(&mut [CharacterEquipment]) -> AppResult<T>,
+    ) -> AppResult<T> {
+        let now = Utc::now();
+        let mut tx = self.pool.begin().await?;
+
+        let mut characters = Self::lock_equipment(&mut tx, character_ids, now).await?;
+        if let Some(other) = characters.iter().find(|character| character.campaign_id != characters[0].campaign_id) {
+            return Err(AppError::InvalidInput(format!(
+                "{} is in a different campaign from {}",
+                other.name, characters[0].name
+            )));
+        }
+        let result = change(&mut characters)?;
+        Self::save_equipment(&mut tx, &characters).await?;
+
+        tx.commit().await?;
+        for character in &characters {
+            self.reindex(&character.character_id, INDEX_CHARACTER).await?;
+        }
+        Ok(result)
+    }
+
+    /// Get a campaign's treasure pool, which is empty until something is added
+    pub async fn get_treasure_pool(&self, campaign_id: &str) -> AppResult<TreasurePool> {
+        let row = sqlx::query("SELECT campaign_id, currency, items, updated_at FROM treasure_pools WHERE campaign_id = ?1")
+            .bind(campaign_id)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        match row {
+            Some(row) => Self::treasure_pool_from_row(&row),
+            None => Ok(Self::empty_treasure_pool(campaign_id)),
+        }
+    }
+
+    /// Change a campaign's treasure pool, and the equipment of characters in
+    /// the campaign taking from it, in a single transaction
+    pub async fn update_treasure_pool<T>(
+        &self,
+        campaign_id: &str,
+        character_ids: &[&str],
+        change: impl FnOnce(&mut TreasurePool, &mut [CharacterEquipment]) -> AppResult<T>,
+    ) -> AppResult<T> {
+        let now = Utc::now();
+        let mut tx = self.pool.begin().await?;
+
+        // Writing first takes SQLite's write lock before anything is read
+        let result = sqlx::query("UPDATE campaigns SET updated_at = updated_at WHERE id = ?1")
+            .bind(campaign_id)
+            .execute(&mut *tx)
+            .await?;
+        if result.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!("Campaign {}", campaign_id)));
+        }
+        let row = sqlx::query("SELECT campaign_id, currency, items, updated_at FROM treasure_pools WHERE campaign_id = ?1")
+            .bind(campaign_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+        let mut pool = match row {
+            Some(row) => Self::treasure_pool_from_row(&row)?,
+            None => Self::empty_treasure_pool(campaign_id),
+        };
+
+        let mut characters = Self::lock_equipment(&mut tx, character_ids, now).await?;
+        if let Some(other) = characters.iter().find(|character| character.campaign_id != campaign_id) {
+            return Err(AppError::InvalidInput(format!("{} isn't in this campaign", other.name)));
+        }
+        let result = change(&mut pool, &mut characters)?;
+
+        pool.updated_at = now;
+        sqlx::query(
+            r#"
+            INSERT INTO treasure_pools (campaign_id, currency, items, updated_at)
+            VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT (campaign_id) DO UPDATE SET
+                currency = excluded.currency, items = excluded.items, updated_at = excluded.updated_at
+            "#
+        )
+        .bind(campaign_id)
+        .bind(serde_json::to_string(&pool.currency)?)
+        .bind(serde_json::to_string(&pool.items)?)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+        Self::save_equipment(&mut tx, &characters).await?;
+
+        tx.commit().await?;
+        for character in &characters {
+            self.reindex(&character.character_id, INDEX_CHARACTER).await?;
+        }
+        Ok(result)
+    }
+
+    /// Load characters' equipment for a transaction to change, touching each
+    /// character first so the transaction holds the write lock before it reads
+    async fn lock_equipment(
+        tx: &mut sqlx::SqliteConnection,
+        character_ids: &[&str],
+        now: DateTime<Utc>,
+    ) -> AppResult<Vec<CharacterEquipment>> {
+        let mut characters: Vec<CharacterEquipment> = Vec::new();
+        for &character_id in character_ids {
+            if characters.iter().any(|character| character.character_id == character_id) {
+                return Err(AppError::InvalidInput(format!("Character {} is listed twice", character_id)));
+            }
+            let result = sqlx::query("UPDATE characters SET updated_at = ?1 WHERE id = ?2")
+                .bind(now)
+                .bind(character_id)
+                .execute(&mut *tx)
+                .await?;
+            if result.rows_affected() == 0 {
+                return Err(AppError::NotFound(format!("Character {}", character_id)));
+            }
+
+            let row = sqlx::query("SELECT id, campaign_id, name, equipment FROM characters WHERE id = ?1")
+                .bind(character_id)
+                .fetch_one(&mut *tx)
+                .await?;
+            let equipment: Equipment = match row.try_get::<Option<&str>, _>("equipment")? {
+                Some(s) => serde_json::from_str(s)?,
+                None => Equipment::default(),
+            };
+            characters.push(CharacterEquipment {
+                character_id: row.try_get("id")?,
+                campaign_id: row.try_get("campaign_id").unwrap_or_default(),
+                name: row.try_get("name").unwrap_or_default(),
+                equipment,
+            });
+        }
+        Ok(characters)
+    }
+
+    async fn save_equipment(tx: &mut sqlx::SqliteConnection, characters: &[CharacterEquipment]) -> AppResult<()> {
+        for character in characters {
+            sqlx::query("UPDATE characters SET equipment = ?1 WHERE id = ?2")
+                .bind(serde_json::to_string(&character.equipment)?)
+                .bind(&character.character_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        Ok(())
+    }
+
+    fn treasure_pool_from_row(row: &sqlx::sqlite::SqliteRow) -> AppResult<TreasurePool> {
+        let campaign_id: String = row.try_get("campaign_id").unwrap_or_default();
+        let currency: Currency = serde_json::from_str(row.try_get::<&str, _>("currency")?)?;
+        let items: Vec<Item> = serde_json::from_str(row.try_get::<&str, _>("items")?)?;
+        let updated_at: DateTime<Utc> = row.try_get("updated_at")?;
+        Ok(TreasurePool {
+            campaign_id,
+            currency,
+            items,
+            updated_at,
+        })
+    }
+
+    fn empty_treasure_pool(campaign_id: &str) -> TreasurePool {
+        TreasurePool {
+            campaign_id: campaign_id.to_string(),
+            currency: Currency::default(),
+            items: Vec::new(),
+            updated_at: Utc::now(),
+        }
+    }
+
+    // =============================================================================
+    // Search Operations
+    // =============================================================================
+
+    /// Search a campaign's content, and global assets, for every word of the
+    /// query; the last word may be partly typed
+    pub async fn search(&self, campaign_id: &str, query: &str, kinds: &[SearchKind]) -> AppResult<Vec<SearchHit>> {
+        let Some(expression) = Self::match_expression(query) else {
+            return Ok(Vec::new());
+        };
+
+        let mut builder = QueryBuilder::<Sqlite>::new(
+            r#"
+            SELECT kind, entity_id, owner_id, title,
+                   snippet(search_index, -1, '<mark>', '</mark>', '…', 16) AS snippet,
+                   bm25(search_index, 0.0, 0.0, 0.0, 0.0, 10.0, 1.0) AS rank
+            FROM search_index
+            WHERE search_index MATCH "#
+        );
+        builder.push_bind(expression);
+        builder.push(" AND (campaign_id = ");
+        builder.push_bind(campaign_id.to_string());
+        builder.push(" OR (campaign_id IS NULL AND kind = 'asset'))");
+        if !kinds.is_empty() {
+            builder.push(" AND kind IN (");
+            let mut separated = builder.separated(", ");
+            for kind in kinds {
+                separated.push_bind(kind.as_str());
+            }
+            separated.push_unseparated(")");
+        }
+        builder.push(" ORDER BY rank LIMIT ");
+        builder.push_bind(SEARCH_LIMIT);
+
+        let rows = builder.build().fetch_all(&self.pool).await?;
+        let mut hits = Vec::new();
+        for row in rows {
+            let kind: SearchKind = serde_json::from_value(serde_json::Value::String(row.try_get("kind")?))?;
+            let owner_id: String = row.try_get("owner_id").unwrap_or_default();
+            let character_id = matches!(kind, SearchKind::Spell | SearchKind::Feature | SearchKind::Item)
+                .then_some(owner_id);
+            hits.push(SearchHit {
+                kind,
+                id: row.try_get("entity_id").unwrap_or_default(),
+                character_id,
+                title: row.try_get("title").unwrap_or_default(),
+                snippet: row.try_get("snippet").unwrap_or_default(),
+                rank: row.try_get("rank").unwrap_or(0.0),
+            });
+        }
+        Ok(hits)
+    }
+
+    /// Turn what was typed into an FTS5 query: each word quoted, so stray
+    /// punctuation can't break the syntax, and matched as a prefix
+    fn match_expression(query: &str) -> Option<String> {
+        let terms: Vec<String> = query
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .map(|word| format!("\"{}\"*", word))
+            .collect();
+        (!terms.is_empty()).then(|| terms.join(" "))
+    }
+
+    /// Rebuild the search rows for one campaign, character, map or asset
+    async fn reindex(&self, owner_id: &str, statements: &[&str]) -> AppResult<()> {
+        self.unindex(owner_id).await?;
+        for statement in statements {
+            sqlx::query(statement)
+                .bind(owner_id)
+                .execute(&self.pool)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn unindex(&self, owner_id: &str) -> AppResult<()> {
+        sqlx::query("DELETE FROM search_index WHERE owner_id = ?")
+            .bind(owner_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
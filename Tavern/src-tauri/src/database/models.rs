@@ -4,8 +4,14 @@ use std::collections::HashMap;
 use uuid::Uuid;
 use chrono::{DateTime, Utc};
 
+use crate::character::classes;
+use crate::errors::PermissionError;
 
 
+fn default_true() -> bool {
+    true
+}
+
 // =============================================================================
 // Core Campaign Models
 // =============================================================================
@@ -90,10 +96,13 @@ impl Default for AdvantageMode {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum CriticalHitRules {
+    /// Roll the damage dice once and double what they show
     #[serde(rename = "double_dice")]
     DoubleDice,
+    /// Maximum damage from the dice plus a normal roll of them
     #[serde(rename = "max_plus_roll")]
     MaxPlusRoll,
+    /// Roll twice as many damage dice
     #[serde(rename = "standard")]
     Standard,
 }
@@ -112,7 +121,7 @@ pub struct CombatSettings {
     pub action_surge_limit: u8,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum InitiativeType {
     #[serde(rename = "individual")]
     Individual,
@@ -133,13 +142,19 @@ impl Default for InitiativeType {
 // =============================================================================
 
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+#[serde(remote = "Self")]
 pub struct Character {
     pub id: String,
     pub campaign_id: String,
     pub name: String,
     pub player_name: Option<String>,
+    /// The first class taken and the total of all class levels, kept in step
+    /// with `classes`
     pub character_class: String,
     pub level: i64,
+    /// Every class the character has levels in, in the order they were taken
+    #[serde(default)]
+    pub classes: Vec<ClassLevel>,
     pub race: String,
     pub background: String,
     pub stats: CharacterStats,
@@ -147,6 +162,8 @@ pub struct Character {
     pub skills: Skills,
     pub equipment: Equipment,
     pub spells: Vec<Spell>,
+    #[serde(default)]
+    pub spellcasting: Spellcasting,
     pub features: Vec<Feature>,
     pub notes: String,
     pub avatar_url: Option<String>,
@@ -155,6 +172,121 @@ pub struct Character {
     pub updated_at: DateTime<Utc>,
 }
 
+impl Serialize for Character {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Character::serialize(self, serializer)
+    }
+}
+
+// Characters saved before multiclassing have no `classes`; they get one
+// entry made from their class and level
+impl<'de> Deserialize<'de> for Character {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut character = Character::deserialize(deserializer)?;
+        character.sync_classes();
+        Ok(character)
+    }
+}
+
+impl Character {
+    /// Bring `character_class` and `level` in line with `classes`, or make
+    /// `classes` from them when it's empty
+    pub fn sync_classes(&mut self) {
+        match self.classes.first() {
+            Some(first) => {
+                self.character_class = first.class.clone();
+                self.level = self.classes.iter().map(|class| class.level).sum();
+            }
+            None => self.classes.push(ClassLevel {
+                class: self.character_class.clone(),
+                subclass: None,
+                level: self.level,
+                hit_die: classes::hit_die(&self.character_class),
+                hit_dice_used: 0,
+            }),
+        }
+    }
+
+    pub fn total_level(&self) -> i64 {
+        self.classes.iter().map(|class| class.level).sum()
+    }
+
+    pub fn class_level(&self, class: &str) -> Option<&ClassLevel> {
+        let class = class.trim();
+        self.classes.iter().find(|entry| entry.class.trim().eq_ignore_ascii_case(class))
+    }
+
+    pub fn proficiency_bonus(&self) -> i64 {
+        classes::proficiency_bonus(self.total_level())
+    }
+
+    /// Level used for spell slots, combining every spellcasting class
+    pub fn caster_level(&self) -> i64 {
+        classes::caster_level(&self.classes)
+    }
+}
+
+#[cfg(test)]
+impl Character {
+    /// A level 1 human fighter with 10 in every ability and 10 hit points,
+    /// for tests to adjust
+    pub fn sample(name: &str) -> Self {
+        let mut character = Character {
+            id: name.to_lowercase(),
+            campaign_id: "campaign".to_string(),
+            name: name.to_string(),
+            player_name: None,
+            character_class: "Fighter".to_string(),
+            level: 1,
+            classes: Vec::new(),
+            race: "Human".to_string(),
+            background: "Soldier".to_string(),
+            stats: CharacterStats {
+                strength: 10,
+                dexterity: 10,
+                constitution: 10,
+                intelligence: 10,
+                wisdom: 10,
+                charisma: 10,
+                proficiency_bonus: 2,
+                ..Default::default()
+            },
+            combat_stats: CombatStats {
+                armor_class: 10,
+                hit_points: 10,
+                max_hit_points: 10,
+                speed: 30,
+                ..Default::default()
+            },
+            skills: Skills::default(),
+            equipment: Equipment::default(),
+            spells: Vec::new(),
+            spellcasting: Spellcasting::default(),
+            features: Vec::new(),
+            notes: String::new(),
+            avatar_url: None,
+            is_npc: false,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        };
+        character.sync_classes();
+        character
+    }
+}
+
+/// Levels in one class
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClassLevel {
+    pub class: String,
+    #[serde(default)]
+    pub subclass: Option<String>,
+    pub level: i64,
+    pub hit_die: u32,
+    /// Hit dice of this class spent since they were last regained
+    #[serde(default)]
+    pub hit_dice_used: i64,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct CharacterStats {
     pub strength: i64,
@@ -164,9 +296,58 @@ pub struct CharacterStats {
     pub wisdom: i64,
     pub charisma: i64,
     pub proficiency_bonus: i64,
+    /// Abilities the character adds proficiency to when saving, e.g. `["dexterity", "intelligence"]`
+    #[serde(default)]
+    pub saving_throw_proficiencies: Vec<String>,
 }
 
 impl CharacterStats {
+    /// Canonical name of an ability from its full name or abbreviation
+    pub fn ability_name(stat: &str) -> Option<&'static str> {
+        match stat.to_lowercase().as_str() {
+            "strength" | "str" => Some("strength"),
+            "dexterity" | "dex" => Some("dexterity"),
+            "constitution" | "con" => Some("constitution"),
+            "intelligence" | "int" => Some("intelligence"),
+            "wisdom" | "wis" => Some("wisdom"),
+            "charisma" | "cha" => Some("charisma"),
+            _ => None,
+        }
+    }
+
+    pub fn is_proficient_in_save(&self, stat: &str) -> bool {
+        let Some(ability) = Self::ability_name(stat) else {
+            return false;
+        };
+        self.saving_throw_proficiencies
+            .iter()
+            .any(|s| Self::ability_name(s) == Some(ability))
+    }
+
+    pub fn score(&self, stat: &str) -> Option<i64> {
+        let score = match Self::ability_name(stat)? {
+            "strength" => self.strength,
+            "dexterity" => self.dexterity,
+            "constitution" => self.constitution,
+            "intelligence" => self.intelligence,
+            "wisdom" => self.wisdom,
+            _ => self.charisma,
+        };
+        Some(score)
+    }
+
+    pub fn score_mut(&mut self, stat: &str) -> Option<&mut i64> {
+        let score = match Self::ability_name(stat)? {
+            "strength" => &mut self.strength,
+            "dexterity" => &mut self.dexterity,
+            "constitution" => &mut self.constitution,
+            "intelligence" => &mut self.intelligence,
+            "wisdom" => &mut self.wisdom,
+            _ => &mut self.charisma,
+        };
+        Some(score)
+    }
+
     pub fn get_modifier(&self, stat: &str) -> i64 {
         let score = match stat.to_lowercase().as_str() {
             "strength" | "str" => self.strength,
@@ -201,14 +382,52 @@ pub struct CombatStats {
     pub death_saves_success: i64,
     pub death_saves_failure: i64,
     pub conditions: Vec<Condition>,
+    #[serde(default)]
+    pub resistances: Vec<DamageType>,
+    #[serde(default)]
+    pub immunities: Vec<DamageType>,
+    #[serde(default)]
+    pub vulnerabilities: Vec<DamageType>,
+    /// At 0 hit points but no longer making death saves
+    #[serde(default)]
+    pub is_stable: bool,
+    #[serde(default)]
+    pub is_dead: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Condition {
     pub name: String,
     pub description: String,
+    /// Rounds left; `None` lasts until removed
     pub duration: Option<i64>,
     pub source: String,
+    /// When in combat the duration counts down
+    #[serde(default)]
+    pub expires: ConditionExpiry,
+    /// Set when the condition only lasts while someone concentrates on a spell
+    #[serde(default)]
+    pub concentration: Option<ConcentrationLink>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub enum ConditionExpiry {
+    /// At the start of the affected creature's turn
+    #[serde(rename = "start_of_turn")]
+    StartOfTurn,
+    /// At the end of the affected creature's turn
+    #[serde(rename = "end_of_turn")]
+    EndOfTurn,
+    #[serde(rename = "end_of_round")]
+    #[default]
+    EndOfRound,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConcentrationLink {
+    /// Character concentrating on the spell
+    pub caster_id: String,
+    pub spell: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -249,6 +468,61 @@ impl Default for SkillProficiency {
     }
 }
 
+impl SkillProficiency {
+    pub fn bonus(&self, proficiency_bonus: i64) -> i64 {
+        match self {
+            SkillProficiency::None => 0,
+            SkillProficiency::Proficient => proficiency_bonus,
+            SkillProficiency::Expertise => proficiency_bonus * 2,
+        }
+    }
+}
+
+impl Skills {
+    /// Normalize a skill name such as "Sleight of Hand" to its field name
+    pub fn normalize(skill: &str) -> String {
+        skill.trim().to_lowercase().replace([' ', '-'], "_")
+    }
+
+    pub fn get(&self, skill: &str) -> Option<&SkillProficiency> {
+        let proficiency = match Self::normalize(skill).as_str() {
+            "acrobatics" => &self.acrobatics,
+            "animal_handling" => &self.animal_handling,
+            "arcana" => &self.arcana,
+            "athletics" => &self.athletics,
+            "deception" => &self.deception,
+            "history" => &self.history,
+            "insight" => &self.insight,
+            "intimidation" => &self.intimidation,
+            "investigation" => &self.investigation,
+            "medicine" => &self.medicine,
+            "nature" => &self.nature,
+            "perception" => &self.perception,
+            "performance" => &self.performance,
+            "persuasion" => &self.persuasion,
+            "religion" => &self.religion,
+            "sleight_of_hand" => &self.sleight_of_hand,
+            "stealth" => &self.stealth,
+            "survival" => &self.survival,
+            _ => return None,
+        };
+        Some(proficiency)
+    }
+
+    /// The ability a skill is rolled with
+    pub fn ability_for(skill: &str) -> Option<&'static str> {
+        let ability = match Self::normalize(skill).as_str() {
+            "athletics" => "strength",
+            "acrobatics" | "sleight_of_hand" | "stealth" => "dexterity",
+            "arcana" | "history" | "investigation" | "nature" | "religion" => "intelligence",
+            "animal_handling" | "insight" | "medicine" | "perception" | "survival" => "wisdom",
+            "deception" | "intimidation" | "performance" | "persuasion" => "charisma",
+            _ => return None,
+        };
+        Some(ability)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Equipment {
     pub items: Vec<Item>,
@@ -267,6 +541,9 @@ pub struct Item {
     pub value: i64, // in copper pieces
     pub rarity: ItemRarity,
     pub item_type: ItemType,
+    /// Compendium entry this was added from
+    #[serde(default)]
+    pub compendium_id: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -308,9 +585,26 @@ pub struct Weapon {
     pub properties: Vec<WeaponProperty>,
     pub range: Option<WeaponRange>,
     pub is_equipped: bool,
+    #[serde(default = "default_true")]
+    pub is_proficient: bool,
+    /// Compendium entry this was added from
+    #[serde(default)]
+    pub compendium_id: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl Weapon {
+    pub fn has_property(&self, property: WeaponProperty) -> bool {
+        self.properties.contains(&property)
+    }
+
+    /// Ranged weapons fire ammunition or have a range without being thrown
+    pub fn is_ranged(&self) -> bool {
+        self.has_property(WeaponProperty::Ammunition)
+            || (self.range.is_some() && !self.has_property(WeaponProperty::Thrown))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum DamageType {
     #[serde(rename = "acid")]
     Acid,
@@ -340,7 +634,7 @@ pub enum DamageType {
     Thunder,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum WeaponProperty {
     #[serde(rename = "ammunition")]
     Ammunition,
@@ -378,6 +672,9 @@ pub struct Armor {
     pub armor_type: ArmorType,
     pub stealth_disadvantage: bool,
     pub is_equipped: bool,
+    /// Compendium entry this was added from
+    #[serde(default)]
+    pub compendium_id: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -409,6 +706,31 @@ impl Currency {
     pub fn total_in_gold(&self) -> f32 {
         self.total_in_copper() as f32 / 100.0
     }
+
+    /// The fewest coins worth `copper`, from platinum down; electrum is left
+    /// out as few players want it back as change
+    pub fn from_copper(copper: i64) -> Self {
+        let copper = copper.max(0);
+        Self {
+            platinum: copper / 1000,
+            gold: copper % 1000 / 100,
+            electrum: 0,
+            silver: copper % 100 / 10,
+            copper: copper % 10,
+        }
+    }
+
+    pub fn add(&mut self, other: &Currency) {
+        self.copper += other.copper;
+        self.silver += other.silver;
+        self.electrum += other.electrum;
+        self.gold += other.gold;
+        self.platinum += other.platinum;
+    }
+
+    pub fn is_negative(&self) -> bool {
+        [self.copper, self.silver, self.electrum, self.gold, self.platinum].iter().any(|&coins| coins < 0)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -425,6 +747,9 @@ pub struct Spell {
     pub is_prepared: bool,
     pub is_ritual: bool,
     pub damage: Option<SpellDamage>,
+    /// Compendium entry this was added from
+    #[serde(default)]
+    pub compendium_id: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -462,12 +787,91 @@ pub struct SpellDamage {
     pub scaling: Option<SpellScaling>,
 }
 
+/// Extra damage when a spell is cast with a higher slot, or for cantrips as
+/// the caster levels up
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SpellScaling {
+    /// Added per slot level above the spell's own, or per cantrip tier
     pub per_level: String,
+    /// Highest slot level that still adds damage
     pub max_level: i64,
 }
 
+impl Spell {
+    pub fn requires_concentration(&self) -> bool {
+        self.duration.to_lowercase().contains("concentration")
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Spellcasting {
+    /// Ability spells are cast with; `None` for characters who don't cast
+    pub ability: Option<String>,
+    #[serde(default)]
+    pub slots: Vec<SpellSlots>,
+    /// Warlock pact magic slots, all of one level and back on a short rest
+    #[serde(default)]
+    pub pact_slots: Option<SpellSlots>,
+    /// The spell being concentrated on, if any
+    #[serde(default)]
+    pub concentration: Option<Concentration>,
+}
+
+impl Spellcasting {
+    pub fn slot_mut(&mut self, level: i64) -> Option<&mut SpellSlots> {
+        self.slots.iter_mut().find(|slot| slot.level == level)
+    }
+
+    pub fn spell_save_dc(&self, stats: &CharacterStats) -> Option<i64> {
+        self.ability.as_ref().map(|ability| 8 + stats.proficiency_bonus + stats.get_modifier(ability))
+    }
+
+    pub fn spell_attack_bonus(&self, stats: &CharacterStats) -> Option<i64> {
+        self.ability.as_ref().map(|ability| stats.proficiency_bonus + stats.get_modifier(ability))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpellSlots {
+    pub level: i64,
+    pub max: i64,
+    pub used: i64,
+}
+
+impl SpellSlots {
+    pub fn remaining(&self) -> i64 {
+        (self.max - self.used).max(0)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Concentration {
+    pub spell_id: String,
+    pub spell_name: String,
+    pub started_at: DateTime<Utc>,
+}
+
+/// What casting a spell did
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpellCast {
+    pub character_id: String,
+    pub spell_id: String,
+    pub spell_name: String,
+    pub spell_level: i64,
+    /// Level the spell was cast at; `None` for cantrips and rituals
+    pub slot_level: Option<i64>,
+    pub used_pact_slot: bool,
+    pub as_ritual: bool,
+    pub save_dc: Option<i64>,
+    pub attack_bonus: Option<i64>,
+    pub damage: Option<DiceRoll>,
+    pub damage_type: Option<DamageType>,
+    /// Spell the caster is concentrating on after this cast
+    pub concentration: Option<String>,
+    /// Concentration spell this cast replaced
+    pub ended_concentration: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Feature {
     pub id: String,
@@ -498,7 +902,7 @@ pub struct FeatureUses {
     pub recharge: RechargeType,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum RechargeType {
     #[serde(rename = "short_rest")]
     ShortRest,
@@ -510,6 +914,121 @@ pub enum RechargeType {
     Weekly,
 }
 
+/// What a player picks when their character gains a level
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct LevelUpChoices {
+    /// Class to take the level in, either one the character has or a new one
+    /// to multiclass into; their first class if not given
+    pub class: Option<String>,
+    #[serde(default)]
+    pub hit_points: HitPointMethod,
+    /// Required at levels that grant an ability score improvement, when the
+    /// campaign allows one
+    pub improvement: Option<AbilityImprovement>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub enum HitPointMethod {
+    /// The hit die's fixed average, rounded up
+    #[default]
+    #[serde(rename = "average")]
+    Average,
+    #[serde(rename = "roll")]
+    Roll,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum AbilityImprovement {
+    /// Two points spread over one or two abilities, keyed by ability name
+    #[serde(rename = "ability_scores")]
+    AbilityScores { increases: HashMap<String, i64> },
+    /// A feat in place of the improvement, optionally raising one ability by 1
+    #[serde(rename = "feat")]
+    Feat {
+        name: String,
+        #[serde(default)]
+        description: String,
+        ability: Option<String>,
+    },
+}
+
+/// What a character gained from a level
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LevelUpResult {
+    pub character_id: String,
+    pub class: String,
+    /// Level in `class`, and the character's total level
+    pub class_level: i64,
+    pub level: i64,
+    /// The hit die rolled, when rolling rather than taking the average
+    pub hit_point_roll: Option<DiceRoll>,
+    pub hit_points_gained: i64,
+    pub proficiency_bonus: i64,
+    /// Names of the features gained and of those whose uses went up
+    pub features_gained: Vec<String>,
+    pub features_improved: Vec<String>,
+    pub spell_slots: Vec<SpellSlots>,
+    pub pact_slots: Option<SpellSlots>,
+}
+
+/// A character as stored, with the values worked out from their classes,
+/// stats and equipment next to it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CharacterSheet {
+    pub character: Character,
+    pub derived: DerivedStats,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DerivedStats {
+    pub level: i64,
+    pub proficiency_bonus: i64,
+    pub caster_level: i64,
+    pub armor_class: i64,
+    /// What makes up the armor class, e.g. the armor worn and Dexterity
+    pub armor_class_modifiers: Vec<DiceModifier>,
+    pub initiative_bonus: i64,
+    pub passive_perception: i64,
+    pub passive_investigation: i64,
+    pub passive_insight: i64,
+    /// Pounds of items carried, and the most the character can carry
+    pub carried_weight: f32,
+    pub carrying_capacity: f32,
+    pub encumbrance: Encumbrance,
+    /// Equipped armor gives disadvantage on Stealth checks
+    pub stealth_disadvantage: bool,
+    pub weapon_attacks: Vec<WeaponAttackBonus>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Encumbrance {
+    #[serde(rename = "unencumbered")]
+    Unencumbered,
+    /// Over 5 times Strength: speed drops by 10 feet
+    #[serde(rename = "encumbered")]
+    Encumbered,
+    /// Over 10 times Strength: speed drops by 20 feet, with disadvantage on
+    /// Strength, Dexterity and Constitution rolls and attacks
+    #[serde(rename = "heavily_encumbered")]
+    HeavilyEncumbered,
+    /// Over the carrying capacity of 15 times Strength
+    #[serde(rename = "over_capacity")]
+    OverCapacity,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WeaponAttackBonus {
+    pub weapon_id: String,
+    pub weapon_name: String,
+    pub is_equipped: bool,
+    pub ability: String,
+    pub attack_bonus: i64,
+    /// Damage dice with the ability modifier, e.g. `1d8 + 3`
+    pub damage: String,
+    pub damage_type: DamageType,
+}
+
 // =============================================================================
 // Map and Token Models
 // =============================================================================
@@ -538,10 +1057,25 @@ pub struct Token {
     pub image_url: Option<String>,
     pub position: Position,
     pub size: TokenSize,
-    pub conditions: Vec<String>,
     pub notes: String,
     pub is_hidden: bool,
     pub initiative: Option<i64>,
+    /// Bestiary entry an NPC token was spawned from
+    #[serde(default)]
+    pub stat_block_id: Option<String>,
+    /// This token's own hit points and conditions, for tokens spawned from a stat block
+    #[serde(default)]
+    pub instance: Option<TokenInstance>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenInstance {
+    pub hit_points: i64,
+    pub max_hit_points: i64,
+    #[serde(default)]
+    pub temporary_hit_points: i64,
+    #[serde(default)]
+    pub conditions: Vec<Condition>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -630,6 +1164,10 @@ pub struct CreateCharacterRequest {
     pub player_name: Option<String>,
     pub character_class: String,
     pub level: i64,
+    /// Class levels for a character starting out multiclassed; one entry
+    /// for `character_class` at `level` if not given
+    #[serde(default)]
+    pub classes: Option<Vec<ClassLevel>>,
     pub race: String,
     pub background: String,
     pub stats: CharacterStats,
@@ -639,7 +1177,11 @@ pub struct CreateCharacterRequest {
 #[derive(Debug, Deserialize)]
 pub struct UpdateCharacterRequest {
     pub name: Option<String>,
+    /// Only for characters with a single class; multiclassed characters
+    /// change `classes` instead
     pub level: Option<i64>,
+    #[serde(default)]
+    pub classes: Option<Vec<ClassLevel>>,
     pub stats: Option<CharacterStats>,
     pub combat_stats: Option<CombatStats>,
     pub equipment: Option<Equipment>,
@@ -673,6 +1215,64 @@ pub struct UpdateTokenPositionRequest {
     pub position: Position,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct CreateCombatRequest {
+    pub campaign_id: String,
+    pub map_id: Option<String>,
+    pub name: String,
+    #[serde(default)]
+    pub participants: Vec<AddCombatantRequest>,
+}
+
+/// A combatant to add; anything left out is taken from the character when
+/// there is one
+#[derive(Debug, Deserialize)]
+pub struct AddCombatantRequest {
+    pub character_id: Option<String>,
+    pub token_id: Option<String>,
+    pub name: Option<String>,
+    pub side: Option<CombatSide>,
+    pub group: Option<String>,
+    pub initiative: Option<i64>,
+    pub initiative_bonus: Option<i64>,
+    pub dexterity: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SpawnStatBlockRequest {
+    pub stat_block_id: String,
+    pub map_id: String,
+    #[serde(default = "default_spawn_count")]
+    pub count: i64,
+    pub origin: Option<Position>,
+    /// Roll each token's hit dice instead of giving them all the average
+    #[serde(default)]
+    pub roll_hit_points: bool,
+}
+
+fn default_spawn_count() -> i64 {
+    1
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateEncounterRequest {
+    pub campaign_id: String,
+    pub map_id: Option<String>,
+    pub name: String,
+    #[serde(default)]
+    pub creatures: Vec<EncounterCreature>,
+    #[serde(default)]
+    pub party_character_ids: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateEncounterRequest {
+    pub name: Option<String>,
+    pub map_id: Option<String>,
+    pub creatures: Option<Vec<EncounterCreature>>,
+    pub party_character_ids: Option<Vec<String>>,
+}
+
 // =============================================================================
 // Dice Rolling Models
 // =============================================================================
@@ -684,6 +1284,66 @@ pub struct DiceRoll {
     pub modifiers: Vec<DiceModifier>,
     pub total: i64,
     pub roll_type: RollType,
+    #[serde(default)]
+    pub terms: Vec<DiceTermResult>,
+    /// Seed the dice were rolled with, so the roll can be replayed exactly;
+    /// sent as a string, which unlike a JavaScript number holds any seed
+    #[serde(default, with = "seed_string")]
+    pub seed: Option<u64>,
+    /// Values of the `@variables` the roll used, needed to replay it
+    #[serde(default)]
+    pub variables: HashMap<String, i64>,
+    /// Face shown on the d20 that decided the roll, if there was one
+    #[serde(default)]
+    pub natural_roll: Option<i64>,
+    #[serde(default)]
+    pub is_critical: bool,
+    #[serde(default)]
+    pub is_fumble: bool,
+}
+
+// Rolls saved before seeds were strings have them as numbers
+mod seed_string {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Seed {
+        Number(u64),
+        Text(String),
+    }
+
+    pub fn serialize<S: Serializer>(seed: &Option<u64>, serializer: S) -> Result<S::Ok, S::Error> {
+        match seed {
+            Some(seed) => serializer.serialize_some(&seed.to_string()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+        match Option::<Seed>::deserialize(deserializer)? {
+            Some(Seed::Number(seed)) => Ok(Some(seed)),
+            Some(Seed::Text(seed)) => seed.parse().map(Some).map_err(serde::de::Error::custom),
+            None => Ok(None),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiceTermResult {
+    pub notation: String,
+    pub label: Option<String>,
+    pub dice: Vec<DieResult>,
+    pub subtotal: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DieResult {
+    pub sides: i64,
+    /// Every face rolled for this die; more than one when it exploded
+    pub rolls: Vec<i64>,
+    pub value: i64,
+    pub kept: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -693,7 +1353,7 @@ pub struct DiceModifier {
     pub source: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum RollType {
     #[serde(rename = "normal")]
     Normal,
@@ -705,6 +1365,36 @@ pub enum RollType {
     Critical,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WeaponAttackRoll {
+    pub weapon_id: String,
+    pub weapon_name: String,
+    pub attack: DiceRoll,
+    /// Not rolled when the attack is a natural 1
+    pub damage: Option<DiceRoll>,
+    pub damage_type: DamageType,
+}
+
+/// Exact odds for a dice expression, computed without rolling
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiceStatistics {
+    pub expression: String,
+    pub min: i64,
+    pub max: i64,
+    pub mean: f64,
+    pub std_dev: f64,
+    pub target: Option<i64>,
+    /// Chance of rolling `target` or more
+    pub chance_at_least: Option<f64>,
+    pub distribution: Vec<OutcomeProbability>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutcomeProbability {
+    pub total: i64,
+    pub probability: f64,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InitiativeRoll {
     pub character_id: String,
@@ -714,6 +1404,618 @@ pub struct InitiativeRoll {
     pub total: i64,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RollKind {
+    #[serde(rename = "dice")]
+    Dice,
+    #[serde(rename = "skill_check")]
+    SkillCheck,
+    #[serde(rename = "saving_throw")]
+    SavingThrow,
+    #[serde(rename = "attack")]
+    Attack,
+    #[serde(rename = "damage")]
+    Damage,
+    #[serde(rename = "initiative")]
+    Initiative,
+    #[serde(rename = "death_save")]
+    DeathSave,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RollRecord {
+    pub id: String,
+    pub campaign_id: String,
+    pub character_id: Option<String>,
+    pub kind: RollKind,
+    pub roll: DiceRoll,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RollHistoryQuery {
+    pub character_id: Option<String>,
+    pub kind: Option<RollKind>,
+    pub roll_type: Option<RollType>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RollHistoryPage {
+    pub rolls: Vec<RollRecord>,
+    pub total_count: i64,
+    pub limit: i64,
+    pub offset: i64,
+}
+
+// =============================================================================
+// Combat Tracker Models
+// =============================================================================
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Combat {
+    pub id: String,
+    pub campaign_id: String,
+    pub map_id: Option<String>,
+    pub name: String,
+    pub initiative_type: InitiativeType,
+    /// 0 until the combat starts
+    pub round: i64,
+    /// Index into `participants` of whoever's turn it is
+    pub turn_index: usize,
+    /// In turn order once initiative has been rolled
+    pub participants: Vec<CombatParticipant>,
+    pub is_active: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Combat {
+    pub fn current_participant(&self) -> Option<&CombatParticipant> {
+        if self.round == 0 {
+            return None;
+        }
+        self.participants.get(self.turn_index)
+    }
+
+    pub fn participant_index(&self, participant_id: &str) -> Option<usize> {
+        self.participants.iter().position(|p| p.id == participant_id)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CombatParticipant {
+    pub id: String,
+    pub character_id: Option<String>,
+    pub token_id: Option<String>,
+    pub name: String,
+    pub side: CombatSide,
+    /// Participants sharing a group roll initiative together in group mode
+    pub group: Option<String>,
+    pub initiative: Option<i64>,
+    pub initiative_bonus: i64,
+    /// Dexterity score, used to break initiative ties
+    pub dexterity: i64,
+    pub status: TurnStatus,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CombatSide {
+    #[serde(rename = "party")]
+    Party,
+    #[serde(rename = "enemies")]
+    Enemies,
+    #[serde(rename = "neutral")]
+    Neutral,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TurnStatus {
+    #[serde(rename = "ready")]
+    Ready,
+    /// Skipped in turn order until the participant chooses to act
+    #[serde(rename = "delayed")]
+    Delayed,
+    /// Holding an action until the trigger happens or their next turn starts
+    #[serde(rename = "readied")]
+    Readied { trigger: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RestType {
+    #[serde(rename = "short")]
+    Short,
+    #[serde(rename = "long")]
+    Long,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ShortRestRequest {
+    pub character_ids: Vec<String>,
+    /// Hit dice each character spends, by character id; anyone left out
+    /// spends none
+    #[serde(default)]
+    pub hit_dice: HashMap<String, Vec<HitDiceSpend>>,
+}
+
+/// Hit dice of one class to spend on a short rest
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HitDiceSpend {
+    pub class: String,
+    pub count: i64,
+}
+
+/// What one character got back from a rest
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RestRecord {
+    pub id: String,
+    pub campaign_id: String,
+    pub character_id: String,
+    pub character_name: String,
+    pub rest_type: RestType,
+    pub hit_dice_spent: i64,
+    /// The hit dice rolled on a short rest, with the Constitution bonus
+    pub hit_dice_roll: Option<DiceRoll>,
+    pub hit_points_restored: i64,
+    pub hit_dice_restored: i64,
+    /// Names of the features whose uses came back
+    pub features_recharged: Vec<String>,
+    #[serde(default)]
+    pub spell_slots_restored: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+/// What damage or healing did to a character
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HitPointChange {
+    pub character_id: String,
+    pub character_name: String,
+    /// Damage or healing as dealt, before resistances
+    pub amount: i64,
+    pub damage_type: Option<DamageType>,
+    /// Damage after immunity, resistance and vulnerability; healing as applied
+    pub applied: i64,
+    pub absorbed_by_temporary: i64,
+    pub hit_points: i64,
+    pub temporary_hit_points: i64,
+    pub is_unconscious: bool,
+    pub is_dead: bool,
+    /// Killed outright by damage of at least their hit point maximum
+    pub instant_death: bool,
+    /// The save a concentrating character made because of this damage
+    pub concentration_check: Option<ConcentrationCheck>,
+}
+
+/// A concentrating character's Constitution save after taking damage
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConcentrationCheck {
+    pub character_id: String,
+    pub spell_name: String,
+    pub dc: i64,
+    /// No roll when dropping to 0 hit points ends concentration outright
+    pub roll: Option<DiceRoll>,
+    pub maintained: bool,
+}
+
+/// Payload of the `concentration-ended` event
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConcentrationEnded {
+    pub character_id: String,
+    pub character_name: String,
+    pub spell_name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeathSaveResult {
+    pub character_id: String,
+    pub character_name: String,
+    pub roll: DiceRoll,
+    pub successes: i64,
+    pub failures: i64,
+    pub is_stable: bool,
+    pub is_dead: bool,
+    /// A natural 20 brings the character back with 1 hit point
+    pub regained_consciousness: bool,
+}
+
+/// Payload of the `condition-expired` event
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExpiredCondition {
+    /// The character it ran out on, unless it was a token's own
+    pub character_id: Option<String>,
+    /// The token spawned from a stat block it ran out on
+    #[serde(default)]
+    pub token_id: Option<String>,
+    /// Name of the character or token
+    pub character_name: String,
+    pub condition: Condition,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Encounter {
+    pub id: String,
+    pub campaign_id: String,
+    pub map_id: Option<String>,
+    pub name: String,
+    pub creatures: Vec<EncounterCreature>,
+    /// Characters the encounter is rated against; empty means every player character in the campaign
+    pub party_character_ids: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Creatures in an encounter come from either an NPC character or a stat block
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncounterCreature {
+    #[serde(default)]
+    pub character_id: Option<String>,
+    #[serde(default)]
+    pub stat_block_id: Option<String>,
+    pub count: i64,
+    /// XP for one of these creatures
+    pub xp: i64,
+}
+
+impl EncounterCreature {
+    /// The id of whichever character or stat block this creature is
+    pub fn source_id(&self) -> &str {
+        self.character_id.as_deref().or(self.stat_block_id.as_deref()).unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncounterRating {
+    pub party_size: i64,
+    pub thresholds: XpThresholds,
+    pub monster_count: i64,
+    pub total_xp: i64,
+    pub multiplier: f64,
+    /// Total XP times the multiplier, compared against the thresholds
+    pub adjusted_xp: i64,
+    pub difficulty: EncounterDifficulty,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct XpThresholds {
+    pub easy: i64,
+    pub medium: i64,
+    pub hard: i64,
+    pub deadly: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EncounterDifficulty {
+    #[serde(rename = "trivial")]
+    Trivial,
+    #[serde(rename = "easy")]
+    Easy,
+    #[serde(rename = "medium")]
+    Medium,
+    #[serde(rename = "hard")]
+    Hard,
+    #[serde(rename = "deadly")]
+    Deadly,
+}
+
+// =============================================================================
+// Bestiary Models
+// =============================================================================
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StatBlock {
+    pub id: String,
+    /// `None` for the global bestiary shared by every campaign
+    pub campaign_id: Option<String>,
+    #[serde(flatten)]
+    pub data: StatBlockData,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StatBlockData {
+    pub name: String,
+    pub size: TokenSize,
+    /// e.g. `humanoid (goblinoid)`
+    pub creature_type: String,
+    #[serde(default)]
+    pub alignment: String,
+    pub armor_class: i64,
+    #[serde(default)]
+    pub armor_description: Option<String>,
+    /// Average hit points
+    pub hit_points: i64,
+    /// e.g. `2d6`; spawned tokens can roll this instead of taking the average
+    #[serde(default)]
+    pub hit_dice: Option<String>,
+    pub speed: Speed,
+    pub stats: CharacterStats,
+    /// Skill bonuses as printed, e.g. `stealth: 6`
+    #[serde(default)]
+    pub skills: HashMap<String, i64>,
+    #[serde(default)]
+    pub damage_resistances: Vec<DamageType>,
+    #[serde(default)]
+    pub damage_immunities: Vec<DamageType>,
+    #[serde(default)]
+    pub damage_vulnerabilities: Vec<DamageType>,
+    #[serde(default)]
+    pub condition_immunities: Vec<String>,
+    #[serde(default)]
+    pub senses: Vec<Sense>,
+    pub passive_perception: i64,
+    #[serde(default)]
+    pub languages: Vec<String>,
+    /// Fractional below 1, e.g. `0.25` for CR 1/4
+    pub challenge_rating: f64,
+    pub xp: i64,
+    #[serde(default)]
+    pub traits: Vec<StatBlockAction>,
+    /// How the creature splits its action between attacks, e.g. "The goblin boss makes two scimitar attacks."
+    #[serde(default)]
+    pub multiattack: Option<String>,
+    #[serde(default)]
+    pub actions: Vec<StatBlockAction>,
+    #[serde(default)]
+    pub bonus_actions: Vec<StatBlockAction>,
+    #[serde(default)]
+    pub reactions: Vec<StatBlockAction>,
+    #[serde(default)]
+    pub legendary_actions: Option<LegendaryActions>,
+    /// Taken on initiative count 20
+    #[serde(default)]
+    pub lair_actions: Vec<StatBlockAction>,
+    #[serde(default)]
+    pub description: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Speed {
+    pub walk: i64,
+    #[serde(default)]
+    pub fly: Option<i64>,
+    #[serde(default)]
+    pub swim: Option<i64>,
+    #[serde(default)]
+    pub climb: Option<i64>,
+    #[serde(default)]
+    pub burrow: Option<i64>,
+    #[serde(default)]
+    pub hover: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sense {
+    /// e.g. `darkvision`, `blindsight`
+    pub name: String,
+    /// Range in feet
+    pub range: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StatBlockAction {
+    pub name: String,
+    pub description: String,
+    #[serde(default)]
+    pub attack_bonus: Option<i64>,
+    /// Damage dice with the modifier, e.g. `1d6 + 2`
+    #[serde(default)]
+    pub damage: Option<String>,
+    #[serde(default)]
+    pub damage_type: Option<DamageType>,
+    /// e.g. `Recharge 5-6` or `3/Day`
+    #[serde(default)]
+    pub usage: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LegendaryActions {
+    pub per_round: i64,
+    #[serde(default)]
+    pub description: String,
+    pub actions: Vec<LegendaryAction>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LegendaryAction {
+    pub name: String,
+    pub description: String,
+    /// Legendary actions this one uses up
+    #[serde(default = "default_action_cost")]
+    pub cost: i64,
+}
+
+fn default_action_cost() -> i64 {
+    1
+}
+
+// =============================================================================
+// Compendium Models
+// =============================================================================
+
+/// A spell, weapon, armor or item from an imported compendium, which
+/// characters copy onto their sheets
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompendiumEntry {
+    /// Made from the source and name, so re-importing a compendium updates its entries
+    pub id: String,
+    pub source: String,
+    pub name: String,
+    #[serde(flatten)]
+    pub content: CompendiumContent,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "data")]
+pub enum CompendiumContent {
+    #[serde(rename = "spell")]
+    Spell(Spell),
+    #[serde(rename = "weapon")]
+    Weapon(Weapon),
+    #[serde(rename = "armor")]
+    Armor(Armor),
+    #[serde(rename = "item")]
+    Item(Item),
+}
+
+impl CompendiumContent {
+    pub fn kind(&self) -> CompendiumKind {
+        match self {
+            CompendiumContent::Spell(_) => CompendiumKind::Spell,
+            CompendiumContent::Weapon(_) => CompendiumKind::Weapon,
+            CompendiumContent::Armor(_) => CompendiumKind::Armor,
+            CompendiumContent::Item(_) => CompendiumKind::Item,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CompendiumKind {
+    #[serde(rename = "spell")]
+    Spell,
+    #[serde(rename = "weapon")]
+    Weapon,
+    #[serde(rename = "armor")]
+    Armor,
+    #[serde(rename = "item")]
+    Item,
+}
+
+/// What one compendium file added or updated
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompendiumImport {
+    pub file: String,
+    pub source: String,
+    pub spells: i64,
+    pub weapons: i64,
+    pub armor: i64,
+    pub items: i64,
+    pub monsters: i64,
+}
+
+// =============================================================================
+// Inventory Models
+// =============================================================================
+
+/// One piece of equipment, whichever list it lives in
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "data")]
+pub enum InventoryEntry {
+    #[serde(rename = "item")]
+    Item(Item),
+    #[serde(rename = "weapon")]
+    Weapon(Weapon),
+    #[serde(rename = "armor")]
+    Armor(Armor),
+}
+
+impl InventoryEntry {
+    pub fn id(&self) -> &str {
+        match self {
+            InventoryEntry::Item(item) => &item.id,
+            InventoryEntry::Weapon(weapon) => &weapon.id,
+            InventoryEntry::Armor(armor) => &armor.id,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            InventoryEntry::Item(item) => &item.name,
+            InventoryEntry::Weapon(weapon) => &weapon.name,
+            InventoryEntry::Armor(armor) => &armor.name,
+        }
+    }
+}
+
+/// A character's equipment, loaded to be changed inside a transaction
+#[derive(Debug, Clone)]
+pub struct CharacterEquipment {
+    pub character_id: String,
+    pub campaign_id: String,
+    pub name: String,
+    pub equipment: Equipment,
+}
+
+/// Coins and items the party has found but not yet shared out
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TreasurePool {
+    pub campaign_id: String,
+    pub currency: Currency,
+    pub items: Vec<Item>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Coins shared out of a treasure pool
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TreasureDistribution {
+    pub campaign_id: String,
+    pub shares: Vec<TreasureShare>,
+    /// Copper that wouldn't split evenly, left in the pool
+    pub remainder: Currency,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TreasureShare {
+    pub character_id: String,
+    pub character_name: String,
+    pub currency: Currency,
+}
+
+// =============================================================================
+// Search Models
+// =============================================================================
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SearchKind {
+    #[serde(rename = "campaign")]
+    Campaign,
+    #[serde(rename = "character")]
+    Character,
+    #[serde(rename = "spell")]
+    Spell,
+    #[serde(rename = "feature")]
+    Feature,
+    #[serde(rename = "item")]
+    Item,
+    #[serde(rename = "map")]
+    Map,
+    #[serde(rename = "asset")]
+    Asset,
+}
+
+impl SearchKind {
+    /// How the kind is stored in the search index
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SearchKind::Campaign => "campaign",
+            SearchKind::Character => "character",
+            SearchKind::Spell => "spell",
+            SearchKind::Feature => "feature",
+            SearchKind::Item => "item",
+            SearchKind::Map => "map",
+            SearchKind::Asset => "asset",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchHit {
+    pub kind: SearchKind,
+    pub id: String,
+    /// The character a spell, feature or item belongs to
+    pub character_id: Option<String>,
+    pub title: String,
+    /// The best matching part of the text, with matches wrapped in `<mark>` tags
+    pub snippet: String,
+    /// Lower is a better match
+    pub rank: f64,
+}
+
 // =============================================================================
 // Network Models
 // =============================================================================
@@ -723,12 +2025,63 @@ pub struct NetworkMessage {
     pub id: String,
     pub sender_id: String,
     pub sender_name: String,
-    pub message_type: MessageType,
-    pub content: serde_json::Value,
+    /// Sent as `message_type` and `content` fields
+    #[serde(flatten)]
+    pub payload: Payload,
     pub timestamp: DateTime<Utc>,
 }
 
+impl NetworkMessage {
+    pub fn message_type(&self) -> MessageType {
+        self.payload.message_type()
+    }
+}
+
+/// What a message carries, one kind for each `MessageType`
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "message_type", content = "content")]
+pub enum Payload {
+    #[serde(rename = "chat")]
+    Chat(ChatMessage),
+    #[serde(rename = "dice_roll")]
+    DiceRoll(DiceRoll),
+    #[serde(rename = "token_update")]
+    TokenUpdate(TokenUpdate),
+    #[serde(rename = "map_change")]
+    MapChange(MapChange),
+    #[serde(rename = "initiative")]
+    Initiative(InitiativeUpdate),
+    #[serde(rename = "cursor")]
+    Cursor(CursorPing),
+    #[serde(rename = "system")]
+    System(SystemMessage),
+}
+
+impl Payload {
+    pub fn message_type(&self) -> MessageType {
+        match self {
+            Payload::Chat(_) => MessageType::Chat,
+            Payload::DiceRoll(_) => MessageType::DiceRoll,
+            Payload::TokenUpdate(_) => MessageType::TokenUpdate,
+            Payload::MapChange(_) => MessageType::MapChange,
+            Payload::Initiative(_) => MessageType::Initiative,
+            Payload::Cursor(_) => MessageType::Cursor,
+            Payload::System(_) => MessageType::System,
+        }
+    }
+
+    /// Whether the message is soon superseded by the next one like it, so
+    /// losing it or getting it late doesn't matter
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Payload::Cursor(cursor) => !cursor.ping,
+            Payload::TokenUpdate(TokenUpdate::Moved { .. }) => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum MessageType {
     #[serde(rename = "chat")]
     Chat,
@@ -740,6 +2093,8 @@ pub enum MessageType {
     MapChange,
     #[serde(rename = "initiative")]
     Initiative,
+    #[serde(rename = "cursor")]
+    Cursor,
     #[serde(rename = "system")]
     System,
 }
@@ -758,10 +2113,14 @@ pub struct PeerInfo {
     pub name: String,
     pub role: PlayerRole,
     pub is_connected: bool,
+    /// Joined with the key the DM issued for their name, so the tokens of
+    /// characters with that player name are theirs to move
+    #[serde(default)]
+    pub is_verified: bool,
     pub last_seen: DateTime<Utc>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum PlayerRole {
     #[serde(rename = "dm")]
     DungeonMaster,
@@ -771,6 +2130,197 @@ pub enum PlayerRole {
     Observer,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "action")]
+pub enum TokenUpdate {
+    /// Sent many times a second while a token is dragged, so it has a
+    /// compact binary encoding as well as JSON and may be dropped; where the
+    /// token comes to rest follows as `Changed`
+    #[serde(rename = "moved")]
+    Moved { map_id: String, token_id: String, position: Position },
+    #[serde(rename = "placed")]
+    Placed { map_id: String, token: Token },
+    #[serde(rename = "changed")]
+    Changed { map_id: String, token: Token },
+    #[serde(rename = "removed")]
+    Removed { map_id: String, token_id: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "action")]
+pub enum MapChange {
+    /// The DM moved everyone to a different map
+    #[serde(rename = "opened")]
+    Opened { map_id: String },
+    #[serde(rename = "updated")]
+    Updated { map: Map },
+    #[serde(rename = "fog_changed")]
+    FogChanged { map_id: String, fog_of_war: Option<FogOfWar> },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "action")]
+pub enum InitiativeUpdate {
+    #[serde(rename = "rolled")]
+    Rolled { combat_id: String, rolls: Vec<InitiativeRoll> },
+    #[serde(rename = "turn_changed")]
+    TurnChanged { combat_id: String, round: i64, participant_id: String },
+    #[serde(rename = "updated")]
+    Updated { combat: Combat },
+    #[serde(rename = "ended")]
+    Ended { combat_id: String },
+}
+
+/// Where someone is pointing on a map, sent as their cursor moves
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CursorPing {
+    pub map_id: String,
+    pub position: Position,
+    /// Whether they clicked to draw everyone's attention there
+    #[serde(default)]
+    pub ping: bool,
+}
+
+/// Session bookkeeping the host and players exchange as `System` messages
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum SystemMessage {
+    /// The protocol versions a player's app speaks, sent before anything else;
+    /// the host answers with the version it picked
+    #[serde(rename = "hello")]
+    Hello { min_version: u16, max_version: u16 },
+    #[serde(rename = "version")]
+    Version { version: u16 },
+    /// First message a player sends after connecting
+    #[serde(rename = "join")]
+    Join {
+        name: String,
+        role: PlayerRole,
+        #[serde(default)]
+        password: Option<String>,
+        #[serde(default)]
+        player_key: Option<String>,
+    },
+    /// The host's answer to a join, with everyone already in the session
+    #[serde(rename = "welcome")]
+    Welcome { peer_id: String, peers: Vec<PeerInfo> },
+    #[serde(rename = "rejected")]
+    Rejected { reason: String },
+    #[serde(rename = "peer_joined")]
+    PeerJoined { peer: PeerInfo },
+    #[serde(rename = "peer_left")]
+    PeerLeft { peer_id: String },
+    /// The host refused a message the player sent; it went no further
+    #[serde(rename = "denied")]
+    Denied(MessageDenied),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageDenied {
+    pub message_id: String,
+    pub error: PermissionError,
+}
+
+/// What two peers swap, through a go-between, to open a WebRTC connection
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Signal {
+    #[serde(rename = "offer")]
+    Offer { sdp: String },
+    #[serde(rename = "answer")]
+    Answer { sdp: String },
+    /// An address the sender can be reached at, sent as it's found
+    #[serde(rename = "candidate")]
+    Candidate {
+        candidate: String,
+        #[serde(default)]
+        sdp_mid: Option<String>,
+        #[serde(default)]
+        sdp_mline_index: Option<u16>,
+    },
+}
+
+/// Options for the DM hosting a session
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HostSessionRequest {
+    pub name: String,
+    /// Campaign being played; players can only move tokens on its maps
+    pub campaign_id: String,
+    /// UDP port to listen on; the default port when not given
+    #[serde(default)]
+    pub port: Option<u16>,
+    /// Players must give this to join, when set
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Signaling server to list the session on, so players can join with an
+    /// invite code instead of the host's address
+    #[serde(default)]
+    pub signaling_url: Option<String>,
+    /// Run a signaling server in this app and list the session on it, for
+    /// when there's no server to use; `signaling_url` is then ignored
+    #[serde(default)]
+    pub serve_signaling: bool,
+    /// STUN or TURN servers for players joining by invite from outside the
+    /// local network
+    #[serde(default)]
+    pub ice_servers: Vec<String>,
+}
+
+/// Where a player finds the host, and the certificate fingerprint the host
+/// shared with them to make sure it's really the DM's app answering
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JoinSessionRequest {
+    /// `host:port` or `ip:port`
+    pub address: String,
+    pub fingerprint: String,
+    pub name: String,
+    pub role: PlayerRole,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// The key the DM issued for `name`, needed to move that player's tokens
+    #[serde(default)]
+    pub player_key: Option<String>,
+}
+
+/// Joining a session by the invite code the DM shared
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JoinInviteRequest {
+    /// The signaling server the session is listed on, like
+    /// `ws://tavern.example.com:7351`
+    pub signaling_url: String,
+    pub invite_code: String,
+    pub name: String,
+    pub role: PlayerRole,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// The key the DM issued for `name`, needed to move that player's tokens
+    #[serde(default)]
+    pub player_key: Option<String>,
+    #[serde(default)]
+    pub ice_servers: Vec<String>,
+}
+
+/// The session this app is in
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub is_host: bool,
+    pub peer_id: String,
+    /// Address players connect to: the host's LAN address where it could be
+    /// found, or the one it's listening on
+    pub address: String,
+    /// SHA-256 fingerprint of the host's certificate, for players to pin;
+    /// empty when joined by invite code
+    pub fingerprint: String,
+    /// What players can join with instead of the address and fingerprint,
+    /// when the session is listed on a signaling server
+    pub invite_code: Option<String>,
+    /// The signaling server the session is listed on
+    pub signaling_url: Option<String>,
+    /// Protocol version agreed with the host
+    pub protocol_version: u16,
+    pub peers: Vec<PeerInfo>,
+}
+
 // =============================================================================
 // Asset Models
 // =============================================================================
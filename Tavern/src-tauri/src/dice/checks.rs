@@ -0,0 +1,261 @@
+use rand::Rng;
+
+use crate::database::models::{
+    AdvantageMode, Character, CharacterStats, DiceModifier, DiceRoll, DiceSettings, RollType, Skills,
+    Weapon, WeaponAttackRoll, WeaponProperty,
+};
+use crate::dice::parser::{self, DiceExpr, DiceTerm};
+use crate::dice::roller::DiceRoller;
+use crate::errors::{AppError, AppResult};
+
+/// What a d20 roll is for, which decides the conditions that affect it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RollPurpose {
+    AbilityCheck,
+    SavingThrow(&'static str),
+    Attack,
+}
+
+/// Roll an ability check with a skill, e.g. `stealth` or `sleight of hand`
+pub fn roll_skill_check<R: Rng>(
+    roller: &mut DiceRoller<R>,
+    character: &Character,
+    skill: &str,
+    requested: RollType,
+    settings: &DiceSettings,
+) -> AppResult<DiceRoll> {
+    let (proficiency, ability) = match (character.skills.get(skill), Skills::ability_for(skill)) {
+        (Some(proficiency), Some(ability)) => (proficiency, ability),
+        _ => return Err(AppError::InvalidInput(format!("Unknown skill: {}", skill))),
+    };
+
+    let mut modifiers = vec![ability_modifier(&character.stats, ability)];
+    let bonus = proficiency.bonus(character.stats.proficiency_bonus);
+    if bonus != 0 {
+        let name = if bonus == character.stats.proficiency_bonus { "Proficiency" } else { "Expertise" };
+        modifiers.push(DiceModifier {
+            name: name.to_string(),
+            value: bonus,
+            source: "skill".to_string(),
+        });
+    }
+
+    let (advantage, mut disadvantage) = condition_effects(character, RollPurpose::AbilityCheck, settings);
+    // Armor that hampers stealth always does, whatever the campaign's advantage mode
+    if Skills::normalize(skill) == "stealth" {
+        disadvantage |= character
+            .equipment
+            .armor
+            .iter()
+            .any(|armor| armor.is_equipped && armor.stealth_disadvantage);
+    }
+
+    let roll_type = resolve_roll_type(requested, advantage, disadvantage);
+    roll_d20(roller, roll_type, settings, modifiers)
+}
+
+/// Roll a saving throw for an ability, adding proficiency when the character has it
+pub fn roll_saving_throw<R: Rng>(
+    roller: &mut DiceRoller<R>,
+    character: &Character,
+    ability: &str,
+    requested: RollType,
+    settings: &DiceSettings,
+) -> AppResult<DiceRoll> {
+    let ability = CharacterStats::ability_name(ability)
+        .ok_or_else(|| AppError::InvalidInput(format!("Unknown ability: {}", ability)))?;
+
+    let mut modifiers = vec![ability_modifier(&character.stats, ability)];
+    if character.stats.is_proficient_in_save(ability) {
+        modifiers.push(DiceModifier {
+            name: "Proficiency".to_string(),
+            value: character.stats.proficiency_bonus,
+            source: "saving_throw".to_string(),
+        });
+    }
+
+    let (advantage, disadvantage) = condition_effects(character, RollPurpose::SavingThrow(ability), settings);
+    let roll_type = resolve_roll_type(requested, advantage, disadvantage);
+    roll_d20(roller, roll_type, settings, modifiers)
+}
+
+/// Roll an attack with one of the character's equipped weapons, plus its damage
+///
+/// Damage is rolled as a critical when the attack is, and not at all on a natural 1.
+pub fn roll_weapon_attack<R: Rng>(
+    roller: &mut DiceRoller<R>,
+    character: &Character,
+    weapon_id: &str,
+    requested: RollType,
+    settings: &DiceSettings,
+) -> AppResult<WeaponAttackRoll> {
+    let weapon = character
+        .equipment
+        .weapons
+        .iter()
+        .find(|weapon| weapon.id == weapon_id)
+        .ok_or_else(|| AppError::NotFound(format!("Weapon {}", weapon_id)))?;
+    if !weapon.is_equipped {
+        return Err(AppError::InvalidInput(format!("{} is not equipped", weapon.name)));
+    }
+
+    let ability = weapon_ability(&character.stats, weapon);
+    let mut ability_bonus = ability_modifier(&character.stats, ability);
+    if weapon.has_property(WeaponProperty::Finesse) {
+        ability_bonus.source = "finesse".to_string();
+    }
+
+    let mut modifiers = vec![ability_bonus.clone()];
+    if weapon.is_proficient {
+        modifiers.push(DiceModifier {
+            name: "Proficiency".to_string(),
+            value: character.stats.proficiency_bonus,
+            source: "weapon".to_string(),
+        });
+    }
+
+    let (advantage, disadvantage) = condition_effects(character, RollPurpose::Attack, settings);
+    let roll_type = resolve_roll_type(requested, advantage, disadvantage);
+    let attack = roll_d20(roller, roll_type, settings, modifiers)?;
+
+    let damage = if attack.natural_roll == Some(1) {
+        None
+    } else {
+        let expr = parser::parse(&weapon.damage_dice)?;
+        let damage_roll_type = if attack.is_critical { RollType::Critical } else { RollType::Normal };
+        let mut damage = roller.roll_with_settings(&expr, damage_roll_type, settings)?;
+        apply_modifiers(&mut damage, vec![ability_bonus]);
+        Some(damage)
+    };
+
+    Ok(WeaponAttackRoll {
+        weapon_id: weapon.id.clone(),
+        weapon_name: weapon.name.clone(),
+        attack,
+        damage,
+        damage_type: weapon.damage_type,
+    })
+}
+
+/// Roll initiative: a d20 plus the initiative bonus
+pub fn roll_initiative<R: Rng>(
+    roller: &mut DiceRoller<R>,
+    initiative_bonus: i64,
+    requested: RollType,
+    settings: &DiceSettings,
+) -> AppResult<DiceRoll> {
+    let modifiers = vec![DiceModifier {
+        name: "Initiative".to_string(),
+        value: initiative_bonus,
+        source: "initiative".to_string(),
+    }];
+    let roll_type = resolve_roll_type(requested, false, false);
+    roll_d20(roller, roll_type, settings, modifiers)
+}
+
+/// Roll a Constitution save to keep concentrating on a spell
+pub fn roll_concentration_save<R: Rng>(
+    roller: &mut DiceRoller<R>,
+    character: &Character,
+    settings: &DiceSettings,
+) -> AppResult<DiceRoll> {
+    let stats = &character.stats;
+    let modifiers = vec![DiceModifier {
+        name: "Constitution save".to_string(),
+        value: stats.get_saving_throw("constitution", stats.is_proficient_in_save("constitution")),
+        source: "concentration".to_string(),
+    }];
+    let (advantage, disadvantage) = condition_effects(character, RollPurpose::SavingThrow("constitution"), settings);
+    let roll_type = resolve_roll_type(RollType::Normal, advantage, disadvantage);
+    roll_d20(roller, roll_type, settings, modifiers)
+}
+
+/// Roll a death save: a plain d20, though the campaign's roll settings still apply
+pub fn roll_death_save<R: Rng>(roller: &mut DiceRoller<R>, settings: &DiceSettings) -> AppResult<DiceRoll> {
+    roll_d20(roller, RollType::Normal, settings, Vec::new())
+}
+
+/// The ability a weapon attacks with: the better of STR and DEX for finesse
+/// weapons, DEX for ranged weapons and STR otherwise
+pub fn weapon_ability(stats: &CharacterStats, weapon: &Weapon) -> &'static str {
+    if weapon.has_property(WeaponProperty::Finesse) {
+        if stats.get_modifier("dexterity") > stats.get_modifier("strength") {
+            "dexterity"
+        } else {
+            "strength"
+        }
+    } else if weapon.is_ranged() {
+        "dexterity"
+    } else {
+        "strength"
+    }
+}
+
+fn ability_modifier(stats: &CharacterStats, ability: &str) -> DiceModifier {
+    let mut name = ability.to_string();
+    if let Some(first) = name.get_mut(0..1) {
+        first.make_ascii_uppercase();
+    }
+    DiceModifier {
+        name,
+        value: stats.get_modifier(ability),
+        source: "ability".to_string(),
+    }
+}
+
+/// Roll a d20 under the campaign's settings and add itemized modifiers to it
+fn roll_d20<R: Rng>(
+    roller: &mut DiceRoller<R>,
+    roll_type: RollType,
+    settings: &DiceSettings,
+    modifiers: Vec<DiceModifier>,
+) -> AppResult<DiceRoll> {
+    let d20 = DiceExpr::Dice(DiceTerm::new(1, 20));
+    let mut roll = roller.roll_with_settings(&d20, roll_type, settings)?;
+    apply_modifiers(&mut roll, modifiers);
+    Ok(roll)
+}
+
+fn apply_modifiers(roll: &mut DiceRoll, modifiers: Vec<DiceModifier>) {
+    for modifier in modifiers {
+        let sign = if modifier.value < 0 { '-' } else { '+' };
+        roll.dice_notation.push_str(&format!(" {} {}", sign, modifier.value.abs()));
+        roll.total += modifier.value;
+        roll.modifiers.push(modifier);
+    }
+}
+
+/// Advantage and disadvantage from the character's conditions
+///
+/// Only campaigns that resolve advantage automatically apply these; the
+/// others leave it to whoever asks for the roll.
+fn condition_effects(character: &Character, purpose: RollPurpose, settings: &DiceSettings) -> (bool, bool) {
+    if !matches!(settings.advantage_mode, AdvantageMode::Automatic) {
+        return (false, false);
+    }
+
+    let mut advantage = false;
+    let mut disadvantage = false;
+    for condition in &character.combat_stats.conditions {
+        match (condition.name.to_lowercase().as_str(), purpose) {
+            ("poisoned" | "frightened", RollPurpose::AbilityCheck | RollPurpose::Attack) => disadvantage = true,
+            ("blinded" | "prone" | "restrained", RollPurpose::Attack) => disadvantage = true,
+            ("restrained", RollPurpose::SavingThrow("dexterity")) => disadvantage = true,
+            ("invisible", RollPurpose::Attack) => advantage = true,
+            _ => {}
+        }
+    }
+    (advantage, disadvantage)
+}
+
+/// Combine a requested roll type with advantage and disadvantage from the
+/// rules; having both cancels out to a normal roll
+fn resolve_roll_type(requested: RollType, advantage: bool, disadvantage: bool) -> RollType {
+    let advantage = advantage || requested == RollType::Advantage;
+    let disadvantage = disadvantage || requested == RollType::Disadvantage;
+    match (advantage, disadvantage) {
+        (true, false) => RollType::Advantage,
+        (false, true) => RollType::Disadvantage,
+        _ => RollType::Normal,
+    }
+}
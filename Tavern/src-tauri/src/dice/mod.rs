@@ -0,0 +1,8 @@
+pub mod checks;
+pub mod parser;
+pub mod roller;
+pub mod stats;
+
+pub use parser::{parse, BinaryOp, DiceExpr, DiceTerm, KeepRule};
+pub use roller::DiceRoller;
+pub use stats::Distribution;
@@ -0,0 +1,633 @@
+use std::fmt;
+
+use crate::errors::{AppError, AppResult};
+
+/// Largest number of dice a single term may roll (e.g. `1000d6`)
+pub const MAX_DICE_COUNT: u32 = 1000;
+/// Largest die size a single term may use (e.g. `1d1000`)
+pub const MAX_DICE_SIDES: u32 = 1000;
+/// How deeply parentheses and unary operators may nest
+const MAX_NESTING: usize = 64;
+/// Most numbers, dice, variables and labels one expression may have; each
+/// one deepens the tree the roller and statistics walk
+pub const MAX_TERMS: usize = 100;
+/// Most dice one expression may roll across all its terms
+pub const MAX_TOTAL_DICE: u32 = 1000;
+
+// =============================================================================
+// Dice AST
+// =============================================================================
+
+/// A parsed dice expression such as `4d6kh3 + 2d8 + @str + 1d4[bless]`
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiceExpr {
+    Number(i64),
+    Dice(DiceTerm),
+    /// A named value resolved at roll time, written `@str`
+    Variable(String),
+    Negate(Box<DiceExpr>),
+    Binary {
+        op: BinaryOp,
+        lhs: Box<DiceExpr>,
+        rhs: Box<DiceExpr>,
+    },
+    /// A parenthesized sub-expression
+    Group(Box<DiceExpr>),
+    /// A sub-expression tagged with a label, written `1d4[bless]`
+    Labeled {
+        label: String,
+        expr: Box<DiceExpr>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiceTerm {
+    pub count: u32,
+    pub sides: u32,
+    pub keep: Option<KeepRule>,
+    pub explode: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeepRule {
+    KeepHighest(u32),
+    KeepLowest(u32),
+    DropHighest(u32),
+    DropLowest(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+impl DiceExpr {
+    /// Whether any part of the expression rolls dice
+    pub fn has_dice(&self) -> bool {
+        match self {
+            DiceExpr::Number(_) | DiceExpr::Variable(_) => false,
+            DiceExpr::Dice(_) => true,
+            DiceExpr::Negate(expr) | DiceExpr::Group(expr) => expr.has_dice(),
+            DiceExpr::Labeled { expr, .. } => expr.has_dice(),
+            DiceExpr::Binary { lhs, rhs, .. } => lhs.has_dice() || rhs.has_dice(),
+        }
+    }
+
+    /// Rebuild the expression with every dice term replaced by `f(term)`
+    pub fn map_dice<F: FnMut(DiceTerm) -> DiceExpr>(self, f: &mut F) -> DiceExpr {
+        match self {
+            DiceExpr::Dice(term) => f(term),
+            DiceExpr::Negate(expr) => DiceExpr::Negate(Box::new(expr.map_dice(f))),
+            DiceExpr::Group(expr) => DiceExpr::Group(Box::new(expr.map_dice(f))),
+            DiceExpr::Labeled { label, expr } => DiceExpr::Labeled { label, expr: Box::new(expr.map_dice(f)) },
+            DiceExpr::Binary { op, lhs, rhs } => DiceExpr::Binary {
+                op,
+                lhs: Box::new(lhs.map_dice(f)),
+                rhs: Box::new(rhs.map_dice(f)),
+            },
+            other => other,
+        }
+    }
+}
+
+impl DiceTerm {
+    pub fn new(count: u32, sides: u32) -> Self {
+        Self { count, sides, keep: None, explode: false }
+    }
+
+    /// Number of dice that count towards the total once keep/drop is applied
+    pub fn kept_count(&self) -> u32 {
+        match self.keep {
+            None => self.count,
+            Some(KeepRule::KeepHighest(n)) | Some(KeepRule::KeepLowest(n)) => n.min(self.count),
+            Some(KeepRule::DropHighest(n)) | Some(KeepRule::DropLowest(n)) => self.count.saturating_sub(n),
+        }
+    }
+}
+
+impl fmt::Display for DiceExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiceExpr::Number(n) => write!(f, "{}", n),
+            DiceExpr::Dice(term) => write!(f, "{}", term),
+            DiceExpr::Variable(name) => write!(f, "@{}", name),
+            DiceExpr::Negate(expr) => write!(f, "-{}", expr),
+            DiceExpr::Binary { op, lhs, rhs } => write!(f, "{} {} {}", lhs, op, rhs),
+            DiceExpr::Group(expr) => write!(f, "({})", expr),
+            DiceExpr::Labeled { label, expr } => write!(f, "{}[{}]", expr, label),
+        }
+    }
+}
+
+impl fmt::Display for DiceTerm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}d{}", self.count, self.sides)?;
+        if self.explode {
+            write!(f, "!")?;
+        }
+        match self.keep {
+            Some(KeepRule::KeepHighest(n)) => write!(f, "kh{}", n),
+            Some(KeepRule::KeepLowest(n)) => write!(f, "kl{}", n),
+            Some(KeepRule::DropHighest(n)) => write!(f, "dh{}", n),
+            Some(KeepRule::DropLowest(n)) => write!(f, "dl{}", n),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for BinaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol = match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
+// =============================================================================
+// Parser
+// =============================================================================
+
+/// Parse a dice expression into its AST
+///
+/// Supported syntax: `NdS`, `dS`, `d%`, exploding dice (`!`), keep/drop
+/// modifiers (`kh`, `kl`, `dh`, `dl`, and the `k`/`d` shorthands for
+/// keep highest/drop lowest), `+ - * /`, parentheses, `@variables` and
+/// `[labels]` after any term.
+pub fn parse(input: &str) -> AppResult<DiceExpr> {
+    let mut parser = Parser {
+        input,
+        chars: input.chars().collect(),
+        pos: 0,
+        depth: 0,
+        terms: 0,
+        dice: 0,
+    };
+
+    parser.skip_whitespace();
+    if parser.at_end() {
+        return Err(parser.error("Expected a dice expression", 0, 0));
+    }
+
+    let expr = parser.parse_expr()?;
+    parser.skip_whitespace();
+    if let Some(c) = parser.peek() {
+        let message = if c == ')' {
+            "Unmatched ')'".to_string()
+        } else {
+            format!("Unexpected '{}'", c)
+        };
+        return Err(parser.error(&message, parser.pos, parser.pos + 1));
+    }
+
+    Ok(expr)
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    chars: Vec<char>,
+    pos: usize,
+    depth: usize,
+    terms: usize,
+    dice: u32,
+}
+
+impl Parser<'_> {
+    fn parse_expr(&mut self) -> AppResult<DiceExpr> {
+        let mut lhs = self.parse_term()?;
+        loop {
+            self.skip_whitespace();
+            let op = match self.peek() {
+                Some('+') => BinaryOp::Add,
+                Some('-') => BinaryOp::Sub,
+                _ => return Ok(lhs),
+            };
+            self.pos += 1;
+            let rhs = self.parse_term()?;
+            lhs = DiceExpr::Binary { op, lhs: Box::new(lhs), rhs: Box::new(rhs) };
+        }
+    }
+
+    fn parse_term(&mut self) -> AppResult<DiceExpr> {
+        let mut lhs = self.parse_unary()?;
+        loop {
+            self.skip_whitespace();
+            let op = match self.peek() {
+                Some('*') => BinaryOp::Mul,
+                Some('/') => BinaryOp::Div,
+                _ => return Ok(lhs),
+            };
+            self.pos += 1;
+            let rhs = self.parse_unary()?;
+            lhs = DiceExpr::Binary { op, lhs: Box::new(lhs), rhs: Box::new(rhs) };
+        }
+    }
+
+    fn parse_unary(&mut self) -> AppResult<DiceExpr> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('-') => {
+                self.pos += 1;
+                self.enter()?;
+                let expr = self.parse_unary()?;
+                self.depth -= 1;
+                Ok(DiceExpr::Negate(Box::new(expr)))
+            }
+            Some('+') => {
+                self.pos += 1;
+                self.enter()?;
+                let expr = self.parse_unary()?;
+                self.depth -= 1;
+                Ok(expr)
+            }
+            _ => self.parse_labeled(),
+        }
+    }
+
+    fn parse_labeled(&mut self) -> AppResult<DiceExpr> {
+        let mut expr = self.parse_primary()?;
+        self.skip_whitespace();
+        while self.peek() == Some('[') {
+            let start = self.pos;
+            self.pos += 1;
+            let label_start = self.pos;
+            while let Some(c) = self.peek() {
+                if c == ']' || c == '[' {
+                    break;
+                }
+                self.pos += 1;
+            }
+            if self.peek() != Some(']') {
+                return Err(self.error("Unterminated label, expected ']'", start, self.pos));
+            }
+            let label: String = self.chars[label_start..self.pos].iter().collect();
+            self.pos += 1;
+            let label = label.trim().to_string();
+            if label.is_empty() {
+                return Err(self.error("Empty label", start, self.pos));
+            }
+            self.count_term(start)?;
+            expr = DiceExpr::Labeled { label, expr: Box::new(expr) };
+            self.skip_whitespace();
+        }
+        Ok(expr)
+    }
+
+    fn parse_primary(&mut self) -> AppResult<DiceExpr> {
+        self.skip_whitespace();
+        let start = self.pos;
+        if self.peek() != Some('(') {
+            self.count_term(start)?;
+        }
+        match self.peek() {
+            Some('(') => {
+                self.pos += 1;
+                self.enter()?;
+                let expr = self.parse_expr()?;
+                self.depth -= 1;
+                self.skip_whitespace();
+                if self.peek() != Some(')') {
+                    return Err(self.error("Expected ')' to close '('", start, self.pos.max(start + 1)));
+                }
+                self.pos += 1;
+                Ok(DiceExpr::Group(Box::new(expr)))
+            }
+            Some('@') => {
+                self.pos += 1;
+                let name_start = self.pos;
+                while let Some(c) = self.peek() {
+                    if c.is_ascii_alphanumeric() || c == '_' {
+                        self.pos += 1;
+                    } else {
+                        break;
+                    }
+                }
+                if self.pos == name_start || self.chars[name_start].is_ascii_digit() {
+                    return Err(self.error("Expected a variable name after '@'", start, self.pos.max(start + 1)));
+                }
+                let name: String = self.chars[name_start..self.pos].iter().collect();
+                Ok(DiceExpr::Variable(name.to_lowercase()))
+            }
+            Some(c) if c.is_ascii_digit() => {
+                let value = self.parse_number()?;
+                if matches!(self.peek(), Some('d') | Some('D')) {
+                    let count = self.to_count(value, start)?;
+                    self.parse_dice(count, start)
+                } else {
+                    Ok(DiceExpr::Number(value))
+                }
+            }
+            Some('d') | Some('D') => self.parse_dice(1, start),
+            Some(c) => Err(self.error(&format!("Unexpected '{}'", c), start, start + 1)),
+            None => Err(self.error("Unexpected end of expression", start, start)),
+        }
+    }
+
+    /// Parse the `dS` part of a dice term plus any trailing modifiers
+    fn parse_dice(&mut self, count: u32, start: usize) -> AppResult<DiceExpr> {
+        // Consume the 'd'
+        self.pos += 1;
+        let sides_start = self.pos;
+        let sides = match self.peek() {
+            Some('%') => {
+                self.pos += 1;
+                100
+            }
+            Some(c) if c.is_ascii_digit() => {
+                let value = self.parse_number()?;
+                if value < 1 || value > MAX_DICE_SIDES as i64 {
+                    return Err(self.error(
+                        &format!("Dice must have between 1 and {} sides", MAX_DICE_SIDES),
+                        sides_start,
+                        self.pos,
+                    ));
+                }
+                value as u32
+            }
+            _ => {
+                return Err(self.error("Expected number of sides after 'd'", start, self.pos));
+            }
+        };
+
+        self.dice += count;
+        if self.dice > MAX_TOTAL_DICE {
+            return Err(self.error(
+                &format!("An expression can roll at most {} dice in all", MAX_TOTAL_DICE),
+                start,
+                self.pos,
+            ));
+        }
+
+        let mut term = DiceTerm::new(count, sides);
+        loop {
+            let modifier_start = self.pos;
+            match self.peek() {
+                Some('!') => {
+                    self.pos += 1;
+                    if term.explode {
+                        return Err(self.error("Dice can only explode once", modifier_start, self.pos));
+                    }
+                    if sides == 1 {
+                        return Err(self.error("A d1 cannot explode", modifier_start, self.pos));
+                    }
+                    term.explode = true;
+                }
+                Some('k') | Some('K') | Some('d') | Some('D') => {
+                    let rule = self.parse_keep_rule(&term, modifier_start)?;
+                    if term.keep.is_some() {
+                        return Err(self.error(
+                            "Only one keep/drop modifier is allowed per dice term",
+                            modifier_start,
+                            self.pos,
+                        ));
+                    }
+                    term.keep = Some(rule);
+                }
+                _ => break,
+            }
+        }
+
+        Ok(DiceExpr::Dice(term))
+    }
+
+    fn parse_keep_rule(&mut self, term: &DiceTerm, start: usize) -> AppResult<KeepRule> {
+        let is_keep = matches!(self.peek(), Some('k') | Some('K'));
+        self.pos += 1;
+        let direction = match self.peek() {
+            Some('h') | Some('H') => {
+                self.pos += 1;
+                Some(true)
+            }
+            Some('l') | Some('L') => {
+                self.pos += 1;
+                Some(false)
+            }
+            _ => None,
+        };
+
+        let amount = match self.peek() {
+            Some(c) if c.is_ascii_digit() => {
+                let number_start = self.pos;
+                let value = self.parse_number()?;
+                if value > term.count as i64 {
+                    return Err(self.error(
+                        &format!("Cannot keep or drop {} of only {} dice", value, term.count),
+                        number_start,
+                        self.pos,
+                    ));
+                }
+                value as u32
+            }
+            _ => 1,
+        };
+
+        if (is_keep && amount == 0) || (!is_keep && amount >= term.count) {
+            return Err(self.error(
+                &format!("Modifier leaves none of the {} dice to count", term.count),
+                start,
+                self.pos,
+            ));
+        }
+
+        // Bare `k` keeps the highest, bare `d` drops the lowest
+        Ok(match (is_keep, direction.unwrap_or(is_keep)) {
+            (true, true) => KeepRule::KeepHighest(amount),
+            (true, false) => KeepRule::KeepLowest(amount),
+            (false, true) => KeepRule::DropHighest(amount),
+            (false, false) => KeepRule::DropLowest(amount),
+        })
+    }
+
+    fn parse_number(&mut self) -> AppResult<i64> {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        let digits: String = self.chars[start..self.pos].iter().collect();
+        digits
+            .parse::<i64>()
+            .map_err(|_| self.error("Number is too large", start, self.pos))
+    }
+
+    fn to_count(&self, value: i64, start: usize) -> AppResult<u32> {
+        if value < 1 || value > MAX_DICE_COUNT as i64 {
+            return Err(self.error(
+                &format!("Dice count must be between 1 and {}", MAX_DICE_COUNT),
+                start,
+                self.pos,
+            ));
+        }
+        Ok(value as u32)
+    }
+
+    fn enter(&mut self) -> AppResult<()> {
+        self.depth += 1;
+        if self.depth > MAX_NESTING {
+            return Err(self.error("Expression is nested too deeply", self.pos, self.pos));
+        }
+        Ok(())
+    }
+
+    fn count_term(&mut self, start: usize) -> AppResult<()> {
+        self.terms += 1;
+        if self.terms > MAX_TERMS {
+            return Err(self.error(
+                &format!("An expression can have at most {} terms", MAX_TERMS),
+                start,
+                start + 1,
+            ));
+        }
+        Ok(())
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(c) if c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.chars.len()
+    }
+
+    fn error(&self, message: &str, start: usize, end: usize) -> AppError {
+        AppError::InvalidInput(format!(
+            "{} at {}..{} in dice expression \"{}\"",
+            message, start, end, self.input
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dice(count: u32, sides: u32) -> DiceExpr {
+        DiceExpr::Dice(DiceTerm::new(count, sides))
+    }
+
+    fn binary(op: BinaryOp, lhs: DiceExpr, rhs: DiceExpr) -> DiceExpr {
+        DiceExpr::Binary { op, lhs: Box::new(lhs), rhs: Box::new(rhs) }
+    }
+
+    fn error_message(input: &str) -> String {
+        match parse(input) {
+            Err(AppError::InvalidInput(message)) => message,
+            other => panic!("expected {:?} to be rejected, got {:?}", input, other),
+        }
+    }
+
+    #[test]
+    fn multiplication_binds_tighter_than_addition() {
+        assert_eq!(
+            parse("1d6 + 2 * 3").unwrap(),
+            binary(BinaryOp::Add, dice(1, 6), binary(BinaryOp::Mul, DiceExpr::Number(2), DiceExpr::Number(3))),
+        );
+    }
+
+    #[test]
+    fn operators_of_equal_precedence_associate_left() {
+        assert_eq!(
+            parse("10 - 2 - 3").unwrap(),
+            binary(
+                BinaryOp::Sub,
+                binary(BinaryOp::Sub, DiceExpr::Number(10), DiceExpr::Number(2)),
+                DiceExpr::Number(3),
+            ),
+        );
+        assert_eq!(
+            parse("12 / 2 * 3").unwrap(),
+            binary(
+                BinaryOp::Mul,
+                binary(BinaryOp::Div, DiceExpr::Number(12), DiceExpr::Number(2)),
+                DiceExpr::Number(3),
+            ),
+        );
+    }
+
+    #[test]
+    fn parentheses_and_negation_override_precedence() {
+        assert_eq!(
+            parse("-(1d4 + 1) * 2").unwrap(),
+            binary(
+                BinaryOp::Mul,
+                DiceExpr::Negate(Box::new(DiceExpr::Group(Box::new(binary(
+                    BinaryOp::Add,
+                    dice(1, 4),
+                    DiceExpr::Number(1),
+                ))))),
+                DiceExpr::Number(2),
+            ),
+        );
+    }
+
+    #[test]
+    fn parses_dice_modifiers_variables_and_labels() {
+        let expr = parse("4d6kh3 + d% + 2d10! + @STR + 1d4[ bless ]").unwrap();
+        assert_eq!(expr.to_string(), "4d6kh3 + 1d100 + 2d10! + @str + 1d4[bless]");
+
+        let DiceExpr::Dice(term) = parse("4d6d").unwrap() else { panic!("expected a dice term") };
+        assert_eq!(term.keep, Some(KeepRule::DropLowest(1)));
+        assert_eq!(term.kept_count(), 3);
+        let DiceExpr::Dice(term) = parse("2d20k").unwrap() else { panic!("expected a dice term") };
+        assert_eq!(term.keep, Some(KeepRule::KeepHighest(1)));
+        let DiceExpr::Dice(term) = parse("3d8!kl2").unwrap() else { panic!("expected a dice term") };
+        assert!(term.explode);
+        assert_eq!(term.keep, Some(KeepRule::KeepLowest(2)));
+    }
+
+    #[test]
+    fn errors_point_at_the_offending_text() {
+        assert!(error_message("").starts_with("Expected a dice expression at 0..0"));
+        assert!(error_message("1d6 + ?").starts_with("Unexpected '?' at 6..7"));
+        assert!(error_message("(1d6 + 2").starts_with("Expected ')' to close '(' at 0..8"));
+        assert!(error_message("1d6)").starts_with("Unmatched ')' at 3..4"));
+        assert!(error_message("1d4[bless").starts_with("Unterminated label, expected ']' at 3..9"));
+        assert!(error_message("1d4[ ]").starts_with("Empty label at 3..6"));
+        assert!(error_message("2d6kh3").starts_with("Cannot keep or drop 3 of only 2 dice at 5..6"));
+        assert!(error_message("2d6d2").starts_with("Modifier leaves none of the 2 dice to count at 3..5"));
+    }
+
+    #[test]
+    fn rejects_terms_beyond_the_limits() {
+        assert!(parse(&format!("{}d6", MAX_DICE_COUNT)).is_ok());
+        assert!(error_message(&format!("{}d6", MAX_DICE_COUNT + 1)).starts_with("Dice count must be between 1"));
+        assert!(error_message("0d6").starts_with("Dice count must be between 1"));
+        assert!(parse(&format!("1d{}", MAX_DICE_SIDES)).is_ok());
+        assert!(error_message(&format!("1d{}", MAX_DICE_SIDES + 1)).starts_with("Dice must have between 1 and"));
+        assert!(error_message("1d1!").starts_with("A d1 cannot explode"));
+        assert!(error_message("99999999999999999999").starts_with("Number is too large"));
+    }
+
+    #[test]
+    fn rejects_deep_nesting() {
+        let nested = format!("{}1{}", "(".repeat(MAX_NESTING), ")".repeat(MAX_NESTING));
+        assert!(parse(&nested).is_ok());
+        let too_deep = format!("{}1", "-".repeat(MAX_NESTING + 1));
+        assert!(error_message(&too_deep).starts_with("Expression is nested too deeply"));
+    }
+
+    #[test]
+    fn rejects_long_chains_of_terms_and_dice() {
+        let chain = |term: &str, count: usize| vec![term; count].join(" + ");
+        assert!(parse(&chain("1", MAX_TERMS)).is_ok());
+        assert!(error_message(&chain("1", MAX_TERMS + 1)).starts_with("An expression can have at most"));
+        assert!(error_message(&chain("d6", MAX_TERMS + 1)).starts_with("An expression can have at most"));
+        let labels = format!("1{}", "[x]".repeat(MAX_TERMS));
+        assert!(error_message(&labels).starts_with("An expression can have at most"));
+
+        let half = MAX_TOTAL_DICE / 2;
+        assert!(parse(&format!("{}d6 + {}d8", half, half)).is_ok());
+        assert!(error_message(&format!("{}d6 + {}d8!", half, half + 1)).starts_with("An expression can roll at most"));
+    }
+}
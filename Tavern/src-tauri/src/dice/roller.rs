@@ -0,0 +1,480 @@
+use std::collections::HashMap;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::database::models::{
+    CriticalHitRules, DiceModifier, DiceRoll, DiceSettings, DiceTermResult, DieResult, RollType,
+};
+use crate::dice::parser::{self, BinaryOp, DiceExpr, DiceTerm, KeepRule, MAX_DICE_COUNT};
+use crate::errors::{AppError, AppResult};
+
+/// Cap on how many extra rolls a single exploding die may chain
+pub const MAX_EXPLOSIONS: usize = 100;
+
+/// Evaluates dice expressions into fully populated `DiceRoll`s
+///
+/// Each roll draws its own seed from the roller's RNG and rolls its dice from
+/// a generator seeded with it. That seed is stored on the resulting `DiceRoll`,
+/// so `replay` can reproduce any roll without the roller that made it.
+pub struct DiceRoller<R: Rng = StdRng> {
+    rng: R,
+    variables: HashMap<String, i64>,
+}
+
+impl DiceRoller<StdRng> {
+    /// Create a roller seeded from the operating system
+    pub fn new() -> Self {
+        Self::with_rng(StdRng::from_os_rng())
+    }
+
+    /// Create a roller whose sequence of rolls is fully determined by `seed`
+    pub fn from_seed(seed: u64) -> Self {
+        Self::with_rng(StdRng::seed_from_u64(seed))
+    }
+
+    /// Reproduce a previous roll of `notation` from its recorded seed and the
+    /// `@variables` it used
+    pub fn replay(notation: &str, seed: u64, variables: &HashMap<String, i64>) -> AppResult<DiceRoll> {
+        let expr = parser::parse(notation)?;
+        let variables = variables.iter().map(|(name, &value)| (name.to_lowercase(), value)).collect();
+        roll_seeded(&expr, seed, &variables)
+    }
+}
+
+impl Default for DiceRoller<StdRng> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<R: Rng> DiceRoller<R> {
+    /// Create a roller that draws its per-roll seeds from the given RNG
+    pub fn with_rng(rng: R) -> Self {
+        Self { rng, variables: HashMap::new() }
+    }
+
+    /// Set the value used for `@name` in expressions
+    pub fn set_variable(&mut self, name: &str, value: i64) {
+        self.variables.insert(name.to_lowercase(), value);
+    }
+
+    pub fn with_variable(mut self, name: &str, value: i64) -> Self {
+        self.set_variable(name, value);
+        self
+    }
+
+    /// Parse and roll a dice expression
+    pub fn roll(&mut self, notation: &str) -> AppResult<DiceRoll> {
+        let expr = parser::parse(notation)?;
+        self.roll_expr(&expr)
+    }
+
+    /// Roll an already parsed dice expression
+    pub fn roll_expr(&mut self, expr: &DiceExpr) -> AppResult<DiceRoll> {
+        let seed = self.rng.random();
+        roll_seeded(expr, seed, &self.variables)
+    }
+
+    /// Roll an expression under a campaign's dice settings
+    ///
+    /// Advantage and disadvantage turn the first `1d20` into `2d20kh1` or
+    /// `2d20kl1`. A critical roll applies the campaign's critical hit rule to
+    /// every dice term, and with exploding dice enabled every die except the
+    /// d20 explodes. A natural 20 on the d20 marks the roll critical, and a
+    /// natural 1 marks it a fumble when the campaign uses fumble rules.
+    pub fn roll_with_settings(
+        &mut self,
+        expr: &DiceExpr,
+        roll_type: RollType,
+        settings: &DiceSettings,
+    ) -> AppResult<DiceRoll> {
+        let expr = apply_settings(expr.clone(), roll_type, settings)?;
+        let mut roll = self.roll_expr(&expr)?;
+
+        let natural_roll = roll
+            .terms
+            .iter()
+            .find(|term| {
+                term.dice.iter().all(|die| die.sides == 20)
+                    && term.dice.iter().filter(|die| die.kept).count() == 1
+            })
+            .and_then(|term| term.dice.iter().find(|die| die.kept))
+            .map(|die| die.value);
+
+        roll.roll_type = roll_type;
+        roll.natural_roll = natural_roll;
+        roll.is_critical = roll_type == RollType::Critical || natural_roll == Some(20);
+        roll.is_fumble = settings.fumble_rules && natural_roll == Some(1);
+        Ok(roll)
+    }
+}
+
+/// Roll `expr` with dice drawn from a generator seeded with `seed`
+fn roll_seeded(expr: &DiceExpr, seed: u64, variables: &HashMap<String, i64>) -> AppResult<DiceRoll> {
+    let mut evaluation = Evaluation {
+        rng: StdRng::seed_from_u64(seed),
+        variables,
+        used_variables: HashMap::new(),
+        terms: Vec::new(),
+        modifiers: Vec::new(),
+    };
+    let total = evaluation.eval(expr, None, 1, true)?;
+
+    let individual_rolls = evaluation
+        .terms
+        .iter()
+        .flat_map(|term| term.dice.iter())
+        .flat_map(|die| die.rolls.iter().copied())
+        .collect();
+
+    Ok(DiceRoll {
+        dice_notation: expr.to_string(),
+        individual_rolls,
+        modifiers: evaluation.modifiers,
+        total,
+        roll_type: RollType::Normal,
+        terms: evaluation.terms,
+        seed: Some(seed),
+        variables: evaluation.used_variables,
+        natural_roll: None,
+        is_critical: false,
+        is_fumble: false,
+    })
+}
+
+/// Rewrite an expression so that rolling it applies the roll type and settings
+pub fn apply_settings(expr: DiceExpr, roll_type: RollType, settings: &DiceSettings) -> AppResult<DiceExpr> {
+    let mut expr = expr;
+
+    if matches!(roll_type, RollType::Advantage | RollType::Disadvantage) {
+        let mut applied = false;
+        expr = expr.map_dice(&mut |mut term| {
+            if !applied && term.count == 1 && term.sides == 20 && term.keep.is_none() {
+                applied = true;
+                term.count = 2;
+                term.keep = Some(if roll_type == RollType::Advantage {
+                    KeepRule::KeepHighest(1)
+                } else {
+                    KeepRule::KeepLowest(1)
+                });
+            }
+            DiceExpr::Dice(term)
+        });
+        if !applied {
+            return Err(AppError::InvalidInput(
+                "Advantage and disadvantage need a single d20 to roll twice".to_string(),
+            ));
+        }
+    }
+
+    if roll_type == RollType::Critical {
+        let mut too_many = false;
+        expr = expr.map_dice(&mut |mut term| match settings.critical_hit_rules {
+            CriticalHitRules::Standard => {
+                too_many |= term.count * 2 > MAX_DICE_COUNT;
+                term.count *= 2;
+                // Keep/drop counts scale with the dice so `2d6kh1` stays one die per roll
+                term.keep = term.keep.map(|rule| match rule {
+                    KeepRule::KeepHighest(n) => KeepRule::KeepHighest(n * 2),
+                    KeepRule::KeepLowest(n) => KeepRule::KeepLowest(n * 2),
+                    KeepRule::DropHighest(n) => KeepRule::DropHighest(n * 2),
+                    KeepRule::DropLowest(n) => KeepRule::DropLowest(n * 2),
+                });
+                DiceExpr::Dice(term)
+            }
+            CriticalHitRules::DoubleDice => DiceExpr::Group(Box::new(DiceExpr::Binary {
+                op: BinaryOp::Mul,
+                lhs: Box::new(DiceExpr::Dice(term)),
+                rhs: Box::new(DiceExpr::Number(2)),
+            })),
+            CriticalHitRules::MaxPlusRoll => {
+                let maximum = term.kept_count() as i64 * term.sides as i64;
+                DiceExpr::Group(Box::new(DiceExpr::Binary {
+                    op: BinaryOp::Add,
+                    lhs: Box::new(DiceExpr::Dice(term)),
+                    rhs: Box::new(DiceExpr::Labeled {
+                        label: "Critical maximum".to_string(),
+                        expr: Box::new(DiceExpr::Number(maximum)),
+                    }),
+                }))
+            }
+        });
+        if too_many {
+            return Err(AppError::InvalidInput(format!(
+                "Critical damage would roll more than {} dice",
+                MAX_DICE_COUNT
+            )));
+        }
+    }
+
+    if settings.exploding_dice {
+        expr = expr.map_dice(&mut |mut term| {
+            if term.sides != 20 && term.sides > 1 {
+                term.explode = true;
+            }
+            DiceExpr::Dice(term)
+        });
+    }
+
+    Ok(expr)
+}
+
+/// State for a single evaluation of an expression
+struct Evaluation<'a> {
+    rng: StdRng,
+    variables: &'a HashMap<String, i64>,
+    /// The variables read so far, with their values
+    used_variables: HashMap<String, i64>,
+    terms: Vec<DiceTermResult>,
+    modifiers: Vec<DiceModifier>,
+}
+
+impl Evaluation<'_> {
+    /// Evaluate `expr`, recording dice terms and flat modifiers as it goes
+    ///
+    /// `sign` tracks whether the node is added to or subtracted from the total.
+    /// `itemize` is false inside products, where a constant on its own doesn't
+    /// say how much it contributed.
+    fn eval(&mut self, expr: &DiceExpr, label: Option<&str>, sign: i64, itemize: bool) -> AppResult<i64> {
+        match expr {
+            DiceExpr::Number(value) => {
+                if itemize {
+                    self.modifiers.push(DiceModifier {
+                        name: label.unwrap_or("Modifier").to_string(),
+                        value: sign * value,
+                        source: "notation".to_string(),
+                    });
+                }
+                Ok(*value)
+            }
+            DiceExpr::Variable(name) => {
+                let value = *self.variables.get(name).ok_or_else(|| {
+                    AppError::InvalidInput(format!("Unknown dice variable @{}", name))
+                })?;
+                self.used_variables.insert(name.clone(), value);
+                if itemize {
+                    self.modifiers.push(DiceModifier {
+                        name: label.unwrap_or(name).to_string(),
+                        value: sign * value,
+                        source: format!("@{}", name),
+                    });
+                }
+                Ok(value)
+            }
+            DiceExpr::Dice(term) => Ok(self.roll_term(term, label)),
+            DiceExpr::Negate(inner) => {
+                let value = self.eval(inner, label, -sign, itemize)?;
+                value.checked_neg().ok_or_else(overflow)
+            }
+            DiceExpr::Group(inner) => self.eval(inner, label, sign, itemize),
+            DiceExpr::Labeled { label, expr } => self.eval(expr, Some(label), sign, itemize),
+            DiceExpr::Binary { op: BinaryOp::Add, lhs, rhs } => {
+                let lhs = self.eval(lhs, label, sign, itemize)?;
+                let rhs = self.eval(rhs, label, sign, itemize)?;
+                lhs.checked_add(rhs).ok_or_else(overflow)
+            }
+            DiceExpr::Binary { op: BinaryOp::Sub, lhs, rhs } => {
+                let lhs = self.eval(lhs, label, sign, itemize)?;
+                let rhs = self.eval(rhs, label, -sign, itemize)?;
+                lhs.checked_sub(rhs).ok_or_else(overflow)
+            }
+            DiceExpr::Binary { op, lhs, rhs } => {
+                let lhs_value = self.eval(lhs, label, sign, false)?;
+                let rhs_value = self.eval(rhs, label, sign, false)?;
+                let value = apply_product(*op, lhs_value, rhs_value)?;
+
+                // A product of constants still contributes a known flat amount
+                if itemize && !expr.has_dice() {
+                    self.modifiers.push(DiceModifier {
+                        name: label.map(str::to_string).unwrap_or_else(|| expr.to_string()),
+                        value: sign * value,
+                        source: "notation".to_string(),
+                    });
+                }
+                Ok(value)
+            }
+        }
+    }
+
+    fn roll_term(&mut self, term: &DiceTerm, label: Option<&str>) -> i64 {
+        let sides = term.sides as i64;
+        let mut dice: Vec<DieResult> = (0..term.count)
+            .map(|_| {
+                let mut rolls = vec![self.rng.random_range(1..=sides)];
+                if term.explode {
+                    while rolls.last() == Some(&sides) && rolls.len() <= MAX_EXPLOSIONS {
+                        rolls.push(self.rng.random_range(1..=sides));
+                    }
+                }
+                DieResult {
+                    sides,
+                    value: rolls.iter().sum(),
+                    rolls,
+                    kept: true,
+                }
+            })
+            .collect();
+
+        apply_keep_rule(&mut dice, term.keep);
+        let subtotal = dice.iter().filter(|die| die.kept).map(|die| die.value).sum();
+
+        self.terms.push(DiceTermResult {
+            notation: term.to_string(),
+            label: label.map(str::to_string),
+            dice,
+            subtotal,
+        });
+        subtotal
+    }
+}
+
+/// Mark the dice a keep/drop rule discards
+///
+/// Dice are ranked by value with ties in the order rolled, so among equal
+/// dice `kh` and `dl` discard the earlier ones and `kl` and `dh` the later.
+fn apply_keep_rule(dice: &mut [DieResult], keep: Option<KeepRule>) {
+    let Some(rule) = keep else {
+        return;
+    };
+
+    let len = dice.len();
+    let mut order: Vec<usize> = (0..len).collect();
+    order.sort_by_key(|&index| dice[index].value);
+
+    let dropped = match rule {
+        KeepRule::KeepHighest(n) => &order[..len - (n as usize).min(len)],
+        KeepRule::KeepLowest(n) => &order[(n as usize).min(len)..],
+        KeepRule::DropHighest(n) => &order[len - (n as usize).min(len)..],
+        KeepRule::DropLowest(n) => &order[..(n as usize).min(len)],
+    };
+    for &index in dropped {
+        dice[index].kept = false;
+    }
+}
+
+fn apply_product(op: BinaryOp, lhs: i64, rhs: i64) -> AppResult<i64> {
+    match op {
+        BinaryOp::Mul => lhs.checked_mul(rhs).ok_or_else(overflow),
+        BinaryOp::Div => {
+            if rhs == 0 {
+                return Err(AppError::InvalidInput("Division by zero in dice expression".to_string()));
+            }
+            // Round down, as the rules do, rather than toward zero
+            let quotient = lhs / rhs;
+            if lhs % rhs != 0 && (lhs < 0) != (rhs < 0) {
+                Ok(quotient - 1)
+            } else {
+                Ok(quotient)
+            }
+        }
+        BinaryOp::Add => lhs.checked_add(rhs).ok_or_else(overflow),
+        BinaryOp::Sub => lhs.checked_sub(rhs).ok_or_else(overflow),
+    }
+}
+
+fn overflow() -> AppError {
+    AppError::InvalidInput("Dice expression result is out of range".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn die(value: i64) -> DieResult {
+        DieResult { sides: 6, rolls: vec![value], value, kept: true }
+    }
+
+    fn kept(dice: &[DieResult]) -> Vec<bool> {
+        dice.iter().map(|die| die.kept).collect()
+    }
+
+    #[test]
+    fn replay_reproduces_a_roll_from_its_seed() {
+        let mut roller = DiceRoller::new();
+        for notation in ["4d6kh3 + 2", "3d6! + 1d4[bless]", "2d20kl1 - 1d8 * 2"] {
+            let roll = roller.roll(notation).unwrap();
+            let replayed = DiceRoller::replay(&roll.dice_notation, roll.seed.unwrap(), &roll.variables).unwrap();
+            assert_eq!(replayed.individual_rolls, roll.individual_rolls);
+            assert_eq!(replayed.total, roll.total);
+            assert_eq!(replayed.dice_notation, roll.dice_notation);
+        }
+    }
+
+    #[test]
+    fn replay_uses_the_variables_the_roll_was_made_with() {
+        let mut roller = DiceRoller::new().with_variable("STR", 4).with_variable("dex", 2);
+        let roll = roller.roll("1d20 + @str").unwrap();
+        assert_eq!(roll.variables, HashMap::from([("str".to_string(), 4)]));
+
+        let replayed = DiceRoller::replay(&roll.dice_notation, roll.seed.unwrap(), &roll.variables).unwrap();
+        assert_eq!(replayed.total, roll.total);
+        assert!(DiceRoller::replay(&roll.dice_notation, roll.seed.unwrap(), &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn rollers_with_the_same_seed_roll_the_same() {
+        let mut first = DiceRoller::from_seed(42);
+        let mut second = DiceRoller::from_seed(42);
+        for _ in 0..10 {
+            assert_eq!(first.roll("8d6").unwrap().individual_rolls, second.roll("8d6").unwrap().individual_rolls);
+        }
+    }
+
+    #[test]
+    fn seeds_travel_as_strings() {
+        let roll = DiceRoller::from_seed(7).roll("1d6").unwrap();
+        let json = serde_json::to_value(&roll).unwrap();
+        assert_eq!(json["seed"], serde_json::Value::String(roll.seed.unwrap().to_string()));
+        let parsed: DiceRoll = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed.seed, roll.seed);
+
+        let mut saved = serde_json::to_value(&roll).unwrap();
+        saved["seed"] = serde_json::json!(u64::MAX);
+        let parsed: DiceRoll = serde_json::from_value(saved).unwrap();
+        assert_eq!(parsed.seed, Some(u64::MAX));
+    }
+
+    #[test]
+    fn keep_rules_break_ties_by_roll_order() {
+        let mut dice = vec![die(5), die(3), die(5)];
+        apply_keep_rule(&mut dice, Some(KeepRule::KeepHighest(1)));
+        assert_eq!(kept(&dice), [false, false, true]);
+
+        let mut dice = vec![die(5), die(3), die(5)];
+        apply_keep_rule(&mut dice, Some(KeepRule::DropHighest(1)));
+        assert_eq!(kept(&dice), [true, true, false]);
+
+        let mut dice = vec![die(2), die(2), die(6)];
+        apply_keep_rule(&mut dice, Some(KeepRule::KeepLowest(1)));
+        assert_eq!(kept(&dice), [true, false, false]);
+
+        let mut dice = vec![die(2), die(2), die(6)];
+        apply_keep_rule(&mut dice, Some(KeepRule::DropLowest(1)));
+        assert_eq!(kept(&dice), [false, true, true]);
+    }
+
+    #[test]
+    fn advantage_rolls_the_first_d20_twice() {
+        let expr = parser::parse("1d20 + 1d20 + 5").unwrap();
+        let advantaged = apply_settings(expr, RollType::Advantage, &DiceSettings::default()).unwrap();
+        assert_eq!(advantaged.to_string(), "2d20kh1 + 1d20 + 5");
+
+        let roll = DiceRoller::from_seed(1)
+            .roll_with_settings(&parser::parse("1d20 + 2").unwrap(), RollType::Disadvantage, &DiceSettings::default())
+            .unwrap();
+        let natural = roll.terms[0].dice.iter().map(|die| die.value).min();
+        assert_eq!(roll.natural_roll, natural);
+        assert_eq!(roll.total, natural.unwrap() + 2);
+
+        let no_d20 = parser::parse("2d6").unwrap();
+        assert!(apply_settings(no_d20, RollType::Advantage, &DiceSettings::default()).is_err());
+    }
+
+    #[test]
+    fn division_rounds_down() {
+        assert_eq!(apply_product(BinaryOp::Div, 7, 2).unwrap(), 3);
+        assert_eq!(apply_product(BinaryOp::Div, -7, 2).unwrap(), -4);
+        assert!(apply_product(BinaryOp::Div, 1, 0).is_err());
+    }
+}
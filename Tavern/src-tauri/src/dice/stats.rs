@@ -0,0 +1,353 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::database::models::{DiceStatistics, OutcomeProbability};
+use crate::dice::parser::{BinaryOp, DiceExpr, DiceTerm, KeepRule};
+use crate::errors::{AppError, AppResult};
+
+/// How many times an exploding die is followed before its remaining
+/// probability is folded into the last step
+pub const MAX_EXPLOSION_DEPTH: usize = 8;
+/// Largest number of dice a keep/drop term may have for exact computation
+pub const MAX_KEEP_DICE: u32 = 60;
+/// Cap on outcome pairs combined for one operator or dice term, to keep
+/// computation bounded
+const MAX_COMBINATIONS: usize = 20_000_000;
+/// Cap on the ways of assigning dice to faces a keep/drop term may weigh
+const MAX_KEEP_STEPS: usize = 5_000_000;
+
+/// Exact probability distribution of a dice expression's total
+#[derive(Debug, Clone, PartialEq)]
+pub struct Distribution {
+    outcomes: BTreeMap<i64, f64>,
+}
+
+impl Distribution {
+    pub fn constant(value: i64) -> Self {
+        Self { outcomes: BTreeMap::from([(value, 1.0)]) }
+    }
+
+    pub fn min(&self) -> i64 {
+        self.outcomes.keys().next().copied().unwrap_or(0)
+    }
+
+    pub fn max(&self) -> i64 {
+        self.outcomes.keys().next_back().copied().unwrap_or(0)
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.outcomes.iter().map(|(&value, &p)| value as f64 * p).sum()
+    }
+
+    pub fn variance(&self) -> f64 {
+        let mean = self.mean();
+        self.outcomes
+            .iter()
+            .map(|(&value, &p)| (value as f64 - mean).powi(2) * p)
+            .sum()
+    }
+
+    pub fn std_dev(&self) -> f64 {
+        self.variance().sqrt()
+    }
+
+    pub fn probability_of(&self, total: i64) -> f64 {
+        self.outcomes.get(&total).copied().unwrap_or(0.0)
+    }
+
+    /// Chance that the total is `target` or more
+    pub fn probability_at_least(&self, target: i64) -> f64 {
+        self.outcomes.range(target..).map(|(_, &p)| p).sum::<f64>().min(1.0)
+    }
+
+    pub fn outcomes(&self) -> impl Iterator<Item = (i64, f64)> + '_ {
+        self.outcomes.iter().map(|(&value, &p)| (value, p))
+    }
+
+    /// Summarize the distribution, optionally against a target total
+    pub fn statistics(&self, expression: &str, target: Option<i64>) -> DiceStatistics {
+        DiceStatistics {
+            expression: expression.to_string(),
+            min: self.min(),
+            max: self.max(),
+            mean: self.mean(),
+            std_dev: self.std_dev(),
+            target,
+            chance_at_least: target.map(|target| self.probability_at_least(target)),
+            distribution: self
+                .outcomes()
+                .map(|(total, probability)| OutcomeProbability { total, probability })
+                .collect(),
+        }
+    }
+
+    fn map(&self, f: impl Fn(i64) -> i64) -> Self {
+        let mut outcomes = BTreeMap::new();
+        for (&value, &p) in &self.outcomes {
+            *outcomes.entry(f(value)).or_insert(0.0) += p;
+        }
+        Self { outcomes }
+    }
+
+    /// Distribution of `f(a, b)` for independent `a` from self and `b` from other
+    fn combine(&self, other: &Self, f: impl Fn(i64, i64) -> AppResult<i64>) -> AppResult<Self> {
+        if self.outcomes.len().saturating_mul(other.outcomes.len()) > MAX_COMBINATIONS {
+            return Err(too_complex());
+        }
+        let mut outcomes = BTreeMap::new();
+        for (&a, &pa) in &self.outcomes {
+            for (&b, &pb) in &other.outcomes {
+                *outcomes.entry(f(a, b)?).or_insert(0.0) += pa * pb;
+            }
+        }
+        Ok(Self { outcomes })
+    }
+}
+
+/// Compute the exact distribution of an expression's total
+///
+/// `@variables` are read from `variables`. Exploding dice are followed up to
+/// `MAX_EXPLOSION_DEPTH` times, which leaves an error far below anything a
+/// table would notice.
+pub fn distribution(expr: &DiceExpr, variables: &HashMap<String, i64>) -> AppResult<Distribution> {
+    match expr {
+        DiceExpr::Number(value) => Ok(Distribution::constant(*value)),
+        DiceExpr::Variable(name) => variables
+            .get(name)
+            .map(|&value| Distribution::constant(value))
+            .ok_or_else(|| AppError::InvalidInput(format!("Unknown dice variable @{}", name))),
+        DiceExpr::Dice(term) => term_distribution(term),
+        DiceExpr::Negate(inner) => Ok(distribution(inner, variables)?.map(|value| -value)),
+        DiceExpr::Group(inner) | DiceExpr::Labeled { expr: inner, .. } => distribution(inner, variables),
+        DiceExpr::Binary { op, lhs, rhs } => {
+            let lhs = distribution(lhs, variables)?;
+            let rhs = distribution(rhs, variables)?;
+            match op {
+                BinaryOp::Add => lhs.combine(&rhs, |a, b| a.checked_add(b).ok_or_else(too_complex)),
+                BinaryOp::Sub => lhs.combine(&rhs, |a, b| a.checked_sub(b).ok_or_else(too_complex)),
+                BinaryOp::Mul => lhs.combine(&rhs, |a, b| a.checked_mul(b).ok_or_else(too_complex)),
+                BinaryOp::Div => lhs.combine(&rhs, |a, b| {
+                    if b == 0 {
+                        return Err(AppError::InvalidInput("Division by zero in dice expression".to_string()));
+                    }
+                    let quotient = a / b;
+                    Ok(if a % b != 0 && (a < 0) != (b < 0) { quotient - 1 } else { quotient })
+                }),
+            }
+        }
+    }
+}
+
+/// Distribution of a single die, following explosions when the term has them
+fn die_distribution(term: &DiceTerm) -> Vec<(i64, f64)> {
+    let sides = term.sides as i64;
+    let face = 1.0 / sides as f64;
+    if !term.explode {
+        return (1..=sides).map(|value| (value, face)).collect();
+    }
+
+    let mut outcomes = Vec::new();
+    let mut reach = 1.0;
+    for depth in 0..=MAX_EXPLOSION_DEPTH {
+        let base = depth as i64 * sides;
+        for value in 1..sides {
+            outcomes.push((base + value, reach * face));
+        }
+        reach *= face;
+        if depth == MAX_EXPLOSION_DEPTH {
+            // Stop following the chain: the last maximum simply counts as itself
+            outcomes.push((base + sides, reach));
+        }
+    }
+    outcomes
+}
+
+fn term_distribution(term: &DiceTerm) -> AppResult<Distribution> {
+    let die = die_distribution(term);
+    let count = term.count as usize;
+
+    let (keep, highest) = match term.keep {
+        None => return sum_of_dice(&die, count),
+        Some(KeepRule::KeepHighest(n)) => (n as usize, true),
+        Some(KeepRule::KeepLowest(n)) => (n as usize, false),
+        Some(KeepRule::DropHighest(n)) => (count.saturating_sub(n as usize), false),
+        Some(KeepRule::DropLowest(n)) => (count.saturating_sub(n as usize), true),
+    };
+
+    if term.count > MAX_KEEP_DICE {
+        return Err(AppError::InvalidInput(format!(
+            "Keep/drop odds can only be computed for up to {} dice",
+            MAX_KEEP_DICE
+        )));
+    }
+    keep_distribution(die, count, keep.min(count), highest)
+}
+
+/// Distribution of the sum of `count` dice, adding one die at a time
+///
+/// The die's faces are laid out by value so each step is a plain
+/// convolution. Gives up once the steps together combine more than
+/// `MAX_COMBINATIONS` pairs of outcomes.
+fn sum_of_dice(die: &[(i64, f64)], count: usize) -> AppResult<Distribution> {
+    let low = die.iter().map(|&(value, _)| value).min().unwrap_or(0);
+    let high = die.iter().map(|&(value, _)| value).max().unwrap_or(0);
+    let mut faces = vec![0.0; (high - low) as usize + 1];
+    for &(value, p) in die {
+        faces[(value - low) as usize] += p;
+    }
+
+    let mut total = faces.clone();
+    let mut combined = 0usize;
+    for _ in 1..count {
+        combined = combined.saturating_add(total.len() * faces.len());
+        if combined > MAX_COMBINATIONS {
+            return Err(too_complex());
+        }
+        let mut next = vec![0.0; total.len() + faces.len() - 1];
+        for (offset, &p) in total.iter().enumerate().filter(|&(_, &p)| p > 0.0) {
+            for (face, &q) in faces.iter().enumerate() {
+                next[offset + face] += p * q;
+            }
+        }
+        total = next;
+    }
+
+    let low = low * count as i64;
+    let outcomes = total
+        .into_iter()
+        .enumerate()
+        .filter(|&(_, p)| p > 0.0)
+        .map(|(offset, p)| (low + offset as i64, p))
+        .collect();
+    Ok(Distribution { outcomes })
+}
+
+/// Distribution of the sum of the `keep` highest (or lowest) of `count` dice
+///
+/// Walks the faces from the kept end, deciding how many dice show each face.
+/// Choosing `c` of the remaining dice for a face is weighted by the binomial
+/// coefficient, so every ordering of the dice is counted exactly once. Gives
+/// up once that takes more than `MAX_KEEP_STEPS` steps.
+fn keep_distribution(mut die: Vec<(i64, f64)>, count: usize, keep: usize, highest: bool) -> AppResult<Distribution> {
+    die.sort_by_key(|&(value, _)| value);
+    if highest {
+        die.reverse();
+    }
+
+    let binomial = binomial_table(count);
+    // states[assigned] maps the kept sum so far to its probability
+    let mut states: Vec<BTreeMap<i64, f64>> = vec![BTreeMap::new(); count + 1];
+    states[0].insert(0, 1.0);
+
+    let mut steps = 0usize;
+    for &(value, p) in &die {
+        let mut next: Vec<BTreeMap<i64, f64>> = vec![BTreeMap::new(); count + 1];
+        for (assigned, sums) in states.iter().enumerate() {
+            let remaining = count - assigned;
+            steps = steps.saturating_add(sums.len() * (remaining + 1));
+            if steps > MAX_KEEP_STEPS {
+                return Err(too_complex());
+            }
+            for (&sum, &weight) in sums {
+                let mut p_power = 1.0;
+                for showing in 0..=remaining {
+                    let kept = showing.min(keep.saturating_sub(assigned));
+                    let entry = next[assigned + showing]
+                        .entry(sum + kept as i64 * value)
+                        .or_insert(0.0);
+                    *entry += weight * binomial[remaining][showing] * p_power;
+                    p_power *= p;
+                }
+            }
+        }
+        states = next;
+    }
+
+    Ok(Distribution { outcomes: std::mem::take(&mut states[count]) })
+}
+
+fn binomial_table(n: usize) -> Vec<Vec<f64>> {
+    let mut table = vec![vec![0.0; n + 1]; n + 1];
+    for row in 0..=n {
+        table[row][0] = 1.0;
+        for col in 1..=row {
+            table[row][col] = table[row - 1][col - 1] + if col < row { table[row - 1][col] } else { 0.0 };
+        }
+    }
+    table
+}
+
+fn too_complex() -> AppError {
+    AppError::InvalidInput("Dice expression has too many outcomes to compute exactly".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dice::parser::parse;
+
+    fn odds(notation: &str) -> AppResult<Distribution> {
+        distribution(&parse(notation)?, &HashMap::new())
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "expected {}, got {}", expected, actual);
+    }
+
+    #[test]
+    fn two_d6_is_triangular() {
+        let odds = odds("2d6").unwrap();
+        assert_eq!((odds.min(), odds.max()), (2, 12));
+        for total in 2..=12 {
+            let ways = 6 - (total - 7i64).abs();
+            assert_close(odds.probability_of(total), ways as f64 / 36.0);
+        }
+        assert_close(odds.mean(), 7.0);
+        assert_close(odds.variance(), 35.0 / 6.0);
+        assert_close(odds.probability_at_least(10), 6.0 / 36.0);
+    }
+
+    #[test]
+    fn four_d6_keep_highest_three_matches_known_odds() {
+        let odds = odds("4d6kh3").unwrap();
+        assert_eq!((odds.min(), odds.max()), (3, 18));
+        assert_close(odds.mean(), 15869.0 / 1296.0);
+        assert_close(odds.probability_of(18), 21.0 / 1296.0);
+        assert_close(odds.probability_of(3), 1.0 / 1296.0);
+        assert_close(odds.outcomes().map(|(_, p)| p).sum(), 1.0);
+        assert_eq!(odds, self::odds("4d6dl1").unwrap());
+    }
+
+    #[test]
+    fn advantage_and_disadvantage_on_a_d20() {
+        assert_close(odds("2d20kh1").unwrap().mean(), 13.825);
+        assert_close(odds("2d20kl1").unwrap().mean(), 7.175);
+        assert_close(odds("2d20kh1").unwrap().probability_of(20), 39.0 / 400.0);
+    }
+
+    #[test]
+    fn arithmetic_combines_independent_outcomes() {
+        let odds = odds("1d4 * 2 - 1").unwrap();
+        for total in [1, 3, 5, 7] {
+            assert_close(odds.probability_of(total), 0.25);
+        }
+        assert_close(self::odds("(1d6 + 1) / 2").unwrap().mean(), 2.0);
+        assert!(self::odds("1d6 / (1d2 - 1)").is_err());
+    }
+
+    #[test]
+    fn exploding_dice_approach_their_limit() {
+        // An exploding d6 averages 3.5 * 6/5
+        let odds = odds("1d6!").unwrap();
+        assert!((odds.mean() - 4.2).abs() < 1e-5);
+        assert_close(odds.probability_of(6), 0.0);
+        assert_close(odds.outcomes().map(|(_, p)| p).sum(), 1.0);
+    }
+
+    #[test]
+    fn refuses_expressions_too_big_to_compute() {
+        assert!(odds("60d1000!kh30").is_err());
+        assert!(odds("61d6kh3").is_err());
+        assert!(odds("1000d20").is_err());
+        assert!(odds("100d20").is_ok());
+    }
+}
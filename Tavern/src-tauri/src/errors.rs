@@ -5,6 +5,9 @@ use sqlx::Error as SqlxError;
 use sqlx::migrate::MigrateError as MigrateError;
 use uuid::Error as UuidError;
 use std::io::Error as IoError;
+use serde::{Deserialize, Serialize};
+
+use crate::database::models::MessageType;
 
 pub type AppResult<T> = Result<T, AppError>;
 
@@ -31,6 +34,12 @@ pub enum AppError {
     #[error("SQLx Migrate error: {0}")]
     MigrateError(#[from] MigrateError),
 
+    #[error("Network error: {0}")]
+    NetworkError(String),
+
+    #[error("Protocol error: {0}")]
+    ProtocolError(String),
+
     #[error("Serialization error: {0}")]
     SerdeError(#[from] serde_json::Error),
 
@@ -38,6 +47,40 @@ pub enum AppError {
     Other(String),
 }
 
+/// Why the host refused a message from a player, sent back to them
+#[derive(Debug, Clone, PartialEq, Eq, Error, Serialize, Deserialize)]
+#[serde(tag = "code")]
+pub enum PermissionError {
+    #[error("Observers can only watch")]
+    #[serde(rename = "read_only")]
+    ReadOnly,
+
+    #[error("Only the DM can {}", dm_action(.message_type))]
+    #[serde(rename = "dm_only")]
+    DmOnly { message_type: MessageType },
+
+    #[error("Join with the player key the DM gave you to move your tokens")]
+    #[serde(rename = "no_player_key")]
+    NoPlayerKey,
+
+    #[error("Token {token_id} isn't your character's")]
+    #[serde(rename = "not_your_token")]
+    NotYourToken { token_id: String },
+
+    #[error("Players can only move their tokens, not change them")]
+    #[serde(rename = "move_only")]
+    MoveOnly { token_id: String },
+}
+
+fn dm_action(message_type: &MessageType) -> &'static str {
+    match message_type {
+        MessageType::TokenUpdate => "place or remove tokens",
+        MessageType::MapChange => "change maps or fog",
+        MessageType::Initiative => "run initiative",
+        _ => "send that",
+    }
+}
+
 // Optional: Convert to Tauri InvokeError so your commands can return them directly
 impl From<AppError> for InvokeError {
     fn from(err: AppError) -> Self {
@@ -15,6 +15,12 @@ use crate::database::models::{Campaign, Character, Map, Token};
 mod errors;
 mod commands;
 mod networking;
+use crate::networking::NetworkManager;
+mod dice;
+mod combat;
+mod character;
+mod bestiary;
+mod compendium;
 use commands::*;
 
 fn main() {
@@ -38,6 +44,7 @@ fn main() {
 
     let database = Arc::new(Mutex::new(db.clone()));
     let app_state = Arc::new(Mutex::new(AppState::default()));
+    let network = Arc::new(Mutex::new(NetworkManager::default()));
 
     //dev code
     // let createCampaignData = database::models::CreateCampaignData {
@@ -102,6 +109,7 @@ fn main() {
         .plugin(tauri_plugin_dialog::init())
         .plugin(tauri_plugin_window_state::Builder::default().build())
         .manage(database)
+        .manage(network)
         .invoke_handler(tauri::generate_handler![
             get_app_version,
             restart_app,
@@ -124,8 +132,79 @@ fn main() {
             // create_token,
             // update_token_position,
             // delete_token,
-            // roll_dice,
-            // roll_initiative
+            roll_dice,
+            roll_skill_check,
+            roll_saving_throw,
+            roll_weapon_attack,
+            replay_dice_roll,
+            get_dice_statistics,
+            get_roll_history,
+            create_combat,
+            get_combats,
+            get_combat,
+            delete_combat,
+            add_combat_participant,
+            remove_combat_participant,
+            roll_initiative,
+            start_combat,
+            end_combat,
+            next_turn,
+            previous_turn,
+            delay_turn,
+            resume_turn,
+            ready_action,
+            trigger_readied_action,
+            apply_damage,
+            apply_healing,
+            grant_temporary_hit_points,
+            roll_death_save,
+            update_spellcasting,
+            cast_spell,
+            break_concentration,
+            short_rest,
+            long_rest,
+            get_rest_history,
+            create_encounter,
+            get_encounters,
+            get_encounter,
+            update_encounter,
+            delete_encounter,
+            rate_encounter,
+            deploy_encounter,
+            create_stat_block,
+            get_stat_blocks,
+            get_stat_block,
+            update_stat_block,
+            delete_stat_block,
+            spawn_stat_block,
+            apply_token_damage,
+            apply_token_healing,
+            set_token_conditions,
+            import_compendium,
+            get_compendium_entries,
+            get_compendium_entry,
+            add_compendium_entry,
+            search,
+            level_up,
+            get_character_sheet,
+            add_item,
+            remove_item,
+            transfer_item,
+            split_item,
+            stack_items,
+            equip_item,
+            unequip_item,
+            get_treasure_pool,
+            add_treasure,
+            distribute_treasure,
+            claim_treasure_item,
+            host_session,
+            join_session,
+            join_session_by_invite,
+            leave_session,
+            get_session,
+            send_network_message,
+            issue_player_key,
         ])
         .setup(|app| {
             // Window setup
@@ -1,4 +1,224 @@
-pub struct NetworkManager;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::database::models::{
+    HostSessionRequest, JoinInviteRequest, JoinSessionRequest, NetworkMessage, Payload, PeerInfo, SessionInfo,
+};
+use crate::errors::{AppError, AppResult};
+
+pub mod permissions;
+pub mod protocol;
+pub mod quic;
+pub mod session;
+pub mod signaling;
+pub mod transport;
+pub mod webrtc;
+
+use permissions::TokenOwners;
+use quic::{HostIdentity, QuicListener};
+use session::{ClientSession, HostSession, SessionEvents};
+use signaling::server::{self, SignalingServer};
+use transport::{Connection, Listener};
+use webrtc::{WebRtcConfig, WebRtcListener};
+
+/// The session this app is hosting or has joined, if any
+#[derive(Default)]
+pub struct NetworkManager {
+    session: Option<Session>,
+}
+
+enum Session {
+    Host {
+        session: HostSession,
+        info: SessionInfo,
+        /// The signaling server this app runs for the session, if any
+        signaling_server: Option<SignalingServer>,
+    },
+    Client { session: ClientSession, info: SessionInfo },
+}
+
 impl NetworkManager {
+    /// Host a session over QUIC with the certificate kept in `identity_directory`,
+    /// and over WebRTC for players with the invite code when it's listed on a
+    /// signaling server; players may move the tokens `tokens` says are theirs
+    pub async fn host(
+        &mut self,
+        request: HostSessionRequest,
+        identity_directory: &Path,
+        tokens: Arc<dyn TokenOwners>,
+        events: SessionEvents,
+    ) -> AppResult<SessionInfo> {
+        self.ensure_idle()?;
+        let identity = HostIdentity::load_or_generate(identity_directory)?;
+
+        // A server of our own is reached over loopback here and over the LAN
+        // by players
+        let (signaling_server, own_url, signaling_url) = if request.serve_signaling {
+            let address = SocketAddr::from(([0, 0, 0, 0], signaling::server::DEFAULT_PORT));
+            let server = SignalingServer::bind(address).await?;
+            let port = server.local_address().port();
+            let reachable = lan_address().map_or_else(|| "127.0.0.1".to_string(), |ip| ip.to_string());
+            (Some(server), Some(format!("ws://127.0.0.1:{}", port)), Some(format!("ws://{}:{}", reachable, port)))
+        } else {
+            (None, request.signaling_url.clone(), request.signaling_url.clone())
+        };
+        let listing = match &own_url {
+            Some(url) => Some(signaling::host(url).await?),
+            None => None,
+        };
+
+        let port = request.port.unwrap_or(quic::DEFAULT_PORT);
+        let listener = QuicListener::bind(SocketAddr::from(([0, 0, 0, 0], port)), &identity)?;
+        let local = listener.local_address()?;
+        let mut listeners: Vec<Arc<dyn Listener>> = vec![Arc::new(listener)];
+        let invite_code = listing.map(|listing| {
+            let config = WebRtcConfig { ice_servers: request.ice_servers.clone() };
+            listeners.push(Arc::new(WebRtcListener::new(listing.players, config)));
+            listing.invite_code
+        });
+
+        let session = HostSession::start(listeners, &request.name, request.password, tokens, events);
+        let address = match lan_address() {
+            Some(ip) => SocketAddr::new(ip, local.port()),
+            None => local,
+        };
+        let info = SessionInfo {
+            is_host: true,
+            peer_id: session.host().id.clone(),
+            address: address.to_string(),
+            fingerprint: identity.fingerprint(),
+            invite_code,
+            signaling_url,
+            protocol_version: protocol::PROTOCOL_VERSION,
+            peers: session.peers().await,
+        };
+        self.session = Some(Session::Host { session, info: info.clone(), signaling_server });
+        Ok(info)
+    }
+
+    /// Join a host's session over QUIC
+    pub async fn join(&mut self, request: JoinSessionRequest, events: SessionEvents) -> AppResult<SessionInfo> {
+        self.ensure_idle()?;
+        let connection = quic::connect(&request.address, &request.fingerprint).await?;
+        let session = ClientSession::join(
+            Arc::new(connection),
+            &request.name,
+            request.role,
+            request.password,
+            request.player_key,
+            events,
+        )
+        .await?;
+
+        let info = SessionInfo {
+            is_host: false,
+            peer_id: session.me().id.clone(),
+            address: request.address,
+            fingerprint: request.fingerprint,
+            invite_code: None,
+            signaling_url: None,
+            protocol_version: session.protocol_version(),
+            peers: session.peers().await,
+        };
+        self.session = Some(Session::Client { session, info: info.clone() });
+        Ok(info)
+    }
+
+    /// Join a session over WebRTC, finding the host by invite code
+    pub async fn join_by_invite(&mut self, request: JoinInviteRequest, events: SessionEvents) -> AppResult<SessionInfo> {
+        self.ensure_idle()?;
+        let signaling = signaling::join(&request.signaling_url, &request.invite_code).await?;
+        let config = WebRtcConfig { ice_servers: request.ice_servers };
+        let connection = webrtc::connect(signaling, &config).await?;
+        let address = connection.remote_address();
+        let session = ClientSession::join(
+            Arc::new(connection),
+            &request.name,
+            request.role,
+            request.password,
+            request.player_key,
+            events,
+        )
+        .await?;
+
+        let info = SessionInfo {
+            is_host: false,
+            peer_id: session.me().id.clone(),
+            address,
+            fingerprint: String::new(),
+            invite_code: Some(server::display_code(&server::normalize_code(&request.invite_code))),
+            signaling_url: Some(request.signaling_url),
+            protocol_version: session.protocol_version(),
+            peers: session.peers().await,
+        };
+        self.session = Some(Session::Client { session, info: info.clone() });
+        Ok(info)
+    }
+
+    /// End the session if hosting, or leave it
+    pub async fn leave(&mut self) {
+        match self.session.take() {
+            Some(Session::Host { session, signaling_server, .. }) => {
+                session.shutdown().await;
+                if let Some(server) = signaling_server {
+                    server.shutdown().await;
+                }
+            }
+            Some(Session::Client { session, .. }) => session.leave().await,
+            None => {}
+        }
+    }
+
+    pub async fn session_info(&self) -> Option<SessionInfo> {
+        let (mut info, peers) = match &self.session {
+            Some(Session::Host { session, info, .. }) => (info.clone(), session.peers().await),
+            Some(Session::Client { session, info }) => (info.clone(), session.peers().await),
+            None => return None,
+        };
+        info.peers = peers;
+        Some(info)
+    }
+
+    pub async fn peers(&self) -> Vec<PeerInfo> {
+        match &self.session {
+            Some(Session::Host { session, .. }) => session.peers().await,
+            Some(Session::Client { session, .. }) => session.peers().await,
+            None => Vec::new(),
+        }
+    }
+
+    /// Send a message to everyone in the session
+    pub async fn send(&self, payload: Payload) -> AppResult<NetworkMessage> {
+        match &self.session {
+            Some(Session::Host { session, .. }) => session.broadcast(payload).await,
+            Some(Session::Client { session, .. }) => session.send(payload).await,
+            None => Err(AppError::InvalidInput("Not in a session".to_string())),
+        }
+    }
+
+    /// A key for the player called `name`, when hosting; see
+    /// `HostSession::issue_player_key`
+    pub async fn issue_player_key(&self, name: &str) -> AppResult<String> {
+        match &self.session {
+            Some(Session::Host { session, .. }) => session.issue_player_key(name).await,
+            _ => Err(AppError::InvalidInput("Only the host can issue player keys".to_string())),
+        }
+    }
+
+    fn ensure_idle(&self) -> AppResult<()> {
+        match self.session {
+            Some(_) => Err(AppError::InvalidInput("Already in a session; leave it first".to_string())),
+            None => Ok(()),
+        }
+    }
+}
 
-}
\ No newline at end of file
+/// This machine's address on the local network, found by asking the OS
+/// which interface it would route through; nothing is sent
+fn lan_address() -> Option<IpAddr> {
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect("192.0.2.1:9").ok()?;
+    let ip = socket.local_addr().ok()?.ip();
+    (!ip.is_unspecified() && !ip.is_loopback()).then_some(ip)
+}
@@ -0,0 +1,272 @@
+//! What each role may do in a session
+//!
+//! The host checks every message a player sends against the role it admitted
+//! them with before acting on it or passing it on:
+//!
+//! - Observers are read-only: they hear everything they're sent and may send
+//!   nothing at all.
+//! - Players may chat, point, roll dice and move the tokens of characters
+//!   whose `player_name` is theirs, but not change those tokens in any other
+//!   way. Names are picked by whoever joins, so a player only owns tokens
+//!   once they've joined with the key the DM issued for their name.
+//! - Only the DM places, changes or removes tokens, changes maps or fog, and
+//!   runs initiative.
+//!
+//! Tokens are looked up on the maps of the campaign being hosted, never
+//! taken on a player's word.
+
+use async_trait::async_trait;
+
+use crate::database::models::{Payload, PeerInfo, PlayerRole, Token, TokenUpdate};
+use crate::database::DatabaseManager;
+use crate::errors::{AppResult, PermissionError};
+
+/// A token as the host has it, and the player whose character it is
+pub struct OwnedToken {
+    pub token: Token,
+    pub player_name: Option<String>,
+}
+
+/// Where the host looks up who a token belongs to
+#[async_trait]
+pub trait TokenOwners: Send + Sync {
+    async fn find_token(&self, map_id: &str, token_id: &str) -> AppResult<Option<OwnedToken>>;
+}
+
+/// Tokens on the maps of one campaign, as the host has them saved
+pub struct CampaignTokens {
+    database: DatabaseManager,
+    campaign_id: String,
+}
+
+impl CampaignTokens {
+    pub fn new(database: DatabaseManager, campaign_id: &str) -> Self {
+        Self { database, campaign_id: campaign_id.to_string() }
+    }
+}
+
+#[async_trait]
+impl TokenOwners for CampaignTokens {
+    async fn find_token(&self, map_id: &str, token_id: &str) -> AppResult<Option<OwnedToken>> {
+        // A map from another campaign is as good as missing
+        let map = self.database.get_map(map_id).await?;
+        let Some(map) = map.filter(|map| map.campaign_id == self.campaign_id) else {
+            return Ok(None);
+        };
+        let Some(token) = map.tokens.into_iter().find(|token| token.id == token_id) else {
+            return Ok(None);
+        };
+        let player_name = match &token.character_id {
+            Some(character_id) => self
+                .database
+                .get_character(character_id)
+                .await?
+                .filter(|character| character.campaign_id == map.campaign_id)
+                .and_then(|character| character.player_name),
+            None => None,
+        };
+        Ok(Some(OwnedToken { token, player_name }))
+    }
+}
+
+/// Whether `peer` may send `payload`
+pub async fn authorize(peer: &PeerInfo, payload: &Payload, tokens: &dyn TokenOwners) -> Result<(), PermissionError> {
+    let dm_only = || Err(PermissionError::DmOnly { message_type: payload.message_type() });
+    match (peer.role, payload) {
+        (PlayerRole::DungeonMaster, _) => Ok(()),
+        (PlayerRole::Observer, _) => Err(PermissionError::ReadOnly),
+
+        (PlayerRole::Player, Payload::Chat(_) | Payload::Cursor(_) | Payload::DiceRoll(_)) => Ok(()),
+        (PlayerRole::Player, Payload::TokenUpdate(TokenUpdate::Moved { map_id, token_id, .. })) => {
+            own_token(peer, map_id, token_id, tokens).await.map(|_| ())
+        }
+        (PlayerRole::Player, Payload::TokenUpdate(TokenUpdate::Changed { map_id, token })) => {
+            let current = own_token(peer, map_id, &token.id, tokens).await?;
+            if only_moved(&current, token) {
+                Ok(())
+            } else {
+                Err(PermissionError::MoveOnly { token_id: token.id.clone() })
+            }
+        }
+        (PlayerRole::Player, Payload::TokenUpdate(_) | Payload::MapChange(_) | Payload::Initiative(_)) => dm_only(),
+        // Session bookkeeping only comes from the host
+        (PlayerRole::Player, Payload::System(_)) => dm_only(),
+    }
+}
+
+/// The token, if it's one of `peer`'s characters on that map
+async fn own_token(peer: &PeerInfo, map_id: &str, token_id: &str, tokens: &dyn TokenOwners) -> Result<Token, PermissionError> {
+    if !peer.is_verified {
+        return Err(PermissionError::NoPlayerKey);
+    }
+    let not_yours = || PermissionError::NotYourToken { token_id: token_id.to_string() };
+    let owned = match tokens.find_token(map_id, token_id).await {
+        Ok(Some(owned)) => owned,
+        Ok(None) => return Err(not_yours()),
+        Err(error) => {
+            tracing::warn!("Couldn't look up token {} on map {}: {}", token_id, map_id, error);
+            return Err(not_yours());
+        }
+    };
+    match owned.player_name {
+        Some(player_name) if player_name.trim().eq_ignore_ascii_case(&peer.name) => Ok(owned.token),
+        _ => Err(not_yours()),
+    }
+}
+
+/// Whether `changed` is `current` with nothing but its position changed
+fn only_moved(current: &Token, changed: &Token) -> bool {
+    let mut moved = current.clone();
+    moved.position = changed.position.clone();
+    match (serde_json::to_value(&moved), serde_json::to_value(changed)) {
+        (Ok(moved), Ok(changed)) => moved == changed,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+    use crate::database::models::{ChatMessage, InitiativeUpdate, MapChange, Position, SystemMessage, TokenSize};
+    use crate::errors::AppError;
+
+    /// Ann's fighter and Bo's rogue, both on map `map`
+    struct Party;
+
+    #[async_trait]
+    impl TokenOwners for Party {
+        async fn find_token(&self, map_id: &str, token_id: &str) -> AppResult<Option<OwnedToken>> {
+            let player_name = match (map_id, token_id) {
+                ("map", "fighter") => "Ann",
+                ("map", "rogue") => "Bo",
+                _ => return Ok(None),
+            };
+            Ok(Some(OwnedToken { token: token(token_id), player_name: Some(player_name.to_string()) }))
+        }
+    }
+
+    struct Unreachable;
+
+    #[async_trait]
+    impl TokenOwners for Unreachable {
+        async fn find_token(&self, _map_id: &str, _token_id: &str) -> AppResult<Option<OwnedToken>> {
+            Err(AppError::Other("The database is locked".to_string()))
+        }
+    }
+
+    fn token(id: &str) -> Token {
+        Token {
+            id: id.to_string(),
+            character_id: Some(id.to_string()),
+            name: id.to_string(),
+            image_url: None,
+            position: Position { x: 0.0, y: 0.0, z: None },
+            size: TokenSize::Medium,
+            notes: String::new(),
+            is_hidden: false,
+            initiative: None,
+            stat_block_id: None,
+            instance: None,
+        }
+    }
+
+    fn peer(name: &str, role: PlayerRole, is_verified: bool) -> PeerInfo {
+        PeerInfo {
+            id: name.to_lowercase(),
+            name: name.to_string(),
+            role,
+            is_connected: true,
+            is_verified,
+            last_seen: Utc::now(),
+        }
+    }
+
+    fn ann() -> PeerInfo {
+        peer("Ann", PlayerRole::Player, true)
+    }
+
+    fn moved(token_id: &str) -> Payload {
+        let position = Position { x: 50.0, y: 50.0, z: None };
+        Payload::TokenUpdate(TokenUpdate::Moved { map_id: "map".to_string(), token_id: token_id.to_string(), position })
+    }
+
+    fn changed(token: Token) -> Payload {
+        Payload::TokenUpdate(TokenUpdate::Changed { map_id: "map".to_string(), token })
+    }
+
+    fn chat() -> Payload {
+        Payload::Chat(ChatMessage {
+            message: "hello".to_string(),
+            is_whisper: false,
+            target_players: None,
+            is_in_character: false,
+        })
+    }
+
+    #[tokio::test]
+    async fn observers_are_refused_everything() {
+        let observer = peer("Cy", PlayerRole::Observer, true);
+        for payload in [chat(), moved("fighter")] {
+            assert!(matches!(authorize(&observer, &payload, &Party).await, Err(PermissionError::ReadOnly)));
+        }
+    }
+
+    #[tokio::test]
+    async fn players_without_a_key_move_nothing() {
+        let unverified = peer("Ann", PlayerRole::Player, false);
+        assert!(authorize(&unverified, &chat(), &Party).await.is_ok());
+        let result = authorize(&unverified, &moved("fighter"), &Party).await;
+        assert!(matches!(result, Err(PermissionError::NoPlayerKey)));
+    }
+
+    #[tokio::test]
+    async fn players_move_only_their_own_tokens() {
+        assert!(authorize(&ann(), &moved("fighter"), &Party).await.is_ok());
+        // Names match whatever their case
+        assert!(authorize(&peer("ANN", PlayerRole::Player, true), &moved("fighter"), &Party).await.is_ok());
+        for token_id in ["rogue", "dragon"] {
+            let result = authorize(&ann(), &moved(token_id), &Party).await;
+            assert!(matches!(result, Err(PermissionError::NotYourToken { .. })), "{}", token_id);
+        }
+    }
+
+    #[tokio::test]
+    async fn changes_beyond_position_are_refused() {
+        let mut fighter = token("fighter");
+        fighter.position = Position { x: 100.0, y: 25.0, z: Some(10.0) };
+        assert!(authorize(&ann(), &changed(fighter.clone()), &Party).await.is_ok());
+
+        fighter.is_hidden = true;
+        let result = authorize(&ann(), &changed(fighter), &Party).await;
+        assert!(matches!(result, Err(PermissionError::MoveOnly { token_id }) if token_id == "fighter"));
+        let result = authorize(&ann(), &changed(token("rogue")), &Party).await;
+        assert!(matches!(result, Err(PermissionError::NotYourToken { .. })));
+    }
+
+    #[tokio::test]
+    async fn maps_initiative_and_session_messages_are_for_the_dm() {
+        let payloads = [
+            Payload::TokenUpdate(TokenUpdate::Removed { map_id: "map".to_string(), token_id: "fighter".to_string() }),
+            Payload::MapChange(MapChange::Opened { map_id: "map".to_string() }),
+            Payload::Initiative(InitiativeUpdate::TurnChanged {
+                combat_id: "combat".to_string(),
+                round: 1,
+                participant_id: "fighter".to_string(),
+            }),
+            Payload::System(SystemMessage::Hello { min_version: 1, max_version: 1 }),
+        ];
+        let dm = peer("Dee", PlayerRole::DungeonMaster, true);
+        for payload in payloads {
+            assert!(matches!(authorize(&ann(), &payload, &Party).await, Err(PermissionError::DmOnly { .. })));
+            assert!(authorize(&dm, &payload, &Party).await.is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn a_token_that_cannot_be_looked_up_is_not_yours() {
+        let result = authorize(&ann(), &moved("fighter"), &Unreachable).await;
+        assert!(matches!(result, Err(PermissionError::NotYourToken { .. })));
+    }
+}
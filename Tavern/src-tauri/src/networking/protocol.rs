@@ -0,0 +1,582 @@
+//! How messages are put on the wire
+//!
+//! Every message travels as a frame:
+//!
+//! ```text
+//! u32 length | u8 version | u8 encoding | body
+//! ```
+//!
+//! `length` (big-endian) counts everything after itself. The body is the
+//! message as JSON, or for token moves, which are sent many times a second
+//! while a token is dragged, a packed binary form.
+//!
+//! Before anything else a player's app sends a `Hello` with the range of
+//! versions it speaks, and the host answers with the highest version both
+//! speak or turns them away, saying why; a hello in a frame it can't read
+//! at all is answered the same way.
+
+use chrono::DateTime;
+
+use crate::database::models::{NetworkMessage, Payload, PeerInfo, Position, SystemMessage, TokenUpdate};
+use crate::errors::{AppError, AppResult};
+use crate::networking::session::new_message;
+use crate::networking::transport::Connection;
+
+/// Newest version of the protocol this app speaks
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Oldest version this app still speaks
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// Largest frame either side will accept, not counting the length prefix
+pub const MAX_FRAME_SIZE: usize = 4 * 1024 * 1024;
+
+const LENGTH_SIZE: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Json = 0,
+    /// Packed token moves; see `encode_token_move`
+    Binary = 1,
+}
+
+impl Encoding {
+    fn from_byte(byte: u8) -> AppResult<Self> {
+        match byte {
+            0 => Ok(Encoding::Json),
+            1 => Ok(Encoding::Binary),
+            other => Err(AppError::ProtocolError(format!("Unknown encoding {}", other))),
+        }
+    }
+}
+
+// =============================================================================
+// Frames
+// =============================================================================
+
+/// A message as a frame ready to send, length prefix and all, in the most
+/// compact encoding it has
+pub fn encode_frame(message: &NetworkMessage) -> AppResult<Vec<u8>> {
+    let encoding = match &message.payload {
+        Payload::TokenUpdate(TokenUpdate::Moved { .. }) => Encoding::Binary,
+        _ => Encoding::Json,
+    };
+    encode_frame_as(message, encoding)
+}
+
+/// A message as a frame in the given encoding; binary falls back to JSON for
+/// anything but a token move that fits it
+pub fn encode_frame_as(message: &NetworkMessage, encoding: Encoding) -> AppResult<Vec<u8>> {
+    let binary = match encoding {
+        Encoding::Binary => encode_token_move(message),
+        Encoding::Json => None,
+    };
+    let (encoding, body) = match binary {
+        Some(body) => (Encoding::Binary, body),
+        None => (Encoding::Json, serde_json::to_vec(message)?),
+    };
+
+    let length = body.len() + 2;
+    if length > MAX_FRAME_SIZE {
+        return Err(AppError::InvalidInput(format!(
+            "Message is {} bytes; the most that can be sent is {}",
+            length, MAX_FRAME_SIZE
+        )));
+    }
+    let mut frame = Vec::with_capacity(LENGTH_SIZE + length);
+    frame.extend_from_slice(&(length as u32).to_be_bytes());
+    frame.push(PROTOCOL_VERSION as u8);
+    frame.push(encoding as u8);
+    frame.extend_from_slice(&body);
+    Ok(frame)
+}
+
+/// How many bytes follow a frame's length prefix
+pub fn frame_length(prefix: [u8; LENGTH_SIZE]) -> AppResult<usize> {
+    let length = u32::from_be_bytes(prefix) as usize;
+    if length > MAX_FRAME_SIZE {
+        return Err(AppError::ProtocolError(format!("Frame of {} bytes is too large", length)));
+    }
+    Ok(length)
+}
+
+/// Read a frame from just after its length prefix
+pub fn decode_frame(frame: &[u8]) -> AppResult<NetworkMessage> {
+    let [version, encoding, body @ ..] = frame else {
+        return Err(AppError::ProtocolError("Frame is too short".to_string()));
+    };
+    let version = *version as u16;
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+        return Err(AppError::ProtocolError(format!(
+            "Frame is protocol version {}; this app speaks {}",
+            version,
+            version_range(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION)
+        )));
+    }
+    match Encoding::from_byte(*encoding)? {
+        Encoding::Json => serde_json::from_slice(body)
+            .map_err(|error| AppError::ProtocolError(format!("Malformed message: {}", error))),
+        Encoding::Binary => decode_token_move(body),
+    }
+}
+
+/// Read a frame sent whole in one datagram, length prefix and all
+pub fn decode_datagram(datagram: &[u8]) -> AppResult<NetworkMessage> {
+    let Some((prefix, frame)) = datagram.split_first_chunk::<LENGTH_SIZE>() else {
+        return Err(AppError::ProtocolError("Datagram is too short".to_string()));
+    };
+    if frame_length(*prefix)? != frame.len() {
+        return Err(AppError::ProtocolError("Datagram doesn't hold exactly one frame".to_string()));
+    }
+    decode_frame(frame)
+}
+
+/// Gathers frames from a transport that delivers them in pieces, in order
+#[derive(Default)]
+pub struct FrameBuffer {
+    bytes: Vec<u8>,
+}
+
+impl FrameBuffer {
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    /// The next whole frame, or `None` until more of it arrives
+    ///
+    /// A frame that can't be read is a `ProtocolError` and is skipped. A
+    /// length that's too large is a `NetworkError`, since there's no telling
+    /// where the next frame starts.
+    pub fn next_frame(&mut self) -> AppResult<Option<NetworkMessage>> {
+        let Some(prefix) = self.bytes.first_chunk::<LENGTH_SIZE>() else {
+            return Ok(None);
+        };
+        let length = match frame_length(*prefix) {
+            Ok(length) => length,
+            Err(AppError::ProtocolError(reason)) => return Err(AppError::NetworkError(reason)),
+            Err(error) => return Err(error),
+        };
+        if self.bytes.len() < LENGTH_SIZE + length {
+            return Ok(None);
+        }
+        let frame: Vec<u8> = self.bytes.drain(..LENGTH_SIZE + length).skip(LENGTH_SIZE).collect();
+        decode_frame(&frame).map(Some)
+    }
+}
+
+// =============================================================================
+// Binary Token Moves
+// =============================================================================
+
+/// A token move packed as:
+///
+/// ```text
+/// id | sender id | sender name | i64 timestamp (ms) | map id | token id
+///    | f32 x | f32 y | u8 has elevation | [f32 elevation]
+/// ```
+///
+/// Strings are a u8 length then UTF-8 and numbers are big-endian. `None`
+/// when the message isn't a token move or a string is too long to pack.
+fn encode_token_move(message: &NetworkMessage) -> Option<Vec<u8>> {
+    let Payload::TokenUpdate(TokenUpdate::Moved { map_id, token_id, position }) = &message.payload else {
+        return None;
+    };
+    let mut body = Vec::with_capacity(128);
+    for text in [&message.id, &message.sender_id, &message.sender_name] {
+        put_str(&mut body, text)?;
+    }
+    body.extend_from_slice(&message.timestamp.timestamp_millis().to_be_bytes());
+    put_str(&mut body, map_id)?;
+    put_str(&mut body, token_id)?;
+    body.extend_from_slice(&position.x.to_be_bytes());
+    body.extend_from_slice(&position.y.to_be_bytes());
+    match position.z {
+        Some(z) => {
+            body.push(1);
+            body.extend_from_slice(&z.to_be_bytes());
+        }
+        None => body.push(0),
+    }
+    Some(body)
+}
+
+fn put_str(body: &mut Vec<u8>, text: &str) -> Option<()> {
+    body.push(u8::try_from(text.len()).ok()?);
+    body.extend_from_slice(text.as_bytes());
+    Some(())
+}
+
+fn decode_token_move(body: &[u8]) -> AppResult<NetworkMessage> {
+    let mut reader = Reader { bytes: body };
+    let id = reader.str()?;
+    let sender_id = reader.str()?;
+    let sender_name = reader.str()?;
+    let timestamp = DateTime::from_timestamp_millis(reader.i64()?)
+        .ok_or_else(|| AppError::ProtocolError("Token move has an invalid timestamp".to_string()))?;
+    let map_id = reader.str()?;
+    let token_id = reader.str()?;
+    let x = reader.f32()?;
+    let y = reader.f32()?;
+    let z = match reader.take(1)?[0] {
+        0 => None,
+        _ => Some(reader.f32()?),
+    };
+    if !reader.bytes.is_empty() {
+        return Err(AppError::ProtocolError("Token move has trailing bytes".to_string()));
+    }
+
+    Ok(NetworkMessage {
+        id,
+        sender_id,
+        sender_name,
+        payload: Payload::TokenUpdate(TokenUpdate::Moved { map_id, token_id, position: Position { x, y, z } }),
+        timestamp,
+    })
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> AppResult<&'a [u8]> {
+        if self.bytes.len() < count {
+            return Err(AppError::ProtocolError("Token move is cut short".to_string()));
+        }
+        let (taken, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn str(&mut self) -> AppResult<String> {
+        let length = self.take(1)?[0] as usize;
+        String::from_utf8(self.take(length)?.to_vec())
+            .map_err(|_| AppError::ProtocolError("Token move has a string that isn't UTF-8".to_string()))
+    }
+
+    fn i64(&mut self) -> AppResult<i64> {
+        Ok(i64::from_be_bytes(self.take(8)?.try_into().expect("took 8 bytes")))
+    }
+
+    fn f32(&mut self) -> AppResult<f32> {
+        Ok(f32::from_be_bytes(self.take(4)?.try_into().expect("took 4 bytes")))
+    }
+}
+
+// =============================================================================
+// Version Handshake
+// =============================================================================
+
+/// The version for the host to speak with a player whose app speaks
+/// `min..=max`
+pub fn negotiate(min: u16, max: u16) -> AppResult<u16> {
+    let version = max.min(PROTOCOL_VERSION);
+    if min > max || version < min.max(MIN_PROTOCOL_VERSION) {
+        let outdated = if min > PROTOCOL_VERSION { "the host's app" } else { "your app" };
+        return Err(AppError::ProtocolError(format!(
+            "Incompatible versions: the host speaks protocol {} and your app speaks {}; {} needs updating",
+            version_range(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION),
+            version_range(min, max),
+            outdated
+        )));
+    }
+    Ok(version)
+}
+
+/// Tell the host which versions this app speaks; returns the one it picked
+pub async fn offer_version(connection: &dyn Connection, me: &PeerInfo) -> AppResult<u16> {
+    let hello = SystemMessage::Hello { min_version: MIN_PROTOCOL_VERSION, max_version: PROTOCOL_VERSION };
+    connection.send(&new_message(me, Payload::System(hello))).await?;
+
+    match connection.recv().await?.map(|message| message.payload) {
+        Some(Payload::System(SystemMessage::Version { version })) => {
+            if (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+                Ok(version)
+            } else {
+                Err(AppError::ProtocolError(format!("The host picked protocol {}, which this app doesn't speak", version)))
+            }
+        }
+        Some(Payload::System(SystemMessage::Rejected { reason })) => Err(AppError::ProtocolError(reason)),
+        Some(_) => Err(AppError::ProtocolError("The host didn't answer the hello".to_string())),
+        None => Err(AppError::NetworkError("The host closed the connection".to_string())),
+    }
+}
+
+/// Read a player's hello and agree a version with them, telling them why
+/// not when there isn't one
+pub async fn accept_version(connection: &dyn Connection, host: &PeerInfo) -> AppResult<u16> {
+    let result = match connection.recv().await {
+        Ok(Some(message)) => match message.payload {
+            Payload::System(SystemMessage::Hello { min_version, max_version }) => negotiate(min_version, max_version),
+            _ => Err(AppError::ProtocolError("Expected a hello".to_string())),
+        },
+        Ok(None) => return Err(AppError::NetworkError("The player closed the connection".to_string())),
+        Err(error @ AppError::ProtocolError(_)) => Err(error),
+        Err(error) => return Err(error),
+    };
+    let reply = match &result {
+        Ok(version) => SystemMessage::Version { version: *version },
+        Err(AppError::ProtocolError(reason)) => SystemMessage::Rejected { reason: reason.clone() },
+        Err(error) => SystemMessage::Rejected { reason: error.to_string() },
+    };
+    connection.send(&new_message(host, Payload::System(reply))).await?;
+    result
+}
+
+fn version_range(min: u16, max: u16) -> String {
+    if min == max {
+        format!("version {}", min)
+    } else {
+        format!("versions {} to {}", min, max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    use async_trait::async_trait;
+    use chrono::Utc;
+
+    use super::*;
+    use crate::database::models::{ChatMessage, PlayerRole};
+
+    /// A connection that hands out scripted replies and keeps what's sent
+    #[derive(Default)]
+    struct Scripted {
+        incoming: Mutex<VecDeque<AppResult<Option<NetworkMessage>>>>,
+        sent: Mutex<Vec<NetworkMessage>>,
+    }
+
+    impl Scripted {
+        fn replying(reply: AppResult<Option<NetworkMessage>>) -> Self {
+            Self { incoming: Mutex::new(VecDeque::from([reply])), ..Default::default() }
+        }
+
+        fn sent(&self) -> Vec<SystemMessage> {
+            let sent = self.sent.lock().unwrap();
+            sent.iter()
+                .map(|message| match &message.payload {
+                    Payload::System(system) => system.clone(),
+                    other => panic!("expected a system message, got {:?}", other),
+                })
+                .collect()
+        }
+    }
+
+    #[async_trait]
+    impl Connection for Scripted {
+        fn remote_address(&self) -> String {
+            "scripted".to_string()
+        }
+
+        async fn send(&self, message: &NetworkMessage) -> AppResult<()> {
+            self.sent.lock().unwrap().push(message.clone());
+            Ok(())
+        }
+
+        async fn recv(&self) -> AppResult<Option<NetworkMessage>> {
+            self.incoming.lock().unwrap().pop_front().unwrap_or(Ok(None))
+        }
+
+        async fn close(&self, _reason: &str) {}
+    }
+
+    fn peer() -> PeerInfo {
+        PeerInfo {
+            id: "peer".to_string(),
+            name: "Alice".to_string(),
+            role: PlayerRole::Player,
+            is_connected: true,
+            is_verified: false,
+            last_seen: Utc::now(),
+        }
+    }
+
+    fn system(message: SystemMessage) -> AppResult<Option<NetworkMessage>> {
+        Ok(Some(new_message(&peer(), Payload::System(message))))
+    }
+
+    fn chat(text: &str) -> NetworkMessage {
+        let chat = ChatMessage {
+            message: text.to_string(),
+            is_whisper: false,
+            target_players: None,
+            is_in_character: true,
+        };
+        new_message(&peer(), Payload::Chat(chat))
+    }
+
+    fn token_move(token_id: &str, z: Option<f32>) -> NetworkMessage {
+        let position = Position { x: 12.5, y: -3.0, z };
+        let payload = TokenUpdate::Moved { map_id: "map".to_string(), token_id: token_id.to_string(), position };
+        let mut message = new_message(&peer(), Payload::TokenUpdate(payload));
+        // The binary form keeps milliseconds
+        message.timestamp = DateTime::from_timestamp_millis(message.timestamp.timestamp_millis()).unwrap();
+        message
+    }
+
+    fn same(a: &NetworkMessage, b: &NetworkMessage) -> bool {
+        serde_json::to_value(a).unwrap() == serde_json::to_value(b).unwrap()
+    }
+
+    fn is_protocol_error<T>(result: AppResult<T>) -> bool {
+        matches!(result, Err(AppError::ProtocolError(_)))
+    }
+
+    #[test]
+    fn negotiation_picks_the_highest_shared_version() {
+        assert_eq!(negotiate(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION).unwrap(), PROTOCOL_VERSION);
+        assert_eq!(negotiate(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION + 5).unwrap(), PROTOCOL_VERSION);
+
+        let Err(AppError::ProtocolError(reason)) = negotiate(PROTOCOL_VERSION + 1, PROTOCOL_VERSION + 2) else {
+            panic!("a newer player should be refused");
+        };
+        assert!(reason.contains("the host's app needs updating"));
+        let Err(AppError::ProtocolError(reason)) = negotiate(0, MIN_PROTOCOL_VERSION - 1) else {
+            panic!("an older player should be refused");
+        };
+        assert!(reason.contains("your app needs updating"));
+        assert!(is_protocol_error(negotiate(PROTOCOL_VERSION, MIN_PROTOCOL_VERSION - 1)));
+    }
+
+    #[tokio::test]
+    async fn the_host_answers_a_hello_with_a_version_or_a_reason() {
+        let hello = |min_version, max_version| system(SystemMessage::Hello { min_version, max_version });
+
+        let connection = Scripted::replying(hello(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION));
+        assert_eq!(accept_version(&connection, &peer()).await.unwrap(), PROTOCOL_VERSION);
+        assert!(matches!(connection.sent()[..], [SystemMessage::Version { version }] if version == PROTOCOL_VERSION));
+
+        let connection = Scripted::replying(hello(PROTOCOL_VERSION + 1, PROTOCOL_VERSION + 1));
+        assert!(is_protocol_error(accept_version(&connection, &peer()).await));
+        assert!(matches!(connection.sent()[..], [SystemMessage::Rejected { .. }]));
+
+        let connection = Scripted::replying(Ok(Some(chat("hi"))));
+        assert!(is_protocol_error(accept_version(&connection, &peer()).await));
+        assert!(matches!(&connection.sent()[..], [SystemMessage::Rejected { reason }] if reason == "Expected a hello"));
+
+        // A hello that couldn't be read is answered too
+        let connection = Scripted::replying(Err(AppError::ProtocolError("Malformed message".to_string())));
+        assert!(is_protocol_error(accept_version(&connection, &peer()).await));
+        assert_eq!(connection.sent().len(), 1);
+
+        let connection = Scripted::default();
+        assert!(matches!(accept_version(&connection, &peer()).await, Err(AppError::NetworkError(_))));
+        assert!(connection.sent().is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_player_offers_its_versions_and_takes_the_answer() {
+        let connection = Scripted::replying(system(SystemMessage::Version { version: PROTOCOL_VERSION }));
+        assert_eq!(offer_version(&connection, &peer()).await.unwrap(), PROTOCOL_VERSION);
+        assert!(matches!(
+            connection.sent()[..],
+            [SystemMessage::Hello { min_version: MIN_PROTOCOL_VERSION, max_version: PROTOCOL_VERSION }]
+        ));
+
+        let rejected = system(SystemMessage::Rejected { reason: "Too old".to_string() });
+        let result = offer_version(&Scripted::replying(rejected), &peer()).await;
+        assert!(matches!(result, Err(AppError::ProtocolError(reason)) if reason == "Too old"));
+        let unspoken = system(SystemMessage::Version { version: PROTOCOL_VERSION + 1 });
+        assert!(is_protocol_error(offer_version(&Scripted::replying(unspoken), &peer()).await));
+        assert!(is_protocol_error(offer_version(&Scripted::replying(Ok(Some(chat("hi")))), &peer()).await));
+        let closed = offer_version(&Scripted::default(), &peer()).await;
+        assert!(matches!(closed, Err(AppError::NetworkError(_))));
+    }
+
+    #[test]
+    fn json_frames_round_trip() {
+        let message = chat("hello");
+        let frame = encode_frame(&message).unwrap();
+        let length = frame_length(frame[..LENGTH_SIZE].try_into().unwrap()).unwrap();
+        assert_eq!(length, frame.len() - LENGTH_SIZE);
+        assert_eq!(frame[LENGTH_SIZE..LENGTH_SIZE + 2], [PROTOCOL_VERSION as u8, Encoding::Json as u8]);
+        assert!(same(&decode_frame(&frame[LENGTH_SIZE..]).unwrap(), &message));
+        assert!(same(&decode_datagram(&frame).unwrap(), &message));
+        // Only token moves have a binary form
+        assert_eq!(encode_frame_as(&message, Encoding::Binary).unwrap(), frame);
+    }
+
+    #[test]
+    fn token_moves_travel_packed() {
+        for z in [None, Some(5.0)] {
+            let message = token_move("goblin", z);
+            let frame = encode_frame(&message).unwrap();
+            assert_eq!(frame[LENGTH_SIZE + 1], Encoding::Binary as u8);
+            assert!(frame.len() < encode_frame_as(&message, Encoding::Json).unwrap().len());
+            assert!(same(&decode_frame(&frame[LENGTH_SIZE..]).unwrap(), &message));
+        }
+
+        // Too long a string to pack falls back to JSON
+        let message = token_move(&"x".repeat(300), None);
+        let frame = encode_frame(&message).unwrap();
+        assert_eq!(frame[LENGTH_SIZE + 1], Encoding::Json as u8);
+        assert!(same(&decode_frame(&frame[LENGTH_SIZE..]).unwrap(), &message));
+    }
+
+    #[test]
+    fn unreadable_frames_are_protocol_errors() {
+        let frame = encode_frame(&token_move("goblin", None)).unwrap();
+        let body = &frame[LENGTH_SIZE..];
+        assert!(is_protocol_error(decode_frame(&body[..1])));
+        assert!(is_protocol_error(decode_frame(&body[..body.len() - 1])));
+        assert!(is_protocol_error(decode_frame(&[body, &[0]].concat())));
+
+        let mut other_version = body.to_vec();
+        other_version[0] = PROTOCOL_VERSION as u8 + 1;
+        assert!(is_protocol_error(decode_frame(&other_version)));
+        let mut other_encoding = body.to_vec();
+        other_encoding[1] = 9;
+        assert!(is_protocol_error(decode_frame(&other_encoding)));
+        assert!(is_protocol_error(decode_frame(&[PROTOCOL_VERSION as u8, Encoding::Json as u8, b'{'])));
+        assert!(is_protocol_error(decode_datagram(&frame[..frame.len() - 1])));
+    }
+
+    #[test]
+    fn frames_are_limited_in_size() {
+        assert_eq!(frame_length((MAX_FRAME_SIZE as u32).to_be_bytes()).unwrap(), MAX_FRAME_SIZE);
+        assert!(is_protocol_error(frame_length((MAX_FRAME_SIZE as u32 + 1).to_be_bytes())));
+        let too_long = chat(&"x".repeat(MAX_FRAME_SIZE));
+        assert!(matches!(encode_frame(&too_long), Err(AppError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn frame_buffers_gather_pieces_and_split_runs_of_frames() {
+        let (first, second) = (chat("first"), token_move("goblin", Some(1.0)));
+        let bytes = [encode_frame(&first).unwrap(), encode_frame(&second).unwrap()].concat();
+
+        // One byte at a time
+        let mut buffer = FrameBuffer::default();
+        let mut received = Vec::new();
+        for byte in &bytes {
+            buffer.push(std::slice::from_ref(byte));
+            if let Some(message) = buffer.next_frame().unwrap() {
+                received.push(message);
+            }
+        }
+        assert_eq!(received.len(), 2);
+        assert!(same(&received[0], &first) && same(&received[1], &second));
+
+        // Both at once
+        buffer.push(&bytes);
+        assert!(same(&buffer.next_frame().unwrap().unwrap(), &first));
+        assert!(same(&buffer.next_frame().unwrap().unwrap(), &second));
+        assert!(buffer.next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn frame_buffers_skip_bad_frames_but_not_bad_lengths() {
+        let mut buffer = FrameBuffer::default();
+        buffer.push(&[0, 0, 0, 2, PROTOCOL_VERSION as u8, 9]);
+        let after = chat("after");
+        buffer.push(&encode_frame(&after).unwrap());
+        assert!(is_protocol_error(buffer.next_frame()));
+        assert!(same(&buffer.next_frame().unwrap().unwrap(), &after));
+
+        let mut buffer = FrameBuffer::default();
+        buffer.push(&(MAX_FRAME_SIZE as u32 + 1).to_be_bytes());
+        assert!(matches!(buffer.next_frame(), Err(AppError::NetworkError(_))));
+    }
+}
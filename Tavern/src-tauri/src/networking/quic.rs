@@ -0,0 +1,498 @@
+use std::fmt;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use quinn::{ConnectionError, Endpoint, ReadError, ReadExactError, RecvStream, SendStream};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use rustls::{CertificateError, DigitallySignedStruct, SignatureScheme};
+use sha2::{Digest, Sha256};
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::timeout;
+
+use crate::database::models::NetworkMessage;
+use crate::errors::{AppError, AppResult};
+use crate::networking::protocol;
+use crate::networking::transport::{Connection, Listener};
+
+/// Port the host listens on unless told otherwise
+pub const DEFAULT_PORT: u16 = 7350;
+
+/// Application protocol both ends must agree on during the TLS handshake
+const ALPN: &[u8] = b"tavern";
+
+/// Name the host's certificate is issued to; players check its fingerprint
+/// rather than the name, since the host is reached by IP address
+const SERVER_NAME: &str = "tavern";
+
+const CERTIFICATE_FILE: &str = "host_certificate.der";
+const KEY_FILE: &str = "host_key.der";
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const KEEP_ALIVE: Duration = Duration::from_secs(5);
+/// How long closing waits for the peer to read what was last sent
+const CLOSE_GRACE: Duration = Duration::from_secs(1);
+
+// =============================================================================
+// Host Identity
+// =============================================================================
+
+/// The self-signed certificate the host proves itself with; players pin its
+/// fingerprint instead of trusting a certificate authority
+pub struct HostIdentity {
+    certificate: CertificateDer<'static>,
+    key: PrivatePkcs8KeyDer<'static>,
+}
+
+impl HostIdentity {
+    pub fn generate() -> AppResult<Self> {
+        let certified = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string()])
+            .map_err(|error| AppError::Other(format!("Couldn't create a certificate: {}", error)))?;
+        Ok(Self {
+            certificate: certified.cert.der().clone(),
+            key: PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()),
+        })
+    }
+
+    /// The identity saved in `directory`, made and saved the first time, so
+    /// the fingerprint players have pinned stays the same between sessions
+    pub fn load_or_generate(directory: &Path) -> AppResult<Self> {
+        let certificate_path = directory.join(CERTIFICATE_FILE);
+        let key_path = directory.join(KEY_FILE);
+        if certificate_path.exists() && key_path.exists() {
+            return Ok(Self {
+                certificate: CertificateDer::from(std::fs::read(certificate_path)?),
+                key: PrivatePkcs8KeyDer::from(std::fs::read(key_path)?),
+            });
+        }
+
+        let identity = Self::generate()?;
+        std::fs::create_dir_all(directory)?;
+        std::fs::write(certificate_path, identity.certificate.as_ref())?;
+        write_private(&key_path, identity.key.secret_pkcs8_der())?;
+        Ok(identity)
+    }
+
+    /// SHA-256 of the certificate, as colon-separated hex
+    pub fn fingerprint(&self) -> String {
+        format_fingerprint(&certificate_fingerprint(&self.certificate))
+    }
+}
+
+/// Write the host's private key so only the user running Tavern can read it
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(contents)
+}
+
+fn certificate_fingerprint(certificate: &CertificateDer<'_>) -> [u8; 32] {
+    Sha256::digest(certificate.as_ref()).into()
+}
+
+fn format_fingerprint(fingerprint: &[u8; 32]) -> String {
+    fingerprint.iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<_>>().join(":")
+}
+
+/// Read a fingerprint as shared by the host, with or without colons
+fn parse_fingerprint(fingerprint: &str) -> AppResult<[u8; 32]> {
+    let digits: String = fingerprint.chars().filter(|c| !c.is_whitespace() && *c != ':').collect();
+    let invalid = || AppError::InvalidInput(format!("Not a certificate fingerprint: {}", fingerprint));
+    if digits.len() != 64 || !digits.is_ascii() {
+        return Err(invalid());
+    }
+    let mut bytes = [0u8; 32];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&digits[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(bytes)
+}
+
+/// Accepts only the certificate with the pinned fingerprint, while still
+/// checking the handshake was signed by its key
+struct PinnedCertificate {
+    fingerprint: [u8; 32],
+    provider: Arc<CryptoProvider>,
+}
+
+impl fmt::Debug for PinnedCertificate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PinnedCertificate({})", format_fingerprint(&self.fingerprint))
+    }
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if certificate_fingerprint(end_entity) == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, certificate, signature, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, certificate, signature, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+// =============================================================================
+// Endpoints
+// =============================================================================
+
+fn transport_config() -> Arc<quinn::TransportConfig> {
+    let mut transport = quinn::TransportConfig::default();
+    transport.keep_alive_interval(Some(KEEP_ALIVE));
+    Arc::new(transport)
+}
+
+fn server_config(identity: &HostIdentity) -> AppResult<quinn::ServerConfig> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut crypto = rustls::ServerConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .and_then(|builder| {
+            builder.with_no_client_auth().with_single_cert(
+                vec![identity.certificate.clone()],
+                PrivateKeyDer::Pkcs8(identity.key.clone_key()),
+            )
+        })
+        .map_err(network_error)?;
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+
+    let crypto = QuicServerConfig::try_from(crypto).map_err(network_error)?;
+    let mut config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    config.transport_config(transport_config());
+    Ok(config)
+}
+
+fn client_config(fingerprint: [u8; 32]) -> AppResult<quinn::ClientConfig> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let verifier = PinnedCertificate { fingerprint, provider: provider.clone() };
+    let mut crypto = rustls::ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(network_error)?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+
+    let crypto = QuicClientConfig::try_from(crypto).map_err(network_error)?;
+    let mut config = quinn::ClientConfig::new(Arc::new(crypto));
+    config.transport_config(transport_config());
+    Ok(config)
+}
+
+/// The host's QUIC endpoint; connections are set up in the background so a
+/// slow handshake doesn't hold up anyone else joining
+pub struct QuicListener {
+    endpoint: Endpoint,
+    connections: Mutex<mpsc::Receiver<QuicConnection>>,
+}
+
+impl QuicListener {
+    pub fn bind(address: SocketAddr, identity: &HostIdentity) -> AppResult<Self> {
+        let endpoint = Endpoint::server(server_config(identity)?, address)?;
+        let (sender, receiver) = mpsc::channel(16);
+        tokio::spawn(accept_connections(endpoint.clone(), sender));
+        Ok(Self {
+            endpoint,
+            connections: Mutex::new(receiver),
+        })
+    }
+
+    pub fn local_address(&self) -> AppResult<SocketAddr> {
+        Ok(self.endpoint.local_addr()?)
+    }
+}
+
+#[async_trait]
+impl Listener for QuicListener {
+    async fn accept(&self) -> AppResult<Option<Box<dyn Connection>>> {
+        let connection = self.connections.lock().await.recv().await;
+        Ok(connection.map(|connection| Box::new(connection) as Box<dyn Connection>))
+    }
+
+    fn close(&self) {
+        self.endpoint.close(0u32.into(), b"Session ended");
+    }
+}
+
+async fn accept_connections(endpoint: Endpoint, connections: mpsc::Sender<QuicConnection>) {
+    while let Some(incoming) = endpoint.accept().await {
+        let connections = connections.clone();
+        tokio::spawn(async move {
+            let address = incoming.remote_address();
+            match timeout(HANDSHAKE_TIMEOUT, open_incoming(incoming)).await {
+                Ok(Ok(connection)) => {
+                    let _ = connections.send(connection).await;
+                }
+                Ok(Err(error)) => tracing::warn!("Connection from {} failed: {}", address, error),
+                Err(_) => tracing::warn!("Connection from {} timed out", address),
+            }
+        });
+    }
+}
+
+async fn open_incoming(incoming: quinn::Incoming) -> AppResult<QuicConnection> {
+    let connection = incoming.await.map_err(network_error)?;
+    let (send, recv) = connection.accept_bi().await.map_err(network_error)?;
+    Ok(QuicConnection::new(connection, send, recv, None))
+}
+
+/// Connect to a host, trusting only the certificate with `fingerprint`
+pub async fn connect(address: &str, fingerprint: &str) -> AppResult<QuicConnection> {
+    let fingerprint = parse_fingerprint(fingerprint)?;
+    let address = tokio::net::lookup_host(address)
+        .await?
+        .next()
+        .ok_or_else(|| AppError::InvalidInput(format!("Couldn't find {}", address)))?;
+
+    let local: SocketAddr = if address.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" }
+        .parse()
+        .expect("valid unspecified address");
+    let mut endpoint = Endpoint::client(local)?;
+    endpoint.set_default_client_config(client_config(fingerprint)?);
+
+    let connecting = endpoint.connect(address, SERVER_NAME).map_err(network_error)?;
+    let connection = timeout(HANDSHAKE_TIMEOUT, connecting)
+        .await
+        .map_err(|_| AppError::NetworkError(format!("Timed out connecting to {}", address)))?
+        .map_err(network_error)?;
+    // The host sees the stream once the first message is written on it
+    let (send, recv) = connection.open_bi().await.map_err(network_error)?;
+    Ok(QuicConnection::new(connection, send, recv, Some(endpoint)))
+}
+
+// =============================================================================
+// Connections
+// =============================================================================
+
+/// A QUIC connection carrying protocol frames on one bidirectional stream
+pub struct QuicConnection {
+    connection: quinn::Connection,
+    send: Mutex<SendStream>,
+    recv: Mutex<RecvStream>,
+    /// A player's own endpoint, kept for as long as their connection
+    endpoint: Option<Endpoint>,
+}
+
+impl QuicConnection {
+    fn new(connection: quinn::Connection, send: SendStream, recv: RecvStream, endpoint: Option<Endpoint>) -> Self {
+        Self {
+            connection,
+            send: Mutex::new(send),
+            recv: Mutex::new(recv),
+            endpoint,
+        }
+    }
+}
+
+#[async_trait]
+impl Connection for QuicConnection {
+    fn remote_address(&self) -> String {
+        self.connection.remote_address().to_string()
+    }
+
+    async fn send(&self, message: &NetworkMessage) -> AppResult<()> {
+        let frame = protocol::encode_frame(message)?;
+        self.send.lock().await.write_all(&frame).await.map_err(network_error)?;
+        Ok(())
+    }
+
+    async fn recv(&self) -> AppResult<Option<NetworkMessage>> {
+        let mut recv = self.recv.lock().await;
+        let mut length = [0u8; 4];
+        match recv.read_exact(&mut length).await {
+            Ok(()) => {}
+            Err(error) if is_closed(&error) => return Ok(None),
+            Err(error) => return Err(network_error(error)),
+        }
+        // Past a length that's too large there's no telling where the next
+        // frame starts, so the connection can't be read any further
+        let length = protocol::frame_length(length).map_err(|error| match error {
+            AppError::ProtocolError(reason) => AppError::NetworkError(reason),
+            error => error,
+        })?;
+        let mut frame = vec![0u8; length];
+        recv.read_exact(&mut frame).await.map_err(network_error)?;
+        protocol::decode_frame(&frame).map(Some)
+    }
+
+    async fn close(&self, reason: &str) {
+        // Give the peer a moment to read what was last sent and hang up
+        // themselves before the connection is cut
+        let _ = self.send.lock().await.finish();
+        let _ = timeout(CLOSE_GRACE, self.connection.closed()).await;
+        self.connection.close(0u32.into(), reason.as_bytes());
+        if let Some(endpoint) = &self.endpoint {
+            let _ = timeout(CLOSE_GRACE, endpoint.wait_idle()).await;
+        }
+    }
+}
+
+/// Whether a read failed because the peer finished or closed cleanly
+fn is_closed(error: &ReadExactError) -> bool {
+    match error {
+        ReadExactError::FinishedEarly(0) => true,
+        ReadExactError::ReadError(ReadError::ConnectionLost(error)) => matches!(
+            error,
+            ConnectionError::ApplicationClosed(_) | ConnectionError::ConnectionClosed(_) | ConnectionError::LocallyClosed
+        ),
+        _ => false,
+    }
+}
+
+fn network_error(error: impl fmt::Display) -> AppError {
+    AppError::NetworkError(error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::database::models::{ChatMessage, Payload};
+
+    fn chat(text: &str) -> NetworkMessage {
+        NetworkMessage {
+            id: Uuid::new_v4().to_string(),
+            sender_id: "player".to_string(),
+            sender_name: "Alice".to_string(),
+            payload: Payload::Chat(ChatMessage {
+                message: text.to_string(),
+                is_whisper: false,
+                target_players: None,
+                is_in_character: true,
+            }),
+            timestamp: Utc::now(),
+        }
+    }
+
+    fn text(message: &NetworkMessage) -> &str {
+        match &message.payload {
+            Payload::Chat(chat) => &chat.message,
+            other => panic!("expected a chat message, got {:?}", other),
+        }
+    }
+
+    fn listen(identity: &HostIdentity) -> (QuicListener, String) {
+        let listener = QuicListener::bind("127.0.0.1:0".parse().unwrap(), identity).unwrap();
+        let address = listener.local_address().unwrap().to_string();
+        (listener, address)
+    }
+
+    #[tokio::test]
+    async fn messages_cross_a_connection_both_ways() {
+        let identity = HostIdentity::generate().unwrap();
+        let (listener, address) = listen(&identity);
+
+        let player = connect(&address, &identity.fingerprint()).await.unwrap();
+        player.send(&chat("hello")).await.unwrap();
+        let host = listener.accept().await.unwrap().unwrap();
+        assert_eq!(text(&host.recv().await.unwrap().unwrap()), "hello");
+
+        host.send(&chat("welcome")).await.unwrap();
+        assert_eq!(text(&player.recv().await.unwrap().unwrap()), "welcome");
+    }
+
+    #[tokio::test]
+    async fn fingerprints_are_matched_whatever_their_format() {
+        let identity = HostIdentity::generate().unwrap();
+        let (_listener, address) = listen(&identity);
+        let bare = identity.fingerprint().replace(':', "").to_lowercase();
+        assert!(connect(&address, &bare).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn a_host_with_another_certificate_is_refused() {
+        let identity = HostIdentity::generate().unwrap();
+        let (_listener, address) = listen(&identity);
+        let someone_else = HostIdentity::generate().unwrap().fingerprint();
+        assert!(matches!(connect(&address, &someone_else).await, Err(AppError::NetworkError(_))));
+    }
+
+    #[tokio::test]
+    async fn a_malformed_fingerprint_is_refused_before_connecting() {
+        let identity = HostIdentity::generate().unwrap();
+        let (_listener, address) = listen(&identity);
+        assert!(matches!(connect(&address, "not a fingerprint").await, Err(AppError::InvalidInput(_))));
+    }
+
+    #[tokio::test]
+    async fn recv_ends_once_the_peer_closes() {
+        let identity = HostIdentity::generate().unwrap();
+        let (listener, address) = listen(&identity);
+
+        let player = connect(&address, &identity.fingerprint()).await.unwrap();
+        player.send(&chat("bye")).await.unwrap();
+        let host = listener.accept().await.unwrap().unwrap();
+        assert!(host.recv().await.unwrap().is_some());
+
+        let (_, next) = tokio::join!(player.close("Leaving"), host.recv());
+        assert!(next.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn an_oversized_frame_drops_the_connection() {
+        let identity = HostIdentity::generate().unwrap();
+        let (listener, address) = listen(&identity);
+
+        let player = connect(&address, &identity.fingerprint()).await.unwrap();
+        let oversized = (protocol::MAX_FRAME_SIZE as u32 + 1).to_be_bytes();
+        player.send.lock().await.write_all(&oversized).await.unwrap();
+        player.send(&chat("lost")).await.unwrap();
+        let host = listener.accept().await.unwrap().unwrap();
+        assert!(matches!(host.recv().await, Err(AppError::NetworkError(_))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn the_saved_key_is_private_and_reloaded() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let directory = std::env::temp_dir().join(format!("tavern-identity-{}", Uuid::new_v4()));
+        let identity = HostIdentity::load_or_generate(&directory).unwrap();
+        let mode = std::fs::metadata(directory.join(KEY_FILE)).unwrap().permissions().mode();
+        let reloaded = HostIdentity::load_or_generate(&directory).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(reloaded.fingerprint(), identity.fingerprint());
+    }
+}
@@ -0,0 +1,585 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use uuid::Uuid;
+
+use crate::database::models::{MessageDenied, NetworkMessage, Payload, PeerInfo, PlayerRole, SystemMessage};
+use crate::errors::{AppError, AppResult};
+use crate::networking::permissions::{self, TokenOwners};
+use crate::networking::protocol;
+use crate::networking::signaling::server::{display_code, normalize_code, random_code};
+use crate::networking::transport::{Connection, Listener};
+
+/// How long a new connection has to ask to join, and a player to hear back
+const JOIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Characters in a player key, about 60 bits' worth
+const PLAYER_KEY_LENGTH: usize = 12;
+
+/// What happens in a session, for the app to pass on to the frontend
+#[derive(Debug, Clone)]
+pub enum SessionEvent {
+    Message(Box<NetworkMessage>),
+    PeerJoined(PeerInfo),
+    PeerLeft(PeerInfo),
+    /// The host refused a message this app sent
+    Denied(MessageDenied),
+    Ended { reason: String },
+}
+
+pub type SessionEvents = mpsc::UnboundedSender<SessionEvent>;
+
+/// A message from `sender`, stamped now
+pub fn new_message(sender: &PeerInfo, payload: Payload) -> NetworkMessage {
+    NetworkMessage {
+        id: Uuid::new_v4().to_string(),
+        sender_id: sender.id.clone(),
+        sender_name: sender.name.clone(),
+        payload,
+        timestamp: Utc::now(),
+    }
+}
+
+fn system_message(sender: &PeerInfo, message: SystemMessage) -> NetworkMessage {
+    new_message(sender, Payload::System(message))
+}
+
+/// Send a message the way its kind calls for: transient ones unreliably,
+/// everything else reliably
+async fn deliver(connection: &dyn Connection, message: &NetworkMessage) -> AppResult<()> {
+    if message.payload.is_transient() {
+        connection.send_unreliable(message).await
+    } else {
+        connection.send(message).await
+    }
+}
+
+/// Whether `peer` should hear `message`: a whisper goes only to the players
+/// it names, everything else to everyone
+///
+/// The host is the DM and hears every whisper through its own events, so
+/// this only decides which players a message is relayed to.
+fn receives(peer: &PeerInfo, message: &NetworkMessage) -> bool {
+    match &message.payload {
+        Payload::Chat(chat) if chat.is_whisper => chat
+            .target_players
+            .iter()
+            .flatten()
+            .any(|target| target.trim().eq_ignore_ascii_case(&peer.name)),
+        _ => true,
+    }
+}
+
+/// The system message in `message`, if it is one
+fn read_system(message: NetworkMessage) -> Option<SystemMessage> {
+    match message.payload {
+        Payload::System(system) => Some(system),
+        _ => None,
+    }
+}
+
+// =============================================================================
+// Host
+// =============================================================================
+
+/// The DM's side of a session
+///
+/// The host is the authority: every message goes through it, it stamps each
+/// one with the sender it knows the connection belongs to, checks the sender
+/// may send it, and relays it on to everyone else. Players never talk to
+/// each other directly.
+///
+/// Anyone can join under any name that isn't in use, so a name alone proves
+/// nothing. The DM issues a key for each player who should be able to move
+/// their characters' tokens; once a name has a key, only someone with the
+/// key can join under it.
+pub struct HostSession {
+    state: Arc<HostState>,
+    listeners: Vec<Arc<dyn Listener>>,
+    accept_tasks: Vec<JoinHandle<()>>,
+}
+
+struct HostState {
+    host: PeerInfo,
+    password: Option<String>,
+    /// Each player's key, by lowercased name
+    player_keys: Mutex<HashMap<String, String>>,
+    peers: Mutex<HashMap<String, Peer>>,
+    /// Lowercased names of players admitted but not yet in `peers`, so no one
+    /// else can take the name in the meantime
+    admitting: Mutex<HashSet<String>>,
+    tokens: Arc<dyn TokenOwners>,
+    events: SessionEvents,
+}
+
+struct Peer {
+    info: PeerInfo,
+    connection: Arc<dyn Connection>,
+}
+
+impl HostSession {
+    /// Start accepting players on each of `listeners`, checking who may move
+    /// which token against `tokens`
+    pub fn start(
+        listeners: Vec<Arc<dyn Listener>>,
+        name: &str,
+        password: Option<String>,
+        tokens: Arc<dyn TokenOwners>,
+        events: SessionEvents,
+    ) -> Self {
+        let state = Arc::new(HostState {
+            host: PeerInfo {
+                id: Uuid::new_v4().to_string(),
+                name: name.to_string(),
+                role: PlayerRole::DungeonMaster,
+                is_connected: true,
+                is_verified: true,
+                last_seen: Utc::now(),
+            },
+            password: password.filter(|password| !password.is_empty()),
+            player_keys: Mutex::new(HashMap::new()),
+            peers: Mutex::new(HashMap::new()),
+            admitting: Mutex::new(HashSet::new()),
+            tokens,
+            events,
+        });
+
+        let accept_tasks = listeners
+            .iter()
+            .map(|listener| tokio::spawn(accept_players(state.clone(), listener.clone())))
+            .collect();
+
+        Self { state, listeners, accept_tasks }
+    }
+
+    pub fn host(&self) -> &PeerInfo {
+        &self.state.host
+    }
+
+    /// Everyone in the session, the host first
+    pub async fn peers(&self) -> Vec<PeerInfo> {
+        self.state.peer_list().await
+    }
+
+    /// Send a message from the host to every player, or to those a whisper
+    /// names
+    pub async fn broadcast(&self, payload: Payload) -> AppResult<NetworkMessage> {
+        let message = new_message(&self.state.host, payload);
+        self.state.relay(&message, None).await;
+        Ok(message)
+    }
+
+    /// Send a message from the host to one player
+    pub async fn send_to(&self, peer_id: &str, payload: Payload) -> AppResult<NetworkMessage> {
+        let connection = self
+            .state
+            .peers
+            .lock()
+            .await
+            .get(peer_id)
+            .map(|peer| peer.connection.clone())
+            .ok_or_else(|| AppError::NotFound(format!("Peer {}", peer_id)))?;
+        let message = new_message(&self.state.host, payload);
+        deliver(&*connection, &message).await?;
+        Ok(message)
+    }
+
+    /// A new key for the player called `name`, for the DM to give them
+    ///
+    /// From now on only someone with this key can join as `name`, and only
+    /// they can move the tokens of characters with that player name. A new
+    /// key replaces any earlier one; keys last as long as the session. Anyone
+    /// already in the session as `name` keeps the standing they joined with.
+    pub async fn issue_player_key(&self, name: &str) -> AppResult<String> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(AppError::InvalidInput("A player key needs a player's name".to_string()));
+        }
+        if self.state.host.name.eq_ignore_ascii_case(name) {
+            return Err(AppError::InvalidInput(format!("{} is hosting the session", name)));
+        }
+        let key = random_code(PLAYER_KEY_LENGTH);
+        self.state.player_keys.lock().await.insert(name.to_ascii_lowercase(), key.clone());
+        Ok(display_code(&key))
+    }
+
+    /// Disconnect a player
+    pub async fn kick(&self, peer_id: &str, reason: &str) -> AppResult<()> {
+        let connection = self
+            .state
+            .peers
+            .lock()
+            .await
+            .get(peer_id)
+            .map(|peer| peer.connection.clone())
+            .ok_or_else(|| AppError::NotFound(format!("Peer {}", peer_id)))?;
+        connection.close(reason).await;
+        Ok(())
+    }
+
+    /// Disconnect everyone and stop accepting players
+    pub async fn shutdown(self) {
+        for task in &self.accept_tasks {
+            task.abort();
+        }
+        let connections: Vec<_> = self.state.peers.lock().await.drain().map(|(_, peer)| peer.connection).collect();
+        for connection in connections {
+            connection.close("The host ended the session").await;
+        }
+        for listener in &self.listeners {
+            listener.close();
+        }
+        let _ = self.state.events.send(SessionEvent::Ended { reason: "Session ended".to_string() });
+    }
+}
+
+impl HostState {
+    async fn peer_list(&self) -> Vec<PeerInfo> {
+        let peers = self.peers.lock().await;
+        std::iter::once(self.host.clone()).chain(peers.values().map(|peer| peer.info.clone())).collect()
+    }
+
+    /// Send a message to every player except `skip`, or for a whisper only
+    /// to the players it's meant for
+    async fn relay(&self, message: &NetworkMessage, skip: Option<&str>) {
+        let connections: Vec<_> = self
+            .peers
+            .lock()
+            .await
+            .values()
+            .filter(|peer| Some(peer.info.id.as_str()) != skip && receives(&peer.info, message))
+            .map(|peer| peer.connection.clone())
+            .collect();
+        for connection in connections {
+            // A peer that can't be reached is dropped by their own read loop
+            if let Err(error) = deliver(&*connection, message).await {
+                tracing::debug!("Couldn't relay to {}: {}", connection.remote_address(), error);
+            }
+        }
+    }
+
+    /// Check a join request, returning who the player will be in the session
+    ///
+    /// Their name stays reserved until they're added to `peers` or it's
+    /// released with `release_name`.
+    async fn admit(&self, request: Option<SystemMessage>) -> Result<PeerInfo, String> {
+        let Some(SystemMessage::Join { name, role, password, player_key }) = request else {
+            return Err("Expected a request to join".to_string());
+        };
+        let name = name.trim();
+        if name.is_empty() {
+            return Err("A name is needed to join".to_string());
+        }
+        if role == PlayerRole::DungeonMaster {
+            return Err("The session already has a DM".to_string());
+        }
+        if self.password.is_some() && self.password != password {
+            return Err("Wrong password".to_string());
+        }
+        let issued = self.player_keys.lock().await.get(&name.to_ascii_lowercase()).cloned();
+        let is_verified = match (issued, player_key) {
+            (None, None) => false,
+            (Some(issued), Some(given)) if issued == normalize_code(&given) => true,
+            (Some(_), _) => return Err(format!("Joining as {} needs the player key the DM gave them", name)),
+            (None, Some(_)) => return Err(format!("The DM hasn't issued a player key for {}", name)),
+        };
+        // A player's name is what ties them to their characters' tokens, so
+        // it's checked and reserved in one go
+        let peers = self.peers.lock().await;
+        let mut admitting = self.admitting.lock().await;
+        let taken = self.host.name.eq_ignore_ascii_case(name)
+            || peers.values().any(|peer| peer.info.name.eq_ignore_ascii_case(name))
+            || !admitting.insert(name.to_ascii_lowercase());
+        if taken {
+            return Err(format!("Someone called {} is already in the session", name));
+        }
+        Ok(PeerInfo {
+            id: Uuid::new_v4().to_string(),
+            name: name.to_string(),
+            role,
+            is_connected: true,
+            is_verified,
+            last_seen: Utc::now(),
+        })
+    }
+
+    /// Free a name `admit` reserved for a player who didn't make it in
+    async fn release_name(&self, name: &str) {
+        self.admitting.lock().await.remove(&name.to_ascii_lowercase());
+    }
+}
+
+async fn accept_players(state: Arc<HostState>, listener: Arc<dyn Listener>) {
+    loop {
+        match listener.accept().await {
+            Ok(Some(connection)) => {
+                tokio::spawn(serve_peer(state.clone(), Arc::from(connection)));
+            }
+            Ok(None) => break,
+            Err(error) => tracing::warn!("Couldn't accept a connection: {}", error),
+        }
+    }
+}
+
+/// Agree a protocol version with a new connection and take their request
+/// to join
+async fn handshake(state: &HostState, connection: &dyn Connection) -> AppResult<(u16, Option<SystemMessage>)> {
+    let version = protocol::accept_version(connection, &state.host).await?;
+    let request = connection.recv().await?.and_then(read_system);
+    Ok((version, request))
+}
+
+/// Admit a player and pass on their messages until they leave
+async fn serve_peer(state: Arc<HostState>, connection: Arc<dyn Connection>) {
+    let address = connection.remote_address();
+    let (protocol_version, request) = match timeout(JOIN_TIMEOUT, handshake(&state, &*connection)).await {
+        Ok(Ok(handshake)) => handshake,
+        Ok(Err(error)) => {
+            tracing::warn!("Failed handshake with {}: {}", address, error);
+            connection.close("Failed handshake").await;
+            return;
+        }
+        Err(_) => {
+            connection.close("Timed out waiting to join").await;
+            return;
+        }
+    };
+    let info = match state.admit(request).await {
+        Ok(info) => info,
+        Err(reason) => {
+            tracing::info!("Turned away {}: {}", address, reason);
+            let rejected = system_message(&state.host, SystemMessage::Rejected { reason: reason.clone() });
+            let _ = connection.send(&rejected).await;
+            connection.close(&reason).await;
+            return;
+        }
+    };
+
+    let welcome = SystemMessage::Welcome { peer_id: info.id.clone(), peers: state.peer_list().await };
+    if connection.send(&system_message(&state.host, welcome)).await.is_err() {
+        state.release_name(&info.name).await;
+        return;
+    }
+    let joined = system_message(&state.host, SystemMessage::PeerJoined { peer: info.clone() });
+    state.relay(&joined, None).await;
+    {
+        let mut peers = state.peers.lock().await;
+        peers.insert(info.id.clone(), Peer { info: info.clone(), connection: connection.clone() });
+        state.release_name(&info.name).await;
+    }
+    tracing::info!("{} joined from {} speaking protocol {}", info.name, address, protocol_version);
+    let _ = state.events.send(SessionEvent::PeerJoined(info.clone()));
+
+    loop {
+        let mut message = match connection.recv().await {
+            Ok(Some(message)) => message,
+            Ok(None) => break,
+            // Frames are length-prefixed, so one that can't be read is skipped
+            Err(error @ AppError::ProtocolError(_)) => {
+                tracing::warn!("Dropped a message from {}: {}", info.name, error);
+                continue;
+            }
+            Err(error) => {
+                tracing::warn!("Lost {}: {}", info.name, error);
+                break;
+            }
+        };
+        if let Some(peer) = state.peers.lock().await.get_mut(&info.id) {
+            peer.info.last_seen = Utc::now();
+        }
+        if let Err(error) = permissions::authorize(&info, &message.payload, &*state.tokens).await {
+            tracing::info!("Refused a {:?} message from {}: {}", message.message_type(), info.name, error);
+            let denied = MessageDenied { message_id: message.id.clone(), error };
+            let _ = connection.send(&system_message(&state.host, SystemMessage::Denied(denied))).await;
+            continue;
+        }
+        message.sender_id = info.id.clone();
+        message.sender_name = info.name.clone();
+        let _ = state.events.send(SessionEvent::Message(Box::new(message.clone())));
+        state.relay(&message, Some(&info.id)).await;
+    }
+
+    let removed = state.peers.lock().await.remove(&info.id);
+    if let Some(mut peer) = removed {
+        connection.close("Disconnected").await;
+        peer.info.is_connected = false;
+        let left = system_message(&state.host, SystemMessage::PeerLeft { peer_id: info.id.clone() });
+        state.relay(&left, None).await;
+        tracing::info!("{} left", peer.info.name);
+        let _ = state.events.send(SessionEvent::PeerLeft(peer.info));
+    }
+}
+
+// =============================================================================
+// Client
+// =============================================================================
+
+/// A player's side of a session: one connection, to the host
+pub struct ClientSession {
+    me: PeerInfo,
+    protocol_version: u16,
+    connection: Arc<dyn Connection>,
+    peers: Arc<Mutex<Vec<PeerInfo>>>,
+    read_task: JoinHandle<()>,
+}
+
+impl ClientSession {
+    /// Ask to join the host on the other end of `connection`, with the key
+    /// the DM issued for `name` if there is one
+    pub async fn join(
+        connection: Arc<dyn Connection>,
+        name: &str,
+        role: PlayerRole,
+        password: Option<String>,
+        player_key: Option<String>,
+        events: SessionEvents,
+    ) -> AppResult<Self> {
+        let player_key = player_key.filter(|key| !key.trim().is_empty());
+        let mut me = PeerInfo {
+            id: String::new(),
+            name: name.trim().to_string(),
+            role,
+            is_connected: true,
+            // The host turns away a key that isn't right for the name
+            is_verified: player_key.is_some(),
+            last_seen: Utc::now(),
+        };
+        let handshake = async {
+            let version = protocol::offer_version(&*connection, &me).await?;
+            let request = SystemMessage::Join { name: me.name.clone(), role, password, player_key };
+            connection.send(&system_message(&me, request)).await?;
+            Ok::<_, AppError>((version, connection.recv().await?))
+        };
+        let (protocol_version, reply) = match timeout(JOIN_TIMEOUT, handshake).await {
+            Ok(Ok(handshake)) => handshake,
+            Ok(Err(error)) => {
+                connection.close("Failed handshake").await;
+                return Err(error);
+            }
+            Err(_) => {
+                connection.close("Timed out").await;
+                return Err(AppError::NetworkError("The host didn't answer".to_string()));
+            }
+        };
+        let peers = match reply.and_then(read_system) {
+            Some(SystemMessage::Welcome { peer_id, peers }) => {
+                me.id = peer_id;
+                peers
+            }
+            Some(SystemMessage::Rejected { reason }) => {
+                connection.close("Rejected").await;
+                return Err(AppError::NetworkError(format!("The host turned us away: {}", reason)));
+            }
+            _ => {
+                connection.close("Unexpected reply").await;
+                return Err(AppError::NetworkError("The host didn't answer the join request".to_string()));
+            }
+        };
+
+        let mut everyone = peers;
+        everyone.push(me.clone());
+        let peers = Arc::new(Mutex::new(everyone));
+        let read_task = tokio::spawn(read_from_host(connection.clone(), peers.clone(), events));
+        Ok(Self { me, protocol_version, connection, peers, read_task })
+    }
+
+    pub fn me(&self) -> &PeerInfo {
+        &self.me
+    }
+
+    pub fn protocol_version(&self) -> u16 {
+        self.protocol_version
+    }
+
+    /// Everyone in the session, as last heard from the host
+    pub async fn peers(&self) -> Vec<PeerInfo> {
+        self.peers.lock().await.clone()
+    }
+
+    /// Send a message through the host to everyone else
+    pub async fn send(&self, payload: Payload) -> AppResult<NetworkMessage> {
+        let message = new_message(&self.me, payload);
+        deliver(&*self.connection, &message).await?;
+        Ok(message)
+    }
+
+    pub async fn leave(self) {
+        self.read_task.abort();
+        self.connection.close("Left the session").await;
+    }
+}
+
+async fn read_from_host(connection: Arc<dyn Connection>, peers: Arc<Mutex<Vec<PeerInfo>>>, events: SessionEvents) {
+    let reason = loop {
+        let message = match connection.recv().await {
+            Ok(Some(message)) => message,
+            Ok(None) => break "The host ended the session".to_string(),
+            Err(error @ AppError::ProtocolError(_)) => {
+                tracing::warn!("Dropped a message from the host: {}", error);
+                continue;
+            }
+            Err(error) => break format!("Lost the connection to the host: {}", error),
+        };
+        let event = match &message.payload {
+            Payload::System(SystemMessage::PeerJoined { peer }) => {
+                let peer = peer.clone();
+                let mut peers = peers.lock().await;
+                peers.retain(|known| known.id != peer.id);
+                peers.push(peer.clone());
+                SessionEvent::PeerJoined(peer)
+            }
+            Payload::System(SystemMessage::PeerLeft { peer_id }) => {
+                let mut peers = peers.lock().await;
+                let Some(index) = peers.iter().position(|peer| &peer.id == peer_id) else {
+                    continue;
+                };
+                let mut peer = peers.remove(index);
+                peer.is_connected = false;
+                SessionEvent::PeerLeft(peer)
+            }
+            Payload::System(SystemMessage::Denied(denied)) => SessionEvent::Denied(denied.clone()),
+            _ => SessionEvent::Message(Box::new(message)),
+        };
+        let _ = events.send(event);
+    };
+    let _ = events.send(SessionEvent::Ended { reason });
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::networking::permissions::OwnedToken;
+
+    struct NoTokens;
+
+    #[async_trait]
+    impl TokenOwners for NoTokens {
+        async fn find_token(&self, _map_id: &str, _token_id: &str) -> AppResult<Option<OwnedToken>> {
+            Ok(None)
+        }
+    }
+
+    fn join(name: &str) -> Option<SystemMessage> {
+        Some(SystemMessage::Join { name: name.to_string(), role: PlayerRole::Player, password: None, player_key: None })
+    }
+
+    #[tokio::test]
+    async fn a_name_being_admitted_stays_reserved_until_released() {
+        let (events, _receiver) = mpsc::unbounded_channel();
+        let session = HostSession::start(Vec::new(), "Dungeon Master", None, Arc::new(NoTokens), events);
+        let state = &session.state;
+
+        assert!(state.admit(join("Ann")).await.is_ok());
+        assert!(state.admit(join(" ann ")).await.is_err());
+        assert!(state.admit(join("dungeon master")).await.is_err());
+        state.release_name("Ann").await;
+        assert!(state.admit(join("ANN")).await.is_ok());
+    }
+}
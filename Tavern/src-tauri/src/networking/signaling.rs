@@ -0,0 +1,221 @@
+//! Finding a host by invite code through a signaling server
+//!
+//! The host keeps a WebSocket open to the server for as long as it's
+//! hosting and is handed each player's signals as they join; a player's
+//! socket is only open until their WebRTC connection is.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use crate::database::models::Signal;
+use crate::errors::{AppError, AppResult};
+use crate::networking::webrtc::Signaling;
+
+pub mod server;
+
+use server::{ClientMessage, ServerMessage};
+
+/// How long the server has to answer a request to host or join
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// A session listed on a signaling server
+pub struct Listing {
+    /// What players type to join, like `K7Q-FM2`
+    pub invite_code: String,
+    /// Each player's signaling, as they join
+    pub players: mpsc::UnboundedReceiver<Arc<dyn Signaling>>,
+}
+
+/// List a session on the server at `url`; it stays listed until
+/// `Listing::players` is dropped
+pub async fn host(url: &str) -> AppResult<Listing> {
+    let (sink, mut source) = open(url).await?;
+    let outgoing = spawn_writer(sink);
+    send(&outgoing, ClientMessage::Host)?;
+    let invite_code = match next_reply(&mut source).await? {
+        ServerMessage::Hosting { code } => code,
+        other => return Err(unexpected(other)),
+    };
+
+    let (players, receiver) = mpsc::unbounded_channel();
+    tokio::spawn(read_for_host(source, outgoing, players));
+    Ok(Listing { invite_code, players: receiver })
+}
+
+/// Reach the host whose session has `invite_code` through the server at
+/// `url`
+pub async fn join(url: &str, invite_code: &str) -> AppResult<Arc<dyn Signaling>> {
+    let (sink, mut source) = open(url).await?;
+    let outgoing = spawn_writer(sink);
+    send(&outgoing, ClientMessage::Join { code: invite_code.to_string() })?;
+    match next_reply(&mut source).await? {
+        ServerMessage::Joined { .. } => {}
+        other => return Err(unexpected(other)),
+    }
+
+    let (signals, incoming) = mpsc::unbounded_channel();
+    tokio::spawn(read_for_player(source, signals));
+    Ok(Arc::new(RelayedSignaling { to: None, outgoing, incoming: Mutex::new(incoming) }))
+}
+
+async fn open(url: &str) -> AppResult<(SplitSink<Socket, Message>, SplitStream<Socket>)> {
+    let (socket, _) = timeout(REQUEST_TIMEOUT, tokio_tungstenite::connect_async(url))
+        .await
+        .map_err(|_| AppError::NetworkError(format!("Timed out reaching the signaling server at {}", url)))?
+        .map_err(|error| AppError::NetworkError(format!("Couldn't reach the signaling server at {}: {}", url, error)))?;
+    Ok(socket.split())
+}
+
+/// Write messages to the server until every sender is dropped, then close
+fn spawn_writer(mut sink: SplitSink<Socket, Message>) -> mpsc::UnboundedSender<ClientMessage> {
+    let (outgoing, mut messages) = mpsc::unbounded_channel::<ClientMessage>();
+    tokio::spawn(async move {
+        while let Some(message) = messages.recv().await {
+            let Ok(text) = serde_json::to_string(&message) else {
+                continue;
+            };
+            if sink.send(Message::text(text)).await.is_err() {
+                break;
+            }
+        }
+        let _ = sink.close().await;
+    });
+    outgoing
+}
+
+fn send(outgoing: &mpsc::UnboundedSender<ClientMessage>, message: ClientMessage) -> AppResult<()> {
+    outgoing
+        .send(message)
+        .map_err(|_| AppError::NetworkError("Lost the signaling server".to_string()))
+}
+
+/// The next message from the server, or `None` once it's closed the socket
+async fn next_message(source: &mut SplitStream<Socket>) -> Option<ServerMessage> {
+    while let Some(frame) = source.next().await {
+        match frame {
+            Ok(Message::Text(text)) => match serde_json::from_str(&text) {
+                Ok(message) => return Some(message),
+                Err(error) => tracing::debug!("Ignoring an unreadable signaling message: {}", error),
+            },
+            Ok(Message::Close(_)) | Err(_) => return None,
+            Ok(_) => {}
+        }
+    }
+    None
+}
+
+async fn next_reply(source: &mut SplitStream<Socket>) -> AppResult<ServerMessage> {
+    match timeout(REQUEST_TIMEOUT, next_message(source)).await {
+        Ok(Some(ServerMessage::Error { reason })) => Err(AppError::NetworkError(reason)),
+        Ok(Some(reply)) => Ok(reply),
+        Ok(None) => Err(AppError::NetworkError("The signaling server closed the connection".to_string())),
+        Err(_) => Err(AppError::NetworkError("The signaling server didn't answer".to_string())),
+    }
+}
+
+fn unexpected(reply: ServerMessage) -> AppError {
+    AppError::ProtocolError(format!("Unexpected answer from the signaling server: {:?}", reply))
+}
+
+/// Hand the host a `Signaling` for each player that joins and pass each
+/// player's signals to theirs, until the host stops listening for players
+async fn read_for_host(
+    mut source: SplitStream<Socket>,
+    outgoing: mpsc::UnboundedSender<ClientMessage>,
+    players: mpsc::UnboundedSender<Arc<dyn Signaling>>,
+) {
+    let mut joined: HashMap<String, mpsc::UnboundedSender<Signal>> = HashMap::new();
+    loop {
+        let message = tokio::select! {
+            message = next_message(&mut source) => message,
+            _ = players.closed() => break,
+        };
+        match message {
+            Some(ServerMessage::PeerJoined { peer_id }) => {
+                let (signals, incoming) = mpsc::unbounded_channel();
+                joined.insert(peer_id.clone(), signals);
+                let signaling = RelayedSignaling {
+                    to: Some(peer_id),
+                    outgoing: outgoing.clone(),
+                    incoming: Mutex::new(incoming),
+                };
+                if players.send(Arc::new(signaling)).is_err() {
+                    break;
+                }
+            }
+            Some(ServerMessage::PeerLeft { peer_id }) => {
+                joined.remove(&peer_id);
+            }
+            Some(ServerMessage::Signal { from: Some(peer_id), signal }) => {
+                let Some(signals) = joined.get(&peer_id) else {
+                    continue;
+                };
+                match serde_json::from_value(signal) {
+                    Ok(signal) => {
+                        let _ = signals.send(signal);
+                    }
+                    Err(error) => tracing::debug!("Ignoring an unreadable signal from {}: {}", peer_id, error),
+                }
+            }
+            Some(ServerMessage::Error { reason }) => tracing::debug!("Signaling server: {}", reason),
+            Some(_) => {}
+            None => {
+                tracing::warn!("Lost the signaling server; players can no longer join by invite code");
+                break;
+            }
+        }
+    }
+}
+
+/// Pass the host's signals on to a player until they stop listening
+async fn read_for_player(mut source: SplitStream<Socket>, signals: mpsc::UnboundedSender<Signal>) {
+    loop {
+        let message = tokio::select! {
+            message = next_message(&mut source) => message,
+            _ = signals.closed() => break,
+        };
+        match message {
+            Some(ServerMessage::Signal { from: None, signal }) => match serde_json::from_value(signal) {
+                Ok(signal) => {
+                    let _ = signals.send(signal);
+                }
+                Err(error) => tracing::debug!("Ignoring an unreadable signal from the host: {}", error),
+            },
+            Some(ServerMessage::Error { reason }) => tracing::debug!("Signaling server: {}", reason),
+            Some(ServerMessage::Closed) | None => break,
+            Some(_) => {}
+        }
+    }
+}
+
+/// Signals passed through the server, to the host or to one player
+struct RelayedSignaling {
+    /// The player these are for, when it's the host sending
+    to: Option<String>,
+    outgoing: mpsc::UnboundedSender<ClientMessage>,
+    incoming: Mutex<mpsc::UnboundedReceiver<Signal>>,
+}
+
+#[async_trait]
+impl Signaling for RelayedSignaling {
+    async fn send(&self, signal: Signal) -> AppResult<()> {
+        let signal = serde_json::to_value(signal)?;
+        send(&self.outgoing, ClientMessage::Signal { to: self.to.clone(), signal })
+    }
+
+    async fn recv(&self) -> AppResult<Option<Signal>> {
+        Ok(self.incoming.lock().await.recv().await)
+    }
+}
@@ -0,0 +1,465 @@
+//! A signaling server: introduces players to a host so they can open a
+//! WebRTC connection, then gets out of the way
+//!
+//! Peers talk to it in JSON over a WebSocket. A host asks to `host` and is
+//! given an invite code; a player asks to `join` with that code. From then
+//! on each `signal` one of them sends is passed to the other, tagged with
+//! which player it's from or addressed to. The server never looks inside a
+//! signal. The session is listed for as long as the host stays connected.
+//!
+//! This module only relies on outside crates, so the standalone
+//! `tavern-signaling` binary can build it on its own.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::Message;
+
+/// Port the server listens on unless told otherwise
+pub const DEFAULT_PORT: u16 = 7351;
+
+/// Letters invite codes are made of, leaving out ones easily mistaken for
+/// each other
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+const CODE_LENGTH: usize = 6;
+
+/// Largest message a peer may send; offers and answers are a few kilobytes
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// Most players one session may have waiting on or holding a connection
+const MAX_PLAYERS: usize = 32;
+
+/// How long a peer has to host or join before it's disconnected
+const FIRST_MESSAGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Failed joins a connection may make before it's closed, so invite codes
+/// can't be guessed one after another
+const MAX_FAILED_JOINS: usize = 3;
+
+/// Messages waiting to go out to one peer; past this the peer isn't keeping
+/// up and further messages to it are dropped
+const OUTBOX_SIZE: usize = 64;
+
+// =============================================================================
+// Messages
+// =============================================================================
+
+/// What a peer sends the server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum ClientMessage {
+    /// List a new session; answered with `Hosting`
+    #[serde(rename = "host")]
+    Host,
+    /// Join the session with this invite code; answered with `Joined`
+    #[serde(rename = "join")]
+    Join { code: String },
+    /// Pass a signal on: a host says which player it's `to`, a player's
+    /// always go to the host
+    #[serde(rename = "signal")]
+    Signal {
+        #[serde(default)]
+        to: Option<String>,
+        signal: serde_json::Value,
+    },
+}
+
+/// What the server sends a peer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum ServerMessage {
+    #[serde(rename = "hosting")]
+    Hosting { code: String },
+    #[serde(rename = "joined")]
+    Joined { peer_id: String },
+    /// To the host, when a player joins
+    #[serde(rename = "peer_joined")]
+    PeerJoined { peer_id: String },
+    /// To the host, when a player disconnects
+    #[serde(rename = "peer_left")]
+    PeerLeft { peer_id: String },
+    /// A signal passed on; `from` is the player it came from, or `None`
+    /// when it came from the host
+    #[serde(rename = "signal")]
+    Signal {
+        #[serde(default)]
+        from: Option<String>,
+        signal: serde_json::Value,
+    },
+    #[serde(rename = "error")]
+    Error { reason: String },
+    /// To players, when the host stops listing the session
+    #[serde(rename = "closed")]
+    Closed,
+}
+
+/// An invite code as people type it: any case, with or without the dash
+pub fn normalize_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+/// An invite code as shown to people, split in two halves for reading out
+pub fn display_code(code: &str) -> String {
+    let (first, second) = code.split_at(code.len() / 2);
+    format!("{}-{}", first, second)
+}
+
+// =============================================================================
+// Server
+// =============================================================================
+
+/// A signaling server running in the background, as when the host's app
+/// runs its own
+///
+/// It stops taking new connections when dropped; sessions already listed
+/// last until their host disconnects.
+pub struct SignalingServer {
+    local_address: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl SignalingServer {
+    pub async fn bind(address: SocketAddr) -> io::Result<Self> {
+        let listener = TcpListener::bind(address).await?;
+        let local_address = listener.local_addr()?;
+        let task = tokio::spawn(serve(listener));
+        Ok(Self { local_address, task })
+    }
+
+    pub fn local_address(&self) -> SocketAddr {
+        self.local_address
+    }
+
+    /// Stop taking connections, waiting until the port is free again
+    pub async fn shutdown(mut self) {
+        self.task.abort();
+        let _ = (&mut self.task).await;
+    }
+}
+
+impl Drop for SignalingServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Every listed session by invite code
+#[derive(Default)]
+struct Sessions {
+    sessions: Mutex<HashMap<String, Listing>>,
+}
+
+struct Listing {
+    host: Outbox,
+    players: HashMap<String, Outbox>,
+}
+
+type Outbox = mpsc::Sender<ServerMessage>;
+
+/// Which side of which session a connection is on, once it's said
+enum Role {
+    Unknown,
+    Host { code: String },
+    Player { code: String, peer_id: String },
+}
+
+/// Serve peers on `listener` until the task is dropped
+pub async fn serve(listener: TcpListener) {
+    let sessions = Arc::new(Sessions::default());
+    loop {
+        match listener.accept().await {
+            Ok((stream, address)) => {
+                tokio::spawn(serve_peer(stream, address, sessions.clone()));
+            }
+            Err(error) => tracing::warn!("Couldn't accept a signaling connection: {}", error),
+        }
+    }
+}
+
+async fn serve_peer(stream: TcpStream, address: SocketAddr, sessions: Arc<Sessions>) {
+    let config = WebSocketConfig::default()
+        .max_message_size(Some(MAX_MESSAGE_SIZE))
+        .max_frame_size(Some(MAX_MESSAGE_SIZE));
+    let socket = match tokio_tungstenite::accept_async_with_config(stream, Some(config)).await {
+        Ok(socket) => socket,
+        Err(error) => {
+            tracing::debug!("Signaling handshake with {} failed: {}", address, error);
+            return;
+        }
+    };
+    let (mut sink, mut source) = socket.split();
+
+    let (outbox, mut outgoing) = mpsc::channel::<ServerMessage>(OUTBOX_SIZE);
+    let writer = tokio::spawn(async move {
+        while let Some(message) = outgoing.recv().await {
+            let closed = matches!(message, ServerMessage::Closed);
+            let Ok(text) = serde_json::to_string(&message) else {
+                continue;
+            };
+            if sink.send(Message::text(text)).await.is_err() || closed {
+                break;
+            }
+        }
+        let _ = sink.close().await;
+    });
+
+    let mut role = Role::Unknown;
+    let mut failed_joins = 0;
+    loop {
+        let frame = if matches!(role, Role::Unknown) {
+            match timeout(FIRST_MESSAGE_TIMEOUT, source.next()).await {
+                Ok(frame) => frame,
+                Err(_) => {
+                    deliver(&outbox, ServerMessage::Error { reason: "Host or join a session sooner".to_string() });
+                    break;
+                }
+            }
+        } else {
+            source.next().await
+        };
+        let text = match frame {
+            Some(Ok(Message::Text(text))) => text,
+            Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+            Some(Ok(_)) => continue,
+        };
+        let reply = match serde_json::from_str::<ClientMessage>(&text) {
+            Ok(message) => {
+                let is_join = matches!(message, ClientMessage::Join { .. });
+                let reply = sessions.handle(&mut role, message, &outbox).await;
+                if is_join && reply.is_err() {
+                    failed_joins += 1;
+                }
+                reply
+            }
+            Err(error) => Err(format!("Unreadable message: {}", error)),
+        };
+        if let Err(reason) = reply {
+            deliver(&outbox, ServerMessage::Error { reason });
+        }
+        if failed_joins >= MAX_FAILED_JOINS {
+            deliver(&outbox, ServerMessage::Error { reason: "Too many failed attempts to join".to_string() });
+            break;
+        }
+    }
+
+    sessions.remove(role).await;
+    drop(outbox);
+    let _ = writer.await;
+}
+
+impl Sessions {
+    async fn handle(&self, role: &mut Role, message: ClientMessage, outbox: &Outbox) -> Result<(), String> {
+        match (message, &*role) {
+            (ClientMessage::Host, Role::Unknown) => {
+                let mut sessions = self.sessions.lock().await;
+                let code = loop {
+                    let code = new_code();
+                    if !sessions.contains_key(&code) {
+                        break code;
+                    }
+                };
+                sessions.insert(code.clone(), Listing { host: outbox.clone(), players: HashMap::new() });
+                deliver(outbox, ServerMessage::Hosting { code: display_code(&code) });
+                *role = Role::Host { code };
+            }
+            (ClientMessage::Join { code }, Role::Unknown) => {
+                let code = normalize_code(&code);
+                let mut sessions = self.sessions.lock().await;
+                let listing = sessions
+                    .get_mut(&code)
+                    .ok_or_else(|| "No session has that invite code".to_string())?;
+                if listing.players.len() >= MAX_PLAYERS {
+                    return Err("The session is full".to_string());
+                }
+                let peer_id = new_peer_id();
+                listing.players.insert(peer_id.clone(), outbox.clone());
+                deliver(&listing.host, ServerMessage::PeerJoined { peer_id: peer_id.clone() });
+                deliver(outbox, ServerMessage::Joined { peer_id: peer_id.clone() });
+                *role = Role::Player { code, peer_id };
+            }
+            (ClientMessage::Host | ClientMessage::Join { .. }, _) => {
+                return Err("Already in a session".to_string());
+            }
+            (ClientMessage::Signal { to, signal }, Role::Host { code }) => {
+                let to = to.ok_or_else(|| "Say which player the signal is for".to_string())?;
+                let sessions = self.sessions.lock().await;
+                let player = sessions
+                    .get(code)
+                    .and_then(|listing| listing.players.get(&to))
+                    .ok_or_else(|| format!("No player {} in the session", to))?;
+                deliver(player, ServerMessage::Signal { from: None, signal });
+            }
+            (ClientMessage::Signal { signal, .. }, Role::Player { code, peer_id }) => {
+                let sessions = self.sessions.lock().await;
+                let listing = sessions.get(code).ok_or_else(|| "The session has closed".to_string())?;
+                deliver(&listing.host, ServerMessage::Signal { from: Some(peer_id.clone()), signal });
+            }
+            (ClientMessage::Signal { .. }, Role::Unknown) => {
+                return Err("Host or join a session first".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// Take a peer that's disconnected out of its session, closing the
+    /// session when it's the host
+    async fn remove(&self, role: Role) {
+        let mut sessions = self.sessions.lock().await;
+        match role {
+            Role::Host { code } => {
+                if let Some(listing) = sessions.remove(&code) {
+                    for player in listing.players.values() {
+                        deliver(player, ServerMessage::Closed);
+                    }
+                }
+            }
+            Role::Player { code, peer_id } => {
+                if let Some(listing) = sessions.get_mut(&code) {
+                    listing.players.remove(&peer_id);
+                    deliver(&listing.host, ServerMessage::PeerLeft { peer_id });
+                }
+            }
+            Role::Unknown => {}
+        }
+    }
+}
+
+/// Queue a message for a peer, dropping it if the peer has fallen too far
+/// behind or is gone
+fn deliver(outbox: &Outbox, message: ServerMessage) {
+    if let Err(mpsc::error::TrySendError::Full(_)) = outbox.try_send(message) {
+        tracing::debug!("Dropped a signaling message for a peer that isn't keeping up");
+    }
+}
+
+fn new_code() -> String {
+    random_code(CODE_LENGTH)
+}
+
+/// `length` characters that are hard to misread, for people to pass on
+pub fn random_code(length: usize) -> String {
+    let mut rng = rand::rng();
+    (0..length)
+        .map(|_| CODE_ALPHABET[rng.random_range(0..CODE_ALPHABET.len())] as char)
+        .collect()
+}
+
+fn new_peer_id() -> String {
+    let mut rng = rand::rng();
+    (0..16).map(|_| format!("{:x}", rng.random_range(0..16u8))).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+    use super::*;
+
+    type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    async fn connect(server: &SignalingServer) -> Socket {
+        let url = format!("ws://{}", server.local_address());
+        tokio_tungstenite::connect_async(url).await.unwrap().0
+    }
+
+    async fn send(socket: &mut Socket, message: serde_json::Value) {
+        socket.send(Message::text(message.to_string())).await.unwrap();
+    }
+
+    /// The next message from the server, or `None` once it's closed the
+    /// connection
+    async fn next(socket: &mut Socket) -> Option<ServerMessage> {
+        loop {
+            let frame = timeout(Duration::from_secs(5), socket.next()).await.expect("the server went quiet");
+            match frame {
+                Some(Ok(Message::Text(text))) => return Some(serde_json::from_str(&text).unwrap()),
+                Some(Ok(Message::Close(_)) | Err(_)) | None => return None,
+                Some(Ok(_)) => {}
+            }
+        }
+    }
+
+    async fn host(socket: &mut Socket) -> String {
+        send(socket, json!({ "type": "host" })).await;
+        match next(socket).await {
+            Some(ServerMessage::Hosting { code }) => code,
+            other => panic!("expected hosting, got {:?}", other),
+        }
+    }
+
+    async fn join(socket: &mut Socket, code: &str) -> Option<ServerMessage> {
+        send(socket, json!({ "type": "join", "code": code })).await;
+        next(socket).await
+    }
+
+    #[tokio::test]
+    async fn signals_pass_between_host_and_player_until_the_host_leaves() {
+        let server = SignalingServer::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let mut host_socket = connect(&server).await;
+        let code = host(&mut host_socket).await;
+
+        let mut player = connect(&server).await;
+        let Some(ServerMessage::Joined { peer_id }) = join(&mut player, &code.to_lowercase().replace('-', "")).await
+        else {
+            panic!("the player should have joined");
+        };
+        let joined = next(&mut host_socket).await;
+        assert!(matches!(joined, Some(ServerMessage::PeerJoined { peer_id: id }) if id == peer_id));
+
+        send(&mut player, json!({ "type": "signal", "signal": { "sdp": "offer" } })).await;
+        match next(&mut host_socket).await {
+            Some(ServerMessage::Signal { from, signal }) => {
+                assert_eq!(from.as_deref(), Some(peer_id.as_str()));
+                assert_eq!(signal, json!({ "sdp": "offer" }));
+            }
+            other => panic!("expected a signal, got {:?}", other),
+        }
+        send(&mut host_socket, json!({ "type": "signal", "to": peer_id, "signal": { "sdp": "answer" } })).await;
+        match next(&mut player).await {
+            Some(ServerMessage::Signal { from: None, signal }) => assert_eq!(signal, json!({ "sdp": "answer" })),
+            other => panic!("expected a signal, got {:?}", other),
+        }
+
+        player.close(None).await.unwrap();
+        let left = next(&mut host_socket).await;
+        assert!(matches!(left, Some(ServerMessage::PeerLeft { peer_id: id }) if id == peer_id));
+
+        let mut second = connect(&server).await;
+        assert!(matches!(join(&mut second, &code).await, Some(ServerMessage::Joined { .. })));
+        assert!(matches!(next(&mut host_socket).await, Some(ServerMessage::PeerJoined { .. })));
+        host_socket.close(None).await.unwrap();
+        assert!(matches!(next(&mut second).await, Some(ServerMessage::Closed)));
+        assert!(next(&mut second).await.is_none());
+
+        // The code is no longer listed
+        let mut late = connect(&server).await;
+        assert!(matches!(join(&mut late, &code).await, Some(ServerMessage::Error { .. })));
+    }
+
+    #[tokio::test]
+    async fn guessing_codes_is_cut_off() {
+        let server = SignalingServer::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let mut guesser = connect(&server).await;
+        for _ in 0..MAX_FAILED_JOINS {
+            assert!(matches!(join(&mut guesser, "AAAAAA").await, Some(ServerMessage::Error { .. })));
+        }
+        assert!(matches!(next(&mut guesser).await, Some(ServerMessage::Error { .. })));
+        assert!(next(&mut guesser).await.is_none());
+    }
+}
@@ -0,0 +1,40 @@
+use async_trait::async_trait;
+
+use crate::database::models::NetworkMessage;
+use crate::errors::AppResult;
+
+/// A connection to one peer that carries whole messages, in order unless
+/// sent unreliably
+///
+/// The session layer only talks to peers through this, so it works the same
+/// whichever transport the connection came from.
+#[async_trait]
+pub trait Connection: Send + Sync {
+    /// Where the peer is connecting from, for showing to the DM
+    fn remote_address(&self) -> String;
+
+    async fn send(&self, message: &NetworkMessage) -> AppResult<()>;
+
+    /// Send a message that may be lost or overtaken by later ones, for
+    /// updates the next one replaces; transports with no such channel send
+    /// it like any other
+    async fn send_unreliable(&self, message: &NetworkMessage) -> AppResult<()> {
+        self.send(message).await
+    }
+
+    /// The next message from the peer, or `None` once they've closed the
+    /// connection
+    async fn recv(&self) -> AppResult<Option<NetworkMessage>>;
+
+    /// Close the connection, telling the peer why
+    async fn close(&self, reason: &str);
+}
+
+/// Where the host accepts connections from players
+#[async_trait]
+pub trait Listener: Send + Sync {
+    /// The next player to connect, or `None` once the listener is closed
+    async fn accept(&self) -> AppResult<Option<Box<dyn Connection>>>;
+
+    fn close(&self);
+}
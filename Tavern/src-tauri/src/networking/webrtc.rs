@@ -0,0 +1,573 @@
+//! Peer-to-peer connections over WebRTC data channels
+//!
+//! Each connection opens two channels. Frames go over an ordered, reliable
+//! one as a stream of bytes, split into pieces small enough for a data
+//! channel to deliver. Transient messages, cursor pings and token drags, go
+//! whole over an unordered one that never retransmits, where a late update
+//! is worse than a lost one.
+//!
+//! Before they can reach each other the two peers swap an offer, an answer
+//! and ICE candidates through a `Signaling` go-between. With no STUN or TURN
+//! servers configured only host candidates are gathered, which is enough on
+//! a LAN. The channels are encrypted, but who is on the other end is only as
+//! trustworthy as the signaling that introduced them.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout, Instant};
+use webrtc::api::setting_engine::SettingEngine;
+use webrtc::api::APIBuilder;
+use webrtc::data_channel::data_channel_init::RTCDataChannelInit;
+use webrtc::data_channel::data_channel_message::DataChannelMessage;
+use webrtc::data_channel::RTCDataChannel;
+use webrtc::ice_transport::ice_candidate::{RTCIceCandidate, RTCIceCandidateInit};
+use webrtc::ice_transport::ice_server::RTCIceServer;
+use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::stats::StatsReportType;
+
+use crate::database::models::{NetworkMessage, Signal};
+use crate::errors::{AppError, AppResult};
+use crate::networking::protocol::{self, FrameBuffer};
+use crate::networking::transport::{Connection, Listener};
+
+/// Label of the ordered, reliable channel
+const RELIABLE_LABEL: &str = "tavern";
+
+/// Label of the unordered channel that never retransmits
+const UNRELIABLE_LABEL: &str = "tavern-unreliable";
+
+/// Largest message a data channel delivers whole
+const MAX_MESSAGE_SIZE: usize = 16 * 1024;
+
+/// How long the peers have to find a route to each other and open both
+/// channels
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How long closing waits for messages already sent to go out
+const CLOSE_GRACE: Duration = Duration::from_secs(1);
+
+/// Where to look for a route between peers that aren't on the same network
+#[derive(Debug, Clone, Default)]
+pub struct WebRtcConfig {
+    /// `stun:` or `turn:` URLs; none is enough on a LAN
+    pub ice_servers: Vec<String>,
+}
+
+// =============================================================================
+// Signaling
+// =============================================================================
+
+/// How two peers reach each other before they're connected
+#[async_trait]
+pub trait Signaling: Send + Sync {
+    async fn send(&self, signal: Signal) -> AppResult<()>;
+
+    /// The next signal from the other peer, or `None` once they've gone
+    async fn recv(&self) -> AppResult<Option<Signal>>;
+}
+
+/// Signaling between two peers in the same process
+pub struct ChannelSignaling {
+    outgoing: mpsc::UnboundedSender<Signal>,
+    incoming: Mutex<mpsc::UnboundedReceiver<Signal>>,
+}
+
+/// Both ends of signaling that stays inside this process
+pub fn signaling_pair() -> (ChannelSignaling, ChannelSignaling) {
+    let (to_second, from_first) = mpsc::unbounded_channel();
+    let (to_first, from_second) = mpsc::unbounded_channel();
+    (
+        ChannelSignaling { outgoing: to_second, incoming: Mutex::new(from_second) },
+        ChannelSignaling { outgoing: to_first, incoming: Mutex::new(from_first) },
+    )
+}
+
+#[async_trait]
+impl Signaling for ChannelSignaling {
+    async fn send(&self, signal: Signal) -> AppResult<()> {
+        self.outgoing
+            .send(signal)
+            .map_err(|_| AppError::NetworkError("The other peer stopped signaling".to_string()))
+    }
+
+    async fn recv(&self) -> AppResult<Option<Signal>> {
+        Ok(self.incoming.lock().await.recv().await)
+    }
+}
+
+// =============================================================================
+// Connecting
+// =============================================================================
+
+/// Messages read off either channel; `None` once the connection has closed
+type Incoming = mpsc::UnboundedSender<Option<AppResult<NetworkMessage>>>;
+
+/// Open a connection to the peer on the other end of `signaling`, making
+/// the offer; a player does this to reach the host
+pub async fn connect(signaling: Arc<dyn Signaling>, config: &WebRtcConfig) -> AppResult<WebRtcConnection> {
+    let peer = new_peer_connection(config).await?;
+    let (incoming, received) = mpsc::unbounded_channel();
+    let (opened, open_channels) = mpsc::unbounded_channel();
+
+    let reliable = peer.create_data_channel(RELIABLE_LABEL, None).await.map_err(network_error)?;
+    let unreliable_options = RTCDataChannelInit {
+        ordered: Some(false),
+        max_retransmits: Some(0),
+        ..Default::default()
+    };
+    let unreliable = peer
+        .create_data_channel(UNRELIABLE_LABEL, Some(unreliable_options))
+        .await
+        .map_err(network_error)?;
+    for channel in [&reliable, &unreliable] {
+        wire_channel(channel, &incoming, &opened);
+    }
+
+    let establish = async {
+        watch_state(&peer, &incoming);
+        send_candidates(&peer, signaling.clone());
+        let offer = peer.create_offer(None).await.map_err(network_error)?;
+        peer.set_local_description(offer.clone()).await.map_err(network_error)?;
+        signaling.send(Signal::Offer { sdp: offer.sdp }).await?;
+        exchange_signals(&peer, &*signaling, open_channels).await
+    };
+    finish_connecting(&peer, establish, received).await
+}
+
+/// Open a connection with the peer on the other end of `signaling`,
+/// answering their offer; the host does this for each player
+pub async fn accept(signaling: Arc<dyn Signaling>, config: &WebRtcConfig) -> AppResult<WebRtcConnection> {
+    let peer = new_peer_connection(config).await?;
+    let (incoming, received) = mpsc::unbounded_channel();
+    let (opened, open_channels) = mpsc::unbounded_channel();
+
+    // Hooked up before the handler returns so no message is missed
+    peer.on_data_channel(Box::new({
+        let (incoming, opened) = (incoming.clone(), opened.clone());
+        move |channel: Arc<RTCDataChannel>| {
+            wire_channel(&channel, &incoming, &opened);
+            Box::pin(async {})
+        }
+    }));
+
+    let establish = async {
+        watch_state(&peer, &incoming);
+        send_candidates(&peer, signaling.clone());
+        exchange_signals(&peer, &*signaling, open_channels).await
+    };
+    finish_connecting(&peer, establish, received).await
+}
+
+async fn finish_connecting(
+    peer: &Arc<RTCPeerConnection>,
+    establish: impl std::future::Future<Output = AppResult<(Arc<RTCDataChannel>, Arc<RTCDataChannel>)>>,
+    received: mpsc::UnboundedReceiver<Option<AppResult<NetworkMessage>>>,
+) -> AppResult<WebRtcConnection> {
+    let result = match timeout(CONNECT_TIMEOUT, establish).await {
+        Ok(result) => result,
+        Err(_) => Err(AppError::NetworkError("Timed out finding a route to the other peer".to_string())),
+    };
+    // Candidates found from here on aren't needed
+    peer.on_ice_candidate(Box::new(|_| Box::pin(async {})));
+    let (reliable, unreliable) = match result {
+        Ok(channels) => channels,
+        Err(error) => {
+            let _ = peer.close().await;
+            return Err(error);
+        }
+    };
+
+    let remote_address = selected_remote_address(peer)
+        .await
+        .unwrap_or_else(|| "a WebRTC peer".to_string());
+    Ok(WebRtcConnection {
+        peer: peer.clone(),
+        reliable,
+        unreliable,
+        remote_address,
+        sending: Mutex::new(()),
+        received: Mutex::new(received),
+    })
+}
+
+async fn new_peer_connection(config: &WebRtcConfig) -> AppResult<Arc<RTCPeerConnection>> {
+    let mut settings = SettingEngine::default();
+    // So two apps on the same machine can reach each other
+    settings.set_include_loopback_candidate(true);
+    let api = APIBuilder::new().with_setting_engine(settings).build();
+
+    let ice_servers = match config.ice_servers.is_empty() {
+        true => Vec::new(),
+        false => vec![RTCIceServer { urls: config.ice_servers.clone(), ..Default::default() }],
+    };
+    let configuration = RTCConfiguration { ice_servers, ..Default::default() };
+    let peer = api.new_peer_connection(configuration).await.map_err(network_error)?;
+    Ok(Arc::new(peer))
+}
+
+/// Pass on messages from a channel, and the channel itself once it opens
+fn wire_channel(channel: &Arc<RTCDataChannel>, incoming: &Incoming, opened: &mpsc::UnboundedSender<Arc<RTCDataChannel>>) {
+    if channel.label() == RELIABLE_LABEL {
+        let mut frames = FrameBuffer::default();
+        let messages = incoming.clone();
+        channel.on_message(Box::new(move |message: DataChannelMessage| {
+            frames.push(&message.data);
+            loop {
+                match frames.next_frame() {
+                    Ok(Some(message)) => {
+                        let _ = messages.send(Some(Ok(message)));
+                    }
+                    Ok(None) => break,
+                    Err(error @ AppError::ProtocolError(_)) => {
+                        let _ = messages.send(Some(Err(error)));
+                    }
+                    Err(error) => {
+                        let _ = messages.send(Some(Err(error)));
+                        break;
+                    }
+                }
+            }
+            Box::pin(async {})
+        }));
+        let closed = incoming.clone();
+        channel.on_close(Box::new(move || {
+            let _ = closed.send(None);
+            Box::pin(async {})
+        }));
+    } else {
+        let messages = incoming.clone();
+        channel.on_message(Box::new(move |message: DataChannelMessage| {
+            let _ = messages.send(Some(protocol::decode_datagram(&message.data)));
+            Box::pin(async {})
+        }));
+    }
+
+    let (channel, opened) = (channel.clone(), opened.clone());
+    channel.clone().on_open(Box::new(move || {
+        let _ = opened.send(channel);
+        Box::pin(async {})
+    }));
+}
+
+/// Treat the connection as closed once it fails for good
+fn watch_state(peer: &RTCPeerConnection, incoming: &Incoming) {
+    let incoming = incoming.clone();
+    peer.on_peer_connection_state_change(Box::new(move |state| {
+        if matches!(state, RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed) {
+            let _ = incoming.send(None);
+        }
+        Box::pin(async {})
+    }));
+}
+
+/// Pass on each ICE candidate to the other peer as it's found
+fn send_candidates(peer: &RTCPeerConnection, signaling: Arc<dyn Signaling>) {
+    peer.on_ice_candidate(Box::new(move |candidate: Option<RTCIceCandidate>| {
+        let signaling = signaling.clone();
+        Box::pin(async move {
+            // `None` marks the end of gathering, which the other peer
+            // doesn't need to hear about
+            let Some(candidate) = candidate else {
+                return;
+            };
+            match candidate.to_json() {
+                Ok(candidate) => {
+                    let signal = Signal::Candidate {
+                        candidate: candidate.candidate,
+                        sdp_mid: candidate.sdp_mid,
+                        sdp_mline_index: candidate.sdp_mline_index,
+                    };
+                    if let Err(error) = signaling.send(signal).await {
+                        tracing::debug!("Couldn't send an ICE candidate: {}", error);
+                    }
+                }
+                Err(error) => tracing::debug!("Couldn't describe an ICE candidate: {}", error),
+            }
+        })
+    }));
+}
+
+/// Answer the other peer's signals until both channels are open
+///
+/// Candidates that arrive before the offer or answer they belong to are held
+/// until it does. The other peer may stop signaling once it's connected, so
+/// after that this just waits for the channels.
+async fn exchange_signals(
+    peer: &RTCPeerConnection,
+    signaling: &dyn Signaling,
+    mut open_channels: mpsc::UnboundedReceiver<Arc<RTCDataChannel>>,
+) -> AppResult<(Arc<RTCDataChannel>, Arc<RTCDataChannel>)> {
+    let mut early_candidates = Vec::new();
+    let mut signaling_open = true;
+    let (mut reliable, mut unreliable) = (None, None);
+
+    while reliable.is_none() || unreliable.is_none() {
+        tokio::select! {
+            Some(channel) = open_channels.recv() => match channel.label() {
+                RELIABLE_LABEL => reliable = Some(channel),
+                UNRELIABLE_LABEL => unreliable = Some(channel),
+                label => tracing::debug!("Ignoring unexpected data channel {}", label),
+            },
+            signal = signaling.recv(), if signaling_open => match signal? {
+                Some(Signal::Offer { sdp }) => {
+                    let offer = RTCSessionDescription::offer(sdp).map_err(protocol_error)?;
+                    peer.set_remote_description(offer).await.map_err(protocol_error)?;
+                    add_candidates(peer, early_candidates.drain(..)).await;
+                    let answer = peer.create_answer(None).await.map_err(network_error)?;
+                    peer.set_local_description(answer.clone()).await.map_err(network_error)?;
+                    signaling.send(Signal::Answer { sdp: answer.sdp }).await?;
+                }
+                Some(Signal::Answer { sdp }) => {
+                    let answer = RTCSessionDescription::answer(sdp).map_err(protocol_error)?;
+                    peer.set_remote_description(answer).await.map_err(protocol_error)?;
+                    add_candidates(peer, early_candidates.drain(..)).await;
+                }
+                Some(Signal::Candidate { candidate, sdp_mid, sdp_mline_index }) => {
+                    let candidate = RTCIceCandidateInit { candidate, sdp_mid, sdp_mline_index, username_fragment: None };
+                    match peer.remote_description().await {
+                        Some(_) => add_candidates(peer, [candidate]).await,
+                        None => early_candidates.push(candidate),
+                    }
+                }
+                None => signaling_open = false,
+            },
+        }
+    }
+    Ok((reliable.expect("loop ends once open"), unreliable.expect("loop ends once open")))
+}
+
+async fn add_candidates(peer: &RTCPeerConnection, candidates: impl IntoIterator<Item = RTCIceCandidateInit>) {
+    for candidate in candidates {
+        // One unusable address doesn't stop the others from working
+        if let Err(error) = peer.add_ice_candidate(candidate).await {
+            tracing::debug!("Ignoring an ICE candidate: {}", error);
+        }
+    }
+}
+
+/// The address of the other peer on the route ICE settled on
+async fn selected_remote_address(peer: &RTCPeerConnection) -> Option<String> {
+    let reports = peer.get_stats().await.reports;
+    let remote_id = reports.values().find_map(|report| match report {
+        StatsReportType::CandidatePair(pair) if pair.nominated => Some(pair.remote_candidate_id.clone()),
+        _ => None,
+    })?;
+    match reports.get(&remote_id)? {
+        StatsReportType::RemoteCandidate(candidate) => Some(format!("{}:{}", candidate.ip, candidate.port)),
+        _ => None,
+    }
+}
+
+// =============================================================================
+// Host Listener
+// =============================================================================
+
+/// Accepts players as their signaling arrives, from a signaling server or
+/// anywhere else
+pub struct WebRtcListener {
+    connections: Mutex<mpsc::Receiver<WebRtcConnection>>,
+    accept_task: JoinHandle<()>,
+}
+
+impl WebRtcListener {
+    pub fn new(mut players: mpsc::UnboundedReceiver<Arc<dyn Signaling>>, config: WebRtcConfig) -> Self {
+        let (sender, receiver) = mpsc::channel(16);
+        let accept_task = tokio::spawn(async move {
+            while let Some(signaling) = players.recv().await {
+                let (sender, config) = (sender.clone(), config.clone());
+                tokio::spawn(async move {
+                    match accept(signaling, &config).await {
+                        Ok(connection) => {
+                            let _ = sender.send(connection).await;
+                        }
+                        Err(error) => tracing::warn!("WebRTC connection failed: {}", error),
+                    }
+                });
+            }
+        });
+        Self { connections: Mutex::new(receiver), accept_task }
+    }
+}
+
+#[async_trait]
+impl Listener for WebRtcListener {
+    async fn accept(&self) -> AppResult<Option<Box<dyn Connection>>> {
+        let connection = self.connections.lock().await.recv().await;
+        Ok(connection.map(|connection| Box::new(connection) as Box<dyn Connection>))
+    }
+
+    fn close(&self) {
+        self.accept_task.abort();
+    }
+}
+
+// =============================================================================
+// Connections
+// =============================================================================
+
+/// A WebRTC peer connection with a reliable and an unreliable data channel
+pub struct WebRtcConnection {
+    peer: Arc<RTCPeerConnection>,
+    reliable: Arc<RTCDataChannel>,
+    unreliable: Arc<RTCDataChannel>,
+    remote_address: String,
+    /// Held while a frame's pieces are sent, so frames don't interleave
+    sending: Mutex<()>,
+    received: Mutex<mpsc::UnboundedReceiver<Option<AppResult<NetworkMessage>>>>,
+}
+
+#[async_trait]
+impl Connection for WebRtcConnection {
+    fn remote_address(&self) -> String {
+        self.remote_address.clone()
+    }
+
+    async fn send(&self, message: &NetworkMessage) -> AppResult<()> {
+        let frame = protocol::encode_frame(message)?;
+        let _sending = self.sending.lock().await;
+        for piece in frame.chunks(MAX_MESSAGE_SIZE) {
+            self.reliable.send(&Bytes::copy_from_slice(piece)).await.map_err(network_error)?;
+        }
+        Ok(())
+    }
+
+    async fn send_unreliable(&self, message: &NetworkMessage) -> AppResult<()> {
+        let frame = protocol::encode_frame(message)?;
+        if frame.len() > MAX_MESSAGE_SIZE {
+            return self.send(message).await;
+        }
+        self.unreliable.send(&Bytes::from(frame)).await.map_err(network_error)?;
+        Ok(())
+    }
+
+    async fn recv(&self) -> AppResult<Option<NetworkMessage>> {
+        let mut received = self.received.lock().await;
+        match received.recv().await {
+            Some(Some(message)) => message.map(Some),
+            Some(None) | None => {
+                // So later calls see the end too, once anything left is read
+                received.close();
+                Ok(None)
+            }
+        }
+    }
+
+    async fn close(&self, reason: &str) {
+        tracing::debug!("Closing WebRTC connection to {}: {}", self.remote_address, reason);
+        let deadline = Instant::now() + CLOSE_GRACE;
+        while self.reliable.buffered_amount().await > 0 && Instant::now() < deadline {
+            sleep(Duration::from_millis(10)).await;
+        }
+        let _ = self.unreliable.close().await;
+        let _ = self.reliable.close().await;
+        let _ = self.peer.close().await;
+    }
+}
+
+fn network_error(error: webrtc::Error) -> AppError {
+    AppError::NetworkError(error.to_string())
+}
+
+fn protocol_error(error: webrtc::Error) -> AppError {
+    AppError::ProtocolError(error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::database::models::{ChatMessage, CursorPing, Payload, Position};
+
+    fn message(payload: Payload) -> NetworkMessage {
+        NetworkMessage {
+            id: Uuid::new_v4().to_string(),
+            sender_id: "player".to_string(),
+            sender_name: "Alice".to_string(),
+            payload,
+            timestamp: Utc::now(),
+        }
+    }
+
+    fn chat(text: &str) -> NetworkMessage {
+        message(Payload::Chat(ChatMessage {
+            message: text.to_string(),
+            is_whisper: false,
+            target_players: None,
+            is_in_character: true,
+        }))
+    }
+
+    fn cursor(x: f32) -> NetworkMessage {
+        message(Payload::Cursor(CursorPing {
+            map_id: "map".to_string(),
+            position: Position { x, y: 0.0, z: None },
+            ping: false,
+        }))
+    }
+
+    async fn pair() -> (WebRtcConnection, WebRtcConnection) {
+        let (player, host) = signaling_pair();
+        let config = WebRtcConfig::default();
+        let (player, host) = tokio::join!(connect(Arc::new(player), &config), accept(Arc::new(host), &config));
+        (player.unwrap(), host.unwrap())
+    }
+
+    async fn next(connection: &WebRtcConnection) -> NetworkMessage {
+        timeout(Duration::from_secs(10), connection.recv())
+            .await
+            .expect("a message in time")
+            .unwrap()
+            .expect("an open connection")
+    }
+
+    fn text(message: &NetworkMessage) -> &str {
+        match &message.payload {
+            Payload::Chat(chat) => &chat.message,
+            other => panic!("expected a chat message, got {:?}", other),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reliable_messages_arrive_in_order_both_ways() {
+        let (player, host) = pair().await;
+
+        // Bigger than one data channel message, so it's sent in pieces
+        let long = "x".repeat(MAX_MESSAGE_SIZE * 3);
+        player.send(&chat(&long)).await.unwrap();
+        player.send(&chat("after")).await.unwrap();
+        assert_eq!(text(&next(&host).await), long);
+        assert_eq!(text(&next(&host).await), "after");
+
+        host.send(&chat("welcome")).await.unwrap();
+        assert_eq!(text(&next(&player).await), "welcome");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn unreliable_messages_arrive_both_ways() {
+        let (player, host) = pair().await;
+
+        player.send_unreliable(&cursor(1.0)).await.unwrap();
+        assert!(matches!(next(&host).await.payload, Payload::Cursor(ping) if ping.position.x == 1.0));
+
+        host.send_unreliable(&cursor(2.0)).await.unwrap();
+        assert!(matches!(next(&player).await.payload, Payload::Cursor(ping) if ping.position.x == 2.0));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn recv_ends_once_the_peer_closes() {
+        let (player, host) = pair().await;
+        player.close("Leaving").await;
+        let ended = timeout(Duration::from_secs(10), host.recv()).await.expect("the close in time");
+        assert!(ended.unwrap().is_none());
+    }
+}